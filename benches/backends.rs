@@ -0,0 +1,336 @@
+//! Times the programs in `benches/lox` on every backend.
+//! The VMs can't compile classes yet, so the programs using them are
+//! skipped on the VMs, with a note saying so.
+//! Only the execution is timed: the programs are parsed or compiled
+//! beforehand, and what they print is discarded by running the benchmarks
+//! in a child process without stdout.
+//!
+//! `cargo bench --bench backends -- [options] [filter]`
+//!
+//! - `--iterations N` runs each program N times, the fastest run is reported
+//! - `--save-baseline` stores the timings as the new baseline
+//! - `--dispatches` counts the instructions executed by the stack and the
+//!   register VMs instead of timing the backends, for the expressions in
+//!   `benches/vm`
+//! - `filter` only runs the benchmarks whose name contains it
+//!
+//! Timings are compared against the baseline stored in
+//! `target/rulox-bench-baseline.txt` (or in `RULOX_BENCH_BASELINE`), and
+//! the run fails when a benchmark got slower by more than the threshold.
+//! Baselines only make sense on the machine they were recorded on.
+//!
+//! For profiling, run a single benchmark on the compiled binary under a
+//! profiler, setting `RULOX_BENCH_CHILD` for it not to start a child, e.g.
+//! `RULOX_BENCH_CHILD=1 perf record target/release/deps/backends-<hash> fib`.
+//! Release builds keep debug symbols.
+
+extern crate rulox;
+
+use rulox::treewalk::{ClosureRuloxInterpreter, Script, TreeWalkRuloxInterpreter};
+use rulox::user_interface::RunError;
+use rulox::vm;
+use rulox::vm::register::RegisterVm;
+use rulox::vm::LoxVm;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// How much slower than the baseline a benchmark can get before it's
+/// reported as a regression
+const REGRESSION_THRESHOLD: f64 = 0.2;
+
+/// Set in the child process that runs the benchmarks
+const CHILD: &str = "RULOX_BENCH_CHILD";
+
+/// Why a benchmark has no timing
+enum Failure {
+    /// The backend can't compile the program yet, which is expected of
+    /// the VMs for now
+    Unsupported,
+    Failed(RunError),
+}
+
+impl From<RunError> for Failure {
+    fn from(error: RunError) -> Failure {
+        Failure::Failed(error)
+    }
+}
+
+/// The VMs don't compile classes yet
+fn unsupported(error: RunError) -> Failure {
+    match error {
+        RunError::CompileError(_) => Failure::Unsupported,
+        error => Failure::Failed(error),
+    }
+}
+
+/// The backends, which parse or compile a program before running it
+trait Backend: Default {
+    type Program;
+    fn prepare(&mut self, source: &str) -> Result<Self::Program, Failure>;
+    fn execute(&mut self, program: &Self::Program) -> Result<(), RunError>;
+}
+
+impl Backend for TreeWalkRuloxInterpreter {
+    type Program = Script;
+
+    fn prepare(&mut self, source: &str) -> Result<Script, Failure> {
+        Ok(self.parse(source)?)
+    }
+
+    fn execute(&mut self, script: &Script) -> Result<(), RunError> {
+        self.run_script(script)
+    }
+}
+
+impl Backend for ClosureRuloxInterpreter {
+    type Program = Script;
+
+    fn prepare(&mut self, source: &str) -> Result<Script, Failure> {
+        Ok(self.parse(source)?)
+    }
+
+    fn execute(&mut self, script: &Script) -> Result<(), RunError> {
+        self.run_script(script)
+    }
+}
+
+impl Backend for LoxVm {
+    type Program = vm::bytecode::Chunk;
+
+    fn prepare(&mut self, source: &str) -> Result<Self::Program, Failure> {
+        self.compile(source).map_err(unsupported)
+    }
+
+    fn execute(&mut self, chunk: &Self::Program) -> Result<(), RunError> {
+        self.run_chunk(chunk)
+    }
+}
+
+impl Backend for RegisterVm {
+    type Program = vm::register::bytecode::Chunk;
+
+    fn prepare(&mut self, source: &str) -> Result<Self::Program, Failure> {
+        self.compile(source).map_err(unsupported)
+    }
+
+    fn execute(&mut self, chunk: &Self::Program) -> Result<(), RunError> {
+        self.run_chunk(chunk)
+    }
+}
+
+type Measure = fn(&str) -> Result<Duration, Failure>;
+
+const BACKENDS: [(&str, Measure); 4] = [
+    ("treewalk", measure::<TreeWalkRuloxInterpreter>),
+    ("closures", measure::<ClosureRuloxInterpreter>),
+    ("vm", measure::<LoxVm>),
+    ("register", measure::<RegisterVm>),
+];
+
+fn measure<B: Backend>(source: &str) -> Result<Duration, Failure> {
+    let mut backend = B::default();
+    let program = backend.prepare(source)?;
+    let start = Instant::now();
+    backend.execute(&program)?;
+    Ok(start.elapsed())
+}
+
+/// Runs the benchmarks again in a child process whose stdout is
+/// discarded, and exits with its exit code
+fn run_without_stdout() -> ! {
+    let status = Command::new(env::current_exe().unwrap())
+        .args(env::args_os().skip(1))
+        .env(CHILD, "1")
+        .stdout(Stdio::null())
+        .status()
+        .unwrap_or_else(|e| panic!("Cannot run the benchmarks: {}", e));
+    process::exit(status.code().unwrap_or(1))
+}
+
+struct Options {
+    iterations: usize,
+    save_baseline: bool,
+    dispatches: bool,
+    filter: Option<String>,
+}
+
+fn parse_options() -> Options {
+    let mut options = Options {
+        iterations: 3,
+        save_baseline: false,
+        dispatches: false,
+        filter: None,
+    };
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            // Passed by cargo bench
+            "--bench" => {}
+            "--save-baseline" => options.save_baseline = true,
+            "--dispatches" => options.dispatches = true,
+            "--iterations" => {
+                options.iterations = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .filter(|&n| n > 0)
+                    .expect("--iterations needs a positive number")
+            }
+            _ => options.filter = Some(arg),
+        }
+    }
+    options
+}
+
+fn baseline_file() -> PathBuf {
+    match env::var("RULOX_BENCH_BASELINE") {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => Path::new(env!("CARGO_MANIFEST_DIR")).join("target/rulox-bench-baseline.txt"),
+    }
+}
+
+/// Seconds taken by each benchmark, keyed by backend and benchmark name
+type Timings = BTreeMap<(String, String), f64>;
+
+fn load_baseline(path: &Path) -> Timings {
+    let mut baseline = Timings::new();
+    for line in fs::read_to_string(path).unwrap_or_default().lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if let [backend, benchmark, seconds] = fields[..] {
+            if let Ok(seconds) = seconds.parse() {
+                baseline.insert((backend.into(), benchmark.into()), seconds);
+            }
+        }
+    }
+    baseline
+}
+
+fn save_baseline(path: &Path, timings: &Timings) {
+    let mut contents = String::new();
+    for (&(ref backend, ref benchmark), seconds) in timings {
+        contents.push_str(&format!("{} {} {:.6}\n", backend, benchmark, seconds));
+    }
+    fs::write(path, contents).unwrap_or_else(|e| panic!("Cannot write {}: {}", path.display(), e));
+}
+
+fn benchmarks(directory: &str, filter: &Option<String>) -> Vec<(String, String)> {
+    let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join(directory);
+    let mut benchmarks: Vec<(String, String)> = fs::read_dir(&directory)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| {
+            path.extension()
+                .map_or(false, |extension| extension == "lox")
+        })
+        .map(|path| {
+            let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+            (name, fs::read_to_string(&path).unwrap())
+        })
+        .filter(|&(ref name, _)| filter.as_ref().map_or(true, |f| name.contains(f.as_str())))
+        .collect();
+    benchmarks.sort();
+    benchmarks
+}
+
+/// Prints how many instructions each VM executes, which doesn't depend
+/// on the machine
+fn count_dispatches(filter: &Option<String>) {
+    for (name, source) in benchmarks("benches/vm", filter) {
+        match (
+            vm::dispatch_count(&source),
+            vm::register::dispatch_count(&source),
+        ) {
+            (Ok(stack), Ok(registers)) => eprintln!(
+                "{:30} vm {:8}  register {:8}  {:+.1}%",
+                name,
+                stack,
+                registers,
+                (registers as f64 - stack as f64) / stack as f64 * 100.0
+            ),
+            (Err(error), _) | (_, Err(error)) => {
+                let reason = error.to_string();
+                eprintln!(
+                    "{:30} failed: {}",
+                    name,
+                    reason.lines().next().unwrap_or("")
+                );
+            }
+        }
+    }
+}
+
+fn main() {
+    if env::var_os(CHILD).is_none() {
+        run_without_stdout();
+    }
+    let options = parse_options();
+    if options.dispatches {
+        count_dispatches(&options.filter);
+        return;
+    }
+    let baseline_file = baseline_file();
+    let baseline = load_baseline(&baseline_file);
+    let mut timings = Timings::new();
+    let mut regressions = 0;
+    for (name, source) in benchmarks("benches/lox", &options.filter) {
+        for &(backend, measure) in &BACKENDS {
+            let mut fastest: Option<Duration> = None;
+            let mut error = None;
+            for _ in 0..options.iterations {
+                match measure(&source) {
+                    Ok(time) => fastest = Some(fastest.map_or(time, |f| f.min(time))),
+                    Err(e) => {
+                        error = Some(e);
+                        break;
+                    }
+                }
+            }
+            let label = format!("{}/{}", backend, name);
+            let seconds = match (error, fastest) {
+                (Some(Failure::Unsupported), _) => {
+                    eprintln!("{:30} skipped: the backend can't compile it yet", label);
+                    continue;
+                }
+                (Some(Failure::Failed(error)), _) => {
+                    let reason = error.to_string();
+                    eprintln!(
+                        "{:30} failed: {}",
+                        label,
+                        reason.lines().next().unwrap_or("")
+                    );
+                    continue;
+                }
+                (None, Some(fastest)) => fastest.as_secs_f64(),
+                (None, None) => unreachable!("There's at least one iteration"),
+            };
+            let key = (backend.to_string(), name.clone());
+            let comparison = match baseline.get(&key) {
+                Some(&previous) => {
+                    let change = (seconds - previous) / previous;
+                    if change > REGRESSION_THRESHOLD {
+                        regressions += 1;
+                        format!("{:+.1}% REGRESSION", change * 100.0)
+                    } else {
+                        format!("{:+.1}%", change * 100.0)
+                    }
+                }
+                None => "no baseline".to_string(),
+            };
+            eprintln!("{:30} {:10.3} ms  {}", label, seconds * 1000.0, comparison);
+            timings.insert(key, seconds);
+        }
+    }
+    if options.save_baseline {
+        // Keep the timings of the benchmarks that were filtered out
+        let mut updated = baseline;
+        updated.extend(timings);
+        save_baseline(&baseline_file, &updated);
+        eprintln!("Baseline saved to {}", baseline_file.display());
+    } else if regressions > 0 {
+        eprintln!("{} benchmarks got slower than the baseline", regressions);
+        process::exit(1);
+    }
+}
@@ -1,12 +1,10 @@
-use rulox::treewalk::TreeWalkRuloxInterpreter;
-use rulox::user_interface::Runner;
-use std::env;
-
 extern crate rulox;
 
+use rulox::user_interface::cli::Backend;
+use std::env;
+
 fn main() {
     let args: Vec<String> = env::args().collect();
-    let mut runner = Runner::new(TreeWalkRuloxInterpreter::default());
-    let exit_code = runner.run(&args);
+    let exit_code = rulox::user_interface::main(&args, Backend::TreeWalk);
     std::process::exit(exit_code)
 }
@@ -1,12 +1,10 @@
 extern crate rulox;
 
-use rulox::user_interface::Runner;
-use rulox::vm::LoxVm;
+use rulox::user_interface::cli::Backend;
 use std::env;
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    let mut runner = Runner::new(LoxVm::default());
-    let exit_code = runner.run(&args);
+    let exit_code = rulox::user_interface::main(&args, Backend::Vm);
     std::process::exit(exit_code)
 }
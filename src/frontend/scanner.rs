@@ -1,12 +1,16 @@
 use itertools::{multipeek, MultiPeek};
+use std::fmt;
 use std::str;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum Token {
     LeftParen,
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
+    Colon,
     Comma,
     Dot,
     Minus,
@@ -14,6 +18,9 @@ pub enum Token {
     Semicolon,
     Slash,
     Star,
+    Percent,
+    /// `~/`, the integer division
+    TildeSlash,
     Bang,
     BangEqual,
     Equal,
@@ -24,22 +31,33 @@ pub enum Token {
     LessEqual,
     Identifier(String),
     StringLiteral(String),
+    /// A string up to an interpolated expression `${`. The expression
+    /// follows, then the rest of the string, starting with its `}`, as
+    /// another fragment or as a literal.
+    StringFragment(String),
     NumberLiteral(f64),
     // Keywords
     And,
+    Break,
+    Catch,
     Class,
+    Continue,
     Else,
     False,
+    Finally,
     Fun,
     For,
     If,
+    Import,
     Nil,
     Or,
     Print,
     Return,
     Super,
     This,
+    Throw,
     True,
+    Try,
     Var,
     While,
 
@@ -51,6 +69,68 @@ pub enum Token {
     Whitespace,
 }
 
+impl fmt::Display for Token {
+    /// Describes the token the way it appears in the source,
+    /// or its kind when it carries a value
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let description = match *self {
+            Token::LeftParen => "'('",
+            Token::RightParen => "')'",
+            Token::LeftBrace => "'{'",
+            Token::RightBrace => "'}'",
+            Token::LeftBracket => "'['",
+            Token::RightBracket => "']'",
+            Token::Colon => "':'",
+            Token::Comma => "','",
+            Token::Dot => "'.'",
+            Token::Minus => "'-'",
+            Token::Plus => "'+'",
+            Token::Semicolon => "';'",
+            Token::Slash => "'/'",
+            Token::Star => "'*'",
+            Token::Percent => "'%'",
+            Token::TildeSlash => "'~/'",
+            Token::Bang => "'!'",
+            Token::BangEqual => "'!='",
+            Token::Equal => "'='",
+            Token::EqualEqual => "'=='",
+            Token::Greater => "'>'",
+            Token::GreaterEqual => "'>='",
+            Token::Less => "'<'",
+            Token::LessEqual => "'<='",
+            Token::Identifier(_) => "identifier",
+            Token::StringLiteral(_) | Token::StringFragment(_) => "string",
+            Token::NumberLiteral(_) => "number",
+            Token::And => "'and'",
+            Token::Break => "'break'",
+            Token::Catch => "'catch'",
+            Token::Class => "'class'",
+            Token::Continue => "'continue'",
+            Token::Else => "'else'",
+            Token::False => "'false'",
+            Token::Finally => "'finally'",
+            Token::Fun => "'fun'",
+            Token::For => "'for'",
+            Token::If => "'if'",
+            Token::Import => "'import'",
+            Token::Nil => "'nil'",
+            Token::Or => "'or'",
+            Token::Print => "'print'",
+            Token::Return => "'return'",
+            Token::Super => "'super'",
+            Token::This => "'this'",
+            Token::Throw => "'throw'",
+            Token::True => "'true'",
+            Token::Try => "'try'",
+            Token::Var => "'var'",
+            Token::While => "'while'",
+            Token::Comment => "comment",
+            Token::Whitespace => "whitespace",
+        };
+        f.write_str(description)
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 /// Represents a position in the source file.
 /// Both line and column are represented by a 1-based
@@ -62,7 +142,7 @@ pub struct Position {
 }
 
 impl Position {
-    fn initial() -> Position {
+    pub fn initial() -> Position {
         Position { line: 1, column: 1 }
     }
 
@@ -76,6 +156,12 @@ impl Position {
     }
 }
 
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[line {}, column {}]", self.line, self.column)
+    }
+}
+
 pub type Lexeme = String;
 
 #[derive(Debug)]
@@ -91,12 +177,43 @@ pub struct TokenWithContext {
 pub enum ScannerError {
     MissingStringTerminator(Position),
     UnexpectedCharacter(char, Position),
+    /// A backslash followed by a character that can't be escaped, the
+    /// position being the one of the backslash
+    InvalidEscape(char, Position),
+    /// A `\u` not followed by the hexadecimal code of a character between
+    /// braces
+    InvalidUnicodeEscape(Position),
+}
+
+impl fmt::Display for ScannerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ScannerError::MissingStringTerminator(ref position) => {
+                write!(f, "{} Error: unterminated string", position)
+            }
+            ScannerError::UnexpectedCharacter(c, ref position) => {
+                write!(f, "{} Error: unexpected character '{}'", position, c)
+            }
+            ScannerError::InvalidEscape(c, ref position) => {
+                write!(f, "{} Error: invalid escape sequence '\\{}'", position, c)
+            }
+            ScannerError::InvalidUnicodeEscape(ref position) => {
+                write!(f, "{} Error: invalid unicode escape sequence", position)
+            }
+        }
+    }
 }
 
 struct Scanner<'a> {
     current_position: Position,
     current_lexeme: String,
     source: MultiPeek<str::Chars<'a>>,
+    /// The braces opened in each interpolated expression being scanned,
+    /// innermost last. The `}` closing the expression resumes its string.
+    interpolations: Vec<usize>,
+    /// The malformed escape sequences of the last string, reported after
+    /// it so that the parsers still get the string
+    escape_errors: Vec<ScannerError>,
 }
 
 fn is_digit(c: char) -> bool {
@@ -124,6 +241,8 @@ impl<'a> Scanner<'a> {
             current_position: Position::initial(),
             current_lexeme: "".into(),
             source: multipeek(source.chars()),
+            interpolations: vec![],
+            escape_errors: vec![],
         }
     }
 
@@ -153,7 +272,7 @@ impl<'a> Scanner<'a> {
             if c == '\n' {
                 self.current_position.increment_line();
             } else {
-                self.current_position.increment_column();;
+                self.current_position.increment_column();
             }
         };
         next
@@ -182,49 +301,156 @@ impl<'a> Scanner<'a> {
         }
     }
 
+    /// Scans a string, which can span several lines, up to its end or
+    /// to an interpolated expression
     fn string(&mut self) -> Result<Token, ScannerError> {
-        self.advance_while(&|c| c != '"' && c != '\n');
-        if !self.advance_if_match('"') {
-            return Err(ScannerError::MissingStringTerminator(self.current_position));
+        let mut value = String::new();
+        loop {
+            let position = self.current_position;
+            match self.advance() {
+                None => return Err(ScannerError::MissingStringTerminator(self.current_position)),
+                Some('"') => break,
+                Some('\\') => match self.escape(position) {
+                    Ok(c) => value.push(c),
+                    Err(error) => self.escape_errors.push(error),
+                },
+                Some('$') if self.advance_if_match('{') => {
+                    self.interpolations.push(0);
+                    return Ok(Token::StringFragment(value));
+                }
+                Some(c) => value.push(c),
+            }
         }
-        let literal_length = self.current_lexeme.len() - 2;
-        // Trims delimiters
-        let literal = self
-            .current_lexeme
-            .chars()
-            .skip(1)
-            .take(literal_length)
-            .collect();
-        Ok(Token::StringLiteral(literal))
-    }
-
-    fn number(&mut self) -> Token {
-        self.advance_while(&is_digit);
+        Ok(Token::StringLiteral(value))
+    }
+
+    /// The character escaped by the backslash at the given position
+    fn escape(&mut self, position: Position) -> Result<char, ScannerError> {
+        match self.advance() {
+            Some('n') => Ok('\n'),
+            Some('t') => Ok('\t'),
+            Some('r') => Ok('\r'),
+            Some('0') => Ok('\0'),
+            Some(c @ '"') | Some(c @ '\\') | Some(c @ '$') => Ok(c),
+            Some('u') => self.unicode_escape(position),
+            Some(c) => Err(ScannerError::InvalidEscape(c, position)),
+            // Left to the string to report
+            None => Ok('\\'),
+        }
+    }
+
+    /// `\u{...}` with up to 6 hexadecimal digits
+    fn unicode_escape(&mut self, position: Position) -> Result<char, ScannerError> {
+        if !self.advance_if_match('{') {
+            return Err(ScannerError::InvalidUnicodeEscape(position));
+        }
+        let mut digits = String::new();
+        while self.peek_check(&|c| c.is_ascii_hexdigit()) {
+            digits.extend(self.advance());
+        }
+        if !self.advance_if_match('}') || digits.is_empty() || digits.len() > 6 {
+            return Err(ScannerError::InvalidUnicodeEscape(position));
+        }
+        u32::from_str_radix(&digits, 16)
+            .ok()
+            .and_then(char::from_u32)
+            .ok_or(ScannerError::InvalidUnicodeEscape(position))
+    }
+
+    /// Decimal numbers, with an optional fractional part and exponent,
+    /// or whole numbers in hexadecimal (`0x`) or binary (`0b`). Digits can
+    /// be separated by single underscores.
+    fn number(&mut self, first: char) -> Token {
+        if first == '0' {
+            if let Some(value) = self.radix_number() {
+                return Token::NumberLiteral(value);
+            }
+        }
+        self.digits(&is_digit);
         if self.peek_check2(&|p1| p1 == '.', &is_digit) {
             self.advance(); // Consume the .
-            self.advance_while(&is_digit);
+            self.digits(&is_digit);
+        }
+        if self.exponent_follows() {
+            self.advance(); // Consume the e
+            let _ = self.advance_if_match('+') || self.advance_if_match('-');
+            self.digits(&is_digit);
         }
-        let value = self.current_lexeme.parse::<f64>().unwrap();
+        let value = self.current_lexeme.replace('_', "").parse::<f64>().unwrap();
         Token::NumberLiteral(value)
     }
 
+    /// The digits of a hexadecimal or binary number, after its `0`
+    fn radix_number(&mut self) -> Option<f64> {
+        let radix = if self.peek_check2(&|c| c == 'x' || c == 'X', &|c| c.is_ascii_hexdigit()) {
+            16
+        } else if self.peek_check2(&|c| c == 'b' || c == 'B', &|c| c.is_digit(2)) {
+            2
+        } else {
+            return None;
+        };
+        self.advance(); // Consume the x or b
+        let start = self.current_lexeme.len();
+        self.digits(&|c| c.is_digit(radix));
+        Some(
+            self.current_lexeme[start..]
+                .chars()
+                .filter_map(|c| c.to_digit(radix))
+                .fold(0.0, |value, digit| {
+                    value * f64::from(radix) + f64::from(digit)
+                }),
+        )
+    }
+
+    /// Digits, an underscore being accepted between two of them
+    fn digits(&mut self, is_digit: &dyn Fn(char) -> bool) {
+        loop {
+            if self.peek_check(is_digit) || self.peek_check2(&|c| c == '_', is_digit) {
+                self.advance();
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Whether an `e` followed by the digits of an exponent is next
+    fn exponent_follows(&mut self) -> bool {
+        self.source.reset_peek();
+        match self.source.peek() {
+            Some(&'e') | Some(&'E') => {}
+            _ => return false,
+        }
+        match self.source.peek() {
+            Some(&'+') | Some(&'-') => self.source.peek().is_some_and(|&c| is_digit(c)),
+            Some(&c) => is_digit(c),
+            None => false,
+        }
+    }
+
     fn identifier(&mut self) -> Token {
         self.advance_while(&is_alphanumeric);
         match self.current_lexeme.as_ref() {
             "and" => Token::And,
+            "break" => Token::Break,
+            "catch" => Token::Catch,
             "class" => Token::Class,
+            "continue" => Token::Continue,
             "else" => Token::Else,
             "false" => Token::False,
+            "finally" => Token::Finally,
             "for" => Token::For,
             "fun" => Token::Fun,
             "if" => Token::If,
+            "import" => Token::Import,
             "nil" => Token::Nil,
             "or" => Token::Or,
             "print" => Token::Print,
             "return" => Token::Return,
             "super" => Token::Super,
             "this" => Token::This,
+            "throw" => Token::Throw,
             "true" => Token::True,
+            "try" => Token::Try,
             "var" => Token::Var,
             "while" => Token::While,
             identifier => Token::Identifier(identifier.into()),
@@ -232,25 +458,64 @@ impl<'a> Scanner<'a> {
     }
 
     fn scan_next(&mut self) -> Option<Result<TokenWithContext, ScannerError>> {
+        if !self.escape_errors.is_empty() {
+            return Some(Err(self.escape_errors.remove(0)));
+        }
         let initial_position = self.current_position;
         self.current_lexeme.clear();
         // Check if there is something left to iterate on.
         // Early return if we're done.
         let next_char = match self.advance() {
             Some(c) => c,
+            // The input ended in an interpolated expression
+            None if !self.interpolations.is_empty() => {
+                self.interpolations.clear();
+                return Some(Err(ScannerError::MissingStringTerminator(
+                    self.current_position,
+                )));
+            }
             None => return None,
         };
         let result = match next_char {
             '(' => Ok(Token::LeftParen),
             ')' => Ok(Token::RightParen),
-            '{' => Ok(Token::LeftBrace),
-            '}' => Ok(Token::RightBrace),
+            '{' => {
+                if let Some(braces) = self.interpolations.last_mut() {
+                    *braces += 1;
+                }
+                Ok(Token::LeftBrace)
+            }
+            '}' => match self.interpolations.last_mut() {
+                Some(braces) if *braces > 0 => {
+                    *braces -= 1;
+                    Ok(Token::RightBrace)
+                }
+                Some(_) => {
+                    self.interpolations.pop();
+                    self.string()
+                }
+                None => Ok(Token::RightBrace),
+            },
+            '[' => Ok(Token::LeftBracket),
+            ']' => Ok(Token::RightBracket),
+            ':' => Ok(Token::Colon),
             ',' => Ok(Token::Comma),
             '.' => Ok(Token::Dot),
             '-' => Ok(Token::Minus),
             '+' => Ok(Token::Plus),
             ';' => Ok(Token::Semicolon),
             '*' => Ok(Token::Star),
+            '%' => Ok(Token::Percent),
+            '~' => {
+                if self.advance_if_match('/') {
+                    Ok(Token::TildeSlash)
+                } else {
+                    Err(ScannerError::UnexpectedCharacter(
+                        '~',
+                        self.current_position,
+                    ))
+                }
+            }
             '!' => {
                 if self.advance_if_match('=') {
                     Ok(Token::BangEqual)
@@ -290,7 +555,7 @@ impl<'a> Scanner<'a> {
             }
             '"' => self.string(),
             c if is_whitespace(c) => Ok(Token::Whitespace),
-            c if is_digit(c) => Ok(self.number()),
+            c if is_digit(c) => Ok(self.number(c)),
             c if is_alpha(c) => Ok(self.identifier()),
             c => Err(ScannerError::UnexpectedCharacter(c, self.current_position)),
         };
@@ -393,6 +658,57 @@ mod tests {
         assert_eq!(tokens[9].position.column, 32); // There are 16 spaces before the text!
     }
 
+    #[test]
+    fn number_literals() {
+        let (tokens, errors) = scan(&"0x1F 0B101 1_000_000 1.5e3 2E-2 7e+1 0.5");
+        assert!(errors.is_empty());
+        let numbers: Vec<Token> = tokens.into_iter().map(|t| t.token).collect();
+        assert_eq!(
+            vec![31.0, 5.0, 1_000_000.0, 1500.0, 0.02, 70.0, 0.5]
+                .into_iter()
+                .map(Token::NumberLiteral)
+                .collect::<Vec<Token>>(),
+            numbers
+        );
+        // What doesn't continue a number starts the next token
+        let (tokens, _) = scan(&"1e 1_ 0x 2__0");
+        let tokens: Vec<Token> = tokens.into_iter().map(|t| t.token).collect();
+        assert_eq!(
+            vec![
+                Token::NumberLiteral(1.0),
+                Token::Identifier("e".into()),
+                Token::NumberLiteral(1.0),
+                Token::Identifier("_".into()),
+                Token::NumberLiteral(0.0),
+                Token::Identifier("x".into()),
+                Token::NumberLiteral(2.0),
+                Token::Identifier("__0".into()),
+            ],
+            tokens
+        );
+    }
+
+    #[test]
+    fn modulo_and_integer_division() {
+        let (tokens, errors) = scan(&"7 % 2 ~/ 1 ~ 3");
+        let tokens: Vec<Token> = tokens.into_iter().map(|t| t.token).collect();
+        assert_eq!(
+            vec![
+                Token::NumberLiteral(7.0),
+                Token::Percent,
+                Token::NumberLiteral(2.0),
+                Token::TildeSlash,
+                Token::NumberLiteral(1.0),
+                Token::NumberLiteral(3.0),
+            ],
+            tokens
+        );
+        assert_eq!(
+            "[line 1, column 13] Error: unexpected character '~'",
+            errors[0].to_string()
+        );
+    }
+
     #[test]
     fn expression_with_bad_character() {
         let (tokens, errors) = scan(&"1 + $2");
@@ -406,19 +722,91 @@ mod tests {
     fn statements_with_unterminated_string() {
         let (tokens, errors) = scan(
             &r#"var a = "Unterminated;
-                var b = "Hello";"#,
+                var b = 1;"#,
         );
         assert_eq!(tokens[0].token, Token::Var);
         assert_eq!(tokens[1].token, Token::Identifier("a".into()));
         assert_eq!(tokens[2].token, Token::Equal);
-        // Literal not reported, it wasn't terminated
-        // Semicolon got embedded into the string
-        assert_eq!(tokens[3].token, Token::Var);
-        assert_eq!(tokens[4].token, Token::Identifier("b".into()));
-        assert_eq!(tokens[5].token, Token::Equal);
-        assert_eq!(tokens[6].token, Token::StringLiteral("Hello".into()));
-        assert_eq!(tokens[7].token, Token::Semicolon);
+        // Strings can span lines, so the rest of the input got embedded
+        // into the string, which wasn't reported as it wasn't terminated
+        assert_eq!(tokens.len(), 3);
         assert_eq!(errors.len(), 1);
     }
 
+    #[test]
+    fn multiline_and_non_ascii_strings() {
+        let (tokens, errors) = scan(&"\"héllo\nÀ\" 1");
+        assert!(errors.is_empty());
+        assert_eq!(tokens[0].token, Token::StringLiteral("héllo\nÀ".into()));
+        assert_eq!(tokens[1].position.line, 2);
+        assert_eq!(tokens[1].position.column, 4);
+    }
+
+    #[test]
+    fn escape_sequences() {
+        let (tokens, errors) = scan(&r#""\"\\\n\t\r\0\$\u{e9}\u{1F600}""#);
+        assert!(errors.is_empty());
+        assert_eq!(
+            tokens[0].token,
+            Token::StringLiteral("\"\\\n\t\r\0$é😀".into())
+        );
+    }
+
+    #[test]
+    fn malformed_escape_sequences() {
+        let (tokens, errors) = scan(&r#"1 "a\q \u{110000}b" 2 "\u{}" "\u41""#);
+        let tokens: Vec<Token> = tokens.into_iter().map(|t| t.token).collect();
+        // The strings are still scanned, without what's malformed
+        assert_eq!(
+            vec![
+                Token::NumberLiteral(1.0f64),
+                Token::StringLiteral("a b".into()),
+                Token::NumberLiteral(2.0f64),
+                Token::StringLiteral("".into()),
+                Token::StringLiteral("41".into()),
+            ],
+            tokens
+        );
+        let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            vec![
+                "[line 1, column 5] Error: invalid escape sequence '\\q'",
+                "[line 1, column 8] Error: invalid unicode escape sequence",
+                "[line 1, column 24] Error: invalid unicode escape sequence",
+                "[line 1, column 31] Error: invalid unicode escape sequence",
+            ],
+            errors
+        );
+    }
+
+    #[test]
+    fn interpolations() {
+        let (tokens, errors) = scan(&r#""a ${ {"b": "${c}"}["b"] } d${e}""#);
+        assert!(errors.is_empty());
+        let tokens: Vec<Token> = tokens.into_iter().map(|t| t.token).collect();
+        assert_eq!(
+            vec![
+                Token::StringFragment("a ".into()),
+                Token::LeftBrace,
+                Token::StringLiteral("b".into()),
+                Token::Colon,
+                Token::StringFragment("".into()),
+                Token::Identifier("c".into()),
+                Token::StringLiteral("".into()),
+                Token::RightBrace,
+                Token::LeftBracket,
+                Token::StringLiteral("b".into()),
+                Token::RightBracket,
+                Token::StringFragment(" d".into()),
+                Token::Identifier("e".into()),
+                Token::StringLiteral("".into()),
+            ],
+            tokens
+        );
+        let (_, errors) = scan(&r#""a ${b"#);
+        assert_eq!(
+            "[line 1, column 7] Error: unterminated string",
+            errors[0].to_string()
+        );
+    }
 }
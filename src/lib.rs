@@ -1,11 +1,19 @@
 mod frontend;
+mod map;
+mod natives;
+mod number;
+mod output;
 pub mod treewalk;
 pub mod user_interface;
 pub mod vm;
 
+pub use natives::Capabilities;
+
+extern crate dirs_next;
 extern crate fnv;
 extern crate itertools;
 extern crate num_traits;
+extern crate rustyline;
 
 #[macro_use]
 extern crate num_derive;
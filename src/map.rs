@@ -0,0 +1,153 @@
+//! The map shared by the backends for Lox maps.
+//!
+//! Only nil, booleans, numbers and strings can be used as keys, as they
+//! are compared by value. Entries are kept in the order their keys were
+//! first inserted, so that maps are printed and iterated the same way by
+//! every backend.
+
+use fnv::FnvHashMap;
+use number;
+use std::fmt;
+use std::rc::Rc;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Key {
+    Nil,
+    Boolean(bool),
+    /// The bits of the number, see `Key::number`
+    Number(u64),
+    String(Rc<str>),
+}
+
+impl Key {
+    pub fn number(n: f64) -> Key {
+        // 0 and -0 are equal, so they must be the same key
+        let n = if n == 0.0 { 0.0 } else { n };
+        Key::Number(n.to_bits())
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Key::Nil => f.write_str("nil"),
+            Key::Boolean(b) => write!(f, "{}", b),
+            Key::Number(bits) => f.write_str(&number::format(f64::from_bits(bits))),
+            Key::String(ref s) => f.write_str(s),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OrderedMap<V> {
+    entries: Vec<(Key, V)>,
+    /// Where the entry of each key is
+    positions: FnvHashMap<Key, usize>,
+}
+
+impl<V> OrderedMap<V> {
+    pub fn new() -> OrderedMap<V> {
+        OrderedMap {
+            entries: vec![],
+            positions: FnvHashMap::default(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn get(&self, key: &Key) -> Option<&V> {
+        self.positions.get(key).map(|&i| &self.entries[i].1)
+    }
+
+    pub fn contains_key(&self, key: &Key) -> bool {
+        self.positions.contains_key(key)
+    }
+
+    /// Replacing the value of a key keeps it where it was
+    pub fn insert(&mut self, key: Key, value: V) {
+        match self.positions.get(&key) {
+            Some(&i) => self.entries[i].1 = value,
+            None => {
+                self.positions.insert(key.clone(), self.entries.len());
+                self.entries.push((key, value));
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: &Key) -> Option<V> {
+        let position = self.positions.remove(key)?;
+        let (_, value) = self.entries.remove(position);
+        for (key, _) in &self.entries[position..] {
+            *self.positions.get_mut(key).unwrap() -= 1;
+        }
+        Some(value)
+    }
+
+    pub fn iter(&self) -> ::std::slice::Iter<'_, (Key, V)> {
+        self.entries.iter()
+    }
+}
+
+/// Maps are equal when they have the same entries, in any order
+impl<V: PartialEq> PartialEq for OrderedMap<V> {
+    fn eq(&self, other: &OrderedMap<V>) -> bool {
+        self.len() == other.len()
+            && self
+                .iter()
+                .all(|(key, value)| other.get(key) == Some(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use map::{Key, OrderedMap};
+
+    fn keys(map: &OrderedMap<u32>) -> Vec<String> {
+        map.iter().map(|(key, _)| key.to_string()).collect()
+    }
+
+    #[test]
+    fn insertion_order_is_kept() {
+        let mut map = OrderedMap::new();
+        map.insert(Key::String("b".into()), 1);
+        map.insert(Key::Nil, 2);
+        map.insert(Key::number(1.5), 3);
+        map.insert(Key::String("b".into()), 4);
+        assert_eq!(vec!["b", "nil", "1.5"], keys(&map));
+        assert_eq!(Some(&4), map.get(&Key::String("b".into())));
+    }
+
+    #[test]
+    fn removing_keeps_the_other_entries() {
+        let mut map = OrderedMap::new();
+        for i in 0..4 {
+            map.insert(Key::number(f64::from(i)), i);
+        }
+        assert_eq!(Some(1), map.remove(&Key::number(1.0)));
+        assert_eq!(None, map.remove(&Key::number(1.0)));
+        assert_eq!(vec!["0", "2", "3"], keys(&map));
+        assert_eq!(Some(&3), map.get(&Key::number(3.0)));
+        map.insert(Key::Boolean(true), 5);
+        assert_eq!(Some(&5), map.get(&Key::Boolean(true)));
+    }
+
+    #[test]
+    fn zeros_are_the_same_key() {
+        assert_eq!(Key::number(0.0), Key::number(-0.0));
+    }
+
+    #[test]
+    fn equality_ignores_the_order() {
+        let mut first = OrderedMap::new();
+        first.insert(Key::Nil, 1);
+        first.insert(Key::Boolean(false), 2);
+        let mut second = OrderedMap::new();
+        second.insert(Key::Boolean(false), 2);
+        second.insert(Key::Nil, 1);
+        assert_eq!(first, second);
+        second.insert(Key::Nil, 3);
+        assert_ne!(first, second);
+    }
+}
@@ -0,0 +1,495 @@
+//! The standard library natives of the backends that can call functions.
+//!
+//! They only deal with nil, booleans, numbers and strings, so they are
+//! written once against `NativeValue`, which the values of each backend
+//! implement. The natives working on lists and maps are part of the
+//! backends, as they share their values.
+//!
+//! The natives accessing the system are grouped into the `fs` and `os`
+//! modules, which programs only get when the `Capabilities` of their
+//! interpreter allow it. The same goes for `input` and `exit`, which are
+//! globals.
+
+use std::cell::Cell;
+use std::env;
+use std::fs;
+use std::io;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Native {
+    /// Seconds since the epoch, with sub-second precision
+    Clock,
+    Str,
+    /// Parses a number, nil when the string isn't one
+    Num,
+    /// The characters between two indices
+    Substring,
+    Upper,
+    Lower,
+    Sqrt,
+    Floor,
+    Pow,
+    /// A number between 0 included and 1 excluded
+    Random,
+    /// Makes the following random numbers the same on every run
+    Seed,
+    /// A line of the standard input, nil at its end
+    Input,
+    Type,
+    /// Ends the program with an exit code
+    Exit,
+    ReadFile,
+    WriteFile,
+    /// The sorted names of the entries of a directory
+    ListDir,
+    /// An environment variable, nil when it isn't set
+    Getenv,
+}
+
+/// The natives every program gets
+pub const NATIVES: [Native; 12] = [
+    Native::Clock,
+    Native::Str,
+    Native::Num,
+    Native::Substring,
+    Native::Upper,
+    Native::Lower,
+    Native::Sqrt,
+    Native::Floor,
+    Native::Pow,
+    Native::Random,
+    Native::Seed,
+    Native::Type,
+];
+
+/// What the `fs` module exports
+pub const FILE_SYSTEM: [Native; 3] = [Native::ReadFile, Native::WriteFile, Native::ListDir];
+
+/// What the `os` module exports, besides the command line arguments which
+/// each backend gives as one of its lists
+pub const OS: [Native; 1] = [Native::Getenv];
+
+/// What programs can access outside of the interpreter. Nothing by
+/// default, so that embedding an interpreter doesn't give the code it
+/// runs access to the system.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Capabilities {
+    /// The `fs` module, reading and writing files
+    pub file_system: bool,
+    /// The `os` module, the environment variables and the command line
+    /// arguments
+    pub os: bool,
+    /// The `input` native, reading the standard input
+    pub input: bool,
+    /// The `exit` native, ending the program with an exit code
+    pub exit: bool,
+}
+
+impl Capabilities {
+    pub fn all() -> Capabilities {
+        Capabilities {
+            file_system: true,
+            os: true,
+            input: true,
+            exit: true,
+        }
+    }
+}
+
+/// What the natives need from the values of a backend
+pub trait NativeValue: Sized {
+    fn nil() -> Self;
+    fn number(n: f64) -> Self;
+    fn string(s: Rc<str>) -> Self;
+    fn list(elements: Vec<Self>) -> Self;
+    fn as_number(&self) -> Option<f64>;
+    fn as_string(&self) -> Option<&str>;
+    /// What `print` shows
+    fn describe(&self) -> String;
+    /// What `type` returns
+    fn type_name(&self) -> &'static str;
+}
+
+#[derive(Debug, PartialEq)]
+pub enum NativeError {
+    /// The number of arguments the native takes
+    WrongNumberOfArguments(usize),
+    /// The position of the argument
+    InvalidArgument(usize),
+    /// Accessing the system failed, with the message to report
+    Io(String),
+    /// Not an error: the program asked to end with the exit code, which
+    /// the backends pass on to whoever runs them
+    Exit(i32),
+}
+
+thread_local! {
+    /// The state of the random number generator, shared by the
+    /// interpreters of a thread
+    static RANDOM: Cell<u64> = Cell::new(time_seed());
+}
+
+fn time_seed() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    (now.as_secs() ^ u64::from(now.subsec_nanos()) << 32) | 1
+}
+
+/// A xorshift64* generator, the state must not be 0
+fn next_random(state: u64) -> (u64, f64) {
+    let mut x = state;
+    x ^= x >> 12;
+    x ^= x << 25;
+    x ^= x >> 27;
+    let output = x.wrapping_mul(0x2545_f491_4f6c_dd1d);
+    // The 53 most significant bits fill the mantissa of a double
+    (x, (output >> 11) as f64 / (1u64 << 53) as f64)
+}
+
+fn number<V: NativeValue>(arguments: &[V], position: usize) -> Result<f64, NativeError> {
+    arguments[position]
+        .as_number()
+        .ok_or(NativeError::InvalidArgument(position))
+}
+
+fn integer<V: NativeValue>(arguments: &[V], position: usize) -> Result<i64, NativeError> {
+    match number(arguments, position)? {
+        n if n.fract() == 0.0 && n.abs() < 2f64.powi(53) => Ok(n as i64),
+        _ => Err(NativeError::InvalidArgument(position)),
+    }
+}
+
+fn string<V: NativeValue>(arguments: &[V], position: usize) -> Result<&str, NativeError> {
+    arguments[position]
+        .as_string()
+        .ok_or(NativeError::InvalidArgument(position))
+}
+
+fn io_error(action: &str, path: &str, error: &io::Error) -> NativeError {
+    NativeError::Io(format!("Error {} {}: {}", action, path, error))
+}
+
+impl Native {
+    pub fn name(self) -> &'static str {
+        match self {
+            Native::Clock => "clock",
+            Native::Str => "str",
+            Native::Num => "num",
+            Native::Substring => "substring",
+            Native::Upper => "upper",
+            Native::Lower => "lower",
+            Native::Sqrt => "sqrt",
+            Native::Floor => "floor",
+            Native::Pow => "pow",
+            Native::Random => "random",
+            Native::Seed => "seed",
+            Native::Input => "input",
+            Native::Type => "type",
+            Native::Exit => "exit",
+            Native::ReadFile => "readFile",
+            Native::WriteFile => "writeFile",
+            Native::ListDir => "listDir",
+            Native::Getenv => "getenv",
+        }
+    }
+
+    fn arity(self) -> usize {
+        match self {
+            Native::Clock | Native::Random | Native::Input => 0,
+            Native::Pow | Native::WriteFile => 2,
+            Native::Substring => 3,
+            _ => 1,
+        }
+    }
+
+    pub fn call<V: NativeValue>(self, arguments: &[V]) -> Result<V, NativeError> {
+        if arguments.len() != self.arity() {
+            return Err(NativeError::WrongNumberOfArguments(self.arity()));
+        }
+        match self {
+            Native::Clock => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+                Ok(V::number(now.as_secs_f64()))
+            }
+            Native::Str => Ok(V::string(arguments[0].describe().into())),
+            Native::Num => Ok(string(arguments, 0)?
+                .trim()
+                .parse()
+                .ok()
+                .filter(|n: &f64| n.is_finite())
+                .map_or_else(V::nil, V::number)),
+            Native::Substring => {
+                let s = string(arguments, 0)?;
+                let length = s.chars().count() as i64;
+                let start = integer(arguments, 1)?;
+                let end = integer(arguments, 2)?;
+                if start < 0 || start > length {
+                    return Err(NativeError::InvalidArgument(1));
+                }
+                if end < start || end > length {
+                    return Err(NativeError::InvalidArgument(2));
+                }
+                let substring: String = s
+                    .chars()
+                    .skip(start as usize)
+                    .take((end - start) as usize)
+                    .collect();
+                Ok(V::string(substring.into()))
+            }
+            Native::Upper => Ok(V::string(string(arguments, 0)?.to_uppercase().into())),
+            Native::Lower => Ok(V::string(string(arguments, 0)?.to_lowercase().into())),
+            Native::Sqrt => Ok(V::number(number(arguments, 0)?.sqrt())),
+            Native::Floor => Ok(V::number(number(arguments, 0)?.floor())),
+            Native::Pow => Ok(V::number(number(arguments, 0)?.powf(number(arguments, 1)?))),
+            Native::Random => Ok(V::number(RANDOM.with(|random| {
+                let (state, n) = next_random(random.get());
+                random.set(state);
+                n
+            }))),
+            Native::Seed => {
+                let seed = integer(arguments, 0)? as u64;
+                // Spreads small seeds over the bits, and avoids 0
+                RANDOM.with(|random| random.set(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1));
+                Ok(V::nil())
+            }
+            Native::Input => {
+                let mut line = String::new();
+                match io::stdin().read_line(&mut line) {
+                    Ok(0) | Err(_) => Ok(V::nil()),
+                    Ok(_) => {
+                        let length = line.trim_end_matches(&['\n', '\r'][..]).len();
+                        line.truncate(length);
+                        Ok(V::string(line.into()))
+                    }
+                }
+            }
+            Native::Type => Ok(V::string(arguments[0].type_name().into())),
+            Native::Exit => match integer(arguments, 0)? {
+                code if code >= i64::from(i32::MIN) && code <= i64::from(i32::MAX) => {
+                    Err(NativeError::Exit(code as i32))
+                }
+                _ => Err(NativeError::InvalidArgument(0)),
+            },
+            Native::ReadFile => {
+                let path = string(arguments, 0)?;
+                fs::read_to_string(path)
+                    .map(|content| V::string(content.into()))
+                    .map_err(|error| io_error("reading", path, &error))
+            }
+            Native::WriteFile => {
+                let path = string(arguments, 0)?;
+                fs::write(path, string(arguments, 1)?)
+                    .map(|_| V::nil())
+                    .map_err(|error| io_error("writing", path, &error))
+            }
+            Native::ListDir => {
+                let path = string(arguments, 0)?;
+                let mut names = fs::read_dir(path)
+                    .and_then(|entries| {
+                        entries
+                            .map(|entry| {
+                                entry.map(|e| e.file_name().to_string_lossy().into_owned())
+                            })
+                            .collect::<Result<Vec<String>, io::Error>>()
+                    })
+                    .map_err(|error| io_error("listing", path, &error))?;
+                names.sort();
+                Ok(V::list(
+                    names
+                        .into_iter()
+                        .map(|name| V::string(name.into()))
+                        .collect(),
+                ))
+            }
+            Native::Getenv => Ok(env::var(string(arguments, 0)?)
+                .map(|value| V::string(value.into()))
+                .unwrap_or_else(|_| V::nil())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use natives::{Native, NativeError, NativeValue};
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::rc::Rc;
+
+    /// Enough of a value to call the natives
+    #[derive(Debug, PartialEq)]
+    enum Value {
+        Nil,
+        Number(f64),
+        String(Rc<str>),
+        List(Vec<Value>),
+    }
+
+    impl NativeValue for Value {
+        fn nil() -> Value {
+            Value::Nil
+        }
+
+        fn number(n: f64) -> Value {
+            Value::Number(n)
+        }
+
+        fn string(s: Rc<str>) -> Value {
+            Value::String(s)
+        }
+
+        fn list(elements: Vec<Value>) -> Value {
+            Value::List(elements)
+        }
+
+        fn as_number(&self) -> Option<f64> {
+            match *self {
+                Value::Number(n) => Some(n),
+                _ => None,
+            }
+        }
+
+        fn as_string(&self) -> Option<&str> {
+            match *self {
+                Value::String(ref s) => Some(s),
+                _ => None,
+            }
+        }
+
+        fn describe(&self) -> String {
+            match *self {
+                Value::Nil => "nil".into(),
+                Value::Number(n) => n.to_string(),
+                Value::String(ref s) => s.to_string(),
+                Value::List(ref elements) => format!("{:?}", elements),
+            }
+        }
+
+        fn type_name(&self) -> &'static str {
+            match *self {
+                Value::Nil => "nil",
+                Value::Number(_) => "number",
+                Value::String(_) => "string",
+                Value::List(_) => "list",
+            }
+        }
+    }
+
+    fn string(s: &str) -> Value {
+        Value::String(s.into())
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(Ok(string("2.5")), Native::Str.call(&[Value::Number(2.5)]));
+        assert_eq!(Ok(Value::Number(2.5)), Native::Num.call(&[string(" 2.5 ")]));
+        assert_eq!(Ok(Value::Nil), Native::Num.call(&[string("two")]));
+        assert_eq!(Ok(Value::Nil), Native::Num.call(&[string("inf")]));
+        assert_eq!(Ok(string("ABÉ")), Native::Upper.call(&[string("abé")]));
+        assert_eq!(
+            Ok(string("él")),
+            Native::Substring.call(&[string("hélo"), Value::Number(1.0), Value::Number(3.0)])
+        );
+        assert_eq!(
+            Err(NativeError::InvalidArgument(2)),
+            Native::Substring.call(&[string("abc"), Value::Number(1.0), Value::Number(4.0)])
+        );
+        assert_eq!(Ok(string("string")), Native::Type.call(&[string("")]));
+    }
+
+    #[test]
+    fn math() {
+        assert_eq!(
+            Ok(Value::Number(3.0)),
+            Native::Sqrt.call(&[Value::Number(9.0)])
+        );
+        assert_eq!(
+            Ok(Value::Number(-2.0)),
+            Native::Floor.call(&[Value::Number(-1.5)])
+        );
+        assert_eq!(
+            Ok(Value::Number(8.0)),
+            Native::Pow.call(&[Value::Number(2.0), Value::Number(3.0)])
+        );
+        assert_eq!(
+            Err(NativeError::InvalidArgument(0)),
+            Native::Sqrt.call(&[Value::Nil])
+        );
+        assert_eq!(
+            Err(NativeError::WrongNumberOfArguments(2)),
+            Native::Pow.call(&[Value::Number(2.0)])
+        );
+    }
+
+    #[test]
+    fn exit_ends_the_program_with_its_code() {
+        assert_eq!(
+            Err(NativeError::Exit(3)),
+            Native::Exit.call(&[Value::Number(3.0)])
+        );
+        assert_eq!(
+            Err(NativeError::InvalidArgument(0)),
+            Native::Exit.call(&[Value::Number(1e10)])
+        );
+    }
+
+    #[test]
+    fn seeded_random_numbers_repeat() {
+        let mut draws = vec![];
+        for _ in 0..2 {
+            Native::Seed.call(&[Value::Number(42.0)]).unwrap();
+            let draw: Vec<Value> = (0..3)
+                .map(|_| Native::Random.call::<Value>(&[]).unwrap())
+                .collect();
+            for value in &draw {
+                let n = value.as_number().unwrap();
+                assert!((0.0..1.0).contains(&n));
+            }
+            draws.push(draw);
+        }
+        assert_eq!(draws[0], draws[1]);
+        assert_ne!(draws[0][0], draws[0][1]);
+    }
+
+    #[test]
+    fn files() {
+        let directory = env::temp_dir().join(format!("rulox_natives_{}", process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = |name: &str| string(directory.join(name).to_str().unwrap());
+        for &name in &["b.txt", "a.txt"] {
+            assert_eq!(
+                Ok(Value::Nil),
+                Native::WriteFile.call(&[path(name), string(name)])
+            );
+        }
+        assert_eq!(Ok(string("b.txt")), Native::ReadFile.call(&[path("b.txt")]));
+        assert_eq!(
+            Ok(Value::List(vec![string("a.txt"), string("b.txt")])),
+            Native::ListDir.call(&[path("")])
+        );
+        match Native::ReadFile.call(&[path("c.txt")]) {
+            Err(NativeError::Io(message)) => assert!(message.starts_with("Error reading ")),
+            other => panic!("Unexpected result {:?}", other),
+        }
+        assert_eq!(
+            Err(NativeError::InvalidArgument(1)),
+            Native::WriteFile.call(&[path("c.txt"), Value::Nil])
+        );
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn environment_variables() {
+        env::set_var("RULOX_NATIVES_TEST", "set");
+        assert_eq!(
+            Ok(string("set")),
+            Native::Getenv.call(&[string("RULOX_NATIVES_TEST")])
+        );
+        env::remove_var("RULOX_NATIVES_TEST");
+        assert_eq!(
+            Ok(Value::Nil),
+            Native::Getenv.call(&[string("RULOX_NATIVES_TEST")])
+        );
+    }
+}
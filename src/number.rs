@@ -0,0 +1,71 @@
+//! The arithmetic and printing of numbers shared by the backends, so
+//! that they agree on both.
+//!
+//! Lox numbers are doubles. The operators on whole numbers floor their
+//! result, like Python's: `a ~/ b` is the largest whole number not above
+//! `a / b`, and `a % b` has the sign of `b`, so that `(a ~/ b) * b + a % b`
+//! is `a`.
+
+/// `a % b`, NaN when `b` is 0
+pub fn modulo(a: f64, b: f64) -> f64 {
+    let remainder = a % b;
+    if remainder != 0.0 && (remainder < 0.0) != (b < 0.0) {
+        remainder + b
+    } else {
+        remainder
+    }
+}
+
+/// `a ~/ b`, infinite or NaN when `b` is 0
+pub fn integer_division(a: f64, b: f64) -> f64 {
+    (a / b).floor()
+}
+
+/// How `print` and `str()` show a number. Whole numbers have no
+/// fractional part, and very large or small numbers are written in
+/// scientific notation, which number literals accept as well.
+pub fn format(n: f64) -> String {
+    if n.is_nan() {
+        "NaN".into()
+    } else if n.is_infinite() {
+        if n > 0.0 { "Infinity" } else { "-Infinity" }.into()
+    } else if n != 0.0 && (n.abs() >= 1e21 || n.abs() < 1e-6) {
+        format!("{:e}", n)
+    } else {
+        n.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use number::{format, integer_division, modulo};
+
+    #[test]
+    fn division_floors() {
+        assert_eq!(1.0, modulo(7.0, 3.0));
+        assert_eq!(2.0, modulo(-7.0, 3.0));
+        assert_eq!(-2.0, modulo(7.0, -3.0));
+        assert_eq!(0.5, modulo(5.5, 1.0));
+        assert!(modulo(1.0, 0.0).is_nan());
+        assert_eq!(2.0, integer_division(7.0, 3.0));
+        assert_eq!(-3.0, integer_division(-7.0, 3.0));
+        assert_eq!(f64::INFINITY, integer_division(1.0, 0.0));
+        for &(a, b) in &[(7.0, 3.0), (-7.0, 3.0), (7.0, -3.0), (-7.5, -2.0)] {
+            assert_eq!(a, integer_division(a, b) * b + modulo(a, b));
+        }
+    }
+
+    #[test]
+    fn formatting() {
+        assert_eq!("1", format(1.0));
+        assert_eq!("-0", format(-0.0));
+        assert_eq!("0.1", format(0.1));
+        assert_eq!("123456789012345680000", format(123456789012345678901.0));
+        assert_eq!("1e21", format(1e21));
+        assert_eq!("-2.5e-7", format(-2.5e-7));
+        assert_eq!("0.000001", format(1e-6));
+        assert_eq!("Infinity", format(1.0 / 0.0));
+        assert_eq!("-Infinity", format(-1.0 / 0.0));
+        assert_eq!("NaN", format(0.0 / 0.0));
+    }
+}
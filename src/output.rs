@@ -0,0 +1,44 @@
+//! Where the `print` statement of every backend writes.
+//!
+//! That's the standard output, except for the tests, which can capture
+//! what the programs they run print on their own thread.
+
+use std::fmt::Display;
+
+#[cfg(test)]
+use std::cell::RefCell;
+
+#[cfg(test)]
+thread_local! {
+    static CAPTURED: RefCell<Option<String>> = RefCell::new(None);
+}
+
+/// Prints the value on its own line
+pub fn print<T: Display>(value: T) {
+    #[cfg(test)]
+    {
+        let captured = CAPTURED.with(|captured| match *captured.borrow_mut() {
+            Some(ref mut output) => {
+                output.push_str(&format!("{}\n", value));
+                true
+            }
+            None => false,
+        });
+        if captured {
+            return;
+        }
+    }
+    println!("{}", value);
+}
+
+/// Runs the function and returns what it printed with its result
+#[cfg(test)]
+pub fn capture<F, T>(run: F) -> (String, T)
+where
+    F: FnOnce() -> T,
+{
+    CAPTURED.with(|captured| *captured.borrow_mut() = Some(String::new()));
+    let result = run();
+    let output = CAPTURED.with(|captured| captured.borrow_mut().take());
+    (output.unwrap_or_default(), result)
+}
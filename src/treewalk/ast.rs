@@ -2,6 +2,7 @@ use fnv::FnvHashMap;
 use std::cmp::PartialEq;
 use std::fmt::{Debug, Error, Formatter};
 use std::rc::Rc;
+use treewalk::scanner::Position;
 
 #[derive(Debug)]
 pub enum UnaryOperator {
@@ -15,6 +16,9 @@ pub enum BinaryOperator {
     Plus,
     Slash,
     Star,
+    Percent,
+    /// `~/`
+    TildeSlash,
     Equal,
     NotEqual,
     Less,
@@ -44,6 +48,10 @@ impl Identifier {
     pub fn super_identifier() -> Identifier {
         Identifier { handle: 2 }
     }
+    /// The name of every anonymous function, which no variable can have
+    pub fn lambda() -> Identifier {
+        Identifier { handle: 3 }
+    }
 }
 
 pub struct IdentifierMap {
@@ -58,6 +66,7 @@ impl IdentifierMap {
         map.insert("this".into(), Identifier::this());
         map.insert("init".into(), Identifier::init());
         map.insert("super".into(), Identifier::super_identifier());
+        map.insert("<lambda>".into(), Identifier::lambda());
         IdentifierMap {
             next: map.len() as u64,
             map,
@@ -83,22 +92,27 @@ impl IdentifierMap {
     }
 }
 
+#[derive(Debug)]
 pub enum Target {
     Identifier(Identifier),
 }
 
+#[derive(Debug)]
 pub enum Literal {
-    NilLiteral,
-    BoolLiteral(bool),
-    StringLiteral(String),
-    NumberLiteral(f64),
+    Nil,
+    Bool(bool),
+    /// Shared with the values created from it, evaluating it is cheap
+    String(Rc<str>),
+    Number(f64),
 }
 
+#[derive(Debug)]
 pub struct UnaryExpr {
     pub operator: UnaryOperator,
     pub right: Expr,
 }
 
+#[derive(Debug)]
 pub struct BinaryExpr {
     pub left: Expr,
     pub operator: BinaryOperator,
@@ -106,38 +120,58 @@ pub struct BinaryExpr {
 }
 
 // Same as BinaryExpr, but with short-circuiting
+#[derive(Debug)]
 pub struct LogicExpr {
     pub left: Expr,
     pub operator: LogicOperator,
     pub right: Expr,
 }
 
+#[derive(Debug)]
 pub struct Grouping {
     pub expr: Expr,
 }
 
+#[derive(Debug)]
 pub struct Assignment {
     pub handle: VariableUseHandle,
     pub lvalue: Target,
     pub rvalue: Expr,
 }
 
+#[derive(Debug)]
 pub struct Call {
     pub callee: Expr,
     pub arguments: Vec<Expr>,
 }
 
+#[derive(Debug)]
 pub struct Get {
     pub instance: Expr,
     pub property: Identifier,
 }
 
+#[derive(Debug)]
 pub struct Set {
     pub instance: Expr,
     pub property: Identifier,
     pub value: Expr,
 }
 
+#[derive(Debug)]
+pub struct Index {
+    /// A list or a map
+    pub collection: Expr,
+    pub index: Expr,
+}
+
+#[derive(Debug)]
+pub struct SetIndex {
+    pub collection: Expr,
+    pub index: Expr,
+    pub value: Expr,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct VariableUseHandle {
     value: u16,
@@ -165,6 +199,7 @@ impl VariableUseHandleFactory {
     }
 }
 
+#[derive(Debug)]
 pub enum Expr {
     This(VariableUseHandle, Identifier),
     Super(VariableUseHandle, Identifier, Identifier),
@@ -178,12 +213,23 @@ pub enum Expr {
     Call(Box<Call>),
     Get(Box<Get>),
     Set(Box<Set>),
+    /// A list literal, with its elements
+    List(Vec<Expr>),
+    /// A map literal, with its keys and values
+    Map(Vec<(Expr, Expr)>),
+    Index(Box<Index>),
+    SetIndex(Box<SetIndex>),
+    /// An anonymous function, closing over the scope it's created in
+    Lambda(Rc<FunctionDefinition>),
 }
 
+#[derive(Debug)]
 pub enum Statement {
     Print(Expr),
     Expression(Expr),
     Return(Option<Expr>),
+    Break,
+    Continue,
     VariableDefinition(Identifier),
     VariableDefinitionWithInitalizer(Identifier, Expr),
     Block(Box<Block>),
@@ -192,26 +238,65 @@ pub enum Statement {
     While(Box<While>),
     FunctionDefinition(Rc<FunctionDefinition>),
     Class(ClassDefinition),
+    Throw(Expr),
+    Try(Box<Try>),
+    Import(Import),
 }
 
+#[derive(Debug)]
 pub struct Block {
     pub statements: Vec<Statement>,
+    /// Where each statement starts, reported by the errors found
+    /// resolving or running it
+    pub positions: Vec<Position>,
+}
+
+/// At least one of the handlers is there
+#[derive(Debug)]
+pub struct Try {
+    pub body: Block,
+    pub catch: Option<Catch>,
+    pub finally: Option<Block>,
+}
+
+/// Like a function, the variable holding what was caught shares the
+/// environment of the body
+#[derive(Debug)]
+pub struct Catch {
+    pub variable: Identifier,
+    pub body: Block,
+}
+
+/// Only allowed at the top level of a script or module
+#[derive(Debug)]
+pub struct Import {
+    /// As written, see `treewalk::modules` for how it's looked up
+    pub path: Rc<str>,
+    /// With `as`, the module is a single variable instead of having its
+    /// names defined in the importing one
+    pub name: Option<Identifier>,
 }
 
+#[derive(Debug)]
 pub struct IfThen {
     pub condition: Expr,
     pub then_branch: Statement,
 }
 
+#[derive(Debug)]
 pub struct IfThenElse {
     pub condition: Expr,
     pub then_branch: Statement,
     pub else_branch: Statement,
 }
 
+#[derive(Debug)]
 pub struct While {
     pub condition: Expr,
     pub body: Statement,
+    /// The increment of a `for` loop, which runs after the body even
+    /// when it's cut short by `continue`
+    pub increment: Option<Expr>,
 }
 
 #[derive(Clone, Copy, PartialEq)]
@@ -219,13 +304,16 @@ pub enum FunctionKind {
     Function,
     Method,
     Initializer,
+    Lambda,
 }
 
 pub struct FunctionDefinition {
     pub kind: FunctionKind,
     pub name: Identifier,
     pub arguments: Vec<Identifier>,
-    pub body: Statement, //This should be a block
+    /// The arguments and the variables declared in the body share the
+    /// environment of the call
+    pub body: Block,
 }
 
 impl Debug for FunctionDefinition {
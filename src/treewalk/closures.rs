@@ -0,0 +1,984 @@
+//! A backend that compiles the resolved syntax tree into a tree of Rust
+//! closures before running it.
+//!
+//! The tree-walker matches on the kind of every node and looks up where
+//! every variable lives each time it runs them. Here that's done once:
+//! each node becomes a closure calling the closures of its children,
+//! with the slots of the variables baked in.
+//!
+//! Both backends run programs with the values and the environment of
+//! `runtime`, only the bodies of the functions differ.
+
+use frontend::scanner;
+use map::OrderedMap;
+use natives::Capabilities;
+use number;
+use output;
+use std::mem;
+use std::path::Path;
+use std::rc::Rc;
+use treewalk::ast::*;
+use treewalk::lexical_scope_resolver::{LexicalScopes, LexicalScopesResolver, VariableLocation};
+use treewalk::modules::{exported_names, module_name, Modules};
+use treewalk::parser::Parser;
+use treewalk::runtime::{
+    map_key, Callable, Class, Environment, Function, Interruption, List, Map, Module, RuntimeError,
+    Value,
+};
+use treewalk::{in_module, scan_and_parse, LoxError, Script};
+use user_interface::{LoxImplementation, RunError};
+
+type CompiledExpr = Box<dyn Fn(&mut Environment) -> Result<Value, RuntimeError>>;
+/// Evaluates to what interrupted the statement, if anything did
+type CompiledStatement =
+    Box<dyn Fn(&mut Environment) -> Result<Option<Interruption>, RuntimeError>>;
+
+fn run_sequence(
+    statements: &[CompiledStatement],
+    environment: &mut Environment,
+) -> Result<Option<Interruption>, RuntimeError> {
+    for statement in statements {
+        if let Some(interruption) = statement(environment)? {
+            return Ok(Some(interruption));
+        }
+    }
+    Ok(None)
+}
+
+/// Turns resolved syntax trees into closures
+struct Compiler<'a> {
+    scopes: &'a LexicalScopes,
+}
+
+impl<'a> Compiler<'a> {
+    fn location(&self, handle: VariableUseHandle) -> Option<VariableLocation> {
+        self.scopes.get_location(handle)
+    }
+
+    fn variable(&self, handle: VariableUseHandle, identifier: Identifier) -> CompiledExpr {
+        match self.location(handle) {
+            Some(location) => Box::new(move |environment| {
+                environment
+                    .get(location, identifier)
+                    .ok_or(RuntimeError::UndefinedIdentifier(identifier))
+            }),
+            None => Box::new(move |_| Err(RuntimeError::UndefinedIdentifier(identifier))),
+        }
+    }
+
+    fn expression(&self, expr: &Expr) -> CompiledExpr {
+        match *expr {
+            Expr::Literal(ref literal) => {
+                let value = match *literal {
+                    Literal::Nil => Value::Nil,
+                    Literal::Bool(b) => Value::Boolean(b),
+                    Literal::String(ref s) => Value::String(s.clone()),
+                    Literal::Number(n) => Value::Number(n),
+                };
+                Box::new(move |_| Ok(value.clone()))
+            }
+            Expr::Grouping(ref g) => self.expression(&g.expr),
+            Expr::Unary(ref u) => self.unary(u),
+            Expr::Binary(ref b) => self.binary(b),
+            Expr::Logic(ref l) => {
+                let left = self.expression(&l.left);
+                let right = self.expression(&l.right);
+                match l.operator {
+                    LogicOperator::Or => Box::new(move |environment| {
+                        let left = left(environment)?;
+                        if left.is_true() {
+                            Ok(left)
+                        } else {
+                            right(environment)
+                        }
+                    }),
+                    LogicOperator::And => Box::new(move |environment| {
+                        let left = left(environment)?;
+                        if !left.is_true() {
+                            Ok(left)
+                        } else {
+                            right(environment)
+                        }
+                    }),
+                }
+            }
+            Expr::This(handle, identifier) | Expr::Identifier(handle, identifier) => {
+                self.variable(handle, identifier)
+            }
+            Expr::Super(handle, super_identifier, member) => {
+                let (superclass, this) = match self.location(handle) {
+                    // This is the first variable of the method, right
+                    // below the scope holding super
+                    Some(VariableLocation::Local(depth, slot)) => (
+                        VariableLocation::Local(depth, slot),
+                        VariableLocation::Local(depth - 1, 0),
+                    ),
+                    // Static analysis should prevent us from getting here
+                    _ => panic!("Invalid use of super"),
+                };
+                Box::new(move |environment| {
+                    let superclass = environment.get(superclass, super_identifier);
+                    let this = environment.get(this, Identifier::this());
+                    match (superclass, this) {
+                        (
+                            Some(Value::Callable(Callable::Class(class))),
+                            Some(Value::Instance(instance)),
+                        ) => match class.find_method(member) {
+                            Some(method) => Ok(Value::Callable(method.bind(&instance))),
+                            None => Err(RuntimeError::UndefinedProperty(member)),
+                        },
+                        _ => panic!("Invalid use of super"),
+                    }
+                })
+            }
+            Expr::Assignment(ref a) => {
+                let Target::Identifier(target) = a.lvalue;
+                let rvalue = self.expression(&a.rvalue);
+                match self.location(a.handle) {
+                    Some(location) => Box::new(move |environment| {
+                        let value = rvalue(environment)?;
+                        if environment.set(location, target, value.clone()) {
+                            Ok(value)
+                        } else {
+                            Err(RuntimeError::UndefinedIdentifier(target))
+                        }
+                    }),
+                    None => Box::new(move |environment| {
+                        rvalue(environment)?;
+                        Err(RuntimeError::UndefinedIdentifier(target))
+                    }),
+                }
+            }
+            Expr::Call(ref c) => {
+                let callee = self.expression(&c.callee);
+                let arguments: Vec<CompiledExpr> =
+                    c.arguments.iter().map(|a| self.expression(a)).collect();
+                Box::new(move |environment| match callee(environment)? {
+                    Value::Callable(callable) => {
+                        let mut values = Vec::with_capacity(arguments.len());
+                        for argument in &arguments {
+                            values.push(argument(environment)?);
+                        }
+                        callable.call(values)
+                    }
+                    value => Err(RuntimeError::NotCallable(value)),
+                })
+            }
+            Expr::Get(ref g) => {
+                let instance = self.expression(&g.instance);
+                let property = g.property;
+                Box::new(move |environment| match instance(environment)? {
+                    Value::Instance(instance) => instance.get(property),
+                    Value::Module(module) => module.get(property),
+                    value => Err(RuntimeError::NotAnInstance(value)),
+                })
+            }
+            Expr::Set(ref s) => {
+                let instance = self.expression(&s.instance);
+                let value = self.expression(&s.value);
+                let property = s.property;
+                Box::new(move |environment| match instance(environment)? {
+                    Value::Instance(instance) => {
+                        let value = value(environment)?;
+                        instance.set(property, value.clone());
+                        Ok(value)
+                    }
+                    value => Err(RuntimeError::NotAnInstance(value)),
+                })
+            }
+            Expr::List(ref elements) => {
+                let elements: Vec<CompiledExpr> =
+                    elements.iter().map(|e| self.expression(e)).collect();
+                Box::new(move |environment| {
+                    let mut values = Vec::with_capacity(elements.len());
+                    for element in &elements {
+                        values.push(element(environment)?);
+                    }
+                    Ok(Value::List(List::new(values)))
+                })
+            }
+            Expr::Lambda(ref f) => {
+                let function = self.function(f);
+                Box::new(move |environment| {
+                    Ok(Value::Callable(Callable::Function(
+                        function.clone(),
+                        environment.clone(),
+                    )))
+                })
+            }
+            Expr::Map(ref entries) => {
+                let entries: Vec<(CompiledExpr, CompiledExpr)> = entries
+                    .iter()
+                    .map(|(key, value)| (self.expression(key), self.expression(value)))
+                    .collect();
+                Box::new(move |environment| {
+                    let mut map = OrderedMap::new();
+                    for (key, value) in &entries {
+                        let key = map_key(&key(environment)?)?;
+                        map.insert(key, value(environment)?);
+                    }
+                    Ok(Value::Map(Map::new(map)))
+                })
+            }
+            Expr::Index(ref i) => {
+                let collection = self.expression(&i.collection);
+                let index = self.expression(&i.index);
+                Box::new(move |environment| {
+                    let collection = collection(environment)?;
+                    let index = index(environment)?;
+                    match collection {
+                        Value::List(list) => list.get(&index),
+                        Value::Map(map) => map.get(&index),
+                        value => Err(RuntimeError::NotACollection(value)),
+                    }
+                })
+            }
+            Expr::SetIndex(ref s) => {
+                let collection = self.expression(&s.collection);
+                let index = self.expression(&s.index);
+                let value = self.expression(&s.value);
+                Box::new(move |environment| {
+                    let collection = collection(environment)?;
+                    let index = index(environment)?;
+                    let value = value(environment)?;
+                    match collection {
+                        Value::List(list) => list.set(&index, value.clone())?,
+                        Value::Map(map) => map.set(&index, value.clone())?,
+                        value => return Err(RuntimeError::NotACollection(value)),
+                    }
+                    Ok(value)
+                })
+            }
+        }
+    }
+
+    fn unary(&self, u: &UnaryExpr) -> CompiledExpr {
+        let right = self.expression(&u.right);
+        match u.operator {
+            UnaryOperator::Bang => {
+                Box::new(move |environment| Ok(Value::Boolean(!right(environment)?.is_true())))
+            }
+            UnaryOperator::Minus => Box::new(move |environment| match right(environment)? {
+                Value::Number(n) => Ok(Value::Number(-n)),
+                value => Err(RuntimeError::UnaryMinusTypeMismatch(value)),
+            }),
+        }
+    }
+
+    fn binary(&self, b: &BinaryExpr) -> CompiledExpr {
+        let left = self.expression(&b.left);
+        let right = self.expression(&b.right);
+        let operator = b.operator;
+        // One closure per operator, so that it's only matched on here
+        macro_rules! numeric {
+            ($result:expr) => {
+                Box::new(move |environment| {
+                    let left = left(environment)?;
+                    let right = right(environment)?;
+                    match (left, right) {
+                        (Value::Number(l), Value::Number(r)) => Ok($result(l, r)),
+                        (l, r) => Err(RuntimeError::BinaryOperatorTypeMismatch(operator, l, r)),
+                    }
+                })
+            };
+        }
+        match operator {
+            BinaryOperator::Minus => numeric!(|l, r| Value::Number(l - r)),
+            BinaryOperator::Slash => numeric!(|l, r| Value::Number(l / r)),
+            BinaryOperator::Star => numeric!(|l, r| Value::Number(l * r)),
+            BinaryOperator::Percent => numeric!(|l, r| Value::Number(number::modulo(l, r))),
+            BinaryOperator::TildeSlash => {
+                numeric!(|l, r| Value::Number(number::integer_division(l, r)))
+            }
+            BinaryOperator::Greater => numeric!(|l, r| Value::Boolean(l > r)),
+            BinaryOperator::GreaterEqual => numeric!(|l, r| Value::Boolean(l >= r)),
+            BinaryOperator::Less => numeric!(|l, r| Value::Boolean(l < r)),
+            BinaryOperator::LessEqual => numeric!(|l, r| Value::Boolean(l <= r)),
+            BinaryOperator::Plus => Box::new(move |environment| {
+                let left = left(environment)?;
+                let right = right(environment)?;
+                match (left, right) {
+                    (Value::Number(l), Value::Number(r)) => Ok(Value::Number(l + r)),
+                    (Value::String(l), Value::String(r)) => {
+                        let mut result = String::with_capacity(l.len() + r.len());
+                        result.push_str(&l);
+                        result.push_str(&r);
+                        Ok(Value::String(result.into()))
+                    }
+                    (l, r) => Err(RuntimeError::BinaryOperatorTypeMismatch(operator, l, r)),
+                }
+            }),
+            BinaryOperator::Equal => Box::new(move |environment| {
+                let left = left(environment)?;
+                Ok(Value::Boolean(left == right(environment)?))
+            }),
+            BinaryOperator::NotEqual => Box::new(move |environment| {
+                let left = left(environment)?;
+                Ok(Value::Boolean(left != right(environment)?))
+            }),
+        }
+    }
+
+    /// The errors of the statements of a block get the line of the
+    /// statement of the block they come from, if they don't have one yet
+    fn block(&self, block: &Block) -> Vec<CompiledStatement> {
+        block
+            .statements
+            .iter()
+            .zip(&block.positions)
+            .map(|(statement, position)| {
+                let line = position.line;
+                let statement = self.statement(statement);
+                let compiled: CompiledStatement = Box::new(move |environment| {
+                    statement(environment).map_err(|error| error.at_line(line))
+                });
+                compiled
+            })
+            .collect()
+    }
+
+    fn function(&self, definition: &FunctionDefinition) -> Rc<Function> {
+        let body = self.block(&definition.body);
+        Rc::new(Function::new(
+            definition.kind,
+            definition.arguments.len(),
+            Box::new(move |environment| run_sequence(&body, environment)),
+        ))
+    }
+
+    fn statement(&self, statement: &Statement) -> CompiledStatement {
+        match *statement {
+            Statement::Expression(ref e) => {
+                let e = self.expression(e);
+                Box::new(move |environment| e(environment).map(|_| None))
+            }
+            Statement::Print(ref e) => {
+                let e = self.expression(e);
+                Box::new(move |environment| {
+                    let value = e(environment)?;
+                    output::print(value);
+                    Ok(None)
+                })
+            }
+            Statement::Return(ref e) => match *e {
+                Some(ref e) => {
+                    let e = self.expression(e);
+                    Box::new(move |environment| {
+                        e(environment).map(|value| Some(Interruption::Return(value)))
+                    })
+                }
+                None => Box::new(|_| Ok(Some(Interruption::Return(Value::Nil)))),
+            },
+            Statement::Break => Box::new(|_| Ok(Some(Interruption::Break))),
+            Statement::Continue => Box::new(|_| Ok(Some(Interruption::Continue))),
+            Statement::Import(_) => {
+                unreachable!("Imports are only at the top level, where the driver runs them")
+            }
+            Statement::Throw(ref e) => {
+                let e = self.expression(e);
+                Box::new(move |environment| Err(RuntimeError::Thrown(e(environment)?)))
+            }
+            Statement::Try(ref t) => {
+                let body = self.block(&t.body);
+                let catch = t.catch.as_ref().map(|c| (c.variable, self.block(&c.body)));
+                let finally = t.finally.as_ref().map(|f| self.block(f));
+                Box::new(move |environment| {
+                    environment.push_scope();
+                    let mut result = run_sequence(&body, environment);
+                    environment.pop_scope();
+                    if let Some((variable, ref handler)) = catch {
+                        match result {
+                            Err(RuntimeError::Exit(_)) | Ok(_) => {}
+                            Err(error) => {
+                                // Same layout as the resolver
+                                environment.push_scope();
+                                environment.define(variable, error.caught_value());
+                                result = run_sequence(handler, environment);
+                                environment.pop_scope();
+                            }
+                        }
+                    }
+                    if let Err(RuntimeError::Exit(_)) = result {
+                        return result;
+                    }
+                    match finally {
+                        // An interruption or an error of the finally block
+                        // replaces the result of the rest
+                        Some(ref finally) => {
+                            environment.push_scope();
+                            let finally_result = run_sequence(finally, environment);
+                            environment.pop_scope();
+                            match finally_result? {
+                                Some(interruption) => Ok(Some(interruption)),
+                                None => result,
+                            }
+                        }
+                        None => result,
+                    }
+                })
+            }
+            Statement::VariableDefinition(identifier) => Box::new(move |environment| {
+                environment.define(identifier, Value::Nil);
+                Ok(None)
+            }),
+            Statement::VariableDefinitionWithInitalizer(identifier, ref initializer) => {
+                let initializer = self.expression(initializer);
+                Box::new(move |environment| {
+                    let value = initializer(environment)?;
+                    environment.define(identifier, value);
+                    Ok(None)
+                })
+            }
+            Statement::Block(ref b) => {
+                let statements = self.block(b);
+                Box::new(move |environment| {
+                    environment.push_scope();
+                    let result = run_sequence(&statements, environment);
+                    environment.pop_scope();
+                    result
+                })
+            }
+            Statement::IfThen(ref c) => {
+                let condition = self.expression(&c.condition);
+                let then_branch = self.statement(&c.then_branch);
+                Box::new(move |environment| {
+                    if condition(environment)?.is_true() {
+                        then_branch(environment)
+                    } else {
+                        Ok(None)
+                    }
+                })
+            }
+            Statement::IfThenElse(ref c) => {
+                let condition = self.expression(&c.condition);
+                let then_branch = self.statement(&c.then_branch);
+                let else_branch = self.statement(&c.else_branch);
+                Box::new(move |environment| {
+                    if condition(environment)?.is_true() {
+                        then_branch(environment)
+                    } else {
+                        else_branch(environment)
+                    }
+                })
+            }
+            Statement::While(ref l) => {
+                let condition = self.expression(&l.condition);
+                let body = self.statement(&l.body);
+                let increment = l.increment.as_ref().map(|i| self.expression(i));
+                Box::new(move |environment| {
+                    while condition(environment)?.is_true() {
+                        match body(environment)? {
+                            Some(Interruption::Break) => break,
+                            Some(Interruption::Continue) | None => {}
+                            returned => return Ok(returned),
+                        }
+                        if let Some(ref increment) = increment {
+                            increment(environment)?;
+                        }
+                    }
+                    Ok(None)
+                })
+            }
+            Statement::FunctionDefinition(ref f) => {
+                let name = f.name;
+                let function = self.function(f);
+                Box::new(move |environment| {
+                    let closure = environment.clone();
+                    environment.define(
+                        name,
+                        Value::Callable(Callable::Function(function.clone(), closure)),
+                    );
+                    Ok(None)
+                })
+            }
+            Statement::Class(ref c) => {
+                let name = c.name;
+                let superclass = c.superclass.as_ref().map(|s| self.expression(s));
+                let methods: Vec<(Identifier, Rc<Function>)> = c
+                    .methods
+                    .iter()
+                    .map(|m| (m.name, self.function(m)))
+                    .collect();
+                Box::new(move |environment| {
+                    let location = environment.define(name, Value::Nil);
+                    let superclass = match superclass {
+                        Some(ref superclass) => match superclass(environment)? {
+                            Value::Callable(Callable::Class(class)) => Some(class),
+                            value => return Err(RuntimeError::NotAClass(value)),
+                        },
+                        None => None,
+                    };
+                    // Methods see super in a scope of its own
+                    if let Some(ref class) = superclass {
+                        environment.push_scope();
+                        environment.define(
+                            Identifier::super_identifier(),
+                            Value::Callable(Callable::Class(class.clone())),
+                        );
+                    }
+                    let methods = methods
+                        .iter()
+                        .map(|&(name, ref function)| {
+                            (
+                                name,
+                                Callable::Function(function.clone(), environment.clone()),
+                            )
+                        })
+                        .collect();
+                    if superclass.is_some() {
+                        environment.pop_scope();
+                    }
+                    let class = Rc::new(Class::new(superclass, methods));
+                    environment.set(location, name, Value::Callable(Callable::Class(class)));
+                    Ok(None)
+                })
+            }
+        }
+    }
+}
+
+/// Runs programs by compiling them into closures, see the module
+/// documentation
+pub struct ClosureRuloxInterpreter {
+    parser: Parser,
+    lexical_scope_resolver: LexicalScopesResolver,
+    environment: Environment,
+    modules: Modules<Module>,
+    /// Kept for the modules, which get `fs` and `os` as well
+    capabilities: Capabilities,
+    arguments: Vec<String>,
+}
+
+impl Default for ClosureRuloxInterpreter {
+    fn default() -> ClosureRuloxInterpreter {
+        let mut identifier_map = IdentifierMap::new();
+        let environment = Environment::new_with_natives(&mut identifier_map);
+        ClosureRuloxInterpreter {
+            parser: Parser::new(identifier_map),
+            lexical_scope_resolver: LexicalScopesResolver::new(),
+            environment,
+            modules: Modules::new(),
+            capabilities: Capabilities::default(),
+            arguments: vec![],
+        }
+    }
+}
+
+fn runtime_error(error: RuntimeError, identifier_map: &IdentifierMap) -> RunError {
+    match error {
+        RuntimeError::Exit(code) => RunError::Exit(code),
+        error => RunError::RuntimeError(error.report(identifier_map)),
+    }
+}
+
+impl ClosureRuloxInterpreter {
+    /// Parses the source without running it, for the benchmarks to time
+    /// the execution alone
+    pub fn parse(&mut self, source: &str) -> Result<Script, RunError> {
+        scan_and_parse(&mut self.parser, source)
+            .map(Script)
+            .map_err(|errors| RunError::from(LoxError::InputError(errors)))
+    }
+
+    pub fn run_script(&mut self, script: &Script) -> Result<(), RunError> {
+        self.execute(&script.0)
+    }
+
+    /// Runs the statements of the script or of a module, in the environment
+    /// holding its globals. Each statement is compiled right before it
+    /// runs, as imports can't be compiled.
+    fn execute(&mut self, script: &Block) -> Result<(), RunError> {
+        self.lexical_scope_resolver
+            .resolve_all(script)
+            .map_err(LoxError::LexicalScopesResolutionError)?;
+        for (statement, position) in script.statements.iter().zip(&script.positions) {
+            match *statement {
+                Statement::Import(ref import) => self.import(import)?,
+                _ => {
+                    let compiled = Compiler {
+                        scopes: self.lexical_scope_resolver.lexical_scopes(),
+                    }
+                    .statement(statement);
+                    compiled(&mut self.environment).map_err(|error| {
+                        runtime_error(error.at_line(position.line), &self.parser.identifier_map)
+                    })?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn import(&mut self, import: &Import) -> Result<(), RunError> {
+        let file = self.modules.find(&import.path)?;
+        let module = match self.modules.get(&file) {
+            Some(module) => module,
+            None => {
+                let source = self.modules.start(file)?;
+                let module = self.run_module(&import.path, &source);
+                self.modules.finish(module.as_ref().ok().cloned());
+                module.map_err(|error| in_module(&import.path, error))?
+            }
+        };
+        match import.name {
+            Some(name) => {
+                self.environment.define(name, Value::Module(module));
+            }
+            None => {
+                for (&name, value) in module.exports() {
+                    self.environment.define(name, value.clone());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs a module in globals of its own
+    fn run_module(&mut self, path: &str, source: &str) -> Result<Module, RunError> {
+        let script = scan_and_parse(&mut self.parser, source).map_err(LoxError::InputError)?;
+        let environment = Environment::new_with_natives(&mut self.parser.identifier_map);
+        environment.define_system_access(
+            &mut self.parser.identifier_map,
+            self.capabilities,
+            &self.arguments,
+        );
+        let importing = mem::replace(&mut self.environment, environment);
+        let result = self.execute(&script);
+        let module = mem::replace(&mut self.environment, importing);
+        result?;
+        let exported = exported_names(&script.statements, &self.parser.identifier_map);
+        let exports = module
+            .globals()
+            .into_iter()
+            .filter(|&(name, _)| exported.contains(&name))
+            .collect();
+        Ok(Module::new(module_name(path), exports))
+    }
+}
+
+impl LoxImplementation for ClosureRuloxInterpreter {
+    fn run(&mut self, source: &str) -> Result<(), RunError> {
+        let script = scan_and_parse(&mut self.parser, source).map_err(LoxError::InputError)?;
+        self.execute(&script)
+    }
+
+    fn run_interactive(&mut self, source: &str) -> Result<(), RunError> {
+        let (tokens, scanner_errors) = scanner::scan(source);
+        let expression = if scanner_errors.is_empty() {
+            self.parser.parse_standalone_expression(&tokens)
+        } else {
+            None
+        };
+        let expression = match expression {
+            Some(expression) => expression,
+            None => return LoxImplementation::run(self, source),
+        };
+        let compiled = {
+            let scopes = self
+                .lexical_scope_resolver
+                .resolve_expression(&expression)
+                .map_err(|error| LoxError::LexicalScopesResolutionError(vec![error]))?;
+            Compiler { scopes }.expression(&expression)
+        };
+        let value = compiled(&mut self.environment)
+            .map_err(|error| runtime_error(error, &self.parser.identifier_map))?;
+        match value {
+            // Like Python's REPL, nothing is shown when there's no value
+            Value::Nil => {}
+            value => println!("{}", value),
+        }
+        Ok(())
+    }
+
+    fn globals(&self) -> Vec<(String, String)> {
+        let mut globals: Vec<(String, String)> = self
+            .environment
+            .globals()
+            .into_iter()
+            .map(|(identifier, value)| {
+                let name = self
+                    .parser
+                    .identifier_map
+                    .lookup(identifier)
+                    .cloned()
+                    .unwrap_or_else(|| "<unknown>".into());
+                (name, value.to_string())
+            })
+            .collect();
+        globals.sort();
+        globals
+    }
+
+    fn set_arguments(&mut self, arguments: &[String]) {
+        self.arguments = arguments.to_vec();
+        // os.args() gives them
+        self.environment.define_system_access(
+            &mut self.parser.identifier_map,
+            self.capabilities,
+            &self.arguments,
+        );
+    }
+
+    fn set_capabilities(&mut self, capabilities: Capabilities) {
+        self.capabilities = capabilities;
+        self.environment.define_system_access(
+            &mut self.parser.identifier_map,
+            capabilities,
+            &self.arguments,
+        );
+    }
+
+    fn set_script_path(&mut self, path: &Path) {
+        self.modules.set_script(path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+    use treewalk::closures::*;
+
+    fn run(source: &str) -> ClosureRuloxInterpreter {
+        let mut interpreter = ClosureRuloxInterpreter::default();
+        LoxImplementation::run(&mut interpreter, source).unwrap();
+        interpreter
+    }
+
+    fn global(interpreter: &ClosureRuloxInterpreter, name: &str) -> Option<String> {
+        interpreter
+            .globals()
+            .into_iter()
+            .find(|&(ref global, _)| global == name)
+            .map(|(_, value)| value)
+    }
+
+    #[test]
+    fn arithmetic_and_strings() {
+        let interpreter = run("var a = (1 + 2) * 3 - 4 / 2; var b = \"a\" + \"b\"; var c = !nil;");
+        assert_eq!(Some("7".into()), global(&interpreter, "a"));
+        assert_eq!(Some("ab".into()), global(&interpreter, "b"));
+        assert_eq!(Some("true".into()), global(&interpreter, "c"));
+    }
+
+    #[test]
+    fn numbers() {
+        let interpreter = run("var a = -7 % 3; var b = 0x10 ~/ 3; var c = 1e21; var d = 1 / 0;");
+        assert_eq!(Some("2".into()), global(&interpreter, "a"));
+        assert_eq!(Some("5".into()), global(&interpreter, "b"));
+        assert_eq!(Some("1e21".into()), global(&interpreter, "c"));
+        assert_eq!(Some("Infinity".into()), global(&interpreter, "d"));
+    }
+
+    #[test]
+    fn closures_capture_their_scope() {
+        let interpreter = run(
+            "fun counter() { var count = 0; fun next() { count = count + 1; return count; } return next; }
+            var next = counter(); next(); var a = next();
+            var b; { var x = 1; { var x = 2; b = x; } b = b + x; }",
+        );
+        assert_eq!(Some("2".into()), global(&interpreter, "a"));
+        assert_eq!(Some("3".into()), global(&interpreter, "b"));
+    }
+
+    #[test]
+    fn lambdas() {
+        let interpreter = run(
+            "fun apply(f, x) { return f(x); }
+            var offset = 10; var a = apply(fun (x) { return x + offset; }, 1);
+            var make = fun () { var count = 0; return fun () { count = count + 1; return count; }; };
+            var next = make(); next(); var b = next();
+            class A { init() { this.x = 3; } getter() { return fun () { return this.x; }; } }
+            var c = A().getter()();",
+        );
+        assert_eq!(Some("11".into()), global(&interpreter, "a"));
+        assert_eq!(Some("2".into()), global(&interpreter, "b"));
+        assert_eq!(Some("3".into()), global(&interpreter, "c"));
+    }
+
+    #[test]
+    fn exceptions() {
+        let interpreter = run("var a; var b; var c = 0;
+            try { throw 1; } catch (e) { a = e; }
+            try {
+              var l = [];
+              l[3];
+            } catch (e) { b = e; }
+            fun f() { try { return 1; } finally { c = c + 1; } }
+            f();");
+        assert_eq!(Some("1".into()), global(&interpreter, "a"));
+        assert_eq!(
+            Some("{message: Invalid index 3, line: 5}".into()),
+            global(&interpreter, "b")
+        );
+        assert_eq!(Some("1".into()), global(&interpreter, "c"));
+    }
+
+    #[test]
+    fn standard_library() {
+        let interpreter =
+            run("var a = num(str(2) + \"5\") * 2; var b = len(\"abc\"); var c = type(clock);");
+        assert_eq!(Some("50".into()), global(&interpreter, "a"));
+        assert_eq!(Some("3".into()), global(&interpreter, "b"));
+        assert_eq!(Some("function".into()), global(&interpreter, "c"));
+    }
+
+    #[test]
+    fn imports() {
+        let directory = env::temp_dir().join(format!("rulox_closure_imports_{}", process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(
+            directory.join("m.lox"),
+            "var shown = \"m\"; var _hidden = 1; fun f() { return shown; }",
+        )
+        .unwrap();
+        let mut interpreter = ClosureRuloxInterpreter::default();
+        interpreter.set_script_path(&directory.join("script.lox"));
+        LoxImplementation::run(
+            &mut interpreter,
+            "import \"m.lox\" as m; var a = m.f(); import \"m.lox\"; var shown = 2; var b = f();",
+        )
+        .unwrap();
+        assert_eq!(Some("m".into()), global(&interpreter, "a"));
+        assert_eq!(Some("m".into()), global(&interpreter, "b"));
+        assert_eq!(None, global(&interpreter, "_hidden"));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn system_modules_need_capabilities() {
+        let directory = env::temp_dir().join(format!("rulox_closure_system_{}", process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(directory.join("m.lox"), "var first = os.args()[0];").unwrap();
+        let mut interpreter = ClosureRuloxInterpreter::default();
+        assert!(LoxImplementation::run(&mut interpreter, "fs;").is_err());
+        interpreter.set_script_path(&directory.join("script.lox"));
+        interpreter.set_capabilities(Capabilities::all());
+        interpreter.set_arguments(&["one".to_string()]);
+        let file = directory.join("file.txt");
+        LoxImplementation::run(
+            &mut interpreter,
+            &format!(
+                "var path = \"{}\"; fs.writeFile(path, \"text\");
+                var content = fs.readFile(path);
+                import \"m.lox\";",
+                file.display()
+            ),
+        )
+        .unwrap();
+        assert_eq!(Some("text".into()), global(&interpreter, "content"));
+        assert_eq!(Some("one".into()), global(&interpreter, "first"));
+        assert_eq!(Some("<module os>".into()), global(&interpreter, "os"));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn exit_needs_its_capability_and_cant_be_caught() {
+        let mut interpreter = ClosureRuloxInterpreter::default();
+        assert!(LoxImplementation::run(&mut interpreter, "exit;").is_err());
+        interpreter.set_capabilities(Capabilities::all());
+        match LoxImplementation::run(
+            &mut interpreter,
+            "var after = false;
+            fun f() { try { exit(3); } catch (e) { after = true; } finally { after = true; } }
+            f();
+            after = true;",
+        ) {
+            Err(RunError::Exit(3)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+        assert_eq!(Some("false".into()), global(&interpreter, "after"));
+    }
+
+    #[test]
+    fn return_from_a_loop() {
+        let interpreter = run("fun f() { while (true) { return 1; } } var a = f();");
+        assert_eq!(Some("1".into()), global(&interpreter, "a"));
+    }
+
+    #[test]
+    fn break_and_continue() {
+        let interpreter = run("var sum = 0; var last;
+            for (var i = 0; i < 10; i = i + 1) {
+                last = i;
+                if (i == 7) break;
+                if (i == 2 or i == 4) continue;
+                sum = sum + i;
+            }");
+        assert_eq!(Some("15".into()), global(&interpreter, "sum"));
+        assert_eq!(Some("7".into()), global(&interpreter, "last"));
+    }
+
+    #[test]
+    fn lists() {
+        let interpreter = run(
+            "var xs = [1, 2, 3]; var ys = xs; ys[0] = \"a\"; push(xs, [4]);
+            insert(xs, 0, nil); var removed = remove(xs, 2); var popped = pop(xs);
+            var sum = 0; for (var i = 0; i < len(popped); i = i + 1) sum = sum + popped[i];
+            var part = slice(xs, 1, 3); var equal = part == [\"a\", 3];",
+        );
+        assert_eq!(Some("[nil, a, 3]".into()), global(&interpreter, "xs"));
+        assert_eq!(Some("2".into()), global(&interpreter, "removed"));
+        assert_eq!(Some("4".into()), global(&interpreter, "sum"));
+        assert_eq!(Some("[a, 3]".into()), global(&interpreter, "part"));
+        assert_eq!(Some("true".into()), global(&interpreter, "equal"));
+    }
+
+    #[test]
+    fn maps() {
+        let interpreter = run(
+            "var m = {\"a\": 1, 2: nil}; var n = m; n[\"b\"] = [3]; m[2] = true;
+            var removed = remove(m, \"a\"); var names = keys(m); var contents = values(m);
+            var found = has(m, 2) and !has(m, \"a\"); var size = len(m);
+            var equal = {1: 2, 3: 4} == {3: 4, 1: 2};",
+        );
+        assert_eq!(Some("{2: true, b: [3]}".into()), global(&interpreter, "m"));
+        assert_eq!(Some("1".into()), global(&interpreter, "removed"));
+        assert_eq!(Some("[2, b]".into()), global(&interpreter, "names"));
+        assert_eq!(Some("[true, [3]]".into()), global(&interpreter, "contents"));
+        assert_eq!(Some("true".into()), global(&interpreter, "found"));
+        assert_eq!(Some("2".into()), global(&interpreter, "size"));
+        assert_eq!(Some("true".into()), global(&interpreter, "equal"));
+    }
+
+    #[test]
+    fn classes() {
+        let interpreter = run(
+            "class A { init(x) { this.x = x; } get() { return this.x; } }
+            class B < A { get() { return super.get() + 1; } }
+            class C < B {}
+            var c = C(1); var a = c.get(); var same = c == c; var different = C(1) == c;",
+        );
+        assert_eq!(Some("2".into()), global(&interpreter, "a"));
+        assert_eq!(Some("true".into()), global(&interpreter, "same"));
+        assert_eq!(Some("false".into()), global(&interpreter, "different"));
+    }
+
+    #[test]
+    fn runtime_errors() {
+        for source in &[
+            "-nil;",
+            "1 + nil;",
+            "undefined;",
+            "nil();",
+            "1.a;",
+            "fun f(a) {} f();",
+            "[1][1];",
+            "[1][0.5] = 2;",
+            "nil[0];",
+            "pop([]);",
+            "slice([1, 2], 2, 1);",
+            "({})[1];",
+            "var m = {}; m[[]] = 1;",
+            "remove({}, 1);",
+            "keys([]);",
+            "push({}, 1);",
+        ] {
+            let mut interpreter = ClosureRuloxInterpreter::default();
+            match LoxImplementation::run(&mut interpreter, source) {
+                Err(RunError::RuntimeError(_)) => {}
+                other => panic!("Expected a runtime error for {}, got {:?}", source, other),
+            }
+        }
+    }
+}
@@ -0,0 +1,350 @@
+//! Differential testing between the tree-walker and the VMs.
+//!
+//! Random well-formed programs are generated as syntax trees, printed
+//! back as Lox source by `PrettyPrint` and run by every backend, which
+//! must print the same lines and stop with the same kind of error, if
+//! any. Failures are shrunk by proptest and saved under
+//! `proptest-regressions/` so they're re-run first.
+//!
+//! The generated programs are limited to what the VMs can compile, and
+//! to statements: no classes, nor functions. Loops count up to a small bound so that the
+//! programs end.
+
+use output;
+use proptest::collection::vec;
+use proptest::prelude::*;
+use std::fmt;
+use treewalk::ast::*;
+use treewalk::pretty_printer::PrettyPrint;
+use treewalk::scanner::Position;
+use treewalk::{LoxError, TreeWalkRuloxInterpreter};
+use vm::{compiler, interpreter, register};
+
+/// The variables the programs define and use
+const VARIABLES: [&str; 3] = ["a", "b", "c"];
+/// The counter of the loops, which their bodies don't assign
+const COUNTER: &str = "i";
+
+#[derive(Debug, PartialEq)]
+enum Error {
+    Compile,
+    Runtime,
+}
+
+/// What running a program printed, and how it failed
+#[derive(Debug, PartialEq)]
+struct Outcome {
+    output: String,
+    error: Option<Error>,
+}
+
+fn run_treewalk(source: &str) -> Outcome {
+    let (output, result) = output::capture(|| TreeWalkRuloxInterpreter::default().run(source));
+    let error = match result {
+        Ok(()) => None,
+        Err(LoxError::RuntimeError(_)) => Some(Error::Runtime),
+        Err(_) => Some(Error::Compile),
+    };
+    Outcome { output, error }
+}
+
+fn run_vm(source: &str) -> Outcome {
+    let chunk = match compiler::compile(source) {
+        Ok(chunk) => chunk,
+        Err(_) => return compile_error(),
+    };
+    let (output, result) = output::capture(|| interpreter::interpret(&chunk));
+    Outcome {
+        output,
+        error: result.err().map(|_| Error::Runtime),
+    }
+}
+
+fn run_register_vm(source: &str) -> Outcome {
+    let chunk = match register::compiler::compile(source) {
+        Ok(chunk) => chunk,
+        Err(_) => return compile_error(),
+    };
+    let (output, result) = output::capture(|| register::interpreter::interpret(&chunk));
+    Outcome {
+        output,
+        error: result.err().map(|_| Error::Runtime),
+    }
+}
+
+fn compile_error() -> Outcome {
+    Outcome {
+        output: String::new(),
+        error: Some(Error::Compile),
+    }
+}
+
+/// Gives the names the same identifiers every time
+fn identifier_map() -> IdentifierMap {
+    let mut identifier_map = IdentifierMap::new();
+    for name in VARIABLES.iter().chain(&[COUNTER]) {
+        identifier_map.for_name(name);
+    }
+    identifier_map
+}
+
+fn identifier(name: &str) -> Identifier {
+    identifier_map().for_name(name)
+}
+
+fn variable(name: &str) -> Expr {
+    Expr::Identifier(VariableUseHandleFactory::new().next(), identifier(name))
+}
+
+fn assignment(name: &str, rvalue: Expr) -> Expr {
+    Expr::Assignment(Box::new(Assignment {
+        handle: VariableUseHandleFactory::new().next(),
+        lvalue: Target::Identifier(identifier(name)),
+        rvalue,
+    }))
+}
+
+fn block(statements: Vec<Statement>) -> Statement {
+    let positions = statements.iter().map(|_| Position::initial()).collect();
+    Statement::Block(Box::new(Block {
+        statements,
+        positions,
+    }))
+}
+
+/// Whether the expression uses the variable, which it can't do when it
+/// initializes a local variable of the same name
+fn reads(expr: &Expr, name: Identifier) -> bool {
+    match *expr {
+        Expr::Identifier(_, identifier) => identifier == name,
+        Expr::Unary(ref u) => reads(&u.right, name),
+        Expr::Binary(ref b) => reads(&b.left, name) || reads(&b.right, name),
+        Expr::Logic(ref l) => reads(&l.left, name) || reads(&l.right, name),
+        Expr::Grouping(ref g) => reads(&g.expr, name),
+        Expr::List(ref elements) => elements.iter().any(|e| reads(e, name)),
+        Expr::Map(ref entries) => entries
+            .iter()
+            .any(|&(ref key, ref value)| reads(key, name) || reads(value, name)),
+        Expr::Index(ref i) => reads(&i.collection, name) || reads(&i.index, name),
+        _ => false,
+    }
+}
+
+/// `{ var i = 0; for (; i < times; i = i + 1) body }`
+fn counted_loop(times: u32, body: Vec<Statement>) -> Statement {
+    let binary = |left, operator, right| {
+        Expr::Binary(Box::new(BinaryExpr {
+            left,
+            operator,
+            right,
+        }))
+    };
+    let condition = binary(
+        variable(COUNTER),
+        BinaryOperator::Less,
+        Expr::Literal(Literal::Number(f64::from(times))),
+    );
+    let increment = assignment(
+        COUNTER,
+        binary(
+            variable(COUNTER),
+            BinaryOperator::Plus,
+            Expr::Literal(Literal::Number(1.0)),
+        ),
+    );
+    block(vec![
+        Statement::VariableDefinitionWithInitalizer(
+            identifier(COUNTER),
+            Expr::Literal(Literal::Number(0.0)),
+        ),
+        Statement::While(Box::new(While {
+            condition,
+            body: block(body),
+            increment: Some(increment),
+        })),
+    ])
+}
+
+/// A script, shown as its source when a case fails
+struct Program(Vec<Statement>);
+
+impl Program {
+    fn source(&self) -> String {
+        let identifier_map = identifier_map();
+        let lines: Vec<String> = self
+            .0
+            .iter()
+            .map(|statement| statement.print_source(&identifier_map))
+            .collect();
+        lines.join("\n")
+    }
+}
+
+impl fmt::Debug for Program {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source())
+    }
+}
+
+fn arb_literal() -> BoxedStrategy<Literal> {
+    prop_oneof![
+        Just(()).prop_map(|_| Literal::Nil),
+        any::<bool>().prop_map(Literal::Bool),
+        // Eighths are printed and parsed back exactly
+        (0u32..100).prop_map(|n| Literal::Number(f64::from(n) / 8.0)),
+        // A string pattern would be compiled for each value
+        vec(prop::char::range('a', 'z'), 0..4)
+            .prop_map(|s| Literal::String(s.into_iter().collect::<String>().into())),
+    ]
+    .boxed()
+}
+
+fn arb_variable() -> BoxedStrategy<&'static str> {
+    prop::sample::select(VARIABLES.to_vec()).boxed()
+}
+
+fn arb_unary_operator() -> BoxedStrategy<UnaryOperator> {
+    prop_oneof![
+        Just(()).prop_map(|_| UnaryOperator::Bang),
+        Just(()).prop_map(|_| UnaryOperator::Minus),
+    ]
+    .boxed()
+}
+
+fn arb_binary_operator() -> BoxedStrategy<BinaryOperator> {
+    prop_oneof![
+        Just(BinaryOperator::Minus),
+        Just(BinaryOperator::Plus),
+        Just(BinaryOperator::Slash),
+        Just(BinaryOperator::Star),
+        Just(BinaryOperator::Percent),
+        Just(BinaryOperator::TildeSlash),
+        Just(BinaryOperator::Equal),
+        Just(BinaryOperator::NotEqual),
+        Just(BinaryOperator::Less),
+        Just(BinaryOperator::LessEqual),
+        Just(BinaryOperator::Greater),
+        Just(BinaryOperator::GreaterEqual),
+    ]
+    .boxed()
+}
+
+fn arb_logic_operator() -> BoxedStrategy<LogicOperator> {
+    prop_oneof![Just(LogicOperator::Or), Just(LogicOperator::And)].boxed()
+}
+
+fn arb_expression() -> BoxedStrategy<Expr> {
+    prop_oneof![
+        arb_literal().prop_map(Expr::Literal),
+        arb_variable().prop_map(variable),
+    ]
+    .prop_recursive(3, 8, 2, |inner| {
+        prop_oneof![
+            (arb_unary_operator(), inner.clone())
+                .prop_map(|(operator, right)| Expr::Unary(Box::new(UnaryExpr { operator, right }))),
+            (inner.clone(), arb_binary_operator(), inner.clone()).prop_map(
+                |(left, operator, right)| Expr::Binary(Box::new(BinaryExpr {
+                    left,
+                    operator,
+                    right,
+                }))
+            ),
+            (inner.clone(), arb_logic_operator(), inner.clone()).prop_map(
+                |(left, operator, right)| Expr::Logic(Box::new(LogicExpr {
+                    left,
+                    operator,
+                    right,
+                }))
+            ),
+            inner
+                .clone()
+                .prop_map(|expr| Expr::Grouping(Box::new(Grouping { expr }))),
+            vec(inner.clone(), 0..3).prop_map(Expr::List),
+            vec((inner.clone(), inner.clone()), 0..3).prop_map(Expr::Map),
+            (inner.clone(), inner)
+                .prop_map(|(collection, index)| Expr::Index(Box::new(Index { collection, index }))),
+        ]
+    })
+    .boxed()
+}
+
+/// Declarations are only allowed in blocks, which are the branches and
+/// the bodies of the loops
+fn arb_statement() -> BoxedStrategy<Statement> {
+    prop_oneof![
+        arb_expression().prop_map(Statement::Print),
+        (arb_variable(), arb_expression())
+            .prop_filter(
+                "A local can't be read in its own initializer",
+                |&(name, ref initializer)| { !reads(initializer, identifier(name)) }
+            )
+            .prop_map(|(name, initializer)| {
+                Statement::VariableDefinitionWithInitalizer(identifier(name), initializer)
+            }),
+        (arb_variable(), arb_expression())
+            .prop_map(|(name, rvalue)| Statement::Expression(assignment(name, rvalue))),
+    ]
+    .prop_recursive(2, 12, 3, |inner| {
+        prop_oneof![
+            vec(inner.clone(), 0..4).prop_map(block),
+            (arb_expression(), vec(inner.clone(), 0..3)).prop_map(|(condition, then_branch)| {
+                Statement::IfThen(Box::new(IfThen {
+                    condition,
+                    then_branch: block(then_branch),
+                }))
+            }),
+            (
+                arb_expression(),
+                vec(inner.clone(), 0..3),
+                vec(inner.clone(), 0..3)
+            )
+                .prop_map(|(condition, then_branch, else_branch)| {
+                    Statement::IfThenElse(Box::new(IfThenElse {
+                        condition,
+                        then_branch: block(then_branch),
+                        else_branch: block(else_branch),
+                    }))
+                }),
+            (0u32..4, vec(inner, 0..3)).prop_map(|(times, body)| counted_loop(times, body)),
+        ]
+    })
+    .boxed()
+}
+
+/// The variables are defined first, so that most programs don't stop at
+/// the first one they use
+fn arb_program() -> BoxedStrategy<Program> {
+    (vec(arb_literal(), 3), vec(arb_statement(), 1..6))
+        .prop_map(|(initializers, statements)| {
+            let definitions = VARIABLES.iter().zip(initializers).map(|(name, literal)| {
+                Statement::VariableDefinitionWithInitalizer(
+                    identifier(name),
+                    Expr::Literal(literal),
+                )
+            });
+            Program(definitions.chain(statements).collect())
+        })
+        .boxed()
+}
+
+proptest! {
+#[test]
+fn backends_agree(ref program in arb_program()) {
+    let source = program.source();
+    let expected = run_treewalk(&source);
+    prop_assert_eq!(&expected, &run_vm(&source), "Running {}", source);
+    prop_assert_eq!(&expected, &run_register_vm(&source), "Running {}", source);
+}
+}
+
+#[test]
+fn output_is_compared() {
+    let source = "var a = 1; { var a = 2; print a; } print a; print -nil;";
+    let expected = Outcome {
+        output: "2\n1\n".into(),
+        error: Some(Error::Runtime),
+    };
+    assert_eq!(expected, run_treewalk(source));
+    assert_eq!(expected, run_vm(source));
+    assert_eq!(expected, run_register_vm(source));
+}
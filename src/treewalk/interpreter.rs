@@ -1,272 +1,14 @@
 use fnv::FnvHashMap;
-use std::cell::RefCell;
-use std::io;
-use std::io::prelude::*;
+use map::OrderedMap;
+use natives::Capabilities;
+use number;
+use output;
 use std::rc::Rc;
-use std::time::{SystemTime, UNIX_EPOCH};
 use treewalk::ast::*;
-use treewalk::lexical_scope_resolver::{Depth, LexicalScopes};
-
-#[derive(Debug, PartialEq, Clone)]
-pub enum Callable {
-    Function(Rc<FunctionDefinition>, Environment),
-    Class(Rc<Class>),
-    // Native functions
-    Clock,
-}
-
-#[derive(Debug, PartialEq)]
-pub struct Class {
-    superclass: Option<Rc<Class>>,
-    methods: FnvHashMap<Identifier, Callable>,
-}
-
-impl Callable {
-    fn to_string(&self) -> String {
-        match *self {
-            Callable::Function(ref function, _) => format!("<fn {} >", function.arguments.len()),
-            Callable::Class(_) => "<class>".to_string(),
-            Callable::Clock => "clock".into(),
-        }
-    }
-
-    fn register_natives(environment: &Environment, identifier_map: &mut IdentifierMap) -> () {
-        environment.define(
-            identifier_map.for_name("clock"),
-            Value::Callable(Callable::Clock),
-        )
-    }
-
-    fn bind(&self, instance: &Instance) -> Callable {
-        match *self {
-            Callable::Function(ref function, ref environment) => {
-                let environment = Environment::new_with_parent(environment);
-                environment.define(Identifier::this(), Value::Instance(instance.clone()));
-                Callable::Function(function.clone(), environment)
-            }
-            _ => panic!(),
-        }
-    }
-
-    fn call(
-        &self,
-        arguments: &[Value],
-        _environment: &Environment,
-        scopes: &LexicalScopes,
-    ) -> Result<Value, RuntimeError> {
-        match *self {
-            Callable::Clock => {
-                if !arguments.is_empty() {
-                    return Err(RuntimeError::WrongNumberOfArguments);
-                }
-                Ok(Value::Number(
-                    SystemTime::now()
-                        .duration_since(UNIX_EPOCH)
-                        .unwrap()
-                        .as_secs() as f64,
-                ))
-            }
-            Callable::Function(ref function_definition, ref environment) => {
-                if arguments.len() != function_definition.arguments.len() {
-                    return Err(RuntimeError::WrongNumberOfArguments);
-                }
-                let local_environment = Environment::new_with_parent(environment);
-                for (i, argument) in arguments.iter().enumerate() {
-                    local_environment.define(function_definition.arguments[i], argument.clone());
-                }
-                let result = function_definition.body.execute(&local_environment, scopes);
-                result.map(|ok| match ok {
-                    Some(value) => value,
-                    None => {
-                        if function_definition.kind == FunctionKind::Initializer {
-                            environment.get(Identifier::this(), 0).unwrap()
-                        // Special case, for when the initializer is
-                        // called explicitly to "re-initialize" the object
-                        } else {
-                            Value::Nil // For when the function didn't return
-                        }
-                    }
-                })
-            }
-            Callable::Class(ref class) => {
-                let instance = Instance::new(class);
-                if let Some(initializer) = class.methods.get(&Identifier::init()) {
-                    try!(
-                        initializer
-                            .bind(&instance)
-                            .call(arguments, _environment, scopes)
-                    );
-                }
-                Ok(Value::Instance(instance))
-            }
-        }
-    }
-}
-
-#[derive(Debug, PartialEq)]
-pub struct _Instance {
-    class: Rc<Class>,
-    fields: FnvHashMap<Identifier, Value>,
-}
-
-// This needs to be Rc a "by reference" semantics
-#[derive(Debug, PartialEq, Clone)]
-pub struct Instance(Rc<RefCell<_Instance>>);
-
-impl Instance {
-    fn new(class: &Rc<Class>) -> Instance {
-        Instance(Rc::new(RefCell::new(_Instance {
-            class: class.clone(),
-            fields: FnvHashMap::default(),
-        })))
-    }
-
-    fn get(&self, property: Identifier) -> Result<Value, RuntimeError> {
-        if let Some(v) = self.0.borrow().fields.get(&property) {
-            return Ok(v.clone());
-        }
-        if let Some(m) = self.find_method(property) {
-            return Ok(Value::Callable(m.clone()));
-        }
-        Err(RuntimeError::UndefinedIdentifier(property))
-    }
-
-    fn find_method(&self, property: Identifier) -> Option<Callable> {
-        let class = &self.0.borrow().class;
-        let method = class.methods.get(&property).cloned().or_else(|| {
-            let superclass = class.superclass.clone();
-            superclass.and_then(|s| s.methods.get(&property).cloned())
-        });
-        method.map(|m| m.bind(self))
-    }
-
-    fn find_super_method(&self, property: Identifier) -> Option<Callable> {
-        let class = self.0.borrow().class.clone();
-        let superclass = class.superclass.clone();
-        // Unwrap is fine, this is checked via static analysis earlier
-        let method = superclass.unwrap().methods.get(&property).cloned();
-        method.map(|m| m.bind(self))
-    }
-
-    fn set(&self, property: Identifier, value: &Value) -> () {
-        self.0.borrow_mut().fields.insert(property, value.clone());
-    }
-}
-
-#[derive(Debug, PartialEq, Clone)]
-pub enum Value {
-    Nil,
-    Boolean(bool),
-    Number(f64),
-    String(String),
-    Callable(Callable),
-    Instance(Instance),
-}
-
-impl Value {
-    fn is_true(&self) -> bool {
-        match *self {
-            Value::Nil => false,
-            Value::Boolean(b) => b,
-            _ => true,
-        }
-    }
-
-    fn to_string(&self) -> String {
-        match *self {
-            Value::Nil => "nil".into(),
-            Value::Boolean(ref b) => b.to_string(),
-            Value::Number(ref n) => n.to_string(),
-            Value::String(ref s) => s.clone(),
-            Value::Callable(ref c) => c.to_string(),
-            //TODO: improve to_string for instance
-            Value::Instance(_) => "Instance".into(),
-        }
-    }
-}
-
-#[derive(Debug)]
-struct EnvironmentImpl {
-    parent: Option<Environment>,
-    values: FnvHashMap<Identifier, Value>,
-}
-
-#[derive(Clone, Debug)]
-pub struct Environment {
-    actual: Rc<RefCell<EnvironmentImpl>>,
-}
-
-impl PartialEq for Environment {
-    fn eq(&self, _other: &Environment) -> bool {
-        false
-    }
-}
-
-impl Environment {
-    pub fn new_with_natives(identifier_map: &mut IdentifierMap) -> Environment {
-        let environment = Environment::new();
-        Callable::register_natives(&environment, identifier_map);
-        environment
-    }
-
-    fn new() -> Environment {
-        let actual = EnvironmentImpl {
-            parent: None,
-            values: FnvHashMap::default(),
-        };
-        Environment {
-            actual: Rc::new(RefCell::new(actual)),
-        }
-    }
-
-    fn new_with_parent(parent: &Environment) -> Environment {
-        let actual = EnvironmentImpl {
-            parent: Some(parent.clone()),
-            values: FnvHashMap::default(),
-        };
-        Environment {
-            actual: Rc::new(RefCell::new(actual)),
-        }
-    }
-
-    fn define(&self, identifier: Identifier, value: Value) {
-        // NOTE that this allow for variable redefinition. See the chapter.
-        self.actual.borrow_mut().values.insert(identifier, value);
-    }
-
-    fn try_set(&self, identifier: Identifier, depth: Depth, value: Value) -> bool {
-        let mut actual = self.actual.borrow_mut();
-        if depth == 0 {
-            if actual.values.contains_key(&identifier) {
-                actual.values.insert(identifier, value);
-                true
-            } else {
-                false
-            }
-        } else {
-            match actual.parent {
-                Some(ref parent) => parent.try_set(identifier, depth - 1, value),
-                None => false,
-            }
-        }
-    }
-
-    fn get(&self, identifier: Identifier, depth: Depth) -> Option<Value> {
-        let actual = self.actual.borrow();
-        if depth == 0 {
-            if let Some(value) = actual.values.get(&identifier) {
-                Some(value.clone())
-            } else {
-                None
-            }
-        } else {
-            match actual.parent {
-                Some(ref parent) => parent.get(identifier, depth - 1),
-                None => None,
-            }
-        }
-    }
-}
+use treewalk::lexical_scope_resolver::{LexicalScopes, VariableLocation};
+use treewalk::runtime::{
+    map_key, Callable, Class, Environment, Function, Interruption, List, Map, RuntimeError, Value,
+};
 
 pub struct StatementInterpreter {
     environment: Environment,
@@ -281,20 +23,36 @@ impl StatementInterpreter {
         &mut self,
         lexical_scopes: &LexicalScopes,
         statement: &Statement,
-    ) -> Result<Option<Value>, RuntimeError> {
+    ) -> Result<Option<Interruption>, RuntimeError> {
         statement.execute(&self.environment, lexical_scopes)
     }
-}
 
-#[derive(Debug)]
-pub enum RuntimeError {
-    UnaryMinusTypeMismatch(Value),
-    BinaryOperatorTypeMismatch(BinaryOperator, Value, Value),
-    UndefinedIdentifier(Identifier),
-    NotCallable(Value),
-    NotAnInstance(Value),
-    NotAClass(Value),
-    WrongNumberOfArguments,
+    pub fn globals(&self) -> Vec<(Identifier, Value)> {
+        self.environment.globals()
+    }
+
+    pub fn define(&mut self, identifier: Identifier, value: Value) {
+        self.environment.define(identifier, value);
+    }
+
+    /// Defines `fs`, `os`, `input` and `exit` if the capabilities allow them
+    pub fn define_system_access(
+        &mut self,
+        identifier_map: &mut IdentifierMap,
+        capabilities: Capabilities,
+        arguments: &[String],
+    ) {
+        self.environment
+            .define_system_access(identifier_map, capabilities, arguments)
+    }
+
+    pub fn evaluate(
+        &mut self,
+        lexical_scopes: &LexicalScopes,
+        expression: &Expr,
+    ) -> Result<Value, RuntimeError> {
+        expression.interpret(&self.environment, lexical_scopes)
+    }
 }
 
 trait Interpret {
@@ -306,7 +64,7 @@ trait Execute {
         &self,
         environment: &Environment,
         scopes: &LexicalScopes,
-    ) -> Result<Option<Value>, RuntimeError>;
+    ) -> Result<Option<Interruption>, RuntimeError>;
 }
 
 impl Interpret for Expr {
@@ -321,24 +79,34 @@ impl Interpret for Expr {
             Expr::Binary(ref b) => b.interpret(environment, scopes),
             Expr::Logic(ref b) => b.interpret(environment, scopes),
             Expr::Grouping(ref g) => g.interpret(environment, scopes),
-            Expr::Super(ref handle, _identifier, member) => {
-                let depth = scopes.get_depth(*handle).unwrap();
+            Expr::Super(ref handle, super_identifier, member) => {
                 // We know where this is because we control the layout
-                // of the environments.
-                let object = environment.get(Identifier::this(), depth - 1).unwrap();
-                if let Value::Instance(instance) = object {
-                    match instance.find_super_method(member) {
-                        Some(member) => Ok(Value::Callable(member)),
-                        None => Err(RuntimeError::UndefinedIdentifier(member)),
-                    }
-                } else {
+                // of the environments: it's the only variable in the
+                // environment right below the one of super.
+                let (superclass_location, this_location) = match scopes.get_location(*handle) {
+                    Some(VariableLocation::Local(depth, slot)) => (
+                        VariableLocation::Local(depth, slot),
+                        VariableLocation::Local(depth - 1, 0),
+                    ),
+                    _ => panic!("Invalid use of super"),
+                };
+                let superclass = environment.get(superclass_location, super_identifier);
+                let object = environment.get(this_location, Identifier::this());
+                match (superclass, object) {
+                    (
+                        Some(Value::Callable(Callable::Class(class))),
+                        Some(Value::Instance(instance)),
+                    ) => match class.find_method(member) {
+                        Some(method) => Ok(Value::Callable(method.bind(&instance))),
+                        None => Err(RuntimeError::UndefinedProperty(member)),
+                    },
                     // Static analysis should prevent us from getting here
-                    panic!("Invalid use of super");
+                    _ => panic!("Invalid use of super"),
                 }
             }
             Expr::This(ref handle, ref i) | Expr::Identifier(ref handle, ref i) => {
-                match scopes.get_depth(*handle) {
-                    Some(depth) => match environment.get(*i, depth.clone()) {
+                match scopes.get_location(*handle) {
+                    Some(location) => match environment.get(location, *i) {
                         Some(value) => Ok(value.clone()),
                         None => Err(RuntimeError::UndefinedIdentifier(*i)),
                     },
@@ -349,18 +117,58 @@ impl Interpret for Expr {
             Expr::Call(ref c) => c.interpret(environment, scopes),
             Expr::Get(ref g) => match g.instance.interpret(environment, scopes) {
                 Ok(Value::Instance(ref instance)) => instance.get(g.property),
+                Ok(Value::Module(ref module)) => module.get(g.property),
                 Ok(v) => Err(RuntimeError::NotAnInstance(v.clone())),
                 e => e,
             },
             Expr::Set(ref s) => match s.instance.interpret(environment, scopes) {
                 Ok(Value::Instance(ref instance)) => {
                     let value = try!(s.value.interpret(environment, scopes));
-                    instance.set(s.property, &value);
+                    instance.set(s.property, value.clone());
                     Ok(value)
                 }
                 Ok(v) => Err(RuntimeError::NotAnInstance(v.clone())),
                 e => e,
             },
+            Expr::List(ref elements) => {
+                let mut values = Vec::with_capacity(elements.len());
+                for element in elements {
+                    values.push(element.interpret(environment, scopes)?);
+                }
+                Ok(Value::List(List::new(values)))
+            }
+            Expr::Lambda(ref f) => Ok(Value::Callable(Callable::Function(
+                function(f, scopes),
+                environment.clone(),
+            ))),
+            Expr::Map(ref entries) => {
+                let mut map = OrderedMap::new();
+                for (key, value) in entries {
+                    let key = map_key(&key.interpret(environment, scopes)?)?;
+                    map.insert(key, value.interpret(environment, scopes)?);
+                }
+                Ok(Value::Map(Map::new(map)))
+            }
+            Expr::Index(ref i) => {
+                let collection = i.collection.interpret(environment, scopes)?;
+                let index = i.index.interpret(environment, scopes)?;
+                match collection {
+                    Value::List(ref list) => list.get(&index),
+                    Value::Map(ref map) => map.get(&index),
+                    value => Err(RuntimeError::NotACollection(value)),
+                }
+            }
+            Expr::SetIndex(ref s) => {
+                let collection = s.collection.interpret(environment, scopes)?;
+                let index = s.index.interpret(environment, scopes)?;
+                let value = s.value.interpret(environment, scopes)?;
+                match collection {
+                    Value::List(ref list) => list.set(&index, value.clone())?,
+                    Value::Map(ref map) => map.set(&index, value.clone())?,
+                    value => return Err(RuntimeError::NotACollection(value)),
+                }
+                Ok(value)
+            }
         }
     }
 }
@@ -368,10 +176,10 @@ impl Interpret for Expr {
 impl Interpret for Literal {
     fn interpret(&self, _: &Environment, _: &LexicalScopes) -> Result<Value, RuntimeError> {
         match *self {
-            Literal::NilLiteral => Ok(Value::Nil),
-            Literal::BoolLiteral(b) => Ok(Value::Boolean(b)),
-            Literal::StringLiteral(ref s) => Ok(Value::String(s.clone())),
-            Literal::NumberLiteral(n) => Ok(Value::Number(n)),
+            Literal::Nil => Ok(Value::Nil),
+            Literal::Bool(b) => Ok(Value::Boolean(b)),
+            Literal::String(ref s) => Ok(Value::String(s.clone())),
+            Literal::Number(n) => Ok(Value::Number(n)),
         }
     }
 }
@@ -385,8 +193,8 @@ impl Interpret for Assignment {
         let Target::Identifier(target) = self.lvalue;
         match self.rvalue.interpret(environment, scopes) {
             Ok(value) => {
-                if let Some(depth) = scopes.get_depth(self.handle) {
-                    if environment.try_set(target, depth.clone(), value.clone()) {
+                if let Some(location) = scopes.get_location(self.handle) {
+                    if environment.set(location, target, value.clone()) {
                         Ok(value.clone())
                     } else {
                         Err(RuntimeError::UndefinedIdentifier(target))
@@ -445,14 +253,20 @@ impl Interpret for BinaryExpr {
             (&BinaryOperator::Star, &Value::Number(l), &Value::Number(r)) => {
                 Ok(Value::Number(l * r))
             }
+            (&BinaryOperator::Percent, &Value::Number(l), &Value::Number(r)) => {
+                Ok(Value::Number(number::modulo(l, r)))
+            }
+            (&BinaryOperator::TildeSlash, &Value::Number(l), &Value::Number(r)) => {
+                Ok(Value::Number(number::integer_division(l, r)))
+            }
             (&BinaryOperator::Plus, &Value::Number(l), &Value::Number(r)) => {
                 Ok(Value::Number(l + r))
             }
             (&BinaryOperator::Plus, &Value::String(ref l), &Value::String(ref r)) => {
-                let mut result = String::new();
+                let mut result = String::with_capacity(l.len() + r.len());
                 result.push_str(l);
                 result.push_str(r);
-                Ok(Value::String(result))
+                Ok(Value::String(result.into()))
             }
             (&BinaryOperator::Greater, &Value::Number(l), &Value::Number(r)) => {
                 Ok(Value::Boolean(l > r))
@@ -519,7 +333,7 @@ impl Interpret for Call {
                         error => return error,
                     }
                 }
-                c.call(&evaluated_arguments, environment, scopes)
+                c.call(evaluated_arguments)
             }
             Ok(value) => Err(RuntimeError::NotCallable(value)),
             error => error,
@@ -527,26 +341,93 @@ impl Interpret for Call {
     }
 }
 
+/// A function running the definition in the environment holding its
+/// arguments, with the scopes the resolver gave to the definition
+fn function(definition: &Rc<FunctionDefinition>, scopes: &LexicalScopes) -> Rc<Function> {
+    let body = definition.clone();
+    let scopes = scopes.clone();
+    Rc::new(Function::new(
+        definition.kind,
+        definition.arguments.len(),
+        Box::new(move |environment| body.body.execute_in(environment, &scopes)),
+    ))
+}
+
+impl Block {
+    /// Executes the statements in the given environment, without creating
+    /// a new one
+    fn execute_in(
+        &self,
+        environment: &Environment,
+        scopes: &LexicalScopes,
+    ) -> Result<Option<Interruption>, RuntimeError> {
+        for (statement, position) in self.statements.iter().zip(&self.positions) {
+            let result = statement
+                .execute(environment, scopes)
+                .map_err(|error| error.at_line(position.line))?;
+            if let Some(interruption) = result {
+                return Ok(Some(interruption));
+            }
+        }
+        Ok(None)
+    }
+}
+
 impl Execute for Statement {
     fn execute(
         &self,
         environment: &Environment,
         scopes: &LexicalScopes,
-    ) -> Result<Option<Value>, RuntimeError> {
+    ) -> Result<Option<Interruption>, RuntimeError> {
         match *self {
             Statement::Expression(ref e) => e.interpret(environment, scopes).map(|_| None),
             // Expression statement are only for side effects
-            Statement::Print(ref e) => {
-                e.interpret(environment, scopes).map(|value| {
-                    println!("{}", value.to_string());
-                    let _ = io::stdout().flush(); //TODO: is this okay?
-                    None
-                })
-            }
+            Statement::Print(ref e) => e.interpret(environment, scopes).map(|value| {
+                output::print(value);
+                None
+            }),
             Statement::Return(ref e) => match *e {
-                Some(ref e) => e.interpret(environment, scopes).map(Some),
-                None => Ok(Some(Value::Nil)),
+                Some(ref e) => e
+                    .interpret(environment, scopes)
+                    .map(|value| Some(Interruption::Return(value))),
+                None => Ok(Some(Interruption::Return(Value::Nil))),
             },
+            Statement::Break => Ok(Some(Interruption::Break)),
+            Statement::Continue => Ok(Some(Interruption::Continue)),
+            Statement::Throw(ref e) => Err(RuntimeError::Thrown(e.interpret(environment, scopes)?)),
+            Statement::Import(_) => {
+                unreachable!("Imports are only at the top level, where the driver runs them")
+            }
+            Statement::Try(ref t) => {
+                let mut result = t
+                    .body
+                    .execute_in(&Environment::new_with_parent(environment), scopes);
+                if let Some(ref catch) = t.catch {
+                    match result {
+                        Err(RuntimeError::Exit(_)) | Ok(_) => {}
+                        Err(error) => {
+                            // The layout must match the one of the resolver
+                            let catch_environment = Environment::new_with_parent(environment);
+                            catch_environment.define(catch.variable, error.caught_value());
+                            result = catch.body.execute_in(&catch_environment, scopes);
+                        }
+                    }
+                }
+                if let Err(RuntimeError::Exit(_)) = result {
+                    return result;
+                }
+                match t.finally {
+                    // An interruption or an error of the finally block
+                    // replaces the result of the rest
+                    Some(ref finally) => match finally
+                        .execute_in(&Environment::new_with_parent(environment), scopes)?
+                    {
+                        Some(interruption) => Ok(Some(interruption)),
+                        None => result,
+                    },
+                    None => result,
+                }
+            }
             Statement::VariableDefinition(ref identifier) => {
                 environment.define(*identifier, Value::Nil);
                 Ok(None)
@@ -560,17 +441,10 @@ impl Execute for Statement {
                     })
             }
             Statement::Block(ref b) => {
-                let environment = Environment::new_with_parent(environment);
-                for statement in &b.statements {
-                    let result = try!(statement.execute(&environment, scopes));
-                    if let Some(value) = result {
-                        return Ok(Some(value));
-                    }
-                }
-                Ok(None)
+                b.execute_in(&Environment::new_with_parent(environment), scopes)
             }
             Statement::IfThen(ref c) => {
-                let condition = try!{c.condition.interpret(environment, scopes)
+                let condition = try! {c.condition.interpret(environment, scopes)
                 .map(|v|v.is_true())};
                 if condition {
                     c.then_branch.execute(environment, scopes)
@@ -579,7 +453,7 @@ impl Execute for Statement {
                 }
             }
             Statement::IfThenElse(ref c) => {
-                let condition = try!{c.condition.interpret(environment, scopes)
+                let condition = try! {c.condition.interpret(environment, scopes)
                 .map(|v|v.is_true())};
                 if condition {
                     c.then_branch.execute(environment, scopes)
@@ -588,23 +462,30 @@ impl Execute for Statement {
                 }
             }
             Statement::While(ref l) => {
-                while try!{l.condition.interpret(environment, scopes)
-                .map(|v|v.is_true())}
-                {
-                    try!{l.body.execute(environment, scopes)};
+                while l.condition.interpret(environment, scopes)?.is_true() {
+                    match l.body.execute(environment, scopes)? {
+                        Some(Interruption::Break) => break,
+                        Some(Interruption::Return(value)) => {
+                            return Ok(Some(Interruption::Return(value)))
+                        }
+                        Some(Interruption::Continue) | None => {}
+                    }
+                    if let Some(ref increment) = l.increment {
+                        increment.interpret(environment, scopes)?;
+                    }
                 }
                 Ok(None)
             }
             Statement::FunctionDefinition(ref f) => {
                 environment.define(
                     f.name,
-                    Value::Callable(Callable::Function(f.clone(), environment.clone())),
+                    Value::Callable(Callable::Function(function(f, scopes), environment.clone())),
                 );
                 Ok(None)
             }
             Statement::Class(ref c) => {
                 // Not entirely sure why
-                environment.define(c.name, Value::Nil);
+                let location = environment.define(c.name, Value::Nil);
                 let (superclass, superclass_environment) =
                     if let Some(ref superclass) = c.superclass {
                         let value = try!(superclass.interpret(environment, scopes));
@@ -624,17 +505,14 @@ impl Execute for Statement {
                     methods.insert(
                         method_definition.name,
                         Callable::Function(
-                            method_definition.clone(),
+                            function(method_definition, scopes),
                             superclass_environment.clone(),
                         ),
                     );
                 }
                 //TODO: pass trough the class name
-                let class = Rc::new(Class {
-                    methods,
-                    superclass,
-                });
-                environment.define(c.name, Value::Callable(Callable::Class(class)));
+                let class = Rc::new(Class::new(superclass, methods));
+                environment.set(location, c.name, Value::Callable(Callable::Class(class)));
                 Ok(None)
             }
         }
@@ -645,9 +523,12 @@ impl Execute for Statement {
 mod tests {
     use frontend::scanner::*;
     use treewalk::ast::*;
-    use treewalk::interpreter::{Environment, Execute, Interpret, StatementInterpreter, Value};
-    use treewalk::lexical_scope_resolver::{LexicalScopes, LexicalScopesResolver};
+    use treewalk::interpreter::{Execute, Interpret, StatementInterpreter};
+    use treewalk::lexical_scope_resolver::{
+        LexicalScopes, LexicalScopesResolver, VariableLocation,
+    };
     use treewalk::parser::*;
+    use treewalk::runtime::{Environment, Value};
 
     //TODO: change these tests so that:
     // - they have an helper with all the repeated structure
@@ -657,9 +538,9 @@ mod tests {
     fn literal() {
         let environment = Environment::new();
         let string = String::from("abc");
-        let expr = Expr::Literal(Literal::StringLiteral(string.clone()));
+        let expr = Expr::Literal(Literal::String(string.as_str().into()));
         assert_eq!(
-            Value::String(string),
+            Value::String(string.into()),
             expr.interpret(&environment, &LexicalScopes::new()).unwrap()
         );
     }
@@ -669,7 +550,7 @@ mod tests {
         let environment = Environment::new();
         let scopes = LexicalScopes::new();
         let expr = Grouping {
-            expr: Expr::Literal(Literal::NumberLiteral(45.67f64)),
+            expr: Expr::Literal(Literal::Number(45.67f64)),
         };
         assert_eq!(
             Value::Number(45.67f64),
@@ -683,7 +564,7 @@ mod tests {
         let scopes = LexicalScopes::new();
         let expr = UnaryExpr {
             operator: UnaryOperator::Bang,
-            right: Expr::Literal(Literal::BoolLiteral(false)),
+            right: Expr::Literal(Literal::Bool(false)),
         };
         assert_eq!(
             Value::Boolean(true),
@@ -699,7 +580,7 @@ mod tests {
         let scopes = LexicalScopes::new();
         let expr = UnaryExpr {
             operator: UnaryOperator::Minus,
-            right: Expr::Literal(Literal::NilLiteral),
+            right: Expr::Literal(Literal::Nil),
         };
         let statement = Statement::Expression(Expr::Unary(Box::new(expr)));
         assert!(interpreter.execute(&scopes, &statement).is_err());
@@ -711,8 +592,8 @@ mod tests {
         let scopes = LexicalScopes::new();
         let expr = BinaryExpr {
             operator: BinaryOperator::Plus,
-            left: Expr::Literal(Literal::NumberLiteral(1.0f64)),
-            right: Expr::Literal(Literal::NumberLiteral(1.0f64)),
+            left: Expr::Literal(Literal::Number(1.0f64)),
+            right: Expr::Literal(Literal::Number(1.0f64)),
         };
         assert_eq!(
             Value::Number(2.0f64),
@@ -720,14 +601,34 @@ mod tests {
         );
     }
 
+    #[test]
+    fn modulo_and_integer_division_floor() {
+        let environment = Environment::new();
+        let scopes = LexicalScopes::new();
+        for &(operator, expected) in &[
+            (BinaryOperator::Percent, 2.0),
+            (BinaryOperator::TildeSlash, -3.0),
+        ] {
+            let expr = BinaryExpr {
+                operator,
+                left: Expr::Literal(Literal::Number(-7.0f64)),
+                right: Expr::Literal(Literal::Number(3.0f64)),
+            };
+            assert_eq!(
+                Value::Number(expected),
+                expr.interpret(&environment, &scopes).unwrap()
+            );
+        }
+    }
+
     #[test]
     fn string_concatenation() {
         let environment = Environment::new();
         let scopes = LexicalScopes::new();
         let expr = BinaryExpr {
             operator: BinaryOperator::Plus,
-            left: Expr::Literal(Literal::StringLiteral("Foo".into())),
-            right: Expr::Literal(Literal::StringLiteral("Bar".into())),
+            left: Expr::Literal(Literal::String("Foo".into())),
+            right: Expr::Literal(Literal::String("Bar".into())),
         };
         assert_eq!(
             Value::String("FooBar".into()),
@@ -741,8 +642,8 @@ mod tests {
         let scopes = LexicalScopes::new();
         let expr = BinaryExpr {
             operator: BinaryOperator::LessEqual,
-            left: Expr::Literal(Literal::NilLiteral),
-            right: Expr::Literal(Literal::NumberLiteral(1.0f64)),
+            left: Expr::Literal(Literal::Nil),
+            right: Expr::Literal(Literal::Number(1.0f64)),
         };
         assert!(expr.interpret(&environment, &scopes).is_err());
     }
@@ -751,7 +652,7 @@ mod tests {
     fn expression_statement() {
         let environment = Environment::new();
         let scopes = LexicalScopes::new();
-        let expr = Expr::Literal(Literal::NumberLiteral(1.0f64));
+        let expr = Expr::Literal(Literal::Number(1.0f64));
         let statement = Statement::Expression(expr);
         assert_eq!(None, statement.execute(&environment, &scopes).unwrap());
     }
@@ -762,10 +663,10 @@ mod tests {
         let scopes = LexicalScopes::new();
         let subexpr1 = UnaryExpr {
             operator: UnaryOperator::Minus,
-            right: Expr::Literal(Literal::NumberLiteral(2f64)),
+            right: Expr::Literal(Literal::Number(2f64)),
         };
         let subexpr2 = Grouping {
-            expr: Expr::Literal(Literal::NumberLiteral(5f64)),
+            expr: Expr::Literal(Literal::Number(5f64)),
         };
         let binary_expr = BinaryExpr {
             left: Expr::Unary(Box::new(subexpr1)),
@@ -785,7 +686,12 @@ mod tests {
         let identifier = identifier_map.for_name(&"x");
         let statement = Statement::VariableDefinition(identifier);
         assert_eq!(None, statement.execute(&environment, &scopes).unwrap());
-        assert_eq!(Value::Nil, environment.get(identifier, 0).unwrap());
+        assert_eq!(
+            Value::Nil,
+            environment
+                .get(VariableLocation::Global, identifier)
+                .unwrap()
+        );
     }
 
     #[test]
@@ -810,7 +716,7 @@ mod tests {
         let statement = Statement::Expression(Expr::Assignment(Box::new(Assignment {
             handle: handle_factory.next(),
             lvalue: Target::Identifier(identifier),
-            rvalue: Expr::Literal(Literal::BoolLiteral(true)),
+            rvalue: Expr::Literal(Literal::Bool(true)),
         })));
         let scopes = scopes_resolver.resolve(&statement).unwrap();
         assert!(statement.execute(&environment, &scopes).is_err());
@@ -822,12 +728,14 @@ mod tests {
         let environment = Environment::new();
         let scopes = LexicalScopes::new();
         let identifier = identifier_map.for_name(&"x");
-        let expr = Expr::Literal(Literal::NumberLiteral(1.0f64));
+        let expr = Expr::Literal(Literal::Number(1.0f64));
         let statement = Statement::VariableDefinitionWithInitalizer(identifier.clone(), expr);
         assert_eq!(None, statement.execute(&environment, &scopes).unwrap());
         assert_eq!(
             Value::Number(1.0f64),
-            environment.get(identifier, 0).unwrap()
+            environment
+                .get(VariableLocation::Global, identifier)
+                .unwrap()
         );
     }
 
@@ -838,7 +746,7 @@ mod tests {
         let mut scopes_resolver = LexicalScopesResolver::new();
         let identifier = identifier_map.for_name(&"x");
         let mut handle_factory = VariableUseHandleFactory::new();
-        let expr = Expr::Literal(Literal::NumberLiteral(1.0f64));
+        let expr = Expr::Literal(Literal::Number(1.0f64));
         let outer_statement = Statement::VariableDefinitionWithInitalizer(identifier.clone(), expr);
         {
             let scopes = scopes_resolver.resolve(&outer_statement).unwrap();
@@ -851,17 +759,20 @@ mod tests {
             Assignment {
                 handle: handle_factory.next(),
                 lvalue: Target::Identifier(identifier),
-                rvalue: Expr::Literal(Literal::BoolLiteral(false)),
+                rvalue: Expr::Literal(Literal::Bool(false)),
             },
         )))];
         let block = Statement::Block(Box::new(Block {
             statements: statements,
+            positions: vec![Position::initial()],
         }));
         let scopes = scopes_resolver.resolve(&block).unwrap();
         assert!(block.execute(&environment, &scopes).is_ok());
         assert_eq!(
             Value::Boolean(false),
-            environment.get(identifier, 0).unwrap()
+            environment
+                .get(VariableLocation::Global, identifier)
+                .unwrap()
         );
     }
     #[test]
@@ -870,17 +781,18 @@ mod tests {
         let environment = Environment::new();
         let scopes = LexicalScopes::new();
         let identifier = identifier_map.for_name(&"x");
-        let expr = Expr::Literal(Literal::NumberLiteral(1.0f64));
+        let expr = Expr::Literal(Literal::Number(1.0f64));
         let statements = vec![Statement::VariableDefinitionWithInitalizer(
             identifier.clone(),
             expr,
         )];
         let block = Statement::Block(Box::new(Block {
             statements: statements,
+            positions: vec![Position::initial()],
         }));
         assert_eq!(None, block.execute(&environment, &scopes).unwrap());
         // The variable declaration gets lost when we exit the scope
-        assert_eq!(None, environment.get(identifier, 0));
+        assert_eq!(None, environment.get(VariableLocation::Global, identifier));
     }
 
     #[test]
@@ -916,9 +828,9 @@ mod tests {
         let environment = Environment::new();
         let scopes = LexicalScopes::new();
         let identifier = identifier_map.for_name(&"x");
-        let condition = Expr::Literal(Literal::BoolLiteral(false));
-        let then_expr = Expr::Literal(Literal::NumberLiteral(1.0f64));
-        let else_expr = Expr::Literal(Literal::NumberLiteral(2.0f64));
+        let condition = Expr::Literal(Literal::Bool(false));
+        let then_expr = Expr::Literal(Literal::Number(1.0f64));
+        let else_expr = Expr::Literal(Literal::Number(2.0f64));
         let then_statement =
             Statement::VariableDefinitionWithInitalizer(identifier.clone(), then_expr);
         let else_statement =
@@ -931,7 +843,9 @@ mod tests {
         assert_eq!(None, block.execute(&environment, &scopes).unwrap());
         assert_eq!(
             Value::Number(2.0f64),
-            environment.get(identifier, 0).unwrap()
+            environment
+                .get(VariableLocation::Global, identifier)
+                .unwrap()
         );
     }
 
@@ -941,21 +855,27 @@ mod tests {
         let mut scopes_resolver = LexicalScopesResolver::new();
         let (tokens, _) = scan(&"var a = 2; var b = 0;while(a > 0){ a = a - 1; b = b + 1;}");
         let mut parser = Parser::default();
-        let statements = parser.parse(&tokens).unwrap();
-        let scopes = scopes_resolver.resolve_all(&statements).unwrap();
-        for statement in statements.iter() {
+        let script = parser.parse(&tokens).unwrap();
+        let scopes = scopes_resolver.resolve_all(&script).unwrap();
+        for statement in script.statements.iter() {
             let _ = statement.execute(&environment, &scopes);
         }
         assert_eq!(
             Value::Number(0.0f64),
             environment
-                .get(parser.identifier_map.for_name(&"a"), 0)
+                .get(
+                    VariableLocation::Global,
+                    parser.identifier_map.for_name(&"a")
+                )
                 .unwrap()
         );
         assert_eq!(
             Value::Number(2.0f64),
             environment
-                .get(parser.identifier_map.for_name(&"b"), 0)
+                .get(
+                    VariableLocation::Global,
+                    parser.identifier_map.for_name(&"b")
+                )
                 .unwrap()
         );
     }
@@ -966,15 +886,18 @@ mod tests {
         let environment = Environment::new();
         let mut scopes_resolver = LexicalScopesResolver::new();
         let (tokens, _) = scan(&"fun double(n) {return 2 * n;} var a = double(3);");
-        let statements = parser.parse(&tokens).unwrap();
-        let scopes = scopes_resolver.resolve_all(&statements).unwrap();
-        for statement in statements.iter() {
+        let script = parser.parse(&tokens).unwrap();
+        let scopes = scopes_resolver.resolve_all(&script).unwrap();
+        for statement in script.statements.iter() {
             let _ = statement.execute(&environment, &scopes);
         }
         assert_eq!(
             Value::Number(6.0f64),
             environment
-                .get(parser.identifier_map.for_name(&"a"), 0)
+                .get(
+                    VariableLocation::Global,
+                    parser.identifier_map.for_name(&"a")
+                )
                 .unwrap()
         );
     }
@@ -985,15 +908,18 @@ mod tests {
         let environment = Environment::new();
         let mut scopes_resolver = LexicalScopesResolver::new();
         let (tokens, _) = scan(&"fun double(n) {print 2 * n;} var a = double(3);");
-        let statements = parser.parse(&tokens).unwrap();
-        let scopes = scopes_resolver.resolve_all(&statements).unwrap();
-        for statement in statements.iter() {
+        let script = parser.parse(&tokens).unwrap();
+        let scopes = scopes_resolver.resolve_all(&script).unwrap();
+        for statement in script.statements.iter() {
             let _ = statement.execute(&environment, &scopes);
         }
         assert_eq!(
             Value::Nil,
             environment
-                .get(parser.identifier_map.for_name(&"a"), 0)
+                .get(
+                    VariableLocation::Global,
+                    parser.identifier_map.for_name(&"a")
+                )
                 .unwrap()
         );
     }
@@ -1004,20 +930,26 @@ mod tests {
         let environment = Environment::new();
         let mut scopes_resolver = LexicalScopesResolver::new();
         let (tokens, _) = scan(&"var a = 21;fun foo(x, y) {var a = 1; var b = x + y;} foo();");
-        let statements = parser.parse(&tokens).unwrap();
-        let scopes = scopes_resolver.resolve_all(&statements).unwrap();
-        for statement in statements.iter() {
+        let script = parser.parse(&tokens).unwrap();
+        let scopes = scopes_resolver.resolve_all(&script).unwrap();
+        for statement in script.statements.iter() {
             let _ = statement.execute(&environment, &scopes);
         }
         assert_eq!(
             Value::Number(21.0f64),
             environment
-                .get(parser.identifier_map.for_name(&"a"), 0)
+                .get(
+                    VariableLocation::Global,
+                    parser.identifier_map.for_name(&"a")
+                )
                 .unwrap()
         );
         assert_eq!(
             None,
-            environment.get(parser.identifier_map.for_name(&"b"), 0)
+            environment.get(
+                VariableLocation::Global,
+                parser.identifier_map.for_name(&"b")
+            )
         );
     }
 
@@ -1027,9 +959,9 @@ mod tests {
         let environment = Environment::new();
         let mut scopes_resolver = LexicalScopesResolver::new();
         let (tokens, _) = scan(&"class C{} var c = C();");
-        let statements = parser.parse(&tokens).unwrap();
-        let scopes = scopes_resolver.resolve_all(&statements).unwrap();
-        for statement in statements.iter() {
+        let script = parser.parse(&tokens).unwrap();
+        let scopes = scopes_resolver.resolve_all(&script).unwrap();
+        for statement in script.statements.iter() {
             let _ = statement.execute(&environment, &scopes);
         }
         fn is_instance(value: &Value) -> bool {
@@ -1042,7 +974,10 @@ mod tests {
             true,
             is_instance(
                 &environment
-                    .get(parser.identifier_map.for_name(&"c"), 0)
+                    .get(
+                        VariableLocation::Global,
+                        parser.identifier_map.for_name(&"c")
+                    )
                     .unwrap()
             )
         );
@@ -1054,13 +989,16 @@ mod tests {
         let environment = Environment::new();
         let mut scopes_resolver = LexicalScopesResolver::new();
         let (tokens, _) = scan(&"class C{} var c = C();c.a = 10;c.b=c.a+1;");
-        let statements = parser.parse(&tokens).unwrap();
-        let scopes = scopes_resolver.resolve_all(&statements).unwrap();
-        for statement in statements.iter() {
+        let script = parser.parse(&tokens).unwrap();
+        let scopes = scopes_resolver.resolve_all(&script).unwrap();
+        for statement in script.statements.iter() {
             let _ = statement.execute(&environment, &scopes);
         }
         if let Value::Instance(instance) = environment
-            .get(parser.identifier_map.for_name(&"c"), 0)
+            .get(
+                VariableLocation::Global,
+                parser.identifier_map.for_name(&"c"),
+            )
             .unwrap()
         {
             assert_eq!(
@@ -1082,15 +1020,18 @@ mod tests {
         let environment = Environment::new();
         let mut scopes_resolver = LexicalScopesResolver::new();
         let (tokens, _) = scan(&"class A {a() { return 1; } } var v = A().a();");
-        let statements = parser.parse(&tokens).unwrap();
-        let scopes = scopes_resolver.resolve_all(&statements).unwrap();
-        for statement in statements.iter() {
+        let script = parser.parse(&tokens).unwrap();
+        let scopes = scopes_resolver.resolve_all(&script).unwrap();
+        for statement in script.statements.iter() {
             let _ = statement.execute(&environment, &scopes);
         }
         assert_eq!(
             Value::Number(1.0),
             environment
-                .get(parser.identifier_map.for_name(&"v"), 0)
+                .get(
+                    VariableLocation::Global,
+                    parser.identifier_map.for_name(&"v")
+                )
                 .unwrap()
         )
     }
@@ -1101,15 +1042,18 @@ mod tests {
         let environment = Environment::new();
         let mut scopes_resolver = LexicalScopesResolver::new();
         let (tokens, _) = scan(&"class A {init(a) { this._a=a; } } var v = A(10)._a;");
-        let statements = parser.parse(&tokens).unwrap();
-        let scopes = scopes_resolver.resolve_all(&statements).unwrap();
-        for statement in statements.iter() {
+        let script = parser.parse(&tokens).unwrap();
+        let scopes = scopes_resolver.resolve_all(&script).unwrap();
+        for statement in script.statements.iter() {
             let _ = statement.execute(&environment, &scopes);
         }
         assert_eq!(
             Value::Number(10.0),
             environment
-                .get(parser.identifier_map.for_name(&"v"), 0)
+                .get(
+                    VariableLocation::Global,
+                    parser.identifier_map.for_name(&"v")
+                )
                 .unwrap()
         )
     }
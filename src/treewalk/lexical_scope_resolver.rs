@@ -1,34 +1,116 @@
 use fnv::FnvHashMap;
+use std::cell::RefCell;
 use std::collections::hash_map::Entry;
+use std::fmt;
+use std::rc::Rc;
 use treewalk::ast::*;
+use treewalk::scanner::Position;
 
 pub type Depth = usize;
+/// Position of a variable in its environment, in order of declaration
+pub type Slot = usize;
 
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum VariableLocation {
+    /// Declared in the environment `Depth` levels up from the current one
+    Local(Depth, Slot),
+    /// Not declared in any enclosing scope, it's looked up by name at runtime
+    Global,
+}
+
+/// Clones share the locations, so that the functions of the tree-walker
+/// can keep them around to run their body
+#[derive(Clone)]
 pub struct LexicalScopes {
-    depths: FnvHashMap<VariableUseHandle, Depth>,
+    locations: Rc<RefCell<FnvHashMap<VariableUseHandle, VariableLocation>>>,
 }
 
 impl LexicalScopes {
     pub fn new() -> LexicalScopes {
         LexicalScopes {
-            depths: FnvHashMap::default(),
+            locations: Rc::new(RefCell::new(FnvHashMap::default())),
         }
     }
 
-    pub fn get_depth(&self, handle: VariableUseHandle) -> Option<&Depth> {
-        self.depths.get(&handle)
+    pub fn get_location(&self, handle: VariableUseHandle) -> Option<VariableLocation> {
+        self.locations.borrow().get(&handle).cloned()
+    }
+
+    fn insert(&self, handle: VariableUseHandle, location: VariableLocation) {
+        self.locations.borrow_mut().insert(handle, location);
     }
 }
 
+/// Every error carries the position of the statement it was found in
 #[derive(Debug)]
 pub enum LexicalScopesResolutionError {
-    ReadLocalInItsOwnInitializer,
-    VariableAlreadyExistsInScope,
-    ReturnFromTopLevelCode,
-    ReturnFromInitializer,
-    UseOfThisOutsideAClass,
-    UseOfSuperOutsideAClass,
-    UseOfSuperOutsideASubClass,
+    ReadLocalInItsOwnInitializer(Position),
+    VariableAlreadyExistsInScope(Position),
+    ReturnFromTopLevelCode(Position),
+    ReturnFromInitializer(Position),
+    UseOfThisOutsideAClass(Position),
+    UseOfSuperOutsideAClass(Position),
+    UseOfSuperOutsideASubClass(Position),
+    BreakOutsideALoop(Position),
+    ContinueOutsideALoop(Position),
+    ImportOutsideTopLevelCode(Position),
+}
+
+impl fmt::Display for LexicalScopesResolutionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LexicalScopesResolutionError::ReadLocalInItsOwnInitializer(ref position) => write!(
+                f,
+                "{} Error: Can't read local variable in its own initializer.",
+                position
+            ),
+            LexicalScopesResolutionError::VariableAlreadyExistsInScope(ref position) => write!(
+                f,
+                "{} Error: Already a variable with this name in this scope.",
+                position
+            ),
+            LexicalScopesResolutionError::ReturnFromTopLevelCode(ref position) => write!(
+                f,
+                "{} Error at 'return': Can't return from top-level code.",
+                position
+            ),
+            LexicalScopesResolutionError::ReturnFromInitializer(ref position) => write!(
+                f,
+                "{} Error at 'return': Can't return from an initializer.",
+                position
+            ),
+            LexicalScopesResolutionError::UseOfThisOutsideAClass(ref position) => write!(
+                f,
+                "{} Error: Can't use 'this' outside of a class.",
+                position
+            ),
+            LexicalScopesResolutionError::UseOfSuperOutsideAClass(ref position) => write!(
+                f,
+                "{} Error: Can't use 'super' outside of a class.",
+                position
+            ),
+            LexicalScopesResolutionError::UseOfSuperOutsideASubClass(ref position) => write!(
+                f,
+                "{} Error: Can't use 'super' in a class with no superclass.",
+                position
+            ),
+            LexicalScopesResolutionError::BreakOutsideALoop(ref position) => write!(
+                f,
+                "{} Error at 'break': Can't use 'break' outside of a loop.",
+                position
+            ),
+            LexicalScopesResolutionError::ContinueOutsideALoop(ref position) => write!(
+                f,
+                "{} Error at 'continue': Can't use 'continue' outside of a loop.",
+                position
+            ),
+            LexicalScopesResolutionError::ImportOutsideTopLevelCode(ref position) => write!(
+                f,
+                "{} Error at 'import': Can't import outside of top-level code.",
+                position
+            ),
+        }
+    }
 }
 
 trait LexicallyScoped {
@@ -50,10 +132,17 @@ enum ClassType {
 }
 
 pub struct LexicalScopesResolver {
-    // Note that this doesn't track globals at all
-    scopes: Vec<FnvHashMap<Identifier, VariableDefinition>>,
+    // Note that this doesn't track globals at all.
+    // Slots are handed out in order of declaration, mirroring how the
+    // interpreter adds variables to an environment.
+    scopes: Vec<FnvHashMap<Identifier, (VariableDefinition, Slot)>>,
     current_function: Option<FunctionKind>,
     current_class: ClassType,
+    /// Whether break and continue have a loop to jump out of, which
+    /// can't be outside the function they're in
+    in_loop: bool,
+    /// Where the statement being resolved starts
+    position: Position,
     lexical_scopes: LexicalScopes,
 }
 
@@ -63,6 +152,8 @@ impl LexicalScopesResolver {
             scopes: vec![],
             current_function: None,
             current_class: ClassType::None,
+            in_loop: false,
+            position: Position::initial(),
             lexical_scopes: LexicalScopes::new(),
         }
     }
@@ -80,35 +171,57 @@ impl LexicalScopesResolver {
         if scopes == 0 {
             return Ok(());
         };
-        match self.scopes[scopes - 1].entry(identifier) {
-            Entry::Occupied(_) => Err(LexicalScopesResolutionError::VariableAlreadyExistsInScope),
+        let scope = &mut self.scopes[scopes - 1];
+        let slot = scope.len();
+        match scope.entry(identifier) {
+            Entry::Occupied(_) => Err(LexicalScopesResolutionError::VariableAlreadyExistsInScope(
+                self.position,
+            )),
             Entry::Vacant(v) => {
-                v.insert(VariableDefinition::Declared);
+                v.insert((VariableDefinition::Declared, slot));
                 Ok(())
             }
         }
     }
 
+    /// Resolves the statements of a block in the current scope,
+    /// keeping track of where each one starts
+    fn resolve_block(&mut self, block: &Block) -> Result<(), LexicalScopesResolutionError> {
+        let enclosing_position = self.position;
+        for (statement, &position) in block.statements.iter().zip(&block.positions) {
+            self.position = position;
+            statement.resolve(self)?;
+        }
+        self.position = enclosing_position;
+        Ok(())
+    }
+
     fn define(&mut self, identifier: Identifier) -> () {
         let scopes = self.scopes.len();
         if scopes == 0 {
             return;
         };
-        self.scopes[scopes - 1].insert(identifier, VariableDefinition::Defined);
+        let scope = &mut self.scopes[scopes - 1];
+        let slot = scope.len();
+        scope
+            .entry(identifier)
+            .or_insert((VariableDefinition::Declared, slot))
+            .0 = VariableDefinition::Defined;
     }
 
     fn resolve_local(&mut self, handle: VariableUseHandle, identifier: Identifier) -> () {
         let max_depth = self.scopes.len();
         for depth in 0..max_depth {
-            if self.scopes[max_depth - depth - 1].contains_key(&identifier) {
-                self.lexical_scopes.depths.insert(handle, depth);
+            if let Some(&(_, slot)) = self.scopes[max_depth - depth - 1].get(&identifier) {
+                self.lexical_scopes
+                    .insert(handle, VariableLocation::Local(depth, slot));
                 return;
             }
         }
         // If we failed to find it in the locals, it must be a global.
         // It might not be there right now, but it might appear later on.
         // We will know it only at runtime.
-        self.lexical_scopes.depths.insert(handle, max_depth);
+        self.lexical_scopes.insert(handle, VariableLocation::Global);
     }
 
     #[allow(dead_code)] // Used in tests
@@ -119,16 +232,30 @@ impl LexicalScopesResolver {
         statement.resolve(self).map(move |_| &self.lexical_scopes)
     }
 
+    pub fn resolve_expression(
+        &mut self,
+        expression: &Expr,
+    ) -> Result<&LexicalScopes, LexicalScopesResolutionError> {
+        self.position = Position::initial();
+        expression.resolve(self).map(move |_| &self.lexical_scopes)
+    }
+
+    /// The scopes of everything resolved so far
+    pub fn lexical_scopes(&self) -> &LexicalScopes {
+        &self.lexical_scopes
+    }
+
     pub fn resolve_all(
         &mut self,
-        statements: &[Statement],
+        script: &Block,
     ) -> Result<&LexicalScopes, Vec<LexicalScopesResolutionError>> {
-        let resolution_errors: Vec<LexicalScopesResolutionError> = statements
-            .iter()
-            .map(|s| s.resolve(self))
-            .filter(|r| r.is_err())
-            .map(|r| r.unwrap_err())
-            .collect();
+        let mut resolution_errors = Vec::new();
+        for (statement, &position) in script.statements.iter().zip(&script.positions) {
+            self.position = position;
+            if let Err(error) = statement.resolve(self) {
+                resolution_errors.push(error);
+            }
+        }
         if resolution_errors.is_empty() {
             Ok(&self.lexical_scopes)
         } else {
@@ -145,9 +272,7 @@ impl LexicallyScoped for Statement {
         match *self {
             Statement::Block(ref b) => {
                 resolver.begin_scope();
-                for statement in &b.statements {
-                    try!(statement.resolve(resolver));
-                }
+                resolver.resolve_block(b)?;
                 resolver.end_scope();
                 Ok(())
             }
@@ -174,12 +299,9 @@ impl LexicallyScoped for Statement {
                     resolver.begin_scope();
                     resolver.define(Identifier::super_identifier());
                 }
-                resolver.begin_scope();
-                resolver.define(Identifier::this());
                 for method in &c.methods {
                     try!(method.resolve(resolver));
                 }
-                resolver.end_scope();
                 if c.superclass.is_some() {
                     resolver.end_scope();
                 }
@@ -196,20 +318,66 @@ impl LexicallyScoped for Statement {
                 .resolve(resolver)
                 .and_then(|_| s.then_branch.resolve(resolver))
                 .and_then(|_| s.else_branch.resolve(resolver)),
-            Statement::While(ref s) => s
-                .condition
-                .resolve(resolver)
-                .and_then(|_| s.body.resolve(resolver)),
-            Statement::Print(ref e) => e.resolve(resolver),
-            Statement::Return(ref r) => match resolver.current_function {
-                Some(FunctionKind::Initializer) => {
-                    Err(LexicalScopesResolutionError::ReturnFromInitializer)
+            Statement::While(ref s) => {
+                s.condition.resolve(resolver)?;
+                let enclosing_loop = resolver.in_loop;
+                resolver.in_loop = true;
+                let body = s.body.resolve(resolver);
+                resolver.in_loop = enclosing_loop;
+                body?;
+                match s.increment {
+                    Some(ref increment) => increment.resolve(resolver),
+                    None => Ok(()),
+                }
+            }
+            Statement::Break if !resolver.in_loop => Err(
+                LexicalScopesResolutionError::BreakOutsideALoop(resolver.position),
+            ),
+            Statement::Continue if !resolver.in_loop => Err(
+                LexicalScopesResolutionError::ContinueOutsideALoop(resolver.position),
+            ),
+            Statement::Break | Statement::Continue => Ok(()),
+            Statement::Print(ref e) | Statement::Throw(ref e) => e.resolve(resolver),
+            // Imports define globals when they run, which aren't tracked
+            Statement::Import(_) => {
+                if resolver.scopes.is_empty() {
+                    Ok(())
+                } else {
+                    Err(LexicalScopesResolutionError::ImportOutsideTopLevelCode(
+                        resolver.position,
+                    ))
+                }
+            }
+            Statement::Try(ref t) => {
+                resolver.begin_scope();
+                resolver.resolve_block(&t.body)?;
+                resolver.end_scope();
+                if let Some(ref catch) = t.catch {
+                    // Same layout as a call: the variable, then the body
+                    resolver.begin_scope();
+                    resolver.declare(catch.variable)?;
+                    resolver.define(catch.variable);
+                    resolver.resolve_block(&catch.body)?;
+                    resolver.end_scope();
                 }
+                if let Some(ref finally) = t.finally {
+                    resolver.begin_scope();
+                    resolver.resolve_block(finally)?;
+                    resolver.end_scope();
+                }
+                Ok(())
+            }
+            Statement::Return(ref r) => match resolver.current_function {
+                Some(FunctionKind::Initializer) => Err(
+                    LexicalScopesResolutionError::ReturnFromInitializer(resolver.position),
+                ),
                 Some(_) => match *r {
                     None => Ok(()),
                     Some(ref e) => e.resolve(resolver),
                 },
-                None => Err(LexicalScopesResolutionError::ReturnFromTopLevelCode),
+                None => Err(LexicalScopesResolutionError::ReturnFromTopLevelCode(
+                    resolver.position,
+                )),
             },
         }
     }
@@ -223,17 +391,21 @@ impl LexicallyScoped for Expr {
         match *self {
             Expr::This(ref handle, ref identifier) => {
                 if let ClassType::None = resolver.current_class {
-                    return Err(LexicalScopesResolutionError::UseOfThisOutsideAClass);
+                    return Err(LexicalScopesResolutionError::UseOfThisOutsideAClass(
+                        resolver.position,
+                    ));
                 }
                 resolver.resolve_local(handle.clone(), *identifier);
                 Ok(())
             }
             Expr::Super(ref handle, ref super_identifier, _member_identifier) => {
                 match resolver.current_class {
-                    ClassType::None => Err(LexicalScopesResolutionError::UseOfSuperOutsideAClass),
-                    ClassType::Class => {
-                        Err(LexicalScopesResolutionError::UseOfSuperOutsideASubClass)
-                    }
+                    ClassType::None => Err(LexicalScopesResolutionError::UseOfSuperOutsideAClass(
+                        resolver.position,
+                    )),
+                    ClassType::Class => Err(
+                        LexicalScopesResolutionError::UseOfSuperOutsideASubClass(resolver.position),
+                    ),
                     _ => {
                         resolver.resolve_local(handle.clone(), *super_identifier);
                         Ok(())
@@ -245,10 +417,12 @@ impl LexicallyScoped for Expr {
                 if scopes != 0
                     && resolver.scopes[scopes - 1]
                         .get(&identifier)
-                        .unwrap_or(&VariableDefinition::Undefined)
+                        .map_or(&VariableDefinition::Undefined, |(definition, _)| definition)
                         == &VariableDefinition::Declared
                 {
-                    Err(LexicalScopesResolutionError::ReadLocalInItsOwnInitializer)
+                    Err(LexicalScopesResolutionError::ReadLocalInItsOwnInitializer(
+                        resolver.position,
+                    ))
                 } else {
                     resolver.resolve_local(*handle, *identifier);
                     Ok(())
@@ -282,6 +456,29 @@ impl LexicallyScoped for Expr {
                 try!(s.instance.resolve(resolver));
                 Ok(())
             }
+            Expr::List(ref elements) => {
+                for element in elements {
+                    element.resolve(resolver)?;
+                }
+                Ok(())
+            }
+            Expr::Lambda(ref f) => f.resolve(resolver),
+            Expr::Map(ref entries) => {
+                for (key, value) in entries {
+                    key.resolve(resolver)?;
+                    value.resolve(resolver)?;
+                }
+                Ok(())
+            }
+            Expr::Index(ref i) => {
+                i.collection.resolve(resolver)?;
+                i.index.resolve(resolver)
+            }
+            Expr::SetIndex(ref s) => {
+                s.collection.resolve(resolver)?;
+                s.index.resolve(resolver)?;
+                s.value.resolve(resolver)
+            }
         }
     }
 }
@@ -291,7 +488,7 @@ impl LexicallyScoped for Assignment {
         &self,
         resolver: &mut LexicalScopesResolver,
     ) -> Result<(), LexicalScopesResolutionError> {
-        try!{self.rvalue.resolve(resolver)};
+        try! {self.rvalue.resolve(resolver)};
         let Target::Identifier(ref identifier) = self.lvalue;
         resolver.resolve_local(self.handle, *identifier);
         Ok(())
@@ -303,18 +500,29 @@ impl LexicallyScoped for FunctionDefinition {
         &self,
         resolver: &mut LexicalScopesResolver,
     ) -> Result<(), LexicalScopesResolutionError> {
-        try!(resolver.declare(self.name));
+        // Methods are looked up on the instance, they're not variables,
+        // and lambdas have no name
+        if self.kind == FunctionKind::Function {
+            try!(resolver.declare(self.name));
+            resolver.define(self.name);
+        }
         let enclosing_function = resolver.current_function;
+        let enclosing_loop = resolver.in_loop;
         resolver.current_function = Some(self.kind);
-        resolver.define(self.name);
+        resolver.in_loop = false;
         resolver.begin_scope();
+        if self.kind == FunctionKind::Method || self.kind == FunctionKind::Initializer {
+            // Methods get the instance they're bound to in the first slot
+            resolver.define(Identifier::this());
+        }
         for argument in &self.arguments {
             try!(resolver.declare(*argument));
             resolver.define(*argument);
         }
-        try!(self.body.resolve(resolver));
+        resolver.resolve_block(&self.body)?;
         resolver.end_scope();
         resolver.current_function = enclosing_function;
+        resolver.in_loop = enclosing_loop;
         Ok(())
     }
 }
@@ -328,7 +536,7 @@ mod tests {
     #[test]
     fn global_variable() {
         let (tokens, _) = scan(&"var a = 0;{fun f() {print a;}}");
-        let statements = Parser::default().parse(&tokens).unwrap();
+        let statements = Parser::default().parse(&tokens).unwrap().statements;
         let mut lexical_scope_resolver = LexicalScopesResolver::new();
         for statement in statements.iter() {
             assert!(lexical_scope_resolver.resolve(&statement).is_ok());
@@ -336,13 +544,16 @@ mod tests {
         let lexical_scopes = lexical_scope_resolver.lexical_scopes;
         let mut handle_factory = VariableUseHandleFactory::new();
         let handle = handle_factory.next(); // Use of a in the function
-        assert_eq!(Some(&3), lexical_scopes.get_depth(handle));
+        assert_eq!(
+            Some(VariableLocation::Global),
+            lexical_scopes.get_location(handle)
+        );
     }
 
     #[test]
     fn captured_variable() {
         let (tokens, _) = scan(&"{var a = 0;fun f() {print a;}}");
-        let statements = Parser::default().parse(&tokens).unwrap();
+        let statements = Parser::default().parse(&tokens).unwrap().statements;
         let mut lexical_scope_resolver = LexicalScopesResolver::new();
         for statement in statements.iter() {
             assert!(lexical_scope_resolver.resolve(&statement).is_ok());
@@ -350,13 +561,56 @@ mod tests {
         let lexical_scopes = lexical_scope_resolver.lexical_scopes;
         let mut handle_factory = VariableUseHandleFactory::new();
         let handle = handle_factory.next(); // Use of a in the function
-        assert_eq!(Some(&2), lexical_scopes.get_depth(handle));
+        assert_eq!(
+            Some(VariableLocation::Local(1, 0)),
+            lexical_scopes.get_location(handle)
+        );
+    }
+
+    #[test]
+    fn lambdas_capture_variables_and_have_no_name() {
+        let (tokens, _) = scan(&"{var a = 0; var f = fun (b) {return a + b;};}");
+        let script = Parser::default().parse(&tokens).unwrap();
+        let mut lexical_scope_resolver = LexicalScopesResolver::new();
+        assert!(lexical_scope_resolver.resolve_all(&script).is_ok());
+        let lexical_scopes = lexical_scope_resolver.lexical_scopes;
+        let mut handle_factory = VariableUseHandleFactory::new();
+        let a = handle_factory.next();
+        let b = handle_factory.next();
+        assert_eq!(
+            Some(VariableLocation::Local(1, 0)),
+            lexical_scopes.get_location(a)
+        );
+        assert_eq!(
+            Some(VariableLocation::Local(0, 0)),
+            lexical_scopes.get_location(b)
+        );
+    }
+
+    #[test]
+    fn caught_values_are_local_to_the_handler() {
+        let (tokens, _) = scan(&"var e; try {} catch (e) {print e;} print e;");
+        let script = Parser::default().parse(&tokens).unwrap();
+        let mut lexical_scope_resolver = LexicalScopesResolver::new();
+        assert!(lexical_scope_resolver.resolve_all(&script).is_ok());
+        let lexical_scopes = lexical_scope_resolver.lexical_scopes;
+        let mut handle_factory = VariableUseHandleFactory::new();
+        let in_handler = handle_factory.next();
+        let after = handle_factory.next();
+        assert_eq!(
+            Some(VariableLocation::Local(0, 0)),
+            lexical_scopes.get_location(in_handler)
+        );
+        assert_eq!(
+            Some(VariableLocation::Global),
+            lexical_scopes.get_location(after)
+        );
     }
 
     #[test]
     fn lexical_capture() {
         let (tokens, _) = scan(&"var a = 0;{fun f() {print a;} var a = 1;}");
-        let statements = Parser::default().parse(&tokens).unwrap();
+        let statements = Parser::default().parse(&tokens).unwrap().statements;
         let mut lexical_scope_resolver = LexicalScopesResolver::new();
         for statement in statements.iter() {
             assert!(lexical_scope_resolver.resolve(&statement).is_ok());
@@ -364,13 +618,57 @@ mod tests {
         let lexical_scopes = lexical_scope_resolver.lexical_scopes;
         let mut handle_factory = VariableUseHandleFactory::new();
         let handle = handle_factory.next(); // Use of a in the function
-        assert_eq!(Some(&3), lexical_scopes.get_depth(handle));
+        assert_eq!(
+            Some(VariableLocation::Global),
+            lexical_scopes.get_location(handle)
+        );
+    }
+
+    #[test]
+    fn slots_follow_the_order_of_declaration() {
+        let (tokens, _) = scan(&"fun f(a, b) {var c = 0; fun g() {} print b; print c; print g;}");
+        let script = Parser::default().parse(&tokens).unwrap();
+        let mut lexical_scope_resolver = LexicalScopesResolver::new();
+        let lexical_scopes = lexical_scope_resolver.resolve_all(&script).unwrap();
+        let mut handle_factory = VariableUseHandleFactory::new();
+        // The arguments and the locals of the body share the
+        // environment of the call
+        for &expected in &[
+            VariableLocation::Local(0, 1),
+            VariableLocation::Local(0, 2),
+            VariableLocation::Local(0, 3),
+        ] {
+            assert_eq!(
+                Some(expected),
+                lexical_scopes.get_location(handle_factory.next())
+            );
+        }
+    }
+
+    #[test]
+    fn this_is_the_first_slot_of_methods() {
+        let (tokens, _) =
+            scan(&"class A {} class B < A {m(a) {return this;} n() {return super.m;}}");
+        let script = Parser::default().parse(&tokens).unwrap();
+        let mut lexical_scope_resolver = LexicalScopesResolver::new();
+        let lexical_scopes = lexical_scope_resolver.resolve_all(&script).unwrap();
+        let mut handle_factory = VariableUseHandleFactory::new();
+        let _superclass = handle_factory.next();
+        assert_eq!(
+            Some(VariableLocation::Local(0, 0)),
+            lexical_scopes.get_location(handle_factory.next())
+        );
+        // Super has its own environment, around the one of the method
+        assert_eq!(
+            Some(VariableLocation::Local(1, 0)),
+            lexical_scopes.get_location(handle_factory.next())
+        );
     }
 
     #[test]
     fn error_on_shadowing() {
         let (tokens, _) = scan(&"var a = 0;{var a = a;}");
-        let statements = Parser::default().parse(&tokens).unwrap();
+        let statements = Parser::default().parse(&tokens).unwrap().statements;
         let mut lexical_scope_resolver = LexicalScopesResolver::new();
         assert!(lexical_scope_resolver.resolve(&statements[0]).is_ok());
         assert!(lexical_scope_resolver.resolve(&statements[1]).is_err());
@@ -379,7 +677,7 @@ mod tests {
     #[test]
     fn error_on_local_redeclaration() {
         let (tokens, _) = scan(&"fun bad() {var a = 1;var a = 2;}");
-        let statements = Parser::default().parse(&tokens).unwrap();
+        let statements = Parser::default().parse(&tokens).unwrap().statements;
         let mut lexical_scope_resolver = LexicalScopesResolver::new();
         assert!(lexical_scope_resolver.resolve(&statements[0]).is_err());
     }
@@ -387,17 +685,80 @@ mod tests {
     #[test]
     fn global_redeclaration_is_allowed() {
         let (tokens, _) = scan(&"var a = 1;var a = 2;");
-        let statements = Parser::default().parse(&tokens).unwrap();
+        let statements = Parser::default().parse(&tokens).unwrap().statements;
         let mut lexical_scope_resolver = LexicalScopesResolver::new();
         assert!(lexical_scope_resolver.resolve(&statements[0]).is_ok());
         assert!(lexical_scope_resolver.resolve(&statements[1]).is_ok());
     }
 
+    #[test]
+    fn error_on_local_shadowing_an_argument() {
+        let (tokens, _) = scan(&"fun bad(a) {var a = 1;}");
+        let statements = Parser::default().parse(&tokens).unwrap().statements;
+        let mut lexical_scope_resolver = LexicalScopesResolver::new();
+        assert!(lexical_scope_resolver.resolve(&statements[0]).is_err());
+    }
+
+    #[test]
+    fn error_on_break_and_continue_outside_a_loop() {
+        for source in &[
+            "break;",
+            "continue;",
+            "if (true) break;",
+            "while (true) { fun f() { continue; } }",
+        ] {
+            let (tokens, _) = scan(source);
+            let script = Parser::default().parse(&tokens).unwrap();
+            let mut lexical_scope_resolver = LexicalScopesResolver::new();
+            assert!(
+                lexical_scope_resolver.resolve_all(&script).is_err(),
+                "Resolving {}",
+                source
+            );
+        }
+        let (tokens, _) = scan(&"for (;;) { if (true) break; else { continue; } }");
+        let script = Parser::default().parse(&tokens).unwrap();
+        let mut lexical_scope_resolver = LexicalScopesResolver::new();
+        assert!(lexical_scope_resolver.resolve_all(&script).is_ok());
+    }
+
     #[test]
     fn error_on_return_outside_a_function() {
         let (tokens, _) = scan(&"return;");
-        let statements = Parser::default().parse(&tokens).unwrap();
+        let statements = Parser::default().parse(&tokens).unwrap().statements;
         let mut lexical_scope_resolver = LexicalScopesResolver::new();
         assert!(lexical_scope_resolver.resolve(&statements[0]).is_err());
+        let (tokens, _) = scan(&"var f = fun () { return; };");
+        let statements = Parser::default().parse(&tokens).unwrap().statements;
+        assert!(lexical_scope_resolver.resolve(&statements[0]).is_ok());
+    }
+
+    #[test]
+    fn error_on_import_outside_top_level_code() {
+        let (tokens, _) =
+            scan(&"import \"a.lox\"; { import \"b.lox\"; } fun f() { import \"c.lox\"; }");
+        let statements = Parser::default().parse(&tokens).unwrap().statements;
+        let mut lexical_scope_resolver = LexicalScopesResolver::new();
+        assert!(lexical_scope_resolver.resolve(&statements[0]).is_ok());
+        assert!(lexical_scope_resolver.resolve(&statements[1]).is_err());
+        assert!(lexical_scope_resolver.resolve(&statements[2]).is_err());
+    }
+
+    #[test]
+    fn errors_report_the_position_of_their_statement() {
+        let (tokens, _) = scan(&"var a = 1;\n{\n  print a;\n  var a = a;\n}\n  return;");
+        let script = Parser::default().parse(&tokens).unwrap();
+        let mut lexical_scope_resolver = LexicalScopesResolver::new();
+        let errors: Vec<String> = match lexical_scope_resolver.resolve_all(&script) {
+            Err(errors) => errors.iter().map(|e| e.to_string()).collect(),
+            Ok(_) => panic!("Expected resolution errors"),
+        };
+        assert_eq!(
+            vec![
+                "[line 4, column 3] Error: Can't read local variable in its own initializer.",
+                "[line 6, column 3] Error at 'return': Can't return from top-level code.",
+            ],
+            errors
+        );
     }
 }
@@ -1,21 +1,42 @@
 mod ast;
+mod closures;
+#[cfg(test)]
+mod differential_tests;
 mod interpreter;
 mod lexical_scope_resolver;
+mod modules;
 mod parser;
 mod pretty_printer;
+mod runtime;
 
-use self::ast::IdentifierMap;
-use self::interpreter::{Environment, RuntimeError, StatementInterpreter};
+use self::ast::{Block, IdentifierMap, Import, Statement};
+use self::interpreter::StatementInterpreter;
 use self::lexical_scope_resolver::{LexicalScopesResolutionError, LexicalScopesResolver};
+use self::modules::{exported_names, module_name, ImportError, Modules};
 use self::parser::{ParseError, Parser};
+use self::pretty_printer::PrettyPrint;
+use self::runtime::{Environment, Module, RuntimeError, Value};
 use frontend::scanner;
+use natives::Capabilities;
+use std::fmt;
+use std::mem;
+use std::path::Path;
+use std::rc::Rc;
 use user_interface::{LoxImplementation, RunError};
 
+pub use self::closures::ClosureRuloxInterpreter;
+
 #[derive(Debug)]
 enum LoxError {
     InputError(Vec<InputError>),
     LexicalScopesResolutionError(Vec<LexicalScopesResolutionError>),
-    RuntimeError(RuntimeError),
+    /// The lines reporting an error the program didn't catch
+    RuntimeError(Vec<String>),
+    /// The program called `exit` with the code
+    Exit(i32),
+    ImportError(ImportError),
+    /// An error of the module imported with the given path
+    InModule(Rc<str>, Box<LoxError>),
 }
 
 #[derive(Debug)]
@@ -24,10 +45,69 @@ enum InputError {
     ParserError(ParseError),
 }
 
+impl fmt::Display for InputError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            InputError::ScannerError(ref error) => write!(f, "{}", error),
+            InputError::ParserError(ref error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl From<LoxError> for RunError {
+    fn from(error: LoxError) -> RunError {
+        match error {
+            LoxError::InputError(errors) => {
+                RunError::CompileError(errors.iter().map(|e| e.to_string()).collect())
+            }
+            LoxError::LexicalScopesResolutionError(errors) => {
+                RunError::CompileError(errors.iter().map(|e| e.to_string()).collect())
+            }
+            LoxError::RuntimeError(lines) => RunError::RuntimeError(lines),
+            LoxError::Exit(code) => RunError::Exit(code),
+            LoxError::ImportError(error) => RunError::from(error),
+            LoxError::InModule(path, error) => in_module(&path, RunError::from(*error)),
+        }
+    }
+}
+
+impl From<ImportError> for RunError {
+    fn from(error: ImportError) -> RunError {
+        match error {
+            ImportError::Cycle(_) => RunError::RuntimeError(vec![error.to_string()]),
+            _ => RunError::IoError(error.to_string()),
+        }
+    }
+}
+
+/// Tells the errors of an imported module apart from the ones of the
+/// file importing it
+fn in_module(path: &str, error: RunError) -> RunError {
+    let in_module = |message: String| format!("{}: {}", path, message);
+    match error {
+        RunError::CompileError(messages) => {
+            RunError::CompileError(messages.into_iter().map(in_module).collect())
+        }
+        RunError::RuntimeError(messages) => {
+            RunError::RuntimeError(messages.into_iter().map(in_module).collect())
+        }
+        RunError::IoError(message) => RunError::IoError(in_module(message)),
+        RunError::Exit(code) => RunError::Exit(code),
+    }
+}
+
+/// A script parsed by one of the tree-walking backends. Only the
+/// interpreter that parsed it can run it, as it holds its identifiers.
+pub struct Script(Block);
+
 pub struct TreeWalkRuloxInterpreter {
     parser: Parser,
     lexical_scope_resolver: LexicalScopesResolver,
     interpreter: StatementInterpreter,
+    modules: Modules<Module>,
+    /// Kept for the modules, which get `fs` and `os` as well
+    capabilities: Capabilities,
+    arguments: Vec<String>,
 }
 
 impl Default for TreeWalkRuloxInterpreter {
@@ -39,61 +119,242 @@ impl Default for TreeWalkRuloxInterpreter {
             parser,
             lexical_scope_resolver: LexicalScopesResolver::new(),
             interpreter: StatementInterpreter::new(environment),
+            modules: Modules::new(),
+            capabilities: Capabilities::default(),
+            arguments: vec![],
         }
     }
 }
 
-impl TreeWalkRuloxInterpreter {
-    fn scan_and_parse(&mut self, source: &str) -> Result<Vec<ast::Statement>, Vec<InputError>> {
-        let (tokens, scanner_errors) = scanner::scan(source);
-        let mut errors: Vec<InputError> = scanner_errors
-            .iter()
-            .map(|e| InputError::ScannerError(e.clone()))
-            .collect();
-        match self.parser.parse(&tokens) {
-            Ok(expr) => {
-                if errors.is_empty() {
-                    Ok(expr)
-                } else {
-                    Err(errors)
-                }
-            }
-            Err(parse_errors) => {
-                for error in parse_errors {
-                    errors.push(InputError::ParserError(error))
-                }
+fn scan_and_parse(parser: &mut Parser, source: &str) -> Result<Block, Vec<InputError>> {
+    let (tokens, scanner_errors) = scanner::scan(source);
+    let mut errors: Vec<InputError> = scanner_errors
+        .iter()
+        .map(|e| InputError::ScannerError(e.clone()))
+        .collect();
+    match parser.parse(&tokens) {
+        Ok(script) => {
+            if errors.is_empty() {
+                Ok(script)
+            } else {
                 Err(errors)
             }
         }
+        Err(parse_errors) => {
+            for error in parse_errors {
+                errors.push(InputError::ParserError(error))
+            }
+            Err(errors)
+        }
+    }
+}
+
+impl TreeWalkRuloxInterpreter {
+    /// Parses the source without running it, for the benchmarks to time
+    /// the execution alone
+    pub fn parse(&mut self, source: &str) -> Result<Script, RunError> {
+        scan_and_parse(&mut self.parser, source)
+            .map(Script)
+            .map_err(|errors| RunError::from(LoxError::InputError(errors)))
+    }
+
+    pub fn run_script(&mut self, script: &Script) -> Result<(), RunError> {
+        self.execute(&script.0).map_err(RunError::from)
     }
 
     fn run(&mut self, source: &str) -> Result<(), LoxError> {
-        let statements = self.scan_and_parse(source).map_err(LoxError::InputError)?;
-        let lexical_scope = self
-            .lexical_scope_resolver
-            .resolve_all(&statements)
+        let script = scan_and_parse(&mut self.parser, source).map_err(LoxError::InputError)?;
+        self.execute(&script)
+    }
+
+    /// Runs the statements of the script or of a module, with the
+    /// interpreter holding its globals
+    fn execute(&mut self, script: &Block) -> Result<(), LoxError> {
+        self.lexical_scope_resolver
+            .resolve_all(script)
             .map_err(LoxError::LexicalScopesResolutionError)?;
-        for statement in &statements {
-            self.interpreter
-                .execute(&lexical_scope, &statement)
-                .map_err(LoxError::RuntimeError)?;
+        for (statement, position) in script.statements.iter().zip(&script.positions) {
+            match *statement {
+                Statement::Import(ref import) => self.import(import)?,
+                _ => {
+                    self.interpreter
+                        .execute(self.lexical_scope_resolver.lexical_scopes(), statement)
+                        .map_err(|error| self.runtime_error(error.at_line(position.line)))?;
+                }
+            }
         }
         Ok(())
     }
+
+    fn import(&mut self, import: &Import) -> Result<(), LoxError> {
+        let file = self
+            .modules
+            .find(&import.path)
+            .map_err(LoxError::ImportError)?;
+        let module = match self.modules.get(&file) {
+            Some(module) => module,
+            None => {
+                let source = self.modules.start(file).map_err(LoxError::ImportError)?;
+                let module = self.run_module(&import.path, &source);
+                self.modules.finish(module.as_ref().ok().cloned());
+                module.map_err(|error| LoxError::InModule(import.path.clone(), Box::new(error)))?
+            }
+        };
+        match import.name {
+            Some(name) => self.interpreter.define(name, Value::Module(module)),
+            None => {
+                for (&name, value) in module.exports() {
+                    self.interpreter.define(name, value.clone());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs a module in globals of its own
+    fn run_module(&mut self, path: &str, source: &str) -> Result<Module, LoxError> {
+        let script = scan_and_parse(&mut self.parser, source).map_err(LoxError::InputError)?;
+        let environment = Environment::new_with_natives(&mut self.parser.identifier_map);
+        let importing = mem::replace(
+            &mut self.interpreter,
+            StatementInterpreter::new(environment),
+        );
+        self.define_system_access();
+        let result = self.execute(&script);
+        let module = mem::replace(&mut self.interpreter, importing);
+        result?;
+        let exported = exported_names(&script.statements, &self.parser.identifier_map);
+        let exports = module
+            .globals()
+            .into_iter()
+            .filter(|(name, _)| exported.contains(name))
+            .collect();
+        Ok(Module::new(module_name(path), exports))
+    }
+
+    fn runtime_error(&self, error: RuntimeError) -> LoxError {
+        match error {
+            RuntimeError::Exit(code) => LoxError::Exit(code),
+            error => LoxError::RuntimeError(error.report(&self.parser.identifier_map)),
+        }
+    }
+
+    fn define_system_access(&mut self) {
+        self.interpreter.define_system_access(
+            &mut self.parser.identifier_map,
+            self.capabilities,
+            &self.arguments,
+        );
+    }
+
+    /// Evaluates the source if it is a bare expression, so that the
+    /// REPL can print its value. Anything else is run as a program.
+    fn run_interactive(&mut self, source: &str) -> Result<Option<Value>, LoxError> {
+        let (tokens, scanner_errors) = scanner::scan(source);
+        let expression = if scanner_errors.is_empty() {
+            self.parser.parse_standalone_expression(&tokens)
+        } else {
+            None
+        };
+        match expression {
+            Some(expression) => {
+                let lexical_scope = self
+                    .lexical_scope_resolver
+                    .resolve_expression(&expression)
+                    .map_err(|error| LoxError::LexicalScopesResolutionError(vec![error]))?;
+                self.interpreter
+                    .evaluate(&lexical_scope, &expression)
+                    .map(Some)
+                    .map_err(|error| self.runtime_error(error))
+            }
+            None => self.run(source).map(|_| None),
+        }
+    }
+}
+
+/// Parses the source and pretty prints its syntax tree, one line per
+/// statement. A bare expression is accepted as well.
+pub fn pretty_print(source: &str) -> Result<Vec<String>, RunError> {
+    let (tokens, scanner_errors) = scanner::scan(source);
+    if !scanner_errors.is_empty() {
+        return Err(RunError::CompileError(
+            scanner_errors.iter().map(|e| e.to_string()).collect(),
+        ));
+    }
+    let mut parser = Parser::default();
+    if let Some(expression) = parser.parse_standalone_expression(&tokens) {
+        return Ok(vec![expression.pretty_print(&parser.identifier_map)]);
+    }
+    let mut parser = Parser::default();
+    match parser.parse(&tokens) {
+        Ok(script) => Ok(script
+            .statements
+            .iter()
+            .map(|s| s.pretty_print(&parser.identifier_map))
+            .collect()),
+        Err(errors) => Err(RunError::CompileError(
+            errors.iter().map(|e| e.to_string()).collect(),
+        )),
+    }
 }
 
 impl LoxImplementation for TreeWalkRuloxInterpreter {
     fn run(&mut self, source: &str) -> Result<(), RunError> {
-        match self.run(source) {
-            Ok(_) => Ok(()),
-            //TODO: improve
-            _ => Err(RunError::Error),
+        self.run(source).map_err(RunError::from)
+    }
+
+    fn run_interactive(&mut self, source: &str) -> Result<(), RunError> {
+        match self.run_interactive(source) {
+            // Like Python's REPL, nothing is shown when there's no value
+            Ok(Some(Value::Nil)) | Ok(None) => Ok(()),
+            Ok(Some(value)) => {
+                println!("{}", value);
+                Ok(())
+            }
+            Err(error) => Err(RunError::from(error)),
         }
     }
+
+    fn globals(&self) -> Vec<(String, String)> {
+        let mut globals: Vec<(String, String)> = self
+            .interpreter
+            .globals()
+            .iter()
+            .map(|&(identifier, ref value)| {
+                let name = self
+                    .parser
+                    .identifier_map
+                    .lookup(identifier)
+                    .cloned()
+                    .unwrap_or_else(|| "<unknown>".into());
+                (name, value.to_string())
+            })
+            .collect();
+        globals.sort();
+        globals
+    }
+
+    fn set_arguments(&mut self, arguments: &[String]) {
+        self.arguments = arguments.to_vec();
+        // os.args() gives them
+        self.define_system_access();
+    }
+
+    fn set_capabilities(&mut self, capabilities: Capabilities) {
+        self.capabilities = capabilities;
+        self.define_system_access();
+    }
+
+    fn set_script_path(&mut self, path: &Path) {
+        self.modules.set_script(path);
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
     use treewalk::*;
 
     proptest! {
@@ -103,4 +364,131 @@ mod tests {
         lox_vm.run(input)
     }
     }
+
+    #[test]
+    fn globals_are_listed_by_name() {
+        let mut interpreter = TreeWalkRuloxInterpreter::default();
+        interpreter.run("var b = 2; var a = \"one\";").unwrap();
+        let globals = LoxImplementation::globals(&interpreter);
+        assert_eq!(
+            vec![
+                ("a".to_string(), "one".to_string()),
+                ("b".to_string(), "2".to_string()),
+                ("clock".to_string(), "clock".to_string()),
+                ("floor".to_string(), "floor".to_string()),
+                ("has".to_string(), "has".to_string()),
+                ("insert".to_string(), "insert".to_string()),
+                ("keys".to_string(), "keys".to_string()),
+                ("len".to_string(), "len".to_string()),
+                ("lower".to_string(), "lower".to_string()),
+                ("num".to_string(), "num".to_string()),
+                ("pop".to_string(), "pop".to_string()),
+                ("pow".to_string(), "pow".to_string()),
+                ("push".to_string(), "push".to_string()),
+                ("random".to_string(), "random".to_string()),
+                ("remove".to_string(), "remove".to_string()),
+                ("seed".to_string(), "seed".to_string()),
+                ("slice".to_string(), "slice".to_string()),
+                ("sqrt".to_string(), "sqrt".to_string()),
+                ("str".to_string(), "str".to_string()),
+                ("substring".to_string(), "substring".to_string()),
+                ("type".to_string(), "type".to_string()),
+                ("upper".to_string(), "upper".to_string()),
+                ("values".to_string(), "values".to_string()),
+            ],
+            globals
+        );
+    }
+
+    #[test]
+    fn script_arguments() {
+        let mut interpreter = TreeWalkRuloxInterpreter::default();
+        LoxImplementation::set_arguments(&mut interpreter, &["first".to_string()]);
+        // Only the os module gives them
+        for name in &["argc", "arg", "os"] {
+            assert!(LoxImplementation::run(&mut interpreter, &format!("{};", name)).is_err());
+        }
+        interpreter.set_capabilities(Capabilities {
+            os: true,
+            ..Capabilities::default()
+        });
+        interpreter
+            .run("var count = len(os.args()); var first = os.args()[0];")
+            .unwrap();
+        let globals = LoxImplementation::globals(&interpreter);
+        assert!(globals.contains(&("count".to_string(), "1".to_string())));
+        assert!(globals.contains(&("first".to_string(), "first".to_string())));
+    }
+
+    #[test]
+    fn lambdas_are_expressions_in_the_repl() {
+        let mut interpreter = TreeWalkRuloxInterpreter::default();
+        match interpreter.run_interactive("fun (a) { return a * 2; }(4)") {
+            Ok(Some(Value::Number(n))) => assert_eq!(8.0, n),
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn missing_modules_are_io_errors() {
+        let mut interpreter = TreeWalkRuloxInterpreter::default();
+        interpreter.set_script_path(&env::temp_dir().join("script.lox"));
+        match LoxImplementation::run(&mut interpreter, "import \"missing.lox\";") {
+            Err(RunError::IoError(message)) => {
+                assert_eq!("Cannot find module missing.lox", message)
+            }
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn system_modules_need_capabilities() {
+        let file = env::temp_dir().join(format!("rulox_system_{}.txt", process::id()));
+        let mut interpreter = TreeWalkRuloxInterpreter::default();
+        match LoxImplementation::run(&mut interpreter, "fs;") {
+            Err(RunError::RuntimeError(errors)) => {
+                assert_eq!("Undefined variable 'fs'.", errors[0])
+            }
+            other => panic!("Unexpected result {:?}", other),
+        }
+        interpreter.set_capabilities(Capabilities::all());
+        interpreter
+            .run(&format!("fs.writeFile(\"{}\", \"text\");", file.display()))
+            .unwrap();
+        assert_eq!("text", fs::read_to_string(&file).unwrap());
+        fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn exit_needs_its_capability_and_cant_be_caught() {
+        let mut interpreter = TreeWalkRuloxInterpreter::default();
+        match LoxImplementation::run(&mut interpreter, "exit(3);") {
+            Err(RunError::RuntimeError(errors)) => {
+                assert_eq!("Undefined variable 'exit'.", errors[0])
+            }
+            other => panic!("Unexpected result {:?}", other),
+        }
+        interpreter.set_capabilities(Capabilities::all());
+        match LoxImplementation::run(
+            &mut interpreter,
+            "var after = false;
+            fun f() { try { exit(3); } catch (e) { after = true; } finally { after = true; } }
+            f();
+            after = true;",
+        ) {
+            Err(RunError::Exit(3)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+        let globals = LoxImplementation::globals(&interpreter);
+        assert!(globals.contains(&("after".to_string(), "false".to_string())));
+    }
+
+    #[test]
+    fn pretty_print_source() {
+        assert_eq!(vec!["(* 2 3)".to_string()], pretty_print("2 * 3").unwrap());
+        assert_eq!(
+            vec!["var a;".to_string(), "print a;".to_string()],
+            pretty_print("var a; print a;").unwrap()
+        );
+    }
 }
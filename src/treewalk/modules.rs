@@ -0,0 +1,185 @@
+//! Finding, running once and caching the modules imported by the
+//! tree-walkers.
+//!
+//! The path of an import is looked up relative to the directory of the
+//! file importing it, then in the directories listed in the `LOX_PATH`
+//! environment variable. Code not read from a file, like the REPL's,
+//! imports relative to the current directory.
+//!
+//! A module runs in globals of its own. What it exports are the names
+//! its top-level declarations define, except those starting with `_`,
+//! with the values they had when it finished running.
+
+use fnv::FnvHashMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use treewalk::ast::{Identifier, IdentifierMap, Statement};
+
+#[derive(Debug)]
+pub enum ImportError {
+    NotFound(String),
+    Unreadable(PathBuf, String),
+    /// The files importing each other, the first one being imported again
+    Cycle(Vec<PathBuf>),
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ImportError::NotFound(ref path) => write!(f, "Cannot find module {}", path),
+            ImportError::Unreadable(ref path, ref error) => {
+                write!(f, "Error reading {}: {}", path.display(), error)
+            }
+            ImportError::Cycle(ref files) => {
+                f.write_str("Import cycle: ")?;
+                for (i, file) in files.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(" -> ")?;
+                    }
+                    let name = file.file_name().unwrap_or_else(|| file.as_os_str());
+                    write!(f, "{}", Path::new(name).display())?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The modules of a program, `M` being how a backend represents one
+/// that finished running
+pub struct Modules<M> {
+    loaded: FnvHashMap<PathBuf, M>,
+    /// The script, then the modules being run because it imported them,
+    /// innermost last
+    running: Vec<PathBuf>,
+}
+
+impl<M: Clone> Modules<M> {
+    pub fn new() -> Modules<M> {
+        Modules {
+            loaded: FnvHashMap::default(),
+            running: vec![],
+        }
+    }
+
+    /// Sets the file the imports of the script are relative to
+    pub fn set_script(&mut self, script: &Path) {
+        self.running = vec![script.canonicalize().unwrap_or_else(|_| script.into())];
+    }
+
+    /// Where the module is, looking for it in the order described in the
+    /// module documentation
+    pub fn find(&self, path: &str) -> Result<PathBuf, ImportError> {
+        let importing_directory = self
+            .running
+            .last()
+            .and_then(|file| file.parent())
+            .map(|directory| directory.to_path_buf())
+            .unwrap_or_default();
+        let search_path: Vec<PathBuf> = env::var_os("LOX_PATH")
+            .map(|paths| env::split_paths(&paths).collect())
+            .unwrap_or_default();
+        ::std::iter::once(importing_directory)
+            .chain(search_path)
+            .map(|directory| directory.join(path))
+            .find(|candidate| candidate.is_file())
+            .and_then(|found| found.canonicalize().ok())
+            .ok_or_else(|| ImportError::NotFound(path.into()))
+    }
+
+    pub fn get(&self, file: &Path) -> Option<M> {
+        self.loaded.get(file).cloned()
+    }
+
+    /// Reads the source of a module about to run, failing when it's
+    /// already running
+    pub fn start(&mut self, file: PathBuf) -> Result<String, ImportError> {
+        if let Some(start) = self.running.iter().position(|running| *running == file) {
+            let mut cycle = self.running[start..].to_vec();
+            cycle.push(file);
+            return Err(ImportError::Cycle(cycle));
+        }
+        let source = fs::read_to_string(&file)
+            .map_err(|error| ImportError::Unreadable(file.clone(), error.to_string()))?;
+        self.running.push(file);
+        Ok(source)
+    }
+
+    /// Records the module that last started, when it ran successfully, so
+    /// that it isn't run again
+    pub fn finish(&mut self, module: Option<M>) {
+        let file = self.running.pop().expect("A module is running");
+        if let Some(module) = module {
+            self.loaded.insert(file, module);
+        }
+    }
+}
+
+/// The names the top-level declarations define that the module exports
+pub fn exported_names(statements: &[Statement], identifier_map: &IdentifierMap) -> Vec<Identifier> {
+    statements
+        .iter()
+        .filter_map(|statement| match *statement {
+            Statement::VariableDefinition(name)
+            | Statement::VariableDefinitionWithInitalizer(name, _) => Some(name),
+            Statement::FunctionDefinition(ref f) => Some(f.name),
+            Statement::Class(ref c) => Some(c.name),
+            Statement::Import(ref i) => i.name,
+            _ => None,
+        })
+        .filter(|&name| {
+            identifier_map
+                .lookup(name)
+                .is_some_and(|name| !name.starts_with('_'))
+        })
+        .collect()
+}
+
+/// The name a module is shown with, from its path
+pub fn module_name(path: &str) -> String {
+    Path::new(path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use treewalk::modules::{ImportError, Modules};
+
+    #[test]
+    fn modules_are_found_and_cycles_detected() {
+        let directory = env::temp_dir().join(format!("rulox_modules_{}", ::std::process::id()));
+        let search_path = directory.join("search");
+        fs::create_dir_all(&search_path).unwrap();
+        let (a, b, c) = (
+            directory.join("a.lox"),
+            directory.join("b.lox"),
+            search_path.join("c.lox"),
+        );
+        for file in &[&a, &b, &c] {
+            fs::write(file, "").unwrap();
+        }
+        let mut modules: Modules<()> = Modules::new();
+        modules.set_script(&a);
+        let found = modules.find("b.lox").unwrap();
+        assert_eq!(b.canonicalize().unwrap(), found);
+        modules.start(found).unwrap();
+        let error = modules.start(modules.find("a.lox").unwrap()).unwrap_err();
+        assert_eq!("Import cycle: a.lox -> b.lox -> a.lox", error.to_string());
+        modules.finish(Some(()));
+        assert!(modules.get(&b.canonicalize().unwrap()).is_some());
+        env::set_var("LOX_PATH", &search_path);
+        assert_eq!(c.canonicalize().unwrap(), modules.find("c.lox").unwrap());
+        env::remove_var("LOX_PATH");
+        match modules.find("c.lox") {
+            Err(ImportError::NotFound(path)) => assert_eq!("c.lox", path),
+            other => panic!("Unexpected result {:?}", other),
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
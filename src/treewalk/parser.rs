@@ -1,3 +1,4 @@
+use std::fmt;
 use std::iter::Peekable;
 use std::mem::replace;
 use std::rc::Rc;
@@ -16,8 +17,17 @@ macro_rules! try_wrap_err {
     };
 }
 
+/// Consumes the next token if it matches the expected pattern.
+/// The parser is needed to report where the file ended in case
+/// we run out of tokens.
 macro_rules! consume_expected_token_with_action {
-    ($tokens:expr, $expected:pat, $transform_token:expr, $required_element:expr) => {
+    (
+        $parser:expr,
+        $tokens:expr,
+        $expected:pat,
+        $transform_token:expr,
+        $required_element:expr
+    ) => {
         match $tokens.peek().map(|t| &t.token) {
             Some($expected) => {
                 let _ = $tokens.next();
@@ -26,76 +36,163 @@ macro_rules! consume_expected_token_with_action {
             Some(_) => {
                 let token = $tokens.next().unwrap();
                 Err(ParseError::Missing(
-                    $required_element,
+                    vec![$required_element],
                     token.lexeme.clone(),
                     token.position,
                 ))
             }
-            None => Err(ParseError::UnexpectedEndOfFile),
+            None => Err($parser.unexpected_end_of_file(vec![$required_element])),
         }
     };
 }
 
 macro_rules! consume_expected_token {
-    ($tokens:expr, $expected:pat, $required_element:expr) => {
-        consume_expected_token_with_action!($tokens, $expected, (), $required_element)
+    ($parser:expr, $tokens:expr, $expected:pat, $required_element:expr) => {
+        consume_expected_token_with_action!($parser, $tokens, $expected, (), $required_element)
     };
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum RequiredElement {
     Subexpression,
+    Statement,
     LeftParen,
     RightParen,
     LeftBrace,
     RightBrace,
+    RightBracket,
     Semicolon,
+    Colon,
+    Comma,
+    Catch,
+    Finally,
     Identifier,
     Block,
     Dot,
+    ModulePath,
+}
+
+impl fmt::Display for RequiredElement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let description = match *self {
+            RequiredElement::Subexpression => "expression",
+            RequiredElement::Statement => "statement",
+            RequiredElement::LeftParen => "'('",
+            RequiredElement::RightParen => "')'",
+            RequiredElement::LeftBrace => "'{'",
+            RequiredElement::RightBrace => "'}'",
+            RequiredElement::RightBracket => "']'",
+            RequiredElement::Semicolon => "';'",
+            RequiredElement::Colon => "':'",
+            RequiredElement::Catch => "'catch'",
+            RequiredElement::Finally => "'finally'",
+            RequiredElement::Comma => "','",
+            RequiredElement::Identifier => "identifier",
+            RequiredElement::Block => "block",
+            RequiredElement::Dot => "'.'",
+            RequiredElement::ModulePath => "module path",
+        };
+        f.write_str(description)
+    }
 }
 
+/// Every error carries the position it refers to.
+/// Errors caused by a wrong or missing token also report the set
+/// of elements that would have been accepted instead.
 #[derive(Debug)]
 pub enum ParseError {
-    UnexpectedEndOfFile,
-    UnexpectedToken(Lexeme, Position),
-    Missing(RequiredElement, Lexeme, Position),
+    /// The position is the one of the last token in the input
+    UnexpectedEndOfFile(Vec<RequiredElement>, Position),
+    Missing(Vec<RequiredElement>, Lexeme, Position),
     InvalidAssignmentTarget(Lexeme, Position),
-    TooManyArguments,
+    TooManyArguments(Lexeme, Position),
+}
+
+fn write_expected(f: &mut fmt::Formatter, expected: &[RequiredElement]) -> fmt::Result {
+    match expected.len() {
+        0 => Ok(()),
+        1 => write!(f, "{}", expected[0]),
+        _ => {
+            f.write_str("one of ")?;
+            for (i, element) in expected.iter().enumerate() {
+                if i > 0 {
+                    f.write_str(", ")?;
+                }
+                write!(f, "{}", element)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::UnexpectedEndOfFile(ref expected, ref position) => {
+                write!(f, "{} Error at end: expected ", position)?;
+                write_expected(f, expected)
+            }
+            ParseError::Missing(ref expected, ref lexeme, ref position) => {
+                write!(f, "{} Error at '{}': expected ", position, lexeme)?;
+                write_expected(f, expected)
+            }
+            ParseError::InvalidAssignmentTarget(ref lexeme, ref position) => write!(
+                f,
+                "{} Error at '{}': invalid assignment target",
+                position, lexeme
+            ),
+            ParseError::TooManyArguments(ref lexeme, ref position) => write!(
+                f,
+                "{} Error at '{}': cannot have more than 8 arguments",
+                position, lexeme
+            ),
+        }
+    }
 }
 
 pub struct Parser {
     pub identifier_map: IdentifierMap,
     variable_use_handle_factory: VariableUseHandleFactory,
+    /// Position of the last token, used to report
+    /// errors when the input ends prematurely
+    end_of_file: Position,
 }
 
 impl Parser {
     #[allow(dead_code)] // Used in tests
     pub fn default() -> Parser {
-        Parser {
-            identifier_map: IdentifierMap::new(),
-            variable_use_handle_factory: VariableUseHandleFactory::new(),
-        }
+        Parser::new(IdentifierMap::new())
     }
 
     pub fn new(identifier_map: IdentifierMap) -> Parser {
         Parser {
             identifier_map,
             variable_use_handle_factory: VariableUseHandleFactory::new(),
+            end_of_file: Position::initial(),
         }
     }
 
-    pub fn parse(
-        &mut self,
-        tokens: &[TokenWithContext],
-    ) -> Result<Vec<Statement>, Vec<ParseError>> {
+    fn unexpected_end_of_file(&self, expected: Vec<RequiredElement>) -> ParseError {
+        ParseError::UnexpectedEndOfFile(expected, self.end_of_file)
+    }
+
+    pub fn parse(&mut self, tokens: &[TokenWithContext]) -> Result<Block, Vec<ParseError>> {
+        if let Some(last) = tokens.last() {
+            self.end_of_file = last.position;
+        }
         let mut statements = Vec::new();
+        let mut positions = Vec::new();
         let mut errors = Vec::new();
         let mut peekable_tokens = tokens.iter().peekable();
-        while let Some(result) = self.parse_declaration(&mut peekable_tokens) {
+        while let Some(position) = peekable_tokens.peek().map(|t| t.position) {
+            let result = match self.parse_declaration(&mut peekable_tokens) {
+                Some(result) => result,
+                None => break,
+            };
             match result {
                 Ok(statement) => {
                     statements.push(statement);
+                    positions.push(position);
                 }
                 Err(error) => {
                     errors.push(error);
@@ -104,12 +201,29 @@ impl Parser {
             }
         }
         if errors.is_empty() {
-            Ok(statements)
+            Ok(Block {
+                statements,
+                positions,
+            })
         } else {
             Err(errors)
         }
     }
 
+    /// Parses the tokens as a single expression.
+    /// Returns None if they're not a valid expression or if some of
+    /// them are left over, e.g. because they're a statement.
+    pub fn parse_standalone_expression(&mut self, tokens: &[TokenWithContext]) -> Option<Expr> {
+        let mut peekable_tokens = tokens.iter().peekable();
+        match self.parse_expression(&mut peekable_tokens) {
+            Some(Ok(expression)) => match peekable_tokens.peek() {
+                None => Some(expression),
+                Some(_) => None,
+            },
+            _ => None,
+        }
+    }
+
     fn consume_expected_identifier<'a, I>(
         &mut self,
         tokens: &mut Peekable<I>,
@@ -118,6 +232,7 @@ impl Parser {
         I: Iterator<Item = &'a TokenWithContext>,
     {
         consume_expected_token_with_action!(
+            self,
             tokens,
             &Token::Identifier(ref identifier),
             self.identifier_map.for_name(identifier),
@@ -144,7 +259,12 @@ impl Parser {
                 | Token::If
                 | Token::While
                 | Token::Print
-                | Token::Return => PositionInConstruct::Start,
+                | Token::Return
+                | Token::Break
+                | Token::Continue
+                | Token::Throw
+                | Token::Try
+                | Token::Import => PositionInConstruct::Start,
                 _ => PositionInConstruct::Body,
             }
         }
@@ -167,8 +287,10 @@ impl Parser {
     fn parse_semicolon_terminated_statement<'a, I>(
         &mut self,
         tokens: &mut Peekable<I>,
-        parse_statement: &Fn(&mut Parser, &mut Peekable<I>)
-            -> Option<Result<Statement, ParseError>>,
+        parse_statement: &Fn(
+            &mut Parser,
+            &mut Peekable<I>,
+        ) -> Option<Result<Statement, ParseError>>,
     ) -> Option<Result<Statement, ParseError>>
     where
         I: Iterator<Item = &'a TokenWithContext>,
@@ -182,16 +304,14 @@ impl Parser {
                     let _ = tokens.next();
                     Some(Ok(statement))
                 }
-                Some(&&TokenWithContext {
-                    ref lexeme,
-                    ref position,
-                    ..
-                }) => Some(Err(ParseError::Missing(
-                    RequiredElement::Semicolon,
-                    lexeme.clone(),
-                    *position,
+                Some(token) => Some(Err(ParseError::Missing(
+                    vec![RequiredElement::Semicolon],
+                    token.lexeme.clone(),
+                    token.position,
                 ))),
-                None => Some(Err(ParseError::UnexpectedEndOfFile)),
+                None => Some(Err(
+                    self.unexpected_end_of_file(vec![RequiredElement::Semicolon])
+                )),
             },
             e => e,
         }
@@ -233,6 +353,10 @@ impl Parser {
                 let _ = tokens.next();
                 self.parse_for_statement(tokens)
             }
+            Some(&Token::Try) => {
+                let _ = tokens.next();
+                self.parse_try_statement(tokens)
+            }
             Some(_) => self.parse_semicolon_terminated_statement(tokens, &Parser::parse_statement),
             None => None,
         }
@@ -247,20 +371,7 @@ impl Parser {
         I: Iterator<Item = &'a TokenWithContext>,
     {
         let identifier = try_wrap_err!(self.consume_expected_identifier(tokens));
-        let parse_identifier = |parser: &mut Parser, tokens: &mut Peekable<I>| {
-            Some(parser.consume_expected_identifier(tokens))
-        };
-        let arguments = try_wrap_err!(self.parse_function_arguments(tokens, &parse_identifier));
-        try_wrap_err!(consume_expected_token!(
-            tokens,
-            &Token::LeftBrace,
-            RequiredElement::Block
-        ));
-        let block = match self.parse_block(tokens) {
-            Some(Ok(block)) => block,
-            Some(err) => return Some(err),
-            None => return Some(Err(ParseError::UnexpectedEndOfFile)),
-        };
+        let (arguments, body) = try_wrap_err!(self.parse_function_rest(tokens));
         Some(Ok(Statement::FunctionDefinition(Rc::new(
             FunctionDefinition {
                 kind: if kind == FunctionKind::Method && identifier == Identifier::init() {
@@ -270,11 +381,92 @@ impl Parser {
                 },
                 name: identifier,
                 arguments,
-                body: block,
+                body,
             },
         ))))
     }
 
+    /// Parses the arguments and the body of a function, after its name
+    fn parse_function_rest<'a, I>(
+        &mut self,
+        tokens: &mut Peekable<I>,
+    ) -> Result<(Vec<Identifier>, Block), ParseError>
+    where
+        I: Iterator<Item = &'a TokenWithContext>,
+    {
+        let parse_identifier = |parser: &mut Parser, tokens: &mut Peekable<I>| {
+            Some(parser.consume_expected_identifier(tokens))
+        };
+        let arguments = self.parse_function_arguments(tokens, &parse_identifier)?;
+        let body = self.parse_required_block(tokens)?;
+        Ok((arguments, body))
+    }
+
+    /// Parses a block, which has to be there, including its opening brace
+    fn parse_required_block<'a, I>(&mut self, tokens: &mut Peekable<I>) -> Result<Block, ParseError>
+    where
+        I: Iterator<Item = &'a TokenWithContext>,
+    {
+        consume_expected_token!(self, tokens, &Token::LeftBrace, RequiredElement::Block)?;
+        match self.parse_block(tokens) {
+            Some(Ok(Statement::Block(block))) => Ok(*block),
+            Some(Ok(_)) => unreachable!("parse_block only returns blocks"),
+            Some(Err(error)) => Err(error),
+            None => Err(self.unexpected_end_of_file(vec![
+                RequiredElement::Statement,
+                RequiredElement::RightBrace,
+            ])),
+        }
+    }
+
+    fn parse_try_statement<'a, I>(
+        &mut self,
+        tokens: &mut Peekable<I>,
+    ) -> Option<Result<Statement, ParseError>>
+    where
+        I: Iterator<Item = &'a TokenWithContext>,
+    {
+        let body = try_wrap_err!(self.parse_required_block(tokens));
+        let catch = if let Some(&Token::Catch) = tokens.peek().map(|t| &t.token) {
+            let _ = tokens.next();
+            try_wrap_err!(consume_expected_token!(
+                self,
+                tokens,
+                &Token::LeftParen,
+                RequiredElement::LeftParen
+            ));
+            let variable = try_wrap_err!(self.consume_expected_identifier(tokens));
+            try_wrap_err!(consume_expected_token!(
+                self,
+                tokens,
+                &Token::RightParen,
+                RequiredElement::RightParen
+            ));
+            let body = try_wrap_err!(self.parse_required_block(tokens));
+            Some(Catch { variable, body })
+        } else {
+            None
+        };
+        let finally = if let Some(&Token::Finally) = tokens.peek().map(|t| &t.token) {
+            let _ = tokens.next();
+            Some(try_wrap_err!(self.parse_required_block(tokens)))
+        } else {
+            None
+        };
+        if catch.is_none() && finally.is_none() {
+            let expected = vec![RequiredElement::Catch, RequiredElement::Finally];
+            return Some(Err(match tokens.next() {
+                Some(token) => ParseError::Missing(expected, token.lexeme.clone(), token.position),
+                None => self.unexpected_end_of_file(expected),
+            }));
+        }
+        Some(Ok(Statement::Try(Box::new(Try {
+            body,
+            catch,
+            finally,
+        }))))
+    }
+
     fn parse_class_declaration<'a, I>(
         &mut self,
         tokens: &mut Peekable<I>,
@@ -291,12 +483,17 @@ impl Parser {
             match self.parse_expression(tokens) {
                 Some(Ok(expr)) => Some(expr),
                 Some(Err(error)) => return Some(Err(error)),
-                None => return Some(Err(ParseError::UnexpectedEndOfFile)),
+                None => {
+                    return Some(Err(
+                        self.unexpected_end_of_file(vec![RequiredElement::Subexpression])
+                    ))
+                }
             }
         } else {
             None
         };
         try_wrap_err!(consume_expected_token!(
+            self,
             tokens,
             &Token::LeftBrace,
             RequiredElement::LeftBrace
@@ -317,11 +514,17 @@ impl Parser {
             match self.parse_function_declaration(tokens, FunctionKind::Method) {
                 Some(Ok(Statement::FunctionDefinition(method))) => methods.push(method),
                 Some(Ok(_)) => panic!("Function parsing didn't return a function"),
-                None => return Some(Err(ParseError::UnexpectedEndOfFile)),
+                None => {
+                    return Some(Err(self.unexpected_end_of_file(vec![
+                        RequiredElement::Identifier,
+                        RequiredElement::RightBrace,
+                    ])))
+                }
                 Some(Err(error)) => return Some(Err(error)),
             }
         }
         try_wrap_err!(consume_expected_token!(
+            self,
             tokens,
             &Token::RightBrace,
             RequiredElement::RightBrace
@@ -341,10 +544,21 @@ impl Parser {
         I: Iterator<Item = &'a TokenWithContext>,
     {
         let identifier = try_wrap_err!(self.consume_expected_identifier(tokens));
+        self.parse_var_initializer(tokens, identifier)
+    }
+
+    /// The rest of a variable declaration, once its name is consumed
+    fn parse_var_initializer<'a, I>(
+        &mut self,
+        tokens: &mut Peekable<I>,
+        identifier: Identifier,
+    ) -> Option<Result<Statement, ParseError>>
+    where
+        I: Iterator<Item = &'a TokenWithContext>,
+    {
         if let Some(&&TokenWithContext {
             token: Token::Equal,
-            ref lexeme,
-            ref position,
+            ..
         }) = tokens.peek()
         {
             let _ = tokens.next();
@@ -353,11 +567,11 @@ impl Parser {
                     identifier, expression,
                 ))),
                 Some(Err(error)) => Some(Err(error)),
-                None => Some(Err(ParseError::Missing(
-                    RequiredElement::Subexpression,
-                    lexeme.clone(),
-                    *position,
-                ))),
+                None => {
+                    Some(Err(self.unexpected_end_of_file(vec![
+                        RequiredElement::Subexpression,
+                    ])))
+                }
             }
         } else {
             Some(Ok(Statement::VariableDefinition(identifier)))
@@ -372,6 +586,7 @@ impl Parser {
         I: Iterator<Item = &'a TokenWithContext>,
     {
         let mut statements = Vec::new();
+        let mut positions = Vec::new();
         fn is_block_end(t: Option<&&TokenWithContext>) -> bool {
             if let Some(&TokenWithContext {
                 token: Token::RightBrace,
@@ -384,17 +599,30 @@ impl Parser {
             }
         };
         while !is_block_end(tokens.peek()) {
+            if let Some(token) = tokens.peek() {
+                positions.push(token.position);
+            }
             match self.parse_declaration(tokens) {
                 Some(Ok(statement)) => statements.push(statement),
-                None => return Some(Err(ParseError::UnexpectedEndOfFile)),
+                None => {
+                    return Some(Err(self.unexpected_end_of_file(vec![
+                        RequiredElement::Statement,
+                        RequiredElement::RightBrace,
+                    ])))
+                }
                 Some(Err(error)) => return Some(Err(error)),
             }
         }
         if is_block_end(tokens.peek()) {
             let _ = tokens.next();
-            Some(Ok(Statement::Block(Box::new(Block { statements }))))
+            Some(Ok(Statement::Block(Box::new(Block {
+                statements,
+                positions,
+            }))))
         } else {
-            Some(Err(ParseError::UnexpectedEndOfFile)) //TODO: better message
+            Some(Err(
+                self.unexpected_end_of_file(vec![RequiredElement::RightBrace])
+            ))
         }
     }
 
@@ -414,6 +642,23 @@ impl Parser {
                 let _ = tokens.next();
                 self.parse_return_statement(tokens)
             }
+            Some(&Token::Break) => {
+                let _ = tokens.next();
+                Some(Ok(Statement::Break))
+            }
+            Some(&Token::Continue) => {
+                let _ = tokens.next();
+                Some(Ok(Statement::Continue))
+            }
+            Some(&Token::Throw) => {
+                let _ = tokens.next();
+                self.parse_expression(tokens)
+                    .map(|r| r.map(Statement::Throw))
+            }
+            Some(&Token::Import) => {
+                let _ = tokens.next();
+                self.parse_import_statement(tokens)
+            }
             Some(_) => self.parse_expression_statement(tokens),
             None => None,
         }
@@ -430,6 +675,31 @@ impl Parser {
             .map(|r| r.map(Statement::Print))
     }
 
+    /// `as` is only a keyword here, so it can still name variables
+    fn parse_import_statement<'a, I>(
+        &mut self,
+        tokens: &mut Peekable<I>,
+    ) -> Option<Result<Statement, ParseError>>
+    where
+        I: Iterator<Item = &'a TokenWithContext>,
+    {
+        let path = try_wrap_err!(consume_expected_token_with_action!(
+            self,
+            tokens,
+            Token::StringLiteral(path),
+            path.as_str().into(),
+            RequiredElement::ModulePath
+        ));
+        let name = match tokens.peek().map(|t| &t.token) {
+            Some(Token::Identifier(identifier)) if identifier == "as" => {
+                let _ = tokens.next();
+                Some(try_wrap_err!(self.consume_expected_identifier(tokens)))
+            }
+            _ => None,
+        };
+        Some(Ok(Statement::Import(Import { path, name })))
+    }
+
     fn parse_return_statement<'a, I>(
         &mut self,
         tokens: &mut Peekable<I>,
@@ -453,6 +723,7 @@ impl Parser {
         I: Iterator<Item = &'a TokenWithContext>,
     {
         try_wrap_err!(consume_expected_token!(
+            self,
             tokens,
             &Token::LeftParen,
             RequiredElement::LeftParen
@@ -460,26 +731,37 @@ impl Parser {
         let condition = match self.parse_expression(tokens) {
             Some(Ok(expression)) => expression,
             Some(Err(error)) => return Some(Err(error)),
-            None => return Some(Err(ParseError::UnexpectedEndOfFile)),
+            None => {
+                return Some(Err(
+                    self.unexpected_end_of_file(vec![RequiredElement::Subexpression])
+                ))
+            }
         };
         try_wrap_err!(consume_expected_token!(
+            self,
             tokens,
             &Token::RightParen,
             RequiredElement::RightParen
         ));
-        // I'd rather use parse_block instead of parse_declaration
-        // that would require the presence of the brackets
-        let then_branch = match self.parse_declaration(tokens) {
+        let then_branch = match self.parse_branch(tokens) {
             Some(Ok(statement)) => statement,
             Some(Err(error)) => return Some(Err(error)),
-            None => return Some(Err(ParseError::UnexpectedEndOfFile)),
+            None => {
+                return Some(Err(
+                    self.unexpected_end_of_file(vec![RequiredElement::Statement])
+                ))
+            }
         };
         if let Some(&Token::Else) = tokens.peek().map(|t| &t.token) {
             let _ = tokens.next();
-            let else_branch = match self.parse_declaration(tokens) {
+            let else_branch = match self.parse_branch(tokens) {
                 Some(Ok(statement)) => statement,
                 Some(Err(error)) => return Some(Err(error)),
-                None => return Some(Err(ParseError::UnexpectedEndOfFile)),
+                None => {
+                    return Some(Err(
+                        self.unexpected_end_of_file(vec![RequiredElement::Statement])
+                    ))
+                }
             };
             Some(Ok(Statement::IfThenElse(Box::new(IfThenElse {
                 condition,
@@ -494,6 +776,31 @@ impl Parser {
         }
     }
 
+    /// The body of an if, while or for: any statement but a declaration.
+    /// A declaration there would define a variable only when the branch
+    /// runs, and the resolver couldn't tell where it lives.
+    fn parse_branch<'a, I>(
+        &mut self,
+        tokens: &mut Peekable<I>,
+    ) -> Option<Result<Statement, ParseError>>
+    where
+        I: Iterator<Item = &'a TokenWithContext>,
+    {
+        match tokens.peek().map(|t| &t.token) {
+            Some(&Token::Var) | Some(&Token::Fun) | Some(&Token::Class) => {
+                let token = tokens.next().unwrap();
+                Some(Err(ParseError::Missing(
+                    vec![RequiredElement::Statement],
+                    token.lexeme.clone(),
+                    token.position,
+                )))
+            }
+            // I'd rather use parse_block instead of parse_declaration
+            // that would require the presence of the brackets
+            _ => self.parse_declaration(tokens),
+        }
+    }
+
     fn parse_while_statement<'a, I>(
         &mut self,
         tokens: &mut Peekable<I>,
@@ -502,6 +809,7 @@ impl Parser {
         I: Iterator<Item = &'a TokenWithContext>,
     {
         try_wrap_err!(consume_expected_token!(
+            self,
             tokens,
             &Token::LeftParen,
             RequiredElement::LeftParen
@@ -509,21 +817,32 @@ impl Parser {
         let condition = match self.parse_expression(tokens) {
             Some(Ok(expression)) => expression,
             Some(Err(error)) => return Some(Err(error)),
-            None => return Some(Err(ParseError::UnexpectedEndOfFile)),
+            None => {
+                return Some(Err(
+                    self.unexpected_end_of_file(vec![RequiredElement::Subexpression])
+                ))
+            }
         };
         try_wrap_err!(consume_expected_token!(
+            self,
             tokens,
             &Token::RightParen,
             RequiredElement::RightParen
         ));
-        // I'd rather use parse_block instead of parse_declaration
-        // that would require the presence of the brackets
-        let body = match self.parse_declaration(tokens) {
+        let body = match self.parse_branch(tokens) {
             Some(Ok(statement)) => statement,
             Some(Err(error)) => return Some(Err(error)),
-            None => return Some(Err(ParseError::UnexpectedEndOfFile)),
+            None => {
+                return Some(Err(
+                    self.unexpected_end_of_file(vec![RequiredElement::Statement])
+                ))
+            }
         };
-        Some(Ok(Statement::While(Box::new(While { condition, body }))))
+        Some(Ok(Statement::While(Box::new(While {
+            condition,
+            body,
+            increment: None,
+        }))))
     }
 
     fn parse_for_statement<'a, I>(
@@ -533,7 +852,10 @@ impl Parser {
     where
         I: Iterator<Item = &'a TokenWithContext>,
     {
+        // Where the statements of the desugared loop start
+        let position = tokens.peek().map_or(self.end_of_file, |t| t.position);
         try_wrap_err!(consume_expected_token!(
+            self,
             tokens,
             &Token::LeftParen,
             RequiredElement::LeftParen
@@ -542,34 +864,56 @@ impl Parser {
             Some(&Token::Semicolon) => None,
             Some(&Token::Var) => {
                 let _ = tokens.next();
-                match self.parse_var_declaration(tokens) {
+                let identifier = try_wrap_err!(self.consume_expected_identifier(tokens));
+                match tokens.peek().map(|t| &t.token) {
+                    Some(Token::Identifier(word)) if word == "in" => {
+                        let _ = tokens.next();
+                        return self.parse_for_in_statement(tokens, identifier, position);
+                    }
+                    _ => {}
+                }
+                match self.parse_var_initializer(tokens, identifier) {
                     Some(Ok(expression)) => Some(expression),
                     Some(Err(error)) => return Some(Err(error)),
-                    None => return Some(Err(ParseError::UnexpectedEndOfFile)),
+                    None => {
+                        return Some(Err(
+                            self.unexpected_end_of_file(vec![RequiredElement::Identifier])
+                        ))
+                    }
                 }
             }
             _ => match self.parse_expression_statement(tokens) {
                 Some(Ok(expression)) => Some(expression),
                 Some(Err(error)) => return Some(Err(error)),
-                None => return Some(Err(ParseError::UnexpectedEndOfFile)),
+                None => {
+                    return Some(Err(
+                        self.unexpected_end_of_file(vec![RequiredElement::Subexpression])
+                    ))
+                }
             },
         };
         try_wrap_err!(consume_expected_token!(
+            self,
             tokens,
             &Token::Semicolon,
             RequiredElement::Semicolon
         ));
 
         let condition = match tokens.peek().map(|t| &t.token) {
-            Some(&Token::Semicolon) => Expr::Literal(Literal::BoolLiteral(true)),
+            Some(&Token::Semicolon) => Expr::Literal(Literal::Bool(true)),
             _ => match self.parse_expression(tokens) {
                 Some(Ok(expression)) => expression,
                 Some(Err(error)) => return Some(Err(error)),
-                None => return Some(Err(ParseError::UnexpectedEndOfFile)),
+                None => {
+                    return Some(Err(
+                        self.unexpected_end_of_file(vec![RequiredElement::Subexpression])
+                    ))
+                }
             },
         };
 
         try_wrap_err!(consume_expected_token!(
+            self,
             tokens,
             &Token::Semicolon,
             RequiredElement::Semicolon
@@ -580,45 +924,136 @@ impl Parser {
             _ => match self.parse_expression(tokens) {
                 Some(Ok(expression)) => Some(expression),
                 Some(Err(error)) => return Some(Err(error)),
-                None => return Some(Err(ParseError::UnexpectedEndOfFile)),
+                None => {
+                    return Some(Err(
+                        self.unexpected_end_of_file(vec![RequiredElement::Subexpression])
+                    ))
+                }
             },
         };
         try_wrap_err!(consume_expected_token!(
+            self,
             tokens,
             &Token::RightParen,
             RequiredElement::RightParen
         ));
 
-        // I'd rather use parse_block instead of parse_declaration
-        // that would require the presence of the brackets
-        let body = match self.parse_declaration(tokens) {
+        let body = match self.parse_branch(tokens) {
             Some(Ok(statement)) => statement,
             Some(Err(error)) => return Some(Err(error)),
-            None => return Some(Err(ParseError::UnexpectedEndOfFile)),
+            None => {
+                return Some(Err(
+                    self.unexpected_end_of_file(vec![RequiredElement::Statement])
+                ))
+            }
         };
         // Desugaring
-        let desugared_body = if let Some(increment_expression) = increment {
-            let desugared_statements = vec![body, Statement::Expression(increment_expression)];
-            Statement::Block(Box::new(Block {
-                statements: desugared_statements,
-            }))
-        } else {
-            body
-        };
         let while_statement = Statement::While(Box::new(While {
             condition,
-            body: desugared_body,
+            body,
+            increment,
         }));
         Some(Ok(if let Some(initializer) = initializer {
             let desugared_statements = vec![initializer, while_statement];
             Statement::Block(Box::new(Block {
                 statements: desugared_statements,
+                positions: vec![position, position],
             }))
         } else {
             while_statement
         }))
     }
 
+    /// Turns `for (var x in xs) body` into
+    /// `{ var list = xs; for (var index = 0; index < len(list); index = index + 1) { var x = list[index]; body } }`,
+    /// where the program can't name `list` and `index`. What's left to
+    /// consume starts with the expression following `in`, which is only
+    /// a keyword here.
+    fn parse_for_in_statement<'a, I>(
+        &mut self,
+        tokens: &mut Peekable<I>,
+        element: Identifier,
+        position: Position,
+    ) -> Option<Result<Statement, ParseError>>
+    where
+        I: Iterator<Item = &'a TokenWithContext>,
+    {
+        let collection = match self.parse_expression(tokens) {
+            Some(Ok(expression)) => expression,
+            Some(Err(error)) => return Some(Err(error)),
+            None => {
+                return Some(Err(
+                    self.unexpected_end_of_file(vec![RequiredElement::Subexpression])
+                ))
+            }
+        };
+        try_wrap_err!(consume_expected_token!(
+            self,
+            tokens,
+            &Token::RightParen,
+            RequiredElement::RightParen
+        ));
+        let body = match self.parse_branch(tokens) {
+            Some(Ok(statement)) => statement,
+            Some(Err(error)) => return Some(Err(error)),
+            None => {
+                return Some(Err(
+                    self.unexpected_end_of_file(vec![RequiredElement::Statement])
+                ))
+            }
+        };
+        // Desugaring
+        let list = self.identifier_map.for_name("$list");
+        let index = self.identifier_map.for_name("$index");
+        let len = self.identifier_map.for_name("len");
+        let variable = |parser: &mut Parser, identifier| {
+            Expr::Identifier(parser.variable_use_handle_factory.next(), identifier)
+        };
+        let condition = Expr::Binary(Box::new(BinaryExpr {
+            left: variable(self, index),
+            operator: BinaryOperator::Less,
+            right: Expr::Call(Box::new(Call {
+                callee: variable(self, len),
+                arguments: vec![variable(self, list)],
+            })),
+        }));
+        let increment = Expr::Assignment(Box::new(Assignment {
+            handle: self.variable_use_handle_factory.next(),
+            lvalue: Target::Identifier(index),
+            rvalue: Expr::Binary(Box::new(BinaryExpr {
+                left: variable(self, index),
+                operator: BinaryOperator::Plus,
+                right: Expr::Literal(Literal::Number(1.0)),
+            })),
+        }));
+        let element = Statement::VariableDefinitionWithInitalizer(
+            element,
+            Expr::Index(Box::new(Index {
+                collection: variable(self, list),
+                index: variable(self, index),
+            })),
+        );
+        let while_statement = Statement::While(Box::new(While {
+            condition,
+            body: Statement::Block(Box::new(Block {
+                statements: vec![element, body],
+                positions: vec![position, position],
+            })),
+            increment: Some(increment),
+        }));
+        Some(Ok(Statement::Block(Box::new(Block {
+            statements: vec![
+                Statement::VariableDefinitionWithInitalizer(list, collection),
+                Statement::VariableDefinitionWithInitalizer(
+                    index,
+                    Expr::Literal(Literal::Number(0.0)),
+                ),
+                while_statement,
+            ],
+            positions: vec![position, position, position],
+        }))))
+    }
+
     fn parse_expression_statement<'a, I>(
         &mut self,
         tokens: &mut Peekable<I>,
@@ -667,7 +1102,7 @@ impl Parser {
                 try_wrap_err!(result)
             } else {
                 return Some(Err(ParseError::Missing(
-                    RequiredElement::Subexpression,
+                    vec![RequiredElement::Subexpression],
                     operator.lexeme.clone(),
                     operator.position,
                 )));
@@ -709,7 +1144,7 @@ impl Parser {
                 try_wrap_err!(result)
             } else {
                 return Some(Err(ParseError::Missing(
-                    RequiredElement::Subexpression,
+                    vec![RequiredElement::Subexpression],
                     operator.lexeme.clone(),
                     operator.position,
                 )));
@@ -739,7 +1174,8 @@ impl Parser {
                         Expr::Identifier(_, identifier) => {
                             let target = Target::Identifier(identifier);
                             match self.parse_assignment(tokens) {
-                                None => Some(Err(ParseError::UnexpectedEndOfFile)),
+                                None => Some(Err(self
+                                    .unexpected_end_of_file(vec![RequiredElement::Subexpression]))),
                                 Some(result) => Some(result.map(|rvalue| {
                                     Expr::Assignment(Box::new(Assignment {
                                         handle: self.variable_use_handle_factory.next(),
@@ -755,13 +1191,14 @@ impl Parser {
                             let get = replace(
                                 &mut *get,
                                 Get {
-                                    instance: Expr::Literal(Literal::NilLiteral),
+                                    instance: Expr::Literal(Literal::Nil),
                                     property,
                                 },
                             );
                             let instance = get.instance;
                             match self.parse_assignment(tokens) {
-                                None => Some(Err(ParseError::UnexpectedEndOfFile)),
+                                None => Some(Err(self
+                                    .unexpected_end_of_file(vec![RequiredElement::Subexpression]))),
                                 Some(result) => Some(result.map(|rvalue| {
                                     Expr::Set(Box::new(Set {
                                         instance,
@@ -771,6 +1208,20 @@ impl Parser {
                                 })),
                             }
                         }
+                        Expr::Index(index) => {
+                            let Index { collection, index } = *index;
+                            match self.parse_assignment(tokens) {
+                                None => Some(Err(self
+                                    .unexpected_end_of_file(vec![RequiredElement::Subexpression]))),
+                                Some(result) => Some(result.map(|value| {
+                                    Expr::SetIndex(Box::new(SetIndex {
+                                        collection,
+                                        index,
+                                        value,
+                                    }))
+                                })),
+                            }
+                        }
                         _ => Some(Err(ParseError::InvalidAssignmentTarget(
                             equal.lexeme.clone(),
                             equal.position,
@@ -868,6 +1319,8 @@ impl Parser {
             match *token {
                 Token::Slash => Some(BinaryOperator::Slash),
                 Token::Star => Some(BinaryOperator::Star),
+                Token::Percent => Some(BinaryOperator::Percent),
+                Token::TildeSlash => Some(BinaryOperator::TildeSlash),
                 _ => None,
             }
         }
@@ -894,7 +1347,7 @@ impl Parser {
                 try_wrap_err!(result)
             } else {
                 return Some(Err(ParseError::Missing(
-                    RequiredElement::Subexpression,
+                    vec![RequiredElement::Subexpression],
                     operator.lexeme.clone(),
                     operator.position,
                 )));
@@ -933,6 +1386,28 @@ impl Parser {
                         property: identifier,
                     }))
                 }
+                Some(&Token::LeftBracket) => {
+                    let _ = tokens.next();
+                    let index = match self.parse_expression(tokens) {
+                        Some(Ok(index)) => index,
+                        None => {
+                            return Some(Err(
+                                self.unexpected_end_of_file(vec![RequiredElement::Subexpression])
+                            ))
+                        }
+                        error => return error,
+                    };
+                    try_wrap_err!(consume_expected_token!(
+                        self,
+                        tokens,
+                        &Token::RightBracket,
+                        RequiredElement::RightBracket
+                    ));
+                    expression = Expr::Index(Box::new(Index {
+                        collection: expression,
+                        index,
+                    }))
+                }
                 _ => break,
             }
         }
@@ -948,6 +1423,7 @@ impl Parser {
         I: Iterator<Item = &'a TokenWithContext>,
     {
         try!(consume_expected_token!(
+            self,
             tokens,
             &Token::LeftParen,
             RequiredElement::LeftParen
@@ -956,15 +1432,22 @@ impl Parser {
         if let Some(&Token::RightParen) = tokens.peek().map(|t| &t.token) {
         } else {
             loop {
+                // Kept to report where the argument list got too long
+                let first_token = tokens.peek().map(|t| (t.lexeme.clone(), t.position));
                 match parse_argument(self, tokens) {
                     Some(Ok(expression)) => {
                         if arguments.len() >= 8 {
-                            return Err(ParseError::TooManyArguments);
+                            let (lexeme, position) = first_token.unwrap();
+                            return Err(ParseError::TooManyArguments(lexeme, position));
                         }
                         arguments.push(expression)
                     }
                     Some(Err(error)) => return Err(error),
-                    None => return Err(ParseError::UnexpectedEndOfFile),
+                    None => {
+                        return Err(
+                            self.unexpected_end_of_file(vec![RequiredElement::Subexpression])
+                        )
+                    }
                 };
                 if let Some(&Token::Comma) = tokens.peek().map(|t| &t.token) {
                     let _ = tokens.next();
@@ -973,12 +1456,127 @@ impl Parser {
                 }
             }
         }
-        try!(consume_expected_token!(
-            tokens,
-            &Token::RightParen,
-            RequiredElement::RightParen
-        ));
-        Ok(arguments)
+        match tokens.next() {
+            Some(&TokenWithContext {
+                token: Token::RightParen,
+                ..
+            }) => Ok(arguments),
+            Some(token) => Err(ParseError::Missing(
+                if arguments.is_empty() {
+                    vec![RequiredElement::RightParen]
+                } else {
+                    vec![RequiredElement::Comma, RequiredElement::RightParen]
+                },
+                token.lexeme.clone(),
+                token.position,
+            )),
+            None => Err(self
+                .unexpected_end_of_file(vec![RequiredElement::Comma, RequiredElement::RightParen])),
+        }
+    }
+
+    /// Parses the entries of a map literal, up to its closing brace
+    fn parse_map_entries<'a, I>(
+        &mut self,
+        tokens: &mut Peekable<I>,
+    ) -> Result<Vec<(Expr, Expr)>, ParseError>
+    where
+        I: Iterator<Item = &'a TokenWithContext>,
+    {
+        let mut entries = vec![];
+        if tokens.peek().map(|t| &t.token) != Some(&Token::RightBrace) {
+            loop {
+                let key = match self.parse_expression(tokens) {
+                    Some(Ok(key)) => key,
+                    Some(Err(error)) => return Err(error),
+                    None => {
+                        return Err(
+                            self.unexpected_end_of_file(vec![RequiredElement::Subexpression])
+                        )
+                    }
+                };
+                consume_expected_token!(self, tokens, &Token::Colon, RequiredElement::Colon)?;
+                match self.parse_expression(tokens) {
+                    Some(Ok(value)) => entries.push((key, value)),
+                    Some(Err(error)) => return Err(error),
+                    None => {
+                        return Err(
+                            self.unexpected_end_of_file(vec![RequiredElement::Subexpression])
+                        )
+                    }
+                }
+                if let Some(&Token::Comma) = tokens.peek().map(|t| &t.token) {
+                    let _ = tokens.next();
+                } else {
+                    break;
+                }
+            }
+        }
+        match tokens.next() {
+            Some(&TokenWithContext {
+                token: Token::RightBrace,
+                ..
+            }) => Ok(entries),
+            Some(token) => Err(ParseError::Missing(
+                if entries.is_empty() {
+                    vec![RequiredElement::RightBrace]
+                } else {
+                    vec![RequiredElement::Comma, RequiredElement::RightBrace]
+                },
+                token.lexeme.clone(),
+                token.position,
+            )),
+            None => Err(self
+                .unexpected_end_of_file(vec![RequiredElement::Comma, RequiredElement::RightBrace])),
+        }
+    }
+
+    /// Parses the elements of a list literal, up to its closing bracket
+    fn parse_list_elements<'a, I>(
+        &mut self,
+        tokens: &mut Peekable<I>,
+    ) -> Result<Vec<Expr>, ParseError>
+    where
+        I: Iterator<Item = &'a TokenWithContext>,
+    {
+        let mut elements = vec![];
+        if tokens.peek().map(|t| &t.token) != Some(&Token::RightBracket) {
+            loop {
+                match self.parse_expression(tokens) {
+                    Some(Ok(element)) => elements.push(element),
+                    Some(Err(error)) => return Err(error),
+                    None => {
+                        return Err(
+                            self.unexpected_end_of_file(vec![RequiredElement::Subexpression])
+                        )
+                    }
+                }
+                if let Some(&Token::Comma) = tokens.peek().map(|t| &t.token) {
+                    let _ = tokens.next();
+                } else {
+                    break;
+                }
+            }
+        }
+        match tokens.next() {
+            Some(&TokenWithContext {
+                token: Token::RightBracket,
+                ..
+            }) => Ok(elements),
+            Some(token) => Err(ParseError::Missing(
+                if elements.is_empty() {
+                    vec![RequiredElement::RightBracket]
+                } else {
+                    vec![RequiredElement::Comma, RequiredElement::RightBracket]
+                },
+                token.lexeme.clone(),
+                token.position,
+            )),
+            None => Err(self.unexpected_end_of_file(vec![
+                RequiredElement::Comma,
+                RequiredElement::RightBracket,
+            ])),
+        }
     }
 
     fn finish_call<'a, I>(
@@ -994,6 +1592,69 @@ impl Parser {
         Some(Ok(Expr::Call(Box::new(Call { callee, arguments }))))
     }
 
+    /// Turns `"a${b}c"` into `"a" + str(b) + "c"`, the first fragment of
+    /// the string being already consumed
+    fn parse_interpolation<'a, I>(
+        &mut self,
+        tokens: &mut Peekable<I>,
+        first: &str,
+    ) -> Result<Expr, ParseError>
+    where
+        I: Iterator<Item = &'a TokenWithContext>,
+    {
+        let literal = |s: &str| Expr::Literal(Literal::String(s.into()));
+        let mut parts = vec![];
+        if !first.is_empty() {
+            parts.push(literal(first));
+        }
+        loop {
+            let expression = match self.parse_expression(tokens) {
+                Some(result) => result?,
+                None => {
+                    return Err(self.unexpected_end_of_file(vec![RequiredElement::Subexpression]))
+                }
+            };
+            parts.push(Expr::Call(Box::new(Call {
+                callee: Expr::Identifier(
+                    self.variable_use_handle_factory.next(),
+                    self.identifier_map.for_name("str"),
+                ),
+                arguments: vec![expression],
+            })));
+            let token = match tokens.next() {
+                Some(token) => token,
+                None => return Err(self.unexpected_end_of_file(vec![RequiredElement::RightBrace])),
+            };
+            let (rest, finished) = match token.token {
+                Token::StringFragment(ref s) => (s, false),
+                Token::StringLiteral(ref s) => (s, true),
+                _ => {
+                    return Err(ParseError::Missing(
+                        vec![RequiredElement::RightBrace],
+                        token.lexeme.clone(),
+                        token.position,
+                    ))
+                }
+            };
+            if !rest.is_empty() {
+                parts.push(literal(rest));
+            }
+            if finished {
+                break;
+            }
+        }
+        Ok(parts
+            .into_iter()
+            .reduce(|left, right| {
+                Expr::Binary(Box::new(BinaryExpr {
+                    left,
+                    operator: BinaryOperator::Plus,
+                    right,
+                }))
+            })
+            .expect("An interpolation has at least an expression"))
+    }
+
     fn parse_primary<'a, I>(&mut self, tokens: &mut Peekable<I>) -> Option<Result<Expr, ParseError>>
     where
         I: Iterator<Item = &'a TokenWithContext>,
@@ -1004,16 +1665,18 @@ impl Parser {
         };
         if let Some(primary_token) = primary_token {
             let parsed_expression = match primary_token.token {
-                Token::False => Expr::Literal(Literal::BoolLiteral(false)),
-                Token::True => Expr::Literal(Literal::BoolLiteral(true)),
-                Token::Nil => Expr::Literal(Literal::NilLiteral),
-                Token::NumberLiteral(n) => Expr::Literal(Literal::NumberLiteral(n)),
-                Token::StringLiteral(ref s) => Expr::Literal(Literal::StringLiteral(s.clone())),
+                Token::False => Expr::Literal(Literal::Bool(false)),
+                Token::True => Expr::Literal(Literal::Bool(true)),
+                Token::Nil => Expr::Literal(Literal::Nil),
+                Token::NumberLiteral(n) => Expr::Literal(Literal::Number(n)),
+                Token::StringLiteral(ref s) => Expr::Literal(Literal::String(s.as_str().into())),
+                Token::StringFragment(ref s) => try_wrap_err!(self.parse_interpolation(tokens, s)),
                 Token::This => {
                     Expr::This(self.variable_use_handle_factory.next(), Identifier::this())
                 }
                 Token::Super => {
                     try_wrap_err!(consume_expected_token!(
+                        self,
                         tokens,
                         &Token::Dot,
                         RequiredElement::Dot
@@ -1029,11 +1692,26 @@ impl Parser {
                     self.variable_use_handle_factory.next(),
                     self.identifier_map.for_name(i),
                 ),
+                Token::LeftBracket => Expr::List(try_wrap_err!(self.parse_list_elements(tokens))),
+                // Blocks are parsed as statements before getting here
+                Token::LeftBrace => Expr::Map(try_wrap_err!(self.parse_map_entries(tokens))),
+                // So are function declarations
+                Token::Fun => {
+                    let (arguments, body) = try_wrap_err!(self.parse_function_rest(tokens));
+                    Expr::Lambda(Rc::new(FunctionDefinition {
+                        kind: FunctionKind::Lambda,
+                        name: Identifier::lambda(),
+                        arguments,
+                        body,
+                    }))
+                }
                 Token::LeftParen => {
                     let expr = if let Some(result) = self.parse_expression(tokens) {
                         try_wrap_err!(result)
                     } else {
-                        return Some(Err(ParseError::UnexpectedEndOfFile));
+                        return Some(Err(
+                            self.unexpected_end_of_file(vec![RequiredElement::Subexpression])
+                        ));
                     };
                     {
                         if let Some(token) = tokens.next() {
@@ -1042,17 +1720,20 @@ impl Parser {
                                 return Some(Ok(Expr::Grouping(Box::new(grouping_expression))));
                             } else {
                                 return Some(Err(ParseError::Missing(
-                                    RequiredElement::RightParen,
+                                    vec![RequiredElement::RightParen],
                                     token.lexeme.clone(),
                                     token.position,
                                 )));
                             }
                         }
-                        return Some(Err(ParseError::UnexpectedEndOfFile));
+                        return Some(Err(
+                            self.unexpected_end_of_file(vec![RequiredElement::RightParen])
+                        ));
                     }
                 }
                 _ => {
-                    return Some(Err(ParseError::UnexpectedToken(
+                    return Some(Err(ParseError::Missing(
+                        vec![RequiredElement::Subexpression],
                         primary_token.lexeme.clone(),
                         primary_token.position,
                     )));
@@ -1093,6 +1774,20 @@ mod tests {
         assert_eq!("(+ 123 456)", &expr.pretty_print(&parser.identifier_map));
     }
 
+    #[test]
+    fn precedence_of_modulo_and_integer_division() {
+        let (tokens, _) = scan(&"1+2%3~/4");
+        let mut parser = Parser::default();
+        let expr = parser
+            .parse_expression(&mut tokens.iter().peekable())
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            "(+ 1 (~/ (% 2 3) 4))",
+            &expr.pretty_print(&parser.identifier_map)
+        );
+    }
+
     #[test]
     fn precedence_add_mul() {
         let (tokens, _) = scan(&"123+456*789");
@@ -1176,7 +1871,7 @@ mod tests {
     fn multiple_statements() {
         let (tokens, _) = scan(&"var x; {var x=10; print x;} print x;");
         let mut parser = Parser::default();
-        let statements = parser.parse(&tokens).unwrap();
+        let statements = parser.parse(&tokens).unwrap().statements;
         assert_eq!("var x;", statements[0].pretty_print(&parser.identifier_map));
         assert_eq!(
             "{ var x = 10; print x; }",
@@ -1192,7 +1887,7 @@ mod tests {
     fn expr_with_equality() {
         let (tokens, _) = scan(&"x/2 == 1;");
         let mut parser = Parser::default();
-        let statements = parser.parse(&tokens).unwrap();
+        let statements = parser.parse(&tokens).unwrap().statements;
         assert_eq!(
             "(== (/ x 2) 1);",
             statements[0].pretty_print(&parser.identifier_map)
@@ -1203,7 +1898,7 @@ mod tests {
     fn assignment() {
         let (tokens, _) = scan(&"a = 1;");
         let mut parser = Parser::default();
-        let statements = parser.parse(&tokens).unwrap();
+        let statements = parser.parse(&tokens).unwrap().statements;
         assert_eq!("a = 1;", statements[0].pretty_print(&parser.identifier_map));
     }
 
@@ -1231,11 +1926,30 @@ mod tests {
         assert!(Parser::default().parse(&tokens).is_err());
     }
 
+    #[test]
+    fn declarations_arent_allowed_as_branches() {
+        for source in &[
+            "if (a) var b = 1;",
+            "if (a) {} else fun f() {}",
+            "while (a) class C {}",
+            "for (;;) var b;",
+        ] {
+            let (tokens, _) = scan(source);
+            let errors = Parser::default().parse(&tokens).err().unwrap();
+            match errors[0] {
+                ParseError::Missing(ref expected, _, _) => {
+                    assert_eq!(&vec![RequiredElement::Statement], expected)
+                }
+                ref error => panic!("Unexpected error {:?} for {}", error, source),
+            }
+        }
+    }
+
     #[test]
     fn if_then_statement() {
         let (tokens, _) = scan(&"if(a) x = 2;");
         let mut parser = Parser::default();
-        let statements = parser.parse(&tokens).unwrap();
+        let statements = parser.parse(&tokens).unwrap().statements;
         assert_eq!(
             "if ( a ) x = 2;",
             statements[0].pretty_print(&parser.identifier_map)
@@ -1246,7 +1960,7 @@ mod tests {
     fn if_then_else_statement() {
         let (tokens, _) = scan(&"if(a and b) { x = 2;} else{x = 3;}");
         let mut parser = Parser::default();
-        let statements = parser.parse(&tokens).unwrap();
+        let statements = parser.parse(&tokens).unwrap().statements;
         assert_eq!(
             "if ( (and a b) ) { x = 2; } else { x = 3; }",
             statements[0].pretty_print(&parser.identifier_map)
@@ -1257,7 +1971,7 @@ mod tests {
     fn while_statement() {
         let (tokens, _) = scan(&"while(a > 0){ a = a - 1;}");
         let mut parser = Parser::default();
-        let statements = parser.parse(&tokens).unwrap();
+        let statements = parser.parse(&tokens).unwrap().statements;
         assert_eq!(
             "while ( (> a 0) ) { a = (- a 1); }",
             statements[0].pretty_print(&parser.identifier_map)
@@ -1268,7 +1982,7 @@ mod tests {
     fn for_statement() {
         let (tokens, _) = scan(&"for (var i = 0; i < 10; i = i + 1) print i;");
         let mut parser = Parser::default();
-        let statements = parser.parse(&tokens).unwrap();
+        let statements = parser.parse(&tokens).unwrap().statements;
         assert_eq!(
             "{ var i = 0; while ( (< i 10) ) { print i; i = (+ i 1); } }",
             statements[0].pretty_print(&parser.identifier_map)
@@ -1278,7 +1992,7 @@ mod tests {
     fn for_statement_no_initializer() {
         let (tokens, _) = scan(&"for (; i < 10; i = i + 1) print i;");
         let mut parser = Parser::default();
-        let statements = parser.parse(&tokens).unwrap();
+        let statements = parser.parse(&tokens).unwrap().statements;
         assert_eq!(
             "while ( (< i 10) ) { print i; i = (+ i 1); }",
             statements[0].pretty_print(&parser.identifier_map)
@@ -1288,7 +2002,7 @@ mod tests {
     fn for_statement_no_condition() {
         let (tokens, _) = scan(&"for (var i = 0;; i = i + 1) print i;");
         let mut parser = Parser::default();
-        let statements = parser.parse(&tokens).unwrap();
+        let statements = parser.parse(&tokens).unwrap().statements;
         assert_eq!(
             "{ var i = 0; while ( true ) { print i; i = (+ i 1); } }",
             statements[0].pretty_print(&parser.identifier_map)
@@ -1299,18 +2013,36 @@ mod tests {
     fn for_statement_no_increment() {
         let (tokens, _) = scan(&"for (var i = 0; i < 10;) print i;");
         let mut parser = Parser::default();
-        let statements = parser.parse(&tokens).unwrap();
+        let statements = parser.parse(&tokens).unwrap().statements;
         assert_eq!(
             "{ var i = 0; while ( (< i 10) ) print i; }",
             statements[0].pretty_print(&parser.identifier_map)
         );
     }
 
+    #[test]
+    fn for_in_statement() {
+        let (tokens, _) = scan(&"for (var x in xs) print x;");
+        let mut parser = Parser::default();
+        let statements = parser.parse(&tokens).unwrap().statements;
+        assert_eq!(
+            "{ var $list = xs; var $index = 0; while ( (< $index len( $list )) ) { { var x = $list[$index]; print x; } $index = (+ $index 1); } }",
+            statements[0].pretty_print(&parser.identifier_map)
+        );
+    }
+
+    #[test]
+    fn in_is_only_a_keyword_in_for() {
+        let (tokens, _) = scan(&"var in = 1; print in;");
+        let mut parser = Parser::default();
+        assert!(parser.parse(&tokens).is_ok());
+    }
+
     #[test]
     fn function_with_no_arguments() {
         let (tokens, _) = scan(&"fun add(){return 1;}");
         let mut parser = Parser::default();
-        let statements = parser.parse(&tokens).unwrap();
+        let statements = parser.parse(&tokens).unwrap().statements;
         assert_eq!(
             "fun add () { return 1; }",
             statements[0].pretty_print(&parser.identifier_map)
@@ -1321,7 +2053,7 @@ mod tests {
     fn function_with_one_argument() {
         let (tokens, _) = scan(&"fun add(x){return 2*x;}");
         let mut parser = Parser::default();
-        let statements = parser.parse(&tokens).unwrap();
+        let statements = parser.parse(&tokens).unwrap().statements;
         assert_eq!(
             "fun add (x ) { return (* 2 x); }",
             statements[0].pretty_print(&parser.identifier_map)
@@ -1332,7 +2064,7 @@ mod tests {
     fn function_with_two_arguments() {
         let (tokens, _) = scan(&"fun add(x,y){return x + y;}");
         let mut parser = Parser::default();
-        let statements = parser.parse(&tokens).unwrap();
+        let statements = parser.parse(&tokens).unwrap().statements;
         assert_eq!(
             "fun add (x y ) { return (+ x y); }",
             statements[0].pretty_print(&parser.identifier_map)
@@ -1343,7 +2075,7 @@ mod tests {
     fn class() {
         let (tokens, _) = scan(&"class A{m(){print 1;}}");
         let mut parser = Parser::default();
-        let statements = parser.parse(&tokens).unwrap();
+        let statements = parser.parse(&tokens).unwrap().statements;
         assert_eq!(
             "class A { m () { print 1; } }",
             statements[0].pretty_print(&parser.identifier_map)
@@ -1354,7 +2086,7 @@ mod tests {
     fn get() {
         let (tokens, _) = scan(&"a.b;");
         let mut parser = Parser::default();
-        let statements = parser.parse(&tokens).unwrap();
+        let statements = parser.parse(&tokens).unwrap().statements;
         assert_eq!("a.b;", statements[0].pretty_print(&parser.identifier_map));
     }
 
@@ -1362,10 +2094,203 @@ mod tests {
     fn set() {
         let (tokens, _) = scan(&"a.b = 10;");
         let mut parser = Parser::default();
-        let statements = parser.parse(&tokens).unwrap();
+        let statements = parser.parse(&tokens).unwrap().statements;
         assert_eq!(
             "a.b = 10;",
             statements[0].pretty_print(&parser.identifier_map)
         );
     }
+
+    #[test]
+    fn list() {
+        let (tokens, _) = scan(&"[]; [1, a]; a[0][1] = [2];");
+        let mut parser = Parser::default();
+        let statements = parser.parse(&tokens).unwrap().statements;
+        let printed: Vec<String> = statements
+            .iter()
+            .map(|s| s.pretty_print(&parser.identifier_map))
+            .collect();
+        assert_eq!(vec!["[ ];", "[ 1 a ];", "a[0][1] = [ 2 ];"], printed);
+    }
+
+    #[test]
+    fn unclosed_list() {
+        let (tokens, _) = scan(&"print [1, 2;");
+        let errors = Parser::default().parse(&tokens).err().unwrap();
+        assert_eq!(
+            "[line 1, column 12] Error at ';': expected one of ',', ']'",
+            errors[0].to_string()
+        );
+    }
+
+    #[test]
+    fn map() {
+        let (tokens, _) = scan(&"print {}; m = {\"a\": 1, 2: [b]}; { m[\"a\"] = {nil: 3}; }");
+        let mut parser = Parser::default();
+        let statements = parser.parse(&tokens).unwrap().statements;
+        let printed: Vec<String> = statements
+            .iter()
+            .map(|s| s.pretty_print(&parser.identifier_map))
+            .collect();
+        assert_eq!(
+            vec![
+                "print { };",
+                "m = { a: 1 2: [ b ] };",
+                "{ m[a] = { null: 3 }; }"
+            ],
+            printed
+        );
+    }
+
+    #[test]
+    fn lambda() {
+        let (tokens, _) = scan(&"f = fun (a, b) { return a; }; fun () {}();");
+        let mut parser = Parser::default();
+        let statements = parser.parse(&tokens);
+        assert!(statements.is_err(), "Declarations can't be anonymous");
+        let (tokens, _) = scan(&"f = fun (a, b) { return a; }; print fun () {}();");
+        let statements = parser.parse(&tokens).unwrap().statements;
+        let printed: Vec<String> = statements
+            .iter()
+            .map(|s| s.pretty_print(&parser.identifier_map))
+            .collect();
+        assert_eq!(
+            vec!["f = fun (a b ) { return a; };", "print fun () { }( );"],
+            printed
+        );
+    }
+
+    #[test]
+    fn try_and_throw() {
+        let (tokens, _) =
+            scan(&"try { throw 1; } catch (e) { print e; } finally { } try {} finally {}");
+        let mut parser = Parser::default();
+        let statements = parser.parse(&tokens).unwrap().statements;
+        let printed: Vec<String> = statements
+            .iter()
+            .map(|s| s.pretty_print(&parser.identifier_map))
+            .collect();
+        assert_eq!(
+            vec![
+                "try { throw 1; } catch ( e ) { print e; } finally { }",
+                "try { } finally { }",
+            ],
+            printed
+        );
+    }
+
+    #[test]
+    fn import() {
+        let (tokens, _) = scan(&"import \"a/b.lox\"; import \"c.lox\" as c; var as;");
+        let mut parser = Parser::default();
+        let statements = parser.parse(&tokens).unwrap().statements;
+        let printed: Vec<String> = statements
+            .iter()
+            .map(|s| s.pretty_print(&parser.identifier_map))
+            .collect();
+        assert_eq!(
+            vec!["import \"a/b.lox\";", "import \"c.lox\" as c;", "var as;"],
+            printed
+        );
+        let (tokens, _) = scan(&"import c;");
+        let errors = parser.parse(&tokens).err().unwrap();
+        assert_eq!(
+            "[line 1, column 8] Error at 'c': expected module path",
+            errors[0].to_string()
+        );
+    }
+
+    #[test]
+    fn interpolations_are_concatenations() {
+        let (tokens, _) = scan(&"print \"${a}, ${ {1: b}[1] }!\";");
+        let mut parser = Parser::default();
+        let statements = parser.parse(&tokens).unwrap().statements;
+        assert_eq!(
+            "print (+ (+ (+ str( a ) , ) str( { 1: b }[1] )) !);",
+            statements[0].pretty_print(&parser.identifier_map)
+        );
+        let (tokens, _) = scan(&"print \"${a b}\";");
+        let errors = parser.parse(&tokens).err().unwrap();
+        assert_eq!(
+            "[line 1, column 12] Error at 'b': expected '}'",
+            errors[0].to_string()
+        );
+    }
+
+    #[test]
+    fn try_needs_a_handler() {
+        let (tokens, _) = scan(&"try {} print 1;");
+        let errors = Parser::default().parse(&tokens).err().unwrap();
+        assert_eq!(
+            "[line 1, column 8] Error at 'print': expected one of 'catch', 'finally'",
+            errors[0].to_string()
+        );
+    }
+
+    #[test]
+    fn map_entries_need_a_colon() {
+        let (tokens, _) = scan(&"print {1, 2};");
+        let errors = Parser::default().parse(&tokens).err().unwrap();
+        assert_eq!(
+            "[line 1, column 9] Error at ',': expected ':'",
+            errors[0].to_string()
+        );
+    }
+
+    #[test]
+    fn standalone_expression() {
+        let (tokens, _) = scan(&"a + 1");
+        let mut parser = Parser::default();
+        let expr = parser.parse_standalone_expression(&tokens).unwrap();
+        assert_eq!("(+ a 1)", &expr.pretty_print(&parser.identifier_map));
+    }
+
+    #[test]
+    fn statements_are_not_standalone_expressions() {
+        let (tokens, _) = scan(&"a + 1;");
+        assert!(Parser::default()
+            .parse_standalone_expression(&tokens)
+            .is_none());
+        let (tokens, _) = scan(&"print a");
+        assert!(Parser::default()
+            .parse_standalone_expression(&tokens)
+            .is_none());
+    }
+
+    #[test]
+    fn missing_semicolon_reports_what_was_expected() {
+        let (tokens, _) = scan(&"print 1 print 2;");
+        let errors = Parser::default().parse(&tokens).err().unwrap();
+        match errors[0] {
+            ParseError::Missing(ref expected, ref lexeme, ref position) => {
+                assert_eq!(&vec![RequiredElement::Semicolon], expected);
+                assert_eq!("print", lexeme);
+                assert_eq!(1, position.line);
+            }
+            ref error => panic!("Unexpected error {:?}", error),
+        }
+    }
+
+    #[test]
+    fn end_of_file_points_at_the_last_token() {
+        let (tokens, _) = scan(&"var a = 1;\nprint a");
+        let errors = Parser::default().parse(&tokens).err().unwrap();
+        match errors[0] {
+            ParseError::UnexpectedEndOfFile(ref expected, ref position) => {
+                assert_eq!(&vec![RequiredElement::Semicolon], expected);
+                assert_eq!(2, position.line);
+            }
+            ref error => panic!("Unexpected error {:?}", error),
+        }
+    }
+
+    #[test]
+    fn unclosed_argument_list() {
+        let (tokens, _) = scan(&"f(1, 2;");
+        let errors = Parser::default().parse(&tokens).err().unwrap();
+        assert_eq!(
+            "[line 1, column 7] Error at ';': expected one of ',', ')'",
+            errors[0].to_string()
+        );
+    }
 }
@@ -1,3 +1,4 @@
+use number;
 use treewalk::ast::*;
 
 pub trait PrettyPrint {
@@ -7,6 +8,17 @@ pub trait PrettyPrint {
         self.pretty_print_into(identifier_map, &mut pretty_printed);
         pretty_printed
     }
+    /// Prints the node back as Lox source, with the parentheses needed to
+    /// parse it into the same tree
+    fn print_source_into(&self, identifier_map: &IdentifierMap, source: &mut String) {
+        self.pretty_print_into(identifier_map, source)
+    }
+    #[cfg(test)]
+    fn print_source(&self, identifier_map: &IdentifierMap) -> String {
+        let mut source = String::new();
+        self.print_source_into(identifier_map, &mut source);
+        source
+    }
 }
 
 impl PrettyPrint for Expr {
@@ -27,7 +39,170 @@ impl PrettyPrint for Expr {
             Expr::Call(ref c) => c.pretty_print_into(identifier_map, pretty_printed),
             Expr::Get(ref g) => g.pretty_print_into(identifier_map, pretty_printed),
             Expr::Set(ref s) => s.pretty_print_into(identifier_map, pretty_printed),
+            Expr::List(ref elements) => {
+                pretty_printed.push_str("[ ");
+                for element in elements {
+                    element.pretty_print_into(identifier_map, pretty_printed);
+                    pretty_printed.push(' ');
+                }
+                pretty_printed.push(']');
+            }
+            Expr::Map(ref entries) => {
+                pretty_printed.push_str("{ ");
+                for (key, value) in entries {
+                    key.pretty_print_into(identifier_map, pretty_printed);
+                    pretty_printed.push_str(": ");
+                    value.pretty_print_into(identifier_map, pretty_printed);
+                    pretty_printed.push(' ');
+                }
+                pretty_printed.push('}');
+            }
+            Expr::Index(ref i) => i.pretty_print_into(identifier_map, pretty_printed),
+            Expr::Lambda(ref f) => Statement::FunctionDefinition(f.clone())
+                .pretty_print_into(identifier_map, pretty_printed),
+            Expr::SetIndex(ref s) => s.pretty_print_into(identifier_map, pretty_printed),
+        }
+    }
+
+    fn print_source_into(&self, identifier_map: &IdentifierMap, source: &mut String) {
+        let own_precedence = precedence(self);
+        match *self {
+            Expr::Unary(ref u) => {
+                u.operator.pretty_print_into(identifier_map, source);
+                print_operand(&u.right, own_precedence, identifier_map, source);
+            }
+            // Operators are left associative, so the right operand needs
+            // parentheses when it binds the same
+            Expr::Binary(ref b) => {
+                print_operand(&b.left, own_precedence, identifier_map, source);
+                source.push(' ');
+                b.operator.pretty_print_into(identifier_map, source);
+                source.push(' ');
+                print_operand(&b.right, own_precedence + 1, identifier_map, source);
+            }
+            Expr::Logic(ref l) => {
+                print_operand(&l.left, own_precedence, identifier_map, source);
+                source.push(' ');
+                l.operator.pretty_print_into(identifier_map, source);
+                source.push(' ');
+                print_operand(&l.right, own_precedence + 1, identifier_map, source);
+            }
+            Expr::Grouping(ref g) => {
+                source.push('(');
+                g.expr.print_source_into(identifier_map, source);
+                source.push(')');
+            }
+            Expr::Assignment(ref a) => {
+                a.lvalue.pretty_print_into(identifier_map, source);
+                source.push_str(" = ");
+                a.rvalue.print_source_into(identifier_map, source);
+            }
+            Expr::Call(ref c) => {
+                print_operand(&c.callee, own_precedence, identifier_map, source);
+                source.push('(');
+                print_list(&c.arguments, identifier_map, source);
+                source.push(')');
+            }
+            Expr::Get(ref g) => {
+                print_operand(&g.instance, own_precedence, identifier_map, source);
+                source.push('.');
+                g.property.pretty_print_into(identifier_map, source);
+            }
+            Expr::Set(ref s) => {
+                print_operand(&s.instance, CALL, identifier_map, source);
+                source.push('.');
+                s.property.pretty_print_into(identifier_map, source);
+                source.push_str(" = ");
+                s.value.print_source_into(identifier_map, source);
+            }
+            Expr::List(ref elements) => {
+                source.push('[');
+                print_list(elements, identifier_map, source);
+                source.push(']');
+            }
+            Expr::Map(ref entries) => {
+                source.push('{');
+                for (i, (key, value)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        source.push_str(", ");
+                    }
+                    key.print_source_into(identifier_map, source);
+                    source.push_str(": ");
+                    value.print_source_into(identifier_map, source);
+                }
+                source.push('}');
+            }
+            Expr::Index(ref i) => {
+                print_operand(&i.collection, own_precedence, identifier_map, source);
+                source.push('[');
+                i.index.print_source_into(identifier_map, source);
+                source.push(']');
+            }
+            Expr::SetIndex(ref s) => {
+                print_operand(&s.collection, CALL, identifier_map, source);
+                source.push('[');
+                s.index.print_source_into(identifier_map, source);
+                source.push_str("] = ");
+                s.value.print_source_into(identifier_map, source);
+            }
+            Expr::Lambda(ref f) => {
+                Statement::FunctionDefinition(f.clone()).print_source_into(identifier_map, source)
+            }
+            Expr::Literal(ref l) => l.print_source_into(identifier_map, source),
+            Expr::This(..) | Expr::Super(..) | Expr::Identifier(..) => {
+                self.pretty_print_into(identifier_map, source)
+            }
+        }
+    }
+}
+
+/// Calls, gets and indexes bind tighter than any operator
+const CALL: u8 = 8;
+
+/// How tightly the expression binds its operands, higher binds tighter
+fn precedence(expr: &Expr) -> u8 {
+    match *expr {
+        Expr::Assignment(_) | Expr::Set(_) | Expr::SetIndex(_) => 0,
+        Expr::Logic(ref l) => match l.operator {
+            LogicOperator::Or => 1,
+            LogicOperator::And => 2,
+        },
+        Expr::Binary(ref b) => match b.operator {
+            BinaryOperator::Equal | BinaryOperator::NotEqual => 3,
+            BinaryOperator::Less
+            | BinaryOperator::LessEqual
+            | BinaryOperator::Greater
+            | BinaryOperator::GreaterEqual => 4,
+            BinaryOperator::Plus | BinaryOperator::Minus => 5,
+            BinaryOperator::Star
+            | BinaryOperator::Slash
+            | BinaryOperator::Percent
+            | BinaryOperator::TildeSlash => 6,
+        },
+        Expr::Unary(_) => 7,
+        Expr::Call(_) | Expr::Get(_) | Expr::Index(_) => CALL,
+        _ => CALL + 1,
+    }
+}
+
+/// Prints the operand as source, in parentheses when it binds looser than
+/// the minimum
+fn print_operand(expr: &Expr, minimum: u8, identifier_map: &IdentifierMap, source: &mut String) {
+    if precedence(expr) < minimum {
+        source.push('(');
+        expr.print_source_into(identifier_map, source);
+        source.push(')');
+    } else {
+        expr.print_source_into(identifier_map, source);
+    }
+}
+
+fn print_list(expressions: &[Expr], identifier_map: &IdentifierMap, source: &mut String) {
+    for (i, expression) in expressions.iter().enumerate() {
+        if i > 0 {
+            source.push_str(", ");
         }
+        expression.print_source_into(identifier_map, source);
     }
 }
 
@@ -55,6 +230,8 @@ impl PrettyPrint for BinaryOperator {
             BinaryOperator::Plus => pretty_printed.push_str("+"),
             BinaryOperator::Slash => pretty_printed.push_str("/"),
             BinaryOperator::Star => pretty_printed.push_str("*"),
+            BinaryOperator::Percent => pretty_printed.push('%'),
+            BinaryOperator::TildeSlash => pretty_printed.push_str("~/"),
             BinaryOperator::Equal => pretty_printed.push_str("=="),
             BinaryOperator::NotEqual => pretty_printed.push_str("!="),
             BinaryOperator::Less => pretty_printed.push_str("<"),
@@ -85,10 +262,22 @@ impl PrettyPrint for Literal {
         pretty_printed: &mut String,
     ) -> () {
         match *self {
-            Literal::NilLiteral => pretty_printed.push_str("null"),
-            Literal::BoolLiteral(ref b) => pretty_printed.push_str(&b.to_string()),
-            Literal::StringLiteral(ref s) => pretty_printed.push_str(s),
-            Literal::NumberLiteral(n) => pretty_printed.push_str(&n.to_string()),
+            Literal::Nil => pretty_printed.push_str("null"),
+            Literal::Bool(ref b) => pretty_printed.push_str(&b.to_string()),
+            Literal::String(ref s) => pretty_printed.push_str(s),
+            Literal::Number(n) => pretty_printed.push_str(&number::format(n)),
+        }
+    }
+
+    fn print_source_into(&self, identifier_map: &IdentifierMap, source: &mut String) {
+        match *self {
+            Literal::Nil => source.push_str("nil"),
+            Literal::String(ref s) => {
+                source.push('"');
+                source.push_str(s);
+                source.push('"');
+            }
+            _ => self.pretty_print_into(identifier_map, source),
         }
     }
 }
@@ -162,98 +351,214 @@ impl PrettyPrint for BinaryExpr {
     }
 }
 
+/// How the children of a statement are printed: as syntax trees, or back
+/// as source
+#[derive(Clone, Copy, PartialEq)]
+enum Style {
+    Tree,
+    Source,
+}
+
+impl Style {
+    fn print<T: PrettyPrint>(self, node: &T, identifier_map: &IdentifierMap, printed: &mut String) {
+        match self {
+            Style::Tree => node.pretty_print_into(identifier_map, printed),
+            Style::Source => node.print_source_into(identifier_map, printed),
+        }
+    }
+}
+
 impl PrettyPrint for Statement {
     fn pretty_print_into(&self, identifier_map: &IdentifierMap, pretty_printed: &mut String) -> () {
-        match *self {
-            Statement::Print(ref e) => {
-                pretty_printed.push_str("print ");
-                e.pretty_print_into(identifier_map, pretty_printed);
-                pretty_printed.push_str(";");
-            }
-            Statement::Return(ref e) => {
-                pretty_printed.push_str("return");
-                if let Some(ref e) = *e {
-                    pretty_printed.push_str(" ");
-                    e.pretty_print_into(identifier_map, pretty_printed);
-                }
-                pretty_printed.push_str(";");
-            }
-            Statement::Expression(ref e) => {
-                e.pretty_print_into(identifier_map, pretty_printed);
-                pretty_printed.push_str(";");
-            }
-            Statement::VariableDefinition(ref identifier) => {
-                pretty_printed.push_str("var ");
-                identifier.pretty_print_into(identifier_map, pretty_printed);
-                pretty_printed.push_str(";");
-            }
-            Statement::VariableDefinitionWithInitalizer(ref identifier, ref initializer) => {
-                pretty_printed.push_str("var ");
-                identifier.pretty_print_into(identifier_map, pretty_printed);
-                pretty_printed.push_str(" = ");
-                initializer.pretty_print_into(identifier_map, pretty_printed);
-                pretty_printed.push_str(";");
+        print_statement(self, Style::Tree, identifier_map, pretty_printed)
+    }
+
+    fn print_source_into(&self, identifier_map: &IdentifierMap, source: &mut String) {
+        print_statement(self, Style::Source, identifier_map, source)
+    }
+}
+
+fn print_statement(
+    statement: &Statement,
+    style: Style,
+    identifier_map: &IdentifierMap,
+    pretty_printed: &mut String,
+) {
+    match *statement {
+        Statement::Print(ref e) => {
+            pretty_printed.push_str("print ");
+            style.print(e, identifier_map, pretty_printed);
+            pretty_printed.push_str(";");
+        }
+        Statement::Return(ref e) => {
+            pretty_printed.push_str("return");
+            if let Some(ref e) = *e {
+                pretty_printed.push_str(" ");
+                style.print(e, identifier_map, pretty_printed);
             }
-            Statement::Block(ref b) => {
-                pretty_printed.push_str("{ ");
-                for statement in &b.statements {
-                    statement.pretty_print_into(identifier_map, pretty_printed);
-                    pretty_printed.push_str(" ");
-                }
-                pretty_printed.push_str("}");
+            pretty_printed.push_str(";");
+        }
+        Statement::Expression(ref e) => {
+            style.print(e, identifier_map, pretty_printed);
+            pretty_printed.push_str(";");
+        }
+        Statement::VariableDefinition(ref identifier) => {
+            pretty_printed.push_str("var ");
+            identifier.pretty_print_into(identifier_map, pretty_printed);
+            pretty_printed.push_str(";");
+        }
+        Statement::VariableDefinitionWithInitalizer(ref identifier, ref initializer) => {
+            pretty_printed.push_str("var ");
+            identifier.pretty_print_into(identifier_map, pretty_printed);
+            pretty_printed.push_str(" = ");
+            style.print(initializer, identifier_map, pretty_printed);
+            pretty_printed.push_str(";");
+        }
+        Statement::Block(ref b) => style.print(&**b, identifier_map, pretty_printed),
+        Statement::Import(ref i) => {
+            pretty_printed.push_str("import \"");
+            pretty_printed.push_str(&i.path);
+            pretty_printed.push('"');
+            if let Some(name) = i.name {
+                pretty_printed.push_str(" as ");
+                name.pretty_print_into(identifier_map, pretty_printed);
             }
-            Statement::IfThen(ref c) => {
-                pretty_printed.push_str("if ( ");
-                c.condition
+            pretty_printed.push(';');
+        }
+        Statement::Throw(ref e) => {
+            pretty_printed.push_str("throw ");
+            style.print(e, identifier_map, pretty_printed);
+            pretty_printed.push(';');
+        }
+        Statement::Try(ref t) => {
+            pretty_printed.push_str("try ");
+            style.print(&t.body, identifier_map, pretty_printed);
+            if let Some(ref catch) = t.catch {
+                pretty_printed.push_str(" catch ( ");
+                catch
+                    .variable
                     .pretty_print_into(identifier_map, pretty_printed);
                 pretty_printed.push_str(" ) ");
-                c.then_branch
-                    .pretty_print_into(identifier_map, pretty_printed);
+                style.print(&catch.body, identifier_map, pretty_printed);
             }
-            Statement::IfThenElse(ref c) => {
-                pretty_printed.push_str("if ( ");
-                c.condition
-                    .pretty_print_into(identifier_map, pretty_printed);
+            if let Some(ref finally) = t.finally {
+                pretty_printed.push_str(" finally ");
+                style.print(finally, identifier_map, pretty_printed);
+            }
+        }
+        Statement::IfThen(ref c) => {
+            pretty_printed.push_str("if ( ");
+            style.print(&c.condition, identifier_map, pretty_printed);
+            pretty_printed.push_str(" ) ");
+            style.print(&c.then_branch, identifier_map, pretty_printed);
+        }
+        Statement::IfThenElse(ref c) => {
+            pretty_printed.push_str("if ( ");
+            style.print(&c.condition, identifier_map, pretty_printed);
+            pretty_printed.push_str(" ) ");
+            style.print(&c.then_branch, identifier_map, pretty_printed);
+            pretty_printed.push_str(" else ");
+            style.print(&c.else_branch, identifier_map, pretty_printed);
+        }
+        Statement::Break => pretty_printed.push_str("break;"),
+        Statement::Continue => pretty_printed.push_str("continue;"),
+        Statement::While(ref l) => match l.increment {
+            // Only a for loop runs the increment after a continue
+            Some(ref increment) if style == Style::Source => {
+                pretty_printed.push_str("for ( ; ");
+                style.print(&l.condition, identifier_map, pretty_printed);
+                pretty_printed.push_str("; ");
+                style.print(increment, identifier_map, pretty_printed);
                 pretty_printed.push_str(" ) ");
-                c.then_branch
-                    .pretty_print_into(identifier_map, pretty_printed);
-                pretty_printed.push_str(" else ");
-                c.else_branch
-                    .pretty_print_into(identifier_map, pretty_printed);
+                style.print(&l.body, identifier_map, pretty_printed);
             }
-            Statement::While(ref l) => {
+            ref increment => {
                 pretty_printed.push_str("while ( ");
-                l.condition
-                    .pretty_print_into(identifier_map, pretty_printed);
+                style.print(&l.condition, identifier_map, pretty_printed);
                 pretty_printed.push_str(" ) ");
-                l.body.pretty_print_into(identifier_map, pretty_printed);
+                match *increment {
+                    // The increment reads as the last statement of the body
+                    Some(ref increment) => {
+                        pretty_printed.push_str("{ ");
+                        style.print(&l.body, identifier_map, pretty_printed);
+                        pretty_printed.push(' ');
+                        style.print(increment, identifier_map, pretty_printed);
+                        pretty_printed.push_str("; }");
+                    }
+                    None => style.print(&l.body, identifier_map, pretty_printed),
+                }
             }
-            Statement::FunctionDefinition(ref f) => {
-                if let FunctionKind::Function = f.kind {
+        },
+        Statement::FunctionDefinition(ref f) => {
+            match f.kind {
+                FunctionKind::Function => {
                     pretty_printed.push_str("fun ");
+                    f.name.pretty_print_into(identifier_map, pretty_printed);
                 }
-                f.name.pretty_print_into(identifier_map, pretty_printed);
-                pretty_printed.push_str(" (");
-                for argument in &f.arguments {
-                    argument.pretty_print_into(identifier_map, pretty_printed);
-                    pretty_printed.push_str(" ");
+                FunctionKind::Lambda => pretty_printed.push_str("fun"),
+                FunctionKind::Method | FunctionKind::Initializer => {
+                    f.name.pretty_print_into(identifier_map, pretty_printed)
                 }
-                pretty_printed.push_str(") ");
-                f.body.pretty_print_into(identifier_map, pretty_printed);
             }
-            Statement::Class(ref c) => {
-                pretty_printed.push_str("class ");
-                c.name.pretty_print_into(identifier_map, pretty_printed);
-                pretty_printed.push_str(" {");
-                for method in &c.methods {
+            pretty_printed.push_str(" (");
+            for (i, argument) in f.arguments.iter().enumerate() {
+                if style == Style::Source && i > 0 {
+                    pretty_printed.push_str(", ");
+                }
+                argument.pretty_print_into(identifier_map, pretty_printed);
+                if style == Style::Tree {
                     pretty_printed.push_str(" ");
-                    Statement::FunctionDefinition(method.clone())
-                        .pretty_print_into(identifier_map, pretty_printed);
                 }
-                pretty_printed.push_str(" }");
             }
-        };
+            pretty_printed.push_str(") ");
+            style.print(&f.body, identifier_map, pretty_printed);
+        }
+        Statement::Class(ref c) => {
+            pretty_printed.push_str("class ");
+            c.name.pretty_print_into(identifier_map, pretty_printed);
+            match c.superclass {
+                Some(ref superclass) if style == Style::Source => {
+                    pretty_printed.push_str(" < ");
+                    superclass.print_source_into(identifier_map, pretty_printed);
+                }
+                _ => {}
+            }
+            pretty_printed.push_str(" {");
+            for method in &c.methods {
+                pretty_printed.push_str(" ");
+                style.print(
+                    &Statement::FunctionDefinition(method.clone()),
+                    identifier_map,
+                    pretty_printed,
+                );
+            }
+            pretty_printed.push_str(" }");
+        }
+    };
+}
+
+impl PrettyPrint for Block {
+    fn pretty_print_into(&self, identifier_map: &IdentifierMap, pretty_printed: &mut String) {
+        print_block(self, Style::Tree, identifier_map, pretty_printed)
+    }
+
+    fn print_source_into(&self, identifier_map: &IdentifierMap, source: &mut String) {
+        print_block(self, Style::Source, identifier_map, source)
+    }
+}
+
+fn print_block(
+    block: &Block,
+    style: Style,
+    identifier_map: &IdentifierMap,
+    pretty_printed: &mut String,
+) {
+    pretty_printed.push_str("{ ");
+    for statement in &block.statements {
+        style.print(statement, identifier_map, pretty_printed);
+        pretty_printed.push(' ');
     }
+    pretty_printed.push('}');
 }
 
 impl PrettyPrint for Call {
@@ -291,16 +596,51 @@ impl PrettyPrint for Set {
     }
 }
 
+impl PrettyPrint for Index {
+    fn pretty_print_into(&self, identifier_map: &IdentifierMap, pretty_printed: &mut String) {
+        self.collection
+            .pretty_print_into(identifier_map, pretty_printed);
+        pretty_printed.push('[');
+        self.index.pretty_print_into(identifier_map, pretty_printed);
+        pretty_printed.push(']');
+    }
+}
+
+impl PrettyPrint for SetIndex {
+    fn pretty_print_into(&self, identifier_map: &IdentifierMap, pretty_printed: &mut String) {
+        self.collection
+            .pretty_print_into(identifier_map, pretty_printed);
+        pretty_printed.push('[');
+        self.index.pretty_print_into(identifier_map, pretty_printed);
+        pretty_printed.push_str("] = ");
+        self.value.pretty_print_into(identifier_map, pretty_printed);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use treewalk::ast::*;
+    use treewalk::parser::Parser;
     use treewalk::pretty_printer::PrettyPrint;
+    use treewalk::scanner::{scan, Position};
+
+    fn parsed_and_printed_back(source: &str) -> String {
+        let (tokens, _) = scan(source);
+        let mut parser = Parser::default();
+        let script = parser.parse(&tokens).unwrap();
+        let statements: Vec<String> = script
+            .statements
+            .iter()
+            .map(|s| s.print_source(&parser.identifier_map))
+            .collect();
+        statements.join(" ")
+    }
 
     #[test]
     fn literal() {
         let identifier_map = IdentifierMap::new();
         let string = String::from("abc");
-        let expr = Expr::Literal(Literal::StringLiteral(string.clone()));
+        let expr = Expr::Literal(Literal::String(string.as_str().into()));
         assert_eq!(string, expr.pretty_print(&identifier_map));
     }
 
@@ -309,10 +649,10 @@ mod tests {
         let identifier_map = IdentifierMap::new();
         let subexpr1 = UnaryExpr {
             operator: UnaryOperator::Minus,
-            right: Expr::Literal(Literal::NumberLiteral(123f64)),
+            right: Expr::Literal(Literal::Number(123f64)),
         };
         let subexpr2 = Grouping {
-            expr: Expr::Literal(Literal::NumberLiteral(45.67f64)),
+            expr: Expr::Literal(Literal::Number(45.67f64)),
         };
         let binary_expr = BinaryExpr {
             left: Expr::Unary(Box::new(subexpr1)),
@@ -334,16 +674,52 @@ mod tests {
         let statements = vec![
             Statement::VariableDefinitionWithInitalizer(
                 identifier.clone(),
-                Expr::Literal(Literal::BoolLiteral(true)),
+                Expr::Literal(Literal::Bool(true)),
             ),
             Statement::Print(Expr::Identifier(handle_factory.next(), identifier.clone())),
         ];
         let block = Statement::Block(Box::new(Block {
             statements: statements,
+            positions: vec![Position::initial(), Position::initial()],
         }));
         assert_eq!(
             "{ var x = true; print x; }",
             &block.pretty_print(&identifier_map)
         );
     }
+
+    #[test]
+    fn source_keeps_the_structure() {
+        let identifier_map = IdentifierMap::new();
+        let expression = Expr::Binary(Box::new(BinaryExpr {
+            left: Expr::Literal(Literal::Number(1.0)),
+            operator: BinaryOperator::Minus,
+            right: Expr::Binary(Box::new(BinaryExpr {
+                left: Expr::Literal(Literal::Number(2.0)),
+                operator: BinaryOperator::Minus,
+                right: Expr::Unary(Box::new(UnaryExpr {
+                    operator: UnaryOperator::Minus,
+                    right: Expr::Literal(Literal::Number(3.0)),
+                })),
+            })),
+        }));
+        assert_eq!("1 - (2 - -3)", &expression.print_source(&identifier_map));
+    }
+
+    #[test]
+    fn source_parses_back() {
+        for source in &[
+            "var a = nil;",
+            "a = [1, \"b\"][0] + {1: 2}[1] * -(3 - 4) or !true and a;",
+            "a.b(1, c)[2] = a.c = 3;",
+            "if ( a ) { print a; } else print b;",
+            "while ( a < 3 ) a = a + 1;",
+            "for ( ; a < 3; a = a + 1 ) print a;",
+            "fun f (a, b) { return fun (c) { return a; }; }",
+            "class B < A { init (x) { this.x = super.x; } }",
+            "try { throw 1; } catch ( e ) { print e; } finally { }",
+        ] {
+            assert_eq!(*source, &parsed_and_printed_back(source));
+        }
+    }
 }
@@ -0,0 +1,981 @@
+//! What both tree-walking backends run programs with: the values, the
+//! errors and the environment holding the variables.
+//!
+//! The backends only differ in how they run code, so a function holds
+//! a body built by the backend that defined it.
+
+use fnv::FnvHashMap;
+use map::{Key, OrderedMap};
+use natives::{Capabilities, Native, NativeError, NativeValue, FILE_SYSTEM, NATIVES, OS};
+use number;
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+use treewalk::ast::{BinaryOperator, FunctionKind, Identifier, IdentifierMap};
+use treewalk::lexical_scope_resolver::{Depth, VariableLocation};
+
+/// Why statements stopped running before reaching their end
+#[derive(Debug, PartialEq)]
+pub enum Interruption {
+    Return(Value),
+    Break,
+    Continue,
+}
+
+/// Runs the code of a function in the environment holding its
+/// arguments, and gives what interrupted it, if anything did
+pub type Body = Box<dyn Fn(&mut Environment) -> Result<Option<Interruption>, RuntimeError>>;
+
+type Globals = FnvHashMap<Identifier, Value>;
+
+#[derive(Clone)]
+pub enum Callable {
+    /// A function and the environment it closes over
+    Function(Rc<Function>, Environment),
+    /// A method and the instance it was accessed on. Binding is just
+    /// a copy of the pointers, this gets its slot when the method is called.
+    BoundMethod(Rc<Function>, Environment, Instance),
+    Class(Rc<Class>),
+    // Native functions
+    Native(Native),
+    /// The list of the command line arguments, exported by `os`
+    Arguments(Rc<Vec<String>>),
+    // Natives working on the list or map given as first argument
+    Length,
+    Push,
+    Pop,
+    Insert,
+    Remove,
+    Slice,
+    Keys,
+    Values,
+    Has,
+}
+
+pub struct Function {
+    kind: FunctionKind,
+    arity: usize,
+    body: Body,
+}
+
+pub struct Class {
+    superclass: Option<Rc<Class>>,
+    methods: FnvHashMap<Identifier, Callable>,
+}
+
+struct InstanceImpl {
+    class: Rc<Class>,
+    fields: FnvHashMap<Identifier, Value>,
+}
+
+// This needs to be Rc a "by reference" semantics
+#[derive(Clone)]
+pub struct Instance(Rc<RefCell<InstanceImpl>>);
+
+/// Lists are shared by the values referring to them, like instances.
+/// Equality compares their elements.
+#[derive(Debug, Clone)]
+pub struct List(Rc<RefCell<Vec<Value>>>);
+
+/// Maps are shared like lists, and compare their entries in any order
+#[derive(Debug, Clone)]
+pub struct Map(Rc<RefCell<OrderedMap<Value>>>);
+
+/// What `import "path" as name;` defines: the names a module exports
+#[derive(Debug, Clone)]
+pub struct Module(Rc<ModuleImpl>);
+
+#[derive(Debug)]
+struct ModuleImpl {
+    name: String,
+    exports: FnvHashMap<Identifier, Value>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Value {
+    Nil,
+    Boolean(bool),
+    Number(f64),
+    /// Immutable, so copies can share it
+    String(Rc<str>),
+    Callable(Callable),
+    Instance(Instance),
+    List(List),
+    Map(Map),
+    Module(Module),
+}
+
+// The fields are only read when the error is reported, through Debug
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum RuntimeError {
+    UnaryMinusTypeMismatch(Value),
+    BinaryOperatorTypeMismatch(BinaryOperator, Value, Value),
+    UndefinedIdentifier(Identifier),
+    UndefinedProperty(Identifier),
+    NotCallable(Value),
+    NotAnInstance(Value),
+    NotACollection(Value),
+    InvalidIndex(Value),
+    UnhashableKey(Value),
+    UndefinedKey(Value),
+    NotAClass(Value),
+    /// The number of arguments expected and the one given
+    WrongNumberOfArguments(usize, usize),
+    InvalidArgument(Value),
+    /// A native failed to access the system
+    Io(String),
+    /// A value thrown by the program
+    Thrown(Value),
+    /// The program called `exit` with the code. Nothing catches it, and
+    /// nothing runs after it, not even `finally` blocks.
+    Exit(i32),
+    /// The line of the statement that raised the error
+    AtLine(usize, Box<RuntimeError>),
+}
+
+impl RuntimeError {
+    /// The error without its line
+    fn without_line(self) -> RuntimeError {
+        match self {
+            RuntimeError::AtLine(_, error) => *error,
+            error => error,
+        }
+    }
+
+    /// Adds the line of the statement the error comes from, unless it
+    /// already has the one of a statement nested in it or isn't reported
+    pub fn at_line(self, line: usize) -> RuntimeError {
+        match self {
+            RuntimeError::AtLine(..) | RuntimeError::Exit(_) => self,
+            error => RuntimeError::AtLine(line, Box::new(error)),
+        }
+    }
+
+    /// The wording of the reference implementation where it has one.
+    /// Identifiers are named when the map of their names is given.
+    fn message(&self, identifier_map: Option<&IdentifierMap>) -> String {
+        let name = |identifier| {
+            identifier_map
+                .and_then(|map| map.lookup(identifier))
+                .map_or_else(String::new, |name| format!(" '{}'", name))
+        };
+        match *self {
+            RuntimeError::UnaryMinusTypeMismatch(_) => "Operand must be a number.".into(),
+            RuntimeError::BinaryOperatorTypeMismatch(BinaryOperator::Plus, _, _) => {
+                "Operands must be two numbers or two strings.".into()
+            }
+            RuntimeError::BinaryOperatorTypeMismatch(..) => "Operands must be numbers.".into(),
+            RuntimeError::UndefinedIdentifier(identifier) => {
+                format!("Undefined variable{}.", name(identifier))
+            }
+            RuntimeError::UndefinedProperty(identifier) => {
+                format!("Undefined property{}.", name(identifier))
+            }
+            RuntimeError::NotCallable(_) => "Can only call functions and classes.".into(),
+            RuntimeError::NotAnInstance(_) => "Only instances have properties.".into(),
+            RuntimeError::NotACollection(ref value) => {
+                format!("{} is not a list or a map", value)
+            }
+            RuntimeError::InvalidIndex(ref value) => format!("Invalid index {}", value),
+            RuntimeError::UnhashableKey(ref value) => format!("{} can't be a key", value),
+            RuntimeError::UndefinedKey(ref value) => format!("Undefined key {}", value),
+            RuntimeError::NotAClass(_) => "Superclass must be a class.".into(),
+            RuntimeError::WrongNumberOfArguments(expected, given) => {
+                format!("Expected {} arguments but got {}.", expected, given)
+            }
+            RuntimeError::InvalidArgument(ref value) => format!("Invalid argument {}", value),
+            RuntimeError::Io(ref message) => message.clone(),
+            RuntimeError::Thrown(ref value) => value.to_string(),
+            RuntimeError::Exit(code) => format!("Exit with code {}", code),
+            RuntimeError::AtLine(_, ref error) => error.message(identifier_map),
+        }
+    }
+
+    /// What is shown for an error the program didn't catch: its message,
+    /// then the line it comes from if it's known
+    pub fn report(&self, identifier_map: &IdentifierMap) -> Vec<String> {
+        let mut lines = vec![self.message(Some(identifier_map))];
+        if let RuntimeError::AtLine(line, _) = *self {
+            lines.push(format!("[line {}]", line));
+        }
+        lines
+    }
+
+    /// What a catch handler gets: thrown values are given as they are,
+    /// the other errors as a map with their message and line
+    pub fn caught_value(self) -> Value {
+        let line = match self {
+            RuntimeError::AtLine(line, _) => Some(line),
+            _ => None,
+        };
+        match self.without_line() {
+            RuntimeError::Thrown(value) => value,
+            error => {
+                let mut map = OrderedMap::new();
+                map.insert(
+                    Key::String("message".into()),
+                    Value::String(error.message(None).into()),
+                );
+                map.insert(
+                    Key::String("line".into()),
+                    line.map_or(Value::Nil, |line| Value::Number(line as f64)),
+                );
+                Value::Map(Map::new(map))
+            }
+        }
+    }
+}
+
+impl Callable {
+    pub fn bind(&self, instance: &Instance) -> Callable {
+        match *self {
+            Callable::Function(ref function, ref closure) => {
+                Callable::BoundMethod(function.clone(), closure.clone(), instance.clone())
+            }
+            _ => panic!("Only functions can be bound"),
+        }
+    }
+
+    fn call_collection_native(&self, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+        let arity = match *self {
+            Callable::Length | Callable::Pop | Callable::Keys | Callable::Values => 1,
+            Callable::Push | Callable::Remove | Callable::Has => 2,
+            _ => 3,
+        };
+        if arguments.len() != arity {
+            return Err(RuntimeError::WrongNumberOfArguments(arity, arguments.len()));
+        }
+        if let (Callable::Length, Value::String(s)) = (self, &arguments[0]) {
+            return Ok(Value::Number(s.chars().count() as f64));
+        }
+        match arguments[0] {
+            Value::List(ref list) => list.call_native(self, &arguments),
+            Value::Map(ref map) => map.call_native(self, &arguments),
+            ref value => Err(RuntimeError::NotACollection(value.clone())),
+        }
+    }
+
+    pub fn call(&self, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+        match *self {
+            Callable::Length
+            | Callable::Push
+            | Callable::Pop
+            | Callable::Insert
+            | Callable::Remove
+            | Callable::Slice
+            | Callable::Keys
+            | Callable::Values
+            | Callable::Has => self.call_collection_native(arguments),
+            Callable::Function(ref function, ref closure) => {
+                function.call(closure, None, arguments)
+            }
+            Callable::BoundMethod(ref function, ref closure, ref instance) => {
+                function.call(closure, Some(instance), arguments)
+            }
+            Callable::Class(ref class) => {
+                let instance = Instance::new(class);
+                match class.find_method(Identifier::init()) {
+                    Some(initializer) => {
+                        initializer.bind(&instance).call(arguments)?;
+                    }
+                    None if !arguments.is_empty() => {
+                        return Err(RuntimeError::WrongNumberOfArguments(0, arguments.len()))
+                    }
+                    None => {}
+                }
+                Ok(Value::Instance(instance))
+            }
+            Callable::Native(native) => native.call(&arguments).map_err(|error| match error {
+                NativeError::WrongNumberOfArguments(arity) => {
+                    RuntimeError::WrongNumberOfArguments(arity, arguments.len())
+                }
+                NativeError::InvalidArgument(i) => {
+                    RuntimeError::InvalidArgument(arguments[i].clone())
+                }
+                NativeError::Io(message) => RuntimeError::Io(message),
+                NativeError::Exit(code) => RuntimeError::Exit(code),
+            }),
+            Callable::Arguments(ref script_arguments) => {
+                if !arguments.is_empty() {
+                    return Err(RuntimeError::WrongNumberOfArguments(0, arguments.len()));
+                }
+                Ok(Value::List(List::new(
+                    script_arguments
+                        .iter()
+                        .map(|argument| Value::String(argument.as_str().into()))
+                        .collect(),
+                )))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Callable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Callable::Function(ref function, _) | Callable::BoundMethod(ref function, _, _) => {
+                write!(f, "<fn {} >", function.arity)
+            }
+            Callable::Class(_) => f.write_str("<class>"),
+            Callable::Native(native) => f.write_str(native.name()),
+            Callable::Arguments(_) => f.write_str("args"),
+            Callable::Length => f.write_str("len"),
+            Callable::Push => f.write_str("push"),
+            Callable::Pop => f.write_str("pop"),
+            Callable::Insert => f.write_str("insert"),
+            Callable::Remove => f.write_str("remove"),
+            Callable::Slice => f.write_str("slice"),
+            Callable::Keys => f.write_str("keys"),
+            Callable::Values => f.write_str("values"),
+            Callable::Has => f.write_str("has"),
+        }
+    }
+}
+
+impl fmt::Debug for Callable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl Function {
+    pub fn new(kind: FunctionKind, arity: usize, body: Body) -> Function {
+        Function { kind, arity, body }
+    }
+
+    fn call(
+        &self,
+        closure: &Environment,
+        this: Option<&Instance>,
+        arguments: Vec<Value>,
+    ) -> Result<Value, RuntimeError> {
+        if arguments.len() != self.arity {
+            return Err(RuntimeError::WrongNumberOfArguments(
+                self.arity,
+                arguments.len(),
+            ));
+        }
+        // Same layout as the scope of the function in the resolver
+        let values = match this {
+            Some(instance) => {
+                let mut values = Vec::with_capacity(arguments.len() + 1);
+                values.push(Value::Instance(instance.clone()));
+                values.extend(arguments);
+                values
+            }
+            None => arguments,
+        };
+        let mut environment = Environment {
+            scope: Some(Rc::new(Scope {
+                values: RefCell::new(values),
+                parent: closure.scope.clone(),
+            })),
+            globals: closure.globals.clone(),
+        };
+        Ok(match (self.body)(&mut environment)? {
+            Some(Interruption::Return(value)) => value,
+            Some(_) => unreachable!("The resolver only allows break and continue in loops"),
+            None => match this {
+                // Special case, for when the initializer is
+                // called explicitly to "re-initialize" the object
+                Some(instance) if self.kind == FunctionKind::Initializer => {
+                    Value::Instance(instance.clone())
+                }
+                _ => Value::Nil, // For when the function didn't return
+            },
+        })
+    }
+}
+
+impl Class {
+    pub fn new(superclass: Option<Rc<Class>>, methods: FnvHashMap<Identifier, Callable>) -> Class {
+        Class {
+            superclass,
+            methods,
+        }
+    }
+
+    /// Looks the method up in the class, then in its superclasses
+    pub fn find_method(&self, name: Identifier) -> Option<&Callable> {
+        self.methods.get(&name).or_else(|| {
+            self.superclass
+                .as_ref()
+                .and_then(|superclass| superclass.find_method(name))
+        })
+    }
+}
+
+impl Instance {
+    fn new(class: &Rc<Class>) -> Instance {
+        Instance(Rc::new(RefCell::new(InstanceImpl {
+            class: class.clone(),
+            fields: FnvHashMap::default(),
+        })))
+    }
+
+    pub fn get(&self, property: Identifier) -> Result<Value, RuntimeError> {
+        let instance = self.0.borrow();
+        if let Some(value) = instance.fields.get(&property) {
+            return Ok(value.clone());
+        }
+        match instance.class.find_method(property) {
+            Some(method) => Ok(Value::Callable(method.bind(self))),
+            None => Err(RuntimeError::UndefinedProperty(property)),
+        }
+    }
+
+    pub fn set(&self, property: Identifier, value: Value) {
+        self.0.borrow_mut().fields.insert(property, value);
+    }
+}
+
+impl fmt::Debug for Instance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Instance")
+    }
+}
+
+/// The position in a list of the given length the value refers to
+fn list_index(value: &Value, length: usize) -> Result<usize, RuntimeError> {
+    match *value {
+        Value::Number(n) if n >= 0.0 && n.fract() == 0.0 && n < length as f64 => Ok(n as usize),
+        _ => Err(RuntimeError::InvalidIndex(value.clone())),
+    }
+}
+
+impl List {
+    pub fn new(elements: Vec<Value>) -> List {
+        List(Rc::new(RefCell::new(elements)))
+    }
+
+    pub fn get(&self, index: &Value) -> Result<Value, RuntimeError> {
+        let elements = self.0.borrow();
+        let index = list_index(index, elements.len())?;
+        Ok(elements[index].clone())
+    }
+
+    pub fn set(&self, index: &Value, value: Value) -> Result<(), RuntimeError> {
+        let mut elements = self.0.borrow_mut();
+        let index = list_index(index, elements.len())?;
+        elements[index] = value;
+        Ok(())
+    }
+
+    fn call_native(&self, native: &Callable, arguments: &[Value]) -> Result<Value, RuntimeError> {
+        let mut elements = self.0.borrow_mut();
+        // Elements can be inserted, or a slice can end, right after the last one
+        let length = elements.len();
+        match *native {
+            Callable::Length => Ok(Value::Number(length as f64)),
+            Callable::Push => {
+                elements.push(arguments[1].clone());
+                Ok(Value::Nil)
+            }
+            Callable::Pop => elements
+                .pop()
+                .ok_or_else(|| RuntimeError::InvalidArgument(arguments[0].clone())),
+            Callable::Insert => {
+                let index = list_index(&arguments[1], length + 1)?;
+                elements.insert(index, arguments[2].clone());
+                Ok(Value::Nil)
+            }
+            Callable::Remove => {
+                let index = list_index(&arguments[1], length)?;
+                Ok(elements.remove(index))
+            }
+            Callable::Slice => {
+                let start = list_index(&arguments[1], length + 1)?;
+                let end = list_index(&arguments[2], length + 1)?;
+                if start > end {
+                    return Err(RuntimeError::InvalidIndex(arguments[2].clone()));
+                }
+                Ok(Value::List(List::new(elements[start..end].to_vec())))
+            }
+            _ => Err(RuntimeError::InvalidArgument(arguments[0].clone())),
+        }
+    }
+}
+
+/// The key of a map the value is, if it can be one
+pub fn map_key(value: &Value) -> Result<Key, RuntimeError> {
+    match *value {
+        Value::Nil => Ok(Key::Nil),
+        Value::Boolean(b) => Ok(Key::Boolean(b)),
+        Value::Number(n) => Ok(Key::number(n)),
+        Value::String(ref s) => Ok(Key::String(s.clone())),
+        _ => Err(RuntimeError::UnhashableKey(value.clone())),
+    }
+}
+
+fn key_value(key: &Key) -> Value {
+    match *key {
+        Key::Nil => Value::Nil,
+        Key::Boolean(b) => Value::Boolean(b),
+        Key::Number(bits) => Value::Number(f64::from_bits(bits)),
+        Key::String(ref s) => Value::String(s.clone()),
+    }
+}
+
+impl Map {
+    pub fn new(map: OrderedMap<Value>) -> Map {
+        Map(Rc::new(RefCell::new(map)))
+    }
+
+    pub fn get(&self, key: &Value) -> Result<Value, RuntimeError> {
+        self.0
+            .borrow()
+            .get(&map_key(key)?)
+            .cloned()
+            .ok_or_else(|| RuntimeError::UndefinedKey(key.clone()))
+    }
+
+    pub fn set(&self, key: &Value, value: Value) -> Result<(), RuntimeError> {
+        let key = map_key(key)?;
+        self.0.borrow_mut().insert(key, value);
+        Ok(())
+    }
+
+    fn call_native(&self, native: &Callable, arguments: &[Value]) -> Result<Value, RuntimeError> {
+        let mut map = self.0.borrow_mut();
+        match *native {
+            Callable::Length => Ok(Value::Number(map.len() as f64)),
+            Callable::Keys => Ok(Value::List(List::new(
+                map.iter().map(|(key, _)| key_value(key)).collect(),
+            ))),
+            Callable::Values => Ok(Value::List(List::new(
+                map.iter().map(|(_, value)| value.clone()).collect(),
+            ))),
+            Callable::Has => Ok(Value::Boolean(map.contains_key(&map_key(&arguments[1])?))),
+            Callable::Remove => map
+                .remove(&map_key(&arguments[1])?)
+                .ok_or_else(|| RuntimeError::UndefinedKey(arguments[1].clone())),
+            _ => Err(RuntimeError::InvalidArgument(arguments[0].clone())),
+        }
+    }
+}
+
+impl Module {
+    pub fn new(name: String, exports: FnvHashMap<Identifier, Value>) -> Module {
+        Module(Rc::new(ModuleImpl { name, exports }))
+    }
+
+    pub fn exports(&self) -> &FnvHashMap<Identifier, Value> {
+        &self.0.exports
+    }
+
+    pub fn get(&self, name: Identifier) -> Result<Value, RuntimeError> {
+        self.0
+            .exports
+            .get(&name)
+            .cloned()
+            .ok_or(RuntimeError::UndefinedProperty(name))
+    }
+}
+
+impl Value {
+    pub fn is_true(&self) -> bool {
+        match *self {
+            Value::Nil => false,
+            Value::Boolean(b) => b,
+            _ => true,
+        }
+    }
+}
+
+/// The contents of the lists and maps being printed or compared, as those
+/// containing themselves would be visited forever
+type Visiting = Vec<*const ()>;
+
+impl Value {
+    /// Where the elements of a list or the entries of a map are
+    fn contents(&self) -> Option<*const ()> {
+        match *self {
+            Value::List(ref list) => Some(Rc::as_ptr(&list.0) as *const ()),
+            Value::Map(ref map) => Some(Rc::as_ptr(&map.0) as *const ()),
+            _ => None,
+        }
+    }
+
+    /// A list or a map already being printed is shown as `[...]` or `{...}`
+    fn write(&self, f: &mut fmt::Formatter, visiting: &mut Visiting) -> fmt::Result {
+        if let Some(contents) = self.contents() {
+            if visiting.contains(&contents) {
+                return f.write_str(match *self {
+                    Value::List(_) => "[...]",
+                    _ => "{...}",
+                });
+            }
+            visiting.push(contents);
+        }
+        let result = self.write_contents(f, visiting);
+        if self.contents().is_some() {
+            visiting.pop();
+        }
+        result
+    }
+
+    fn write_contents(&self, f: &mut fmt::Formatter, visiting: &mut Visiting) -> fmt::Result {
+        match *self {
+            Value::Nil => f.write_str("nil"),
+            Value::Boolean(b) => write!(f, "{}", b),
+            Value::Number(n) => f.write_str(&number::format(n)),
+            Value::String(ref s) => f.write_str(s),
+            Value::Callable(ref c) => write!(f, "{}", c),
+            Value::Instance(_) => f.write_str("Instance"),
+            Value::List(ref list) => {
+                f.write_str("[")?;
+                for (i, element) in list.0.borrow().iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    element.write(f, visiting)?;
+                }
+                f.write_str("]")
+            }
+            Value::Map(ref map) => {
+                f.write_str("{")?;
+                for (i, (key, value)) in map.0.borrow().iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{}: ", key)?;
+                    value.write(f, visiting)?;
+                }
+                f.write_str("}")
+            }
+            Value::Module(ref module) => write!(f, "<module {}>", module.0.name),
+        }
+    }
+
+    /// Two lists or maps already being compared are taken as equal, the
+    /// other elements deciding whether they are
+    fn equals(&self, other: &Value, comparing: &mut Vec<(*const (), *const ())>) -> bool {
+        match (self, other) {
+            (Value::List(l), Value::List(r)) => {
+                if Rc::ptr_eq(&l.0, &r.0) {
+                    return true;
+                }
+                let pair = (Rc::as_ptr(&l.0) as *const (), Rc::as_ptr(&r.0) as *const ());
+                if comparing.contains(&pair) {
+                    return true;
+                }
+                comparing.push(pair);
+                let (l, r) = (l.0.borrow(), r.0.borrow());
+                let equal = l.len() == r.len()
+                    && l.iter().zip(r.iter()).all(|(l, r)| l.equals(r, comparing));
+                comparing.pop();
+                equal
+            }
+            (Value::Map(l), Value::Map(r)) => {
+                if Rc::ptr_eq(&l.0, &r.0) {
+                    return true;
+                }
+                let pair = (Rc::as_ptr(&l.0) as *const (), Rc::as_ptr(&r.0) as *const ());
+                if comparing.contains(&pair) {
+                    return true;
+                }
+                comparing.push(pair);
+                let (l, r) = (l.0.borrow(), r.0.borrow());
+                let equal = l.len() == r.len()
+                    && l.iter().all(|(key, value)| {
+                        r.get(key)
+                            .is_some_and(|other| value.equals(other, comparing))
+                    });
+                comparing.pop();
+                equal
+            }
+            _ => self == other,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(f, &mut vec![])
+    }
+}
+
+impl NativeValue for Value {
+    fn nil() -> Value {
+        Value::Nil
+    }
+
+    fn number(n: f64) -> Value {
+        Value::Number(n)
+    }
+
+    fn string(s: Rc<str>) -> Value {
+        Value::String(s)
+    }
+
+    fn list(elements: Vec<Value>) -> Value {
+        Value::List(List::new(elements))
+    }
+
+    fn as_number(&self) -> Option<f64> {
+        match *self {
+            Value::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    fn as_string(&self) -> Option<&str> {
+        match *self {
+            Value::String(ref s) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn describe(&self) -> String {
+        self.to_string()
+    }
+
+    fn type_name(&self) -> &'static str {
+        match *self {
+            Value::Nil => "nil",
+            Value::Boolean(_) => "boolean",
+            Value::Number(_) => "number",
+            Value::String(_) => "string",
+            Value::Callable(Callable::Class(_)) => "class",
+            Value::Callable(_) => "function",
+            Value::Instance(_) => "instance",
+            Value::List(_) => "list",
+            Value::Map(_) => "map",
+            Value::Module(_) => "module",
+        }
+    }
+}
+
+/// Instances, classes, functions and modules are only equal to themselves,
+/// lists and maps are equal when their contents are
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Nil, Value::Nil) => true,
+            (Value::Boolean(l), Value::Boolean(r)) => l == r,
+            (Value::Number(l), Value::Number(r)) => l == r,
+            (Value::String(l), Value::String(r)) => l == r,
+            (Value::Instance(l), Value::Instance(r)) => Rc::ptr_eq(&l.0, &r.0),
+            (Value::Module(l), Value::Module(r)) => Rc::ptr_eq(&l.0, &r.0),
+            (Value::List(_), Value::List(_)) | (Value::Map(_), Value::Map(_)) => {
+                self.equals(other, &mut vec![])
+            }
+            (Value::Callable(l), Value::Callable(r)) => match (l, r) {
+                (Callable::Function(l, _), Callable::Function(r, _)) => Rc::ptr_eq(l, r),
+                (Callable::BoundMethod(l, _, l_this), Callable::BoundMethod(r, _, r_this)) => {
+                    Rc::ptr_eq(l, r) && Rc::ptr_eq(&l_this.0, &r_this.0)
+                }
+                (Callable::Class(l), Callable::Class(r)) => Rc::ptr_eq(l, r),
+                (Callable::Native(l), Callable::Native(r)) => l == r,
+                (Callable::Arguments(_), Callable::Arguments(_)) => true,
+                (Callable::Length, Callable::Length)
+                | (Callable::Push, Callable::Push)
+                | (Callable::Pop, Callable::Pop)
+                | (Callable::Insert, Callable::Insert)
+                | (Callable::Remove, Callable::Remove)
+                | (Callable::Slice, Callable::Slice)
+                | (Callable::Keys, Callable::Keys)
+                | (Callable::Values, Callable::Values)
+                | (Callable::Has, Callable::Has) => true,
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+}
+
+/// The local variables of a block or of a call, in the slots assigned by
+/// the resolver
+struct Scope {
+    values: RefCell<Vec<Value>>,
+    parent: Option<Rc<Scope>>,
+}
+
+/// Where code runs: the innermost local scope, if any, and the globals.
+/// Globals are looked up by name, as they can be defined at any time and
+/// redefined, all the other variables by position.
+///
+/// Cloning it only copies the pointers, which is how functions close
+/// over the environment they're defined in.
+#[derive(Clone)]
+pub struct Environment {
+    scope: Option<Rc<Scope>>,
+    globals: Rc<RefCell<Globals>>,
+}
+
+impl Environment {
+    /// The environment of a script or module, with globals of its own
+    pub fn new() -> Environment {
+        Environment {
+            scope: None,
+            globals: Rc::new(RefCell::new(Globals::default())),
+        }
+    }
+
+    pub fn new_with_natives(identifier_map: &mut IdentifierMap) -> Environment {
+        let environment = Environment::new();
+        for &native in NATIVES.iter() {
+            environment.define(
+                identifier_map.for_name(native.name()),
+                Value::Callable(Callable::Native(native)),
+            );
+        }
+        for native in &[
+            Callable::Length,
+            Callable::Push,
+            Callable::Pop,
+            Callable::Insert,
+            Callable::Remove,
+            Callable::Slice,
+            Callable::Keys,
+            Callable::Values,
+            Callable::Has,
+        ] {
+            environment.define(
+                identifier_map.for_name(&native.to_string()),
+                Value::Callable(native.clone()),
+            );
+        }
+        environment
+    }
+
+    /// A new local scope, nested in the one of the parent
+    pub fn new_with_parent(parent: &Environment) -> Environment {
+        Environment {
+            scope: Some(Rc::new(Scope {
+                values: RefCell::new(vec![]),
+                parent: parent.scope.clone(),
+            })),
+            globals: parent.globals.clone(),
+        }
+    }
+
+    /// Enters a new local scope, like `new_with_parent` but in place
+    pub fn push_scope(&mut self) {
+        let parent = self.scope.take();
+        self.scope = Some(Rc::new(Scope {
+            values: RefCell::new(vec![]),
+            parent,
+        }));
+    }
+
+    pub fn pop_scope(&mut self) {
+        let parent = self.scope.as_ref().and_then(|s| s.parent.clone());
+        self.scope = parent;
+    }
+
+    fn scope_at(&self, depth: Depth) -> Option<&Rc<Scope>> {
+        let mut scope = self.scope.as_ref();
+        for _ in 0..depth {
+            scope = scope.and_then(|s| s.parent.as_ref());
+        }
+        scope
+    }
+
+    /// All the global variables
+    pub fn globals(&self) -> Vec<(Identifier, Value)> {
+        self.globals
+            .borrow()
+            .iter()
+            .map(|(identifier, value)| (*identifier, value.clone()))
+            .collect()
+    }
+
+    /// Defines the variable in the next slot of the innermost scope, or by
+    /// name when there's none, and returns where it ended up
+    pub fn define(&self, identifier: Identifier, value: Value) -> VariableLocation {
+        match self.scope {
+            Some(ref scope) => {
+                let mut values = scope.values.borrow_mut();
+                values.push(value);
+                VariableLocation::Local(0, values.len() - 1)
+            }
+            None => {
+                // NOTE that this allow for variable redefinition. See the chapter.
+                self.globals.borrow_mut().insert(identifier, value);
+                VariableLocation::Global
+            }
+        }
+    }
+
+    pub fn get(&self, location: VariableLocation, identifier: Identifier) -> Option<Value> {
+        match location {
+            VariableLocation::Local(depth, slot) => self
+                .scope_at(depth)
+                .and_then(|scope| scope.values.borrow().get(slot).cloned()),
+            VariableLocation::Global => self.globals.borrow().get(&identifier).cloned(),
+        }
+    }
+
+    /// Assigns the variable, if it exists
+    pub fn set(&self, location: VariableLocation, identifier: Identifier, value: Value) -> bool {
+        match location {
+            VariableLocation::Local(depth, slot) => match self.scope_at(depth) {
+                Some(scope) => match scope.values.borrow_mut().get_mut(slot) {
+                    Some(local) => {
+                        *local = value;
+                        true
+                    }
+                    None => false,
+                },
+                None => false,
+            },
+            VariableLocation::Global => match self.globals.borrow_mut().get_mut(&identifier) {
+                Some(global) => {
+                    *global = value;
+                    true
+                }
+                None => false,
+            },
+        }
+    }
+
+    /// Defines the modules and the natives accessing the system that the
+    /// capabilities allow
+    pub fn define_system_access(
+        &self,
+        identifier_map: &mut IdentifierMap,
+        capabilities: Capabilities,
+        arguments: &[String],
+    ) {
+        let mut define_module =
+            |name: &str, natives: &[Native], extra: Option<(&str, Callable)>| {
+                let mut exports: FnvHashMap<Identifier, Value> = natives
+                    .iter()
+                    .map(|&native| {
+                        (
+                            identifier_map.for_name(native.name()),
+                            Value::Callable(Callable::Native(native)),
+                        )
+                    })
+                    .collect();
+                if let Some((extra_name, callable)) = extra {
+                    exports.insert(
+                        identifier_map.for_name(extra_name),
+                        Value::Callable(callable),
+                    );
+                }
+                let module = Module::new(name.into(), exports);
+                self.define(identifier_map.for_name(name), Value::Module(module));
+            };
+        if capabilities.file_system {
+            define_module("fs", &FILE_SYSTEM, None);
+        }
+        if capabilities.os {
+            let args = Callable::Arguments(Rc::new(arguments.to_vec()));
+            define_module("os", &OS, Some(("args", args)));
+        }
+        for &(allowed, native) in &[
+            (capabilities.input, Native::Input),
+            (capabilities.exit, Native::Exit),
+        ] {
+            if allowed {
+                self.define(
+                    identifier_map.for_name(native.name()),
+                    Value::Callable(Callable::Native(native)),
+                );
+            }
+        }
+    }
+}
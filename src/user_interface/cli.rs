@@ -0,0 +1,242 @@
+use natives::Capabilities;
+
+/// The interpreters a program can be run with
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Backend {
+    TreeWalk,
+    Vm,
+    /// The syntax tree compiled into closures
+    Closures,
+    /// The VM working with registers instead of a stack
+    Register,
+}
+
+/// Where the program comes from
+#[derive(Debug, PartialEq)]
+pub enum Input {
+    /// No program given, start the REPL
+    Prompt,
+    File(String),
+    /// The program is read from the standard input (`-`)
+    Stdin,
+    /// The program is passed on the command line (`-e`)
+    Code(String),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Options {
+    pub backend: Backend,
+    pub input: Input,
+    /// Whatever follows the script, made available to the program
+    pub arguments: Vec<String>,
+    /// All of them, unless running in the sandbox
+    pub capabilities: Capabilities,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    Run(Options),
+    Help,
+    Version,
+}
+
+pub fn usage(program_name: &str) -> String {
+    format!(
+        "Usage: {} [options] [script | -] [arguments...]
+
+Runs the script, the code given with -e or the standard input (-).
+Starts the REPL when there is nothing to run.
+
+Options:
+  -b, --backend <treewalk|vm|closures|register>  Selects the interpreter
+  -e <code>                                      Runs the code instead of a script
+  --sandbox                                      Runs without the fs and os modules,
+                                                 input and exit
+  -h, --help                                     Prints this message
+  -V, --version                                  Prints the version",
+        program_name
+    )
+}
+
+fn parse_backend(name: &str) -> Result<Backend, String> {
+    match name {
+        "treewalk" => Ok(Backend::TreeWalk),
+        "vm" => Ok(Backend::Vm),
+        "closures" => Ok(Backend::Closures),
+        "register" => Ok(Backend::Register),
+        _ => Err(format!("Unknown backend '{}'", name)),
+    }
+}
+
+/// Parses the command line arguments, the first one being the program name.
+/// Options are only accepted before the script: everything after it is
+/// passed through to the program. `--` ends the options as well.
+pub fn parse(args: &[String], default_backend: Backend) -> Result<Action, String> {
+    let mut backend = default_backend;
+    let mut capabilities = Capabilities::all();
+    let mut input = None;
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Action::Help),
+            "-V" | "--version" => return Ok(Action::Version),
+            "-b" | "--backend" => {
+                let name = args
+                    .next()
+                    .ok_or_else(|| format!("Missing value for {}", arg))?;
+                backend = parse_backend(name)?;
+            }
+            "-e" => {
+                let code = args
+                    .next()
+                    .ok_or_else(|| format!("Missing value for {}", arg))?;
+                input = Some(Input::Code(code.clone()));
+                break;
+            }
+            "--sandbox" => capabilities = Capabilities::default(),
+            "--" => break,
+            "-" => {
+                input = Some(Input::Stdin);
+                break;
+            }
+            _ if arg.starts_with("--backend=") => {
+                backend = parse_backend(&arg["--backend=".len()..])?;
+            }
+            _ if arg.starts_with('-') => return Err(format!("Unknown option '{}'", arg)),
+            _ => {
+                input = Some(Input::File(arg.clone()));
+                break;
+            }
+        }
+    }
+    let input = match input {
+        Some(input) => input,
+        // Whatever follows -- is the script
+        None => match args.next() {
+            Some(file_name) => Input::File(file_name.clone()),
+            None => Input::Prompt,
+        },
+    };
+    Ok(Action::Run(Options {
+        backend,
+        input,
+        arguments: args.cloned().collect(),
+        capabilities,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use user_interface::cli::*;
+
+    fn parse_line(line: &str) -> Result<Action, String> {
+        let args: Vec<String> = line.split_whitespace().map(|s| s.to_string()).collect();
+        parse(&args, Backend::TreeWalk)
+    }
+
+    fn run(backend: Backend, input: Input, arguments: &[&str]) -> Result<Action, String> {
+        Ok(Action::Run(Options {
+            backend,
+            input,
+            arguments: arguments.iter().map(|s| s.to_string()).collect(),
+            capabilities: Capabilities::all(),
+        }))
+    }
+
+    #[test]
+    fn no_arguments_starts_the_prompt() {
+        assert_eq!(
+            run(Backend::TreeWalk, Input::Prompt, &[]),
+            parse_line("rulox")
+        );
+    }
+
+    #[test]
+    fn script_with_arguments() {
+        assert_eq!(
+            run(
+                Backend::TreeWalk,
+                Input::File("test.lox".into()),
+                &["a", "-e", "--help"]
+            ),
+            parse_line("rulox test.lox a -e --help")
+        );
+    }
+
+    #[test]
+    fn backend_selection() {
+        assert_eq!(
+            run(Backend::Vm, Input::File("test.lox".into()), &[]),
+            parse_line("rulox --backend vm test.lox")
+        );
+        assert_eq!(
+            run(Backend::Vm, Input::Prompt, &[]),
+            parse_line("rulox --backend=vm")
+        );
+        assert_eq!(
+            run(Backend::Closures, Input::Prompt, &[]),
+            parse_line("rulox -b closures")
+        );
+        assert_eq!(
+            run(Backend::Register, Input::Prompt, &[]),
+            parse_line("rulox -b register")
+        );
+        assert_eq!(
+            Err("Unknown backend 'jit'".to_string()),
+            parse_line("rulox -b jit")
+        );
+        assert_eq!(
+            Err("Missing value for --backend".to_string()),
+            parse_line("rulox --backend")
+        );
+    }
+
+    #[test]
+    fn code_and_stdin() {
+        assert_eq!(
+            run(Backend::TreeWalk, Input::Code("1".into()), &["a"]),
+            parse_line("rulox -e 1 a")
+        );
+        assert_eq!(
+            run(Backend::TreeWalk, Input::Stdin, &["a"]),
+            parse_line("rulox - a")
+        );
+    }
+
+    #[test]
+    fn double_dash_ends_the_options() {
+        assert_eq!(
+            run(Backend::TreeWalk, Input::File("-x.lox".into()), &["b"]),
+            parse_line("rulox -- -x.lox b")
+        );
+    }
+
+    #[test]
+    fn sandbox_removes_the_capabilities() {
+        match parse_line("rulox --sandbox test.lox --sandbox") {
+            Ok(Action::Run(options)) => {
+                assert_eq!(Capabilities::default(), options.capabilities);
+                assert_eq!(vec!["--sandbox".to_string()], options.arguments);
+            }
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn help_and_version() {
+        assert_eq!(Ok(Action::Help), parse_line("rulox -b vm --help"));
+        assert_eq!(Ok(Action::Version), parse_line("rulox -V"));
+    }
+
+    #[test]
+    fn invalid_arguments() {
+        assert_eq!(
+            Err("Unknown option '-x'".to_string()),
+            parse_line("rulox -x")
+        );
+        assert_eq!(
+            Err("Missing value for -e".to_string()),
+            parse_line("rulox -e")
+        );
+    }
+}
@@ -0,0 +1,77 @@
+/// Meta-commands understood by the REPL.
+/// They all start with a colon, so they can't be confused with Lox code.
+#[derive(Debug, PartialEq)]
+pub enum Command<'a> {
+    Quit,
+    Help,
+    /// Lists the global variables and their values
+    Environment,
+    /// Shows the syntax tree of the given code
+    Ast(&'a str),
+    /// Shows the bytecode the given code compiles to
+    Disassemble(&'a str),
+    /// Runs a file in the current session
+    Load(&'a str),
+    /// Throws away the current interpreter and starts from scratch
+    Reset,
+    Unknown(&'a str),
+}
+
+pub const HELP: &str = ":quit            Exits the REPL
+:env             Lists the global variables and their values
+:ast <code>      Shows the syntax tree of the code
+:dis <code>      Shows the bytecode the code compiles to
+:load <file>     Runs a file in the current session
+:reset           Starts over with a fresh interpreter
+:help            Shows this message";
+
+/// Returns the command in the line, if any.
+pub fn parse(line: &str) -> Option<Command<'_>> {
+    let line = line.trim();
+    if !line.starts_with(':') {
+        return None;
+    }
+    let (name, argument) = match line.find(char::is_whitespace) {
+        Some(index) => (&line[..index], line[index..].trim()),
+        None => (line, ""),
+    };
+    Some(match name {
+        ":quit" => Command::Quit,
+        ":help" => Command::Help,
+        ":env" => Command::Environment,
+        ":ast" => Command::Ast(argument),
+        ":dis" => Command::Disassemble(argument),
+        ":load" => Command::Load(argument),
+        ":reset" => Command::Reset,
+        _ => Command::Unknown(name),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use user_interface::command::*;
+
+    #[test]
+    fn lox_code_is_not_a_command() {
+        assert_eq!(None, parse("print 1;"));
+    }
+
+    #[test]
+    fn commands_without_arguments() {
+        assert_eq!(Some(Command::Quit), parse(":quit"));
+        assert_eq!(Some(Command::Environment), parse("  :env "));
+        assert_eq!(Some(Command::Reset), parse(":reset"));
+    }
+
+    #[test]
+    fn commands_with_arguments() {
+        assert_eq!(Some(Command::Ast("1 + 2 * 3")), parse(":ast 1 + 2 * 3"));
+        assert_eq!(Some(Command::Disassemble("-1")), parse(":dis   -1"));
+        assert_eq!(Some(Command::Load("file.lox")), parse(":load file.lox"));
+    }
+
+    #[test]
+    fn unknown_command() {
+        assert_eq!(Some(Command::Unknown(":foo")), parse(":foo bar"));
+    }
+}
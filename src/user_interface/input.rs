@@ -0,0 +1,137 @@
+use frontend::scanner::{scan, ScannerError, Token};
+
+/// Tells whether the text typed so far in the REPL can be run
+/// or if we should wait for more lines.
+///
+/// The input is considered incomplete when it has unbalanced
+/// parentheses, brackets or braces, when a string isn't closed yet
+/// or when the last statement is missing its closing semicolon.
+/// A bare expression doesn't need the semicolon, as the REPL
+/// prints its value, but it has to end with something that can
+/// close an expression.
+/// We only look at tokens, errors are left to the actual parsers.
+pub fn is_complete(source: &str) -> bool {
+    let (tokens, errors) = scan(source);
+    if errors
+        .iter()
+        .any(|error| matches!(error, ScannerError::MissingStringTerminator(_)))
+    {
+        return false;
+    }
+    let mut open_parens = 0;
+    let mut open_brackets = 0;
+    let mut open_braces = 0;
+    for token in &tokens {
+        match token.token {
+            Token::LeftParen => open_parens += 1,
+            Token::RightParen => open_parens -= 1,
+            Token::LeftBracket => open_brackets += 1,
+            Token::RightBracket => open_brackets -= 1,
+            Token::LeftBrace => open_braces += 1,
+            Token::RightBrace => open_braces -= 1,
+            _ => {}
+        }
+    }
+    if open_parens > 0 || open_brackets > 0 || open_braces > 0 {
+        return false;
+    }
+    match tokens.last().map(|t| &t.token) {
+        None | Some(&Token::Semicolon) | Some(&Token::RightBrace) => true,
+        Some(last) => {
+            let second = tokens.get(1).map(|t| &t.token);
+            starts_expression(&tokens[0].token, second) && ends_expression(last)
+        }
+    }
+}
+
+/// Statements can't be typed without their semicolon,
+/// everything else might be a bare expression.
+/// `fun` followed by the arguments starts an anonymous function.
+fn starts_expression(token: &Token, next: Option<&Token>) -> bool {
+    match *token {
+        Token::Fun => next == Some(&Token::LeftParen),
+        Token::Var
+        | Token::Class
+        | Token::If
+        | Token::While
+        | Token::For
+        | Token::Print
+        | Token::Return
+        | Token::Break
+        | Token::Continue
+        | Token::Throw
+        | Token::Try
+        | Token::Import
+        | Token::LeftBrace => false,
+        _ => true,
+    }
+}
+
+fn ends_expression(token: &Token) -> bool {
+    matches!(
+        *token,
+        Token::Identifier(_)
+            | Token::StringLiteral(_)
+            | Token::NumberLiteral(_)
+            | Token::True
+            | Token::False
+            | Token::Nil
+            | Token::This
+            | Token::RightParen
+            | Token::RightBracket
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use user_interface::input::is_complete;
+
+    #[test]
+    fn empty_input() {
+        assert!(is_complete(""));
+        assert!(is_complete("  // Just a comment"));
+    }
+
+    #[test]
+    fn terminated_statement() {
+        assert!(is_complete("print 1;"));
+        assert!(is_complete("fun f() { return 1; }"));
+    }
+
+    #[test]
+    fn missing_semicolon() {
+        assert!(!is_complete("print 1"));
+        assert!(!is_complete("if (a)"));
+    }
+
+    #[test]
+    fn bare_expression() {
+        assert!(is_complete("1 + 2"));
+        assert!(is_complete("f(1)"));
+        assert!(is_complete("fun (a) { return a; }(1)"));
+        assert!(!is_complete("1 +"));
+        assert!(!is_complete("a ="));
+    }
+
+    #[test]
+    fn unbalanced_delimiters() {
+        assert!(!is_complete("fun f() {"));
+        assert!(!is_complete("fun f() {\n print 1;"));
+        assert!(!is_complete("print (1 +"));
+        assert!(!is_complete("var a = [1,\n 2"));
+        assert!(is_complete("[1,\n 2]"));
+        assert!(is_complete("fun f() {\n print 1;\n}"));
+    }
+
+    #[test]
+    fn unterminated_strings() {
+        assert!(!is_complete("\"multi\nline"));
+        assert!(!is_complete("\"a ${ {1: 2}[1] "));
+        assert!(is_complete("\"multi\nline\""));
+    }
+
+    #[test]
+    fn extra_closing_delimiters_are_left_to_the_parser() {
+        assert!(is_complete("print 1);"));
+    }
+}
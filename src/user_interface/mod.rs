@@ -1,71 +1,311 @@
+pub mod cli;
+mod command;
+mod input;
+
+use self::cli::{Action, Backend, Input, Options};
+use self::command::Command;
+use dirs_next::home_dir;
+use natives::Capabilities;
+use rustyline::error::ReadlineError;
+use rustyline::Editor;
+use std::fmt;
 use std::fs::File;
-use std::io;
 use std::io::prelude::*;
+use std::io::stdin;
+use std::path::{Path, PathBuf};
+use treewalk;
+use vm;
 
 #[derive(Debug)]
 pub enum RunError {
-    Error,
+    /// Describes all the problems that prevented the program from running
+    CompileError(Vec<String>),
+    /// The program started but failed while running
+    RuntimeError(Vec<String>),
     IoError(String), //TODO: improve error reporting
+    /// Not an error: the program called `exit` with the code
+    Exit(i32),
+}
+
+impl RunError {
+    /// The exit codes used by the reference implementations, taken
+    /// from sysexits.h
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            RunError::CompileError(_) => 65,
+            RunError::RuntimeError(_) => 70,
+            RunError::IoError(_) => 74,
+            RunError::Exit(code) => code,
+        }
+    }
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RunError::CompileError(ref messages) | RunError::RuntimeError(ref messages) => {
+                for (i, message) in messages.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    f.write_str(message)?;
+                }
+                Ok(())
+            }
+            RunError::IoError(ref message) => f.write_str(message),
+            RunError::Exit(code) => write!(f, "Exit with code {}", code),
+        }
+    }
 }
 
 type RunResult = Result<(), RunError>;
 
 pub trait LoxImplementation {
     fn run(&mut self, source: &str) -> RunResult;
+
+    /// Runs some input typed in the REPL.
+    /// Implementations should also accept a bare expression, without
+    /// the trailing semicolon, and print its value.
+    fn run_interactive(&mut self, source: &str) -> RunResult {
+        self.run(source)
+    }
+
+    /// Lists the global variables, sorted by name, together with a
+    /// description of their values.
+    fn globals(&self) -> Vec<(String, String)> {
+        vec![]
+    }
+
+    /// Makes the command line arguments following the script available
+    /// to the program, through `os.args()` when the capabilities allow
+    /// it. Implementations that can't call functions ignore them.
+    fn set_arguments(&mut self, _arguments: &[String]) {}
+
+    /// Tells where the script being run is, for the files it imports to
+    /// be looked up relative to it. Implementations without imports
+    /// ignore it.
+    fn set_script_path(&mut self, _path: &Path) {}
+
+    /// Gives the program the access to the system the capabilities
+    /// allow, which it has none of otherwise. Implementations that can't
+    /// call functions ignore them.
+    fn set_capabilities(&mut self, _capabilities: Capabilities) {}
+}
+
+/// The REPL history is kept in the user's home directory, if there is one
+fn history_file() -> Option<PathBuf> {
+    home_dir().map(|home| home.join(".rulox_history"))
 }
 
 pub struct Runner<I: LoxImplementation> {
     rulox: I,
+    arguments: Vec<String>,
+    capabilities: Capabilities,
 }
 
-impl<I: LoxImplementation> Runner<I> {
+/// What the REPL should do after a command has been executed
+enum Next {
+    Continue,
+    Quit,
+    /// The program called `exit` with the code
+    Exit(i32),
+}
+
+impl<I: LoxImplementation + Default> Runner<I> {
     pub fn new(implementation: I) -> Runner<I> {
         Runner {
             rulox: implementation,
+            arguments: vec![],
+            capabilities: Capabilities::default(),
         }
     }
+
+    pub fn set_arguments(&mut self, arguments: Vec<String>) {
+        self.rulox.set_arguments(&arguments);
+        self.arguments = arguments;
+    }
+
+    pub fn set_capabilities(&mut self, capabilities: Capabilities) {
+        self.rulox.set_capabilities(capabilities);
+        self.capabilities = capabilities;
+    }
+
     pub fn run_file(&mut self, file_name: &str) -> RunResult {
-        let mut file =
-            File::open(file_name).map_err(|_| RunError::IoError("Error opening file".into()))?; // TODO: add context
+        let mut file = File::open(file_name)
+            .map_err(|e| RunError::IoError(format!("Error opening {}: {}", file_name, e)))?;
         let mut source = String::new();
         file.read_to_string(&mut source)
-            .map_err(|_| RunError::IoError("Error reading file".into()))?;
+            .map_err(|e| RunError::IoError(format!("Error reading {}: {}", file_name, e)))?;
+        self.rulox.set_script_path(Path::new(file_name));
         self.rulox.run(&source)
     }
 
+    pub fn run_stdin(&mut self) -> RunResult {
+        let mut source = String::new();
+        stdin()
+            .read_to_string(&mut source)
+            .map_err(|e| RunError::IoError(format!("Error reading the standard input: {}", e)))?;
+        self.rulox.run(&source)
+    }
+
+    /// Interactive mode.
+    /// Lines are accumulated until they form a complete input, which
+    /// is then run. An empty line forces the accumulated input to run.
+    /// The session ends on end of file (Ctrl-D), with `:quit` or when
+    /// the program calls `exit`.
+    /// Meta-commands (see `command::HELP`) are accepted when nothing
+    /// is pending.
     fn run_prompt(&mut self) -> Result<(), RunError> {
         println!("Rulox - A lox interpreter written in Rust");
-        let _ = io::stdout().flush(); //TODO: is this okay?
+        let mut editor = Editor::<()>::new();
+        let history = history_file();
+        if let Some(ref history) = history {
+            // There is no history the first time the REPL is used
+            let _ = editor.load_history(history);
+        }
         let mut source = String::new();
+        let mut exit = None;
         loop {
-            print!("> ");
-            io::stdout().flush().unwrap();
-            let _ = io::stdin().read_line(&mut source);
-            // TODO: add a way to exit
-            self.rulox.run(&source).unwrap_or_else(|error| {
-                println!("{:?}", error);
-                io::stdout().flush().unwrap()
-            });
-            source.clear();
+            let prompt = if source.is_empty() { "> " } else { ".. " };
+            match editor.readline(prompt) {
+                Ok(line) => {
+                    if source.is_empty() {
+                        if let Some(command) = command::parse(&line) {
+                            editor.add_history_entry(line.trim());
+                            match self.run_command(command) {
+                                Next::Continue => continue,
+                                Next::Quit => break,
+                                Next::Exit(code) => {
+                                    exit = Some(code);
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                    let force_run = !source.is_empty() && line.trim().is_empty();
+                    if !source.is_empty() {
+                        source.push('\n');
+                    }
+                    source.push_str(&line);
+                    if force_run || input::is_complete(&source) {
+                        if !source.trim().is_empty() {
+                            editor.add_history_entry(source.trim_end());
+                            match self.rulox.run_interactive(&source) {
+                                Ok(()) => {}
+                                Err(RunError::Exit(code)) => {
+                                    exit = Some(code);
+                                    break;
+                                }
+                                Err(error) => eprintln!("{}", error),
+                            }
+                        }
+                        source.clear();
+                    }
+                }
+                // Ctrl-C discards what has been typed so far
+                Err(ReadlineError::Interrupted) => source.clear(),
+                Err(ReadlineError::Eof) => break,
+                Err(error) => return Err(RunError::IoError(error.to_string())),
+            }
+        }
+        if let Some(ref history) = history {
+            editor
+                .save_history(history)
+                .map_err(|e| RunError::IoError(e.to_string()))?;
         }
+        exit.map_or(Ok(()), |code| Err(RunError::Exit(code)))
     }
 
-    pub fn run(&mut self, args: &[String]) -> i32 {
-        let result = match args.len() {
-            // The first argument is the program name
-            1 => self.run_prompt(),
-            2 => self.run_file(&args[1]),
-            _ => {
-                println!("Usage: rulox [script]");
+    fn run_command(&mut self, command: Command) -> Next {
+        let result = match command {
+            Command::Quit => return Next::Quit,
+            Command::Help => {
+                println!("{}", command::HELP);
+                Ok(())
+            }
+            Command::Environment => {
+                for (name, value) in self.rulox.globals() {
+                    println!("{} = {}", name, value);
+                }
+                Ok(())
+            }
+            Command::Ast(source) => treewalk::pretty_print(source).map(|lines| {
+                for line in lines {
+                    println!("{}", line);
+                }
+            }),
+            Command::Disassemble(source) => vm::disassemble(source),
+            Command::Load(file_name) => self.run_file(file_name),
+            Command::Reset => {
+                self.rulox = I::default();
+                self.rulox.set_capabilities(self.capabilities);
+                self.rulox.set_arguments(&self.arguments);
+                Ok(())
+            }
+            Command::Unknown(name) => {
+                println!("Unknown command {}", name);
+                println!("{}", command::HELP);
                 Ok(())
             }
         };
+        match result {
+            Ok(()) => Next::Continue,
+            Err(RunError::Exit(code)) => Next::Exit(code),
+            Err(error) => {
+                eprintln!("{}", error);
+                Next::Continue
+            }
+        }
+    }
+
+    /// Runs the program described by the options and returns the
+    /// process exit code
+    pub fn run(&mut self, options: &Options) -> i32 {
+        self.set_capabilities(options.capabilities);
+        self.set_arguments(options.arguments.clone());
+        let result = match options.input {
+            Input::Prompt => self.run_prompt(),
+            Input::File(ref file_name) => self.run_file(file_name),
+            Input::Stdin => self.run_stdin(),
+            Input::Code(ref source) => self.rulox.run(source),
+        };
         match result {
             Ok(_) => 0,
-            _ => {
-                println!("{:?}", result);
-                1
+            Err(RunError::Exit(code)) => code,
+            Err(error) => {
+                eprintln!("{}", error);
+                error.exit_code()
+            }
+        }
+    }
+}
+
+/// Entry point of the binaries, which only differ in their default backend.
+/// Returns the process exit code.
+pub fn main(args: &[String], default_backend: Backend) -> i32 {
+    let program_name = args.first().map(|s| s.as_str()).unwrap_or("rulox");
+    match cli::parse(args, default_backend) {
+        Ok(Action::Help) => {
+            println!("{}", cli::usage(program_name));
+            0
+        }
+        Ok(Action::Version) => {
+            println!("rulox {}", env!("CARGO_PKG_VERSION"));
+            0
+        }
+        Ok(Action::Run(options)) => match options.backend {
+            Backend::TreeWalk => {
+                Runner::new(treewalk::TreeWalkRuloxInterpreter::default()).run(&options)
+            }
+            Backend::Vm => Runner::new(vm::LoxVm::default()).run(&options),
+            Backend::Closures => {
+                Runner::new(treewalk::ClosureRuloxInterpreter::default()).run(&options)
             }
+            Backend::Register => Runner::new(vm::register::RegisterVm::default()).run(&options),
+        },
+        Err(message) => {
+            eprintln!("{}\n\n{}", message, cli::usage(program_name));
+            64
         }
     }
 }
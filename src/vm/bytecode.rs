@@ -1,17 +1,60 @@
+use std::fmt;
 use std::io::{Error, LineWriter, Write};
+use std::rc::Rc;
 
 type Offset = usize;
 /// Subset of values that can be initialised when a chunk is created.
 /// They will be turned into proper values when the VM accesses them.
+/// The chunk of functions is the one of the VM running them.
 #[derive(Debug)]
-pub enum Constant {
+pub enum Constant<C = Chunk> {
     Number(f64),
     Bool(bool),
     Nil,
     String(String),
+    Function(Rc<Function<C>>),
 }
+
 type Line = usize;
 
+/// Where a closure gets one of the variables it captures from, when it's
+/// created
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Capture {
+    /// The local variable in the given slot of the function creating it
+    Local(usize),
+    /// One of the variables captured by the function creating it
+    Upvalue(usize),
+}
+
+/// A function compiled into a chunk of its own, which closures share
+pub struct Function<C = Chunk> {
+    /// None for anonymous functions
+    pub name: Option<String>,
+    pub arity: usize,
+    /// The variables the closures of the function capture, in the order
+    /// of their index
+    pub upvalues: Vec<Capture>,
+    pub chunk: C,
+}
+
+impl<C> fmt::Display for Function<C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name {
+            Some(ref name) => write!(f, "<fn {}>", name),
+            None => f.write_str("<fn>"),
+        }
+    }
+}
+
+/// Functions are shown like `print` shows them, their chunk is
+/// disassembled separately
+impl<C> fmt::Debug for Function<C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum OpCode {
     Constant(Offset),
@@ -23,22 +66,121 @@ pub enum OpCode {
     // Since constant already has an offset we're not making the
     // encoding worse. The extra space would have been allocated anyway.
     Binary(BinaryOp),
+    /// Skips the given number of instructions when the value on top
+    /// of the stack is falsey. The value is left on the stack.
+    JumpIfFalse(Offset),
+    /// Skips the given number of instructions
+    Jump(Offset),
+    Pop,
+    /// Replaces the given number of values on top of the stack with a
+    /// list holding them
+    List(usize),
+    /// Replaces the given number of keys and values on top of the
+    /// stack, alternating and starting with a key, with a map holding them
+    Map(usize),
+    /// Replaces a list or map and an index on top of the stack with the
+    /// element at that index
+    Index,
+    /// Replaces a list, an index and a value on top of the stack with the
+    /// value, which becomes the element at that index
+    SetIndex,
+    /// Replaces the string, list or map on top of the stack with its
+    /// length, like `len` does
+    Length,
+    /// Goes back to the instruction the given number of instructions
+    /// before the next one
+    Loop(Offset),
+    /// Prints the value on top of the stack, popping it
+    Print,
+    /// Pops the value on top of the stack into the global variable
+    /// named by the constant
+    DefineGlobal(Offset),
+    /// Pushes the value of the global variable named by the constant
+    GetGlobal(Offset),
+    /// Assigns the value on top of the stack, which is left there, to the
+    /// global variable named by the constant
+    SetGlobal(Offset),
+    /// Pushes the value of the local variable in the given stack slot
+    GetLocal(usize),
+    /// Assigns the value on top of the stack, which is left there, to the
+    /// local variable in the given stack slot
+    SetLocal(usize),
+    /// Replaces the value on top of the stack with the string `print`
+    /// shows for it
+    ToString,
+    /// Replaces the module on top of the stack with what it exports
+    /// under the name in the constant
+    GetProperty(Offset),
+    /// Calls the value below the given number of arguments on top of the
+    /// stack, replacing them all with the result
+    Call(usize),
+    /// Pushes a closure of the function in the constant, capturing the
+    /// variables it needs
+    Closure(Offset),
+    /// Pushes the value of the variable the closure being run captured
+    /// with the given index
+    GetUpvalue(usize),
+    /// Assigns the value on top of the stack, which is left there, to the
+    /// variable the closure being run captured with the given index
+    SetUpvalue(usize),
+    /// Pops the local variable on top of the stack, moving it out of the
+    /// stack for the closures that captured it
+    CloseUpvalue,
+    /// Raises the value on top of the stack as an error
+    Throw,
+    /// Starts a `finally` block when nothing failed, so there is no
+    /// error for `EndFinally` to raise again
+    EnterFinally,
+    /// Ends a `finally` block, raising again the error its handler kept
+    /// if it was entered because of one
+    EndFinally,
+}
+
+/// What a handler does with the errors it gets
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HandlerKind {
+    /// Pushes what was caught: the thrown value, or a map with the
+    /// message and the line of a runtime error
+    Catch,
+    /// Keeps the error for `EndFinally` to raise it again at the end of
+    /// the `finally` block
+    Finally,
 }
 
+/// Where the errors raised by the instructions in a range go: handlers
+/// are found in a table instead of being set up by instructions, so
+/// `try` costs nothing until something fails.
 #[derive(Debug, Clone, Copy)]
+pub struct Handler {
+    /// The first instruction covered
+    pub start: usize,
+    /// The first instruction after the covered ones
+    pub end: usize,
+    /// Where the execution continues
+    pub target: usize,
+    /// How many values the stack is cut down to, the local variables in
+    /// scope when the `try` started
+    pub stack_depth: usize,
+    /// How many errors, or their absence, kept for `finally` blocks are
+    /// still needed: the ones of the blocks the `try` is in
+    pub kept_errors: usize,
+    pub kind: HandlerKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum BinaryOp {
     Add,
     Subtract,
     Multiply,
     Divide,
+    Modulo,
+    IntegerDivide,
     Equals,
     NotEqual,
     Greater,
     GreaterEqual,
     Less,
     LessEqual,
-    Or,
-    And,
 }
 
 #[derive(Debug, Default)]
@@ -46,6 +188,9 @@ pub struct Chunk {
     instructions: Vec<OpCode>,
     values: Vec<Constant>,
     lines: Vec<Line>,
+    /// Innermost first, so that the first one covering an instruction is
+    /// the one handling its errors
+    handlers: Vec<Handler>,
 }
 
 impl Chunk {
@@ -57,6 +202,15 @@ impl Chunk {
         &self.values[index]
     }
 
+    /// The constant at the given offset, if there is one
+    pub fn find_value(&self, index: usize) -> Option<&Constant> {
+        self.values.get(index)
+    }
+
+    pub fn get_line(&self, index: usize) -> Line {
+        self.lines[index]
+    }
+
     /// Adds a new instruction to the chunk
     /// # Example
     /// ```
@@ -70,6 +224,23 @@ impl Chunk {
         self.lines.push(line);
     }
 
+    /// Makes the jump at the given index land on the next instruction
+    /// that will be added to the chunk
+    pub fn patch_jump(&mut self, index: usize) {
+        let offset = self.instructions.len() - index - 1;
+        self.instructions[index] = match self.instructions[index] {
+            OpCode::Jump(_) => OpCode::Jump(offset),
+            OpCode::JumpIfFalse(_) => OpCode::JumpIfFalse(offset),
+            instruction => panic!("{:?} is not a jump", instruction),
+        }
+    }
+
+    /// Adds a loop going back to the instruction at the given index
+    pub fn add_loop(&mut self, start: usize, line: Line) {
+        let offset = self.instructions.len() + 1 - start;
+        self.add_instruction(OpCode::Loop(offset), line)
+    }
+
     pub fn instruction_count(&self) -> usize {
         self.instructions.len()
     }
@@ -93,6 +264,23 @@ impl Chunk {
     pub fn values_count(&self) -> usize {
         self.values.len()
     }
+
+    /// Adds a handler. The ones of nested `try` statements have to be
+    /// added before the ones of the statements they're in.
+    pub fn add_handler(&mut self, handler: Handler) {
+        self.handlers.push(handler)
+    }
+
+    /// The handler for the errors of the instruction at the given index
+    pub fn handler_for(&self, index: usize) -> Option<&Handler> {
+        self.handlers
+            .iter()
+            .find(|handler| handler.start <= index && index < handler.end)
+    }
+
+    pub fn handlers(&self) -> &[Handler] {
+        &self.handlers
+    }
 }
 
 pub fn disassemble_instruction<T>(
@@ -105,17 +293,19 @@ where
 {
     match *instruction {
         OpCode::Return => writeln!(out, "OP_RETURN"),
-        OpCode::Constant(offset) => if offset >= chunk.values_count() {
-            //TODO: this should probably return an error
-            writeln!(out, "OP_CONSTANT {:4} 'ILLEGAL_ACCESS'", offset)
-        } else {
-            writeln!(
-                out,
-                "OP_CONSTANT {:4} '{:?}'",
-                offset,
-                chunk.get_value(offset)
-            )
-        },
+        OpCode::Constant(offset) => {
+            if offset >= chunk.values_count() {
+                //TODO: this should probably return an error
+                writeln!(out, "OP_CONSTANT {:4} 'ILLEGAL_ACCESS'", offset)
+            } else {
+                writeln!(
+                    out,
+                    "OP_CONSTANT {:4} '{:?}'",
+                    offset,
+                    chunk.get_value(offset)
+                )
+            }
+        }
         OpCode::Not => writeln!(out, "OP_NOT"),
         OpCode::Negate => writeln!(out, "OP_NEGATE"),
         OpCode::Binary(ref operator) => match *operator {
@@ -123,18 +313,96 @@ where
             BinaryOp::Subtract => writeln!(out, "OP_SUBTRACT"),
             BinaryOp::Multiply => writeln!(out, "OP_MULTIPLY"),
             BinaryOp::Divide => writeln!(out, "OP_DIVIDE"),
+            BinaryOp::Modulo => writeln!(out, "OP_MODULO"),
+            BinaryOp::IntegerDivide => writeln!(out, "OP_INTEGER_DIVIDE"),
             BinaryOp::Equals => writeln!(out, "OP_EQUALS"),
             BinaryOp::NotEqual => writeln!(out, "OP_NOT_EQUAL"),
             BinaryOp::Greater => writeln!(out, "OP_GREATER"),
             BinaryOp::GreaterEqual => writeln!(out, "OP_GREATER_EQUAL"),
             BinaryOp::Less => writeln!(out, "OP_LESS"),
             BinaryOp::LessEqual => writeln!(out, "OP_LESS_EQUAL"),
-            BinaryOp::Or => writeln!(out, "OP_OR"),
-            BinaryOp::And => writeln!(out, "OP_AND"),
         },
+        OpCode::JumpIfFalse(offset) => writeln!(out, "OP_JUMP_IF_FALSE {:4}", offset),
+        OpCode::Jump(offset) => writeln!(out, "OP_JUMP {:10}", offset),
+        OpCode::Pop => writeln!(out, "OP_POP"),
+        OpCode::List(count) => writeln!(out, "OP_LIST {:10}", count),
+        OpCode::Map(count) => writeln!(out, "OP_MAP {:11}", count),
+        OpCode::Index => writeln!(out, "OP_INDEX"),
+        OpCode::SetIndex => writeln!(out, "OP_SET_INDEX"),
+        OpCode::Length => writeln!(out, "OP_LENGTH"),
+        OpCode::Loop(offset) => writeln!(out, "OP_LOOP {:10}", offset),
+        OpCode::Print => writeln!(out, "OP_PRINT"),
+        OpCode::DefineGlobal(offset) => write_name(out, "OP_DEFINE_GLOBAL", offset, chunk),
+        OpCode::GetGlobal(offset) => write_name(out, "OP_GET_GLOBAL", offset, chunk),
+        OpCode::SetGlobal(offset) => write_name(out, "OP_SET_GLOBAL", offset, chunk),
+        OpCode::GetLocal(slot) => writeln!(out, "OP_GET_LOCAL {:5}", slot),
+        OpCode::SetLocal(slot) => writeln!(out, "OP_SET_LOCAL {:5}", slot),
+        OpCode::ToString => writeln!(out, "OP_TO_STRING"),
+        OpCode::GetProperty(offset) => write_name(out, "OP_GET_PROPERTY", offset, chunk),
+        OpCode::Call(count) => writeln!(out, "OP_CALL {:10}", count),
+        OpCode::Closure(offset) => {
+            if offset >= chunk.values_count() {
+                writeln!(out, "OP_CLOSURE {:5} 'ILLEGAL_ACCESS'", offset)
+            } else {
+                writeln!(
+                    out,
+                    "OP_CLOSURE {:5} '{:?}'",
+                    offset,
+                    chunk.get_value(offset)
+                )
+            }
+        }
+        OpCode::GetUpvalue(index) => writeln!(out, "OP_GET_UPVALUE {:3}", index),
+        OpCode::SetUpvalue(index) => writeln!(out, "OP_SET_UPVALUE {:3}", index),
+        OpCode::CloseUpvalue => writeln!(out, "OP_CLOSE_UPVALUE"),
+        OpCode::Throw => writeln!(out, "OP_THROW"),
+        OpCode::EnterFinally => writeln!(out, "OP_ENTER_FINALLY"),
+        OpCode::EndFinally => writeln!(out, "OP_END_FINALLY"),
     }
 }
 
+/// Writes an instruction using the name in the given constant
+fn write_name<T>(
+    out: &mut LineWriter<T>,
+    instruction: &str,
+    offset: Offset,
+    chunk: &Chunk,
+) -> Result<(), Error>
+where
+    T: Write,
+{
+    match chunk.find_value(offset) {
+        Some(Constant::String(ref name)) => {
+            writeln!(out, "{} {:4} '{}'", instruction, offset, name)
+        }
+        _ => writeln!(out, "{} {:4} 'ILLEGAL_ACCESS'", instruction, offset),
+    }
+}
+
+/// Writes the handler table, one handler per line
+pub fn disassemble_handlers<T>(handlers: &[Handler], out: &mut LineWriter<T>) -> Result<(), Error>
+where
+    T: Write,
+{
+    for handler in handlers {
+        let kind = match handler.kind {
+            HandlerKind::Catch => "catch",
+            HandlerKind::Finally => "finally",
+        };
+        writeln!(
+            out,
+            "{:04}..{:04} -> {:04} {} (stack {}, kept {})",
+            handler.start,
+            handler.end,
+            handler.target,
+            kind,
+            handler.stack_depth,
+            handler.kept_errors
+        )?;
+    }
+    Ok(())
+}
+
 pub fn disassemble<T>(chunk: &Chunk, name: &str, out: &mut LineWriter<T>) -> Result<(), Error>
 where
     T: Write,
@@ -153,7 +421,14 @@ where
             try!(write!(out, "{:4}", line));
         }
         try!(write!(out, " "));
-        try!{disassemble_instruction(instruction, chunk, out)};
+        try! {disassemble_instruction(instruction, chunk, out)};
+    }
+    disassemble_handlers(&chunk.handlers, out)?;
+    // Then the functions, which have their own chunk
+    for constant in &chunk.values {
+        if let Constant::Function(ref function) = *constant {
+            disassemble(&function.chunk, &function.to_string(), out)?;
+        }
     }
     Ok(())
 }
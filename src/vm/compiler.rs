@@ -1,16 +1,74 @@
 use frontend::scanner::{scan_into_iterator, Position, ScannerError, Token, TokenWithContext};
 use num_traits::{FromPrimitive, ToPrimitive};
+use std::fmt;
 use std::iter::Peekable;
-use vm::bytecode::{BinaryOp, Chunk, Constant, OpCode};
+use std::mem;
+use std::rc::Rc;
+use vm::bytecode::{BinaryOp, Capture, Chunk, Constant, Function, Handler, HandlerKind, OpCode};
+
+/// Something the parser would have accepted at the point
+/// where it found an error
+#[derive(Debug, PartialEq)]
+pub enum Expected {
+    Expression,
+    Operator,
+    Identifier,
+    Token(Token),
+}
+
+impl fmt::Display for Expected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Expected::Expression => f.write_str("expression"),
+            Expected::Operator => f.write_str("operator"),
+            Expected::Identifier => f.write_str("identifier"),
+            Expected::Token(ref token) => write!(f, "{}", token),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum ParsingError {
     /// The end of file has been reached but the parser expected
-    /// to find some other token
-    UnexpectedEndOfFile,
-    /// The parser was expecting a specific token (TODO: we should
-    /// report which) but it instead found the reported lexeme
-    Unexpected(String, Position),
+    /// to find one of the reported elements.
+    /// The position is the one of the last token in the input.
+    UnexpectedEndOfFile(Vec<Expected>, Position),
+    /// The parser was expecting one of the reported elements
+    /// but it instead found the reported lexeme
+    Unexpected(Vec<Expected>, String, Position),
+    /// The reported lexeme can't be where it is, for the given reason
+    Invalid(&'static str, String, Position),
+}
+
+fn write_expected(f: &mut fmt::Formatter, expected: &[Expected]) -> fmt::Result {
+    if expected.len() > 1 {
+        f.write_str("one of ")?;
+    }
+    for (i, element) in expected.iter().enumerate() {
+        if i > 0 {
+            f.write_str(", ")?;
+        }
+        write!(f, "{}", element)?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for ParsingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParsingError::UnexpectedEndOfFile(ref expected, ref position) => {
+                write!(f, "{} Error at end: expected ", position)?;
+                write_expected(f, expected)
+            }
+            ParsingError::Unexpected(ref expected, ref lexeme, ref position) => {
+                write!(f, "{} Error at '{}': expected ", position, lexeme)?;
+                write_expected(f, expected)
+            }
+            ParsingError::Invalid(reason, ref lexeme, ref position) => {
+                write!(f, "{} Error at '{}': {}", position, lexeme, reason)
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -19,6 +77,15 @@ pub enum CompilationError {
     ParsingError(ParsingError),
 }
 
+impl fmt::Display for CompilationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CompilationError::ScannerError(ref error) => write!(f, "{}", error),
+            CompilationError::ParsingError(ref error) => write!(f, "{}", error),
+        }
+    }
+}
+
 #[derive(PartialEq, PartialOrd, FromPrimitive, ToPrimitive, Clone, Copy)]
 enum Precedence {
     None,
@@ -44,6 +111,72 @@ impl Precedence {
     }
 }
 
+/// A variable declared in a block. Its slot on the stack is its position
+/// among the locals in scope.
+struct Local {
+    name: String,
+    /// How many blocks it is in
+    depth: usize,
+    /// False while its initializer is compiled, which can't use it
+    initialized: bool,
+    /// Whether a closure captures it
+    captured: bool,
+}
+
+impl Local {
+    fn new(name: String, depth: usize) -> Local {
+        Local {
+            name,
+            depth,
+            initialized: true,
+            captured: false,
+        }
+    }
+
+    /// What takes it off the stack: the closures that captured it need
+    /// it moved out of the stack
+    fn pop(&self) -> OpCode {
+        if self.captured {
+            OpCode::CloseUpvalue
+        } else {
+            OpCode::Pop
+        }
+    }
+}
+
+/// A loop whose body is being compiled
+struct Loop {
+    /// Where `continue` jumps back to: the increment of a `for` loop, the
+    /// condition otherwise
+    start: usize,
+    /// How many locals were in scope when the body started, the others
+    /// are popped when leaving it
+    locals: usize,
+    /// How many `finally` blocks the loop is in
+    kept_errors: usize,
+    /// The jumps of its `break` statements, patched to its exit
+    breaks: Vec<usize>,
+    /// Where its `break` and `continue` statements leave the body, to
+    /// reject those skipping a `finally` block
+    exits: Vec<Exit>,
+}
+
+/// Where a `break`, `continue` or `return` statement leaves the code it's
+/// in, as the index of its jump and its keyword
+type Exit = (usize, String, Position);
+
+/// What the parser keeps of a function while compiling one declared in
+/// it, see `Parser::function`
+struct Enclosing {
+    chunk: Chunk,
+    locals: Vec<Local>,
+    upvalues: Vec<Capture>,
+    scope_depth: usize,
+    kept_errors: usize,
+    loops: Vec<Loop>,
+    returns: Vec<Exit>,
+}
+
 /// A single-pass Pratt Parser that consumes tokens from an iterator,
 /// parses them into a Lox programs and emits a chunk of bytecode.
 /// The parser also keeps tracks of errors.
@@ -54,6 +187,31 @@ where
     chunk: &'a mut Chunk,
     tokens: Peekable<I>,
     errors: Vec<CompilationError>,
+    /// Position of the last consumed token, used to report
+    /// errors when the input ends prematurely
+    last_position: Position,
+    /// Innermost last
+    locals: Vec<Local>,
+    /// How many blocks the code being compiled is in
+    scope_depth: usize,
+    /// How many statements the code being compiled is in. A bare
+    /// expression ending the program is only its result at the top level.
+    nesting: usize,
+    /// Whether the expression being compiled can be assigned to, set for
+    /// each prefix rule by `parse_precedence`
+    can_assign: bool,
+    /// How many `finally` blocks the code being compiled is in, see
+    /// `Handler`
+    kept_errors: usize,
+    /// Innermost last
+    loops: Vec<Loop>,
+    /// The variables the function being compiled captures
+    upvalues: Vec<Capture>,
+    /// Where the function being compiled returns, like the exits of loops
+    returns: Vec<Exit>,
+    /// The functions the one being compiled is in, innermost last. The
+    /// script isn't a function: the chunk of the parser is its own.
+    enclosing: Vec<Enclosing>,
 }
 type Rule<'a, I> = fn(&mut Parser<'a, I>) -> Result<(), ParsingError>;
 
@@ -66,6 +224,16 @@ where
             chunk,
             tokens: tokens.peekable(),
             errors: vec![],
+            last_position: Position::initial(),
+            locals: vec![],
+            scope_depth: 0,
+            nesting: 0,
+            can_assign: false,
+            kept_errors: 0,
+            loops: vec![],
+            upvalues: vec![],
+            returns: vec![],
+            enclosing: vec![],
         }
     }
 
@@ -104,7 +272,15 @@ where
         // Peek to skip errors and keep track of them.
         // This makes unwrapping safe.
         self.skip_to_valid();
-        self.tokens.next().map(|r| r.unwrap())
+        let next = self.tokens.next().map(|r| r.unwrap());
+        if let Some(ref token) = next {
+            self.last_position = token.position;
+        }
+        next
+    }
+
+    fn unexpected_end_of_file(&self, expected: Vec<Expected>) -> ParsingError {
+        ParsingError::UnexpectedEndOfFile(expected, self.last_position)
     }
 
     /// Adds an opcode to the current chunk
@@ -121,15 +297,32 @@ where
         })
     }
 
+    fn check(&mut self, token: &Token) -> bool {
+        self.peek().map(|t| &t.token) == Some(token)
+    }
+
+    /// Consumes the next token if it's the given one, telling whether it was
+    fn advance_if(&mut self, token: &Token) -> bool {
+        let matches = self.check(token);
+        if matches {
+            self.advance();
+        }
+        matches
+    }
+
     /// Ensures that a specific token is the next in the input iterator.
     /// If that's the case, it will just consumes it.
     /// If not, it will return a parsing error.
     fn consume(&mut self, token: &Token) -> Result<(), ParsingError> {
-        let current = self.advance().ok_or(ParsingError::UnexpectedEndOfFile)?;
+        let current = match self.advance() {
+            Some(current) => current,
+            None => return Err(self.unexpected_end_of_file(vec![Expected::Token(token.clone())])),
+        };
         if &current.token == token {
             Ok(())
         } else {
             Err(ParsingError::Unexpected(
+                vec![Expected::Token(token.clone())],
                 current.lexeme.clone(),
                 current.position,
             ))
@@ -153,15 +346,19 @@ where
         I: Iterator<Item = Result<TokenWithContext, ScannerError>>,
     {
         match token {
-            Token::LeftParen => (Precedence::Call, Some(Parser::grouping), None),
+            Token::LeftParen => (Precedence::Call, Some(Parser::grouping), Some(Parser::call)),
+            Token::LeftBracket => (Precedence::Call, Some(Parser::list), Some(Parser::index)),
+            Token::LeftBrace => (Precedence::None, Some(Parser::map), None),
             Token::RightParen => (Precedence::None, None, None),
             Token::Comma => (Precedence::None, None, None),
-            Token::Dot => (Precedence::Call, None, None),
+            Token::Dot => (Precedence::Call, None, Some(Parser::dot)),
             Token::Minus => (Precedence::Term, Some(Parser::unary), Some(Parser::binary)),
             Token::Bang => (Precedence::None, Some(Parser::unary), None),
             Token::Plus => (Precedence::Term, None, Some(Parser::binary)),
             Token::Slash => (Precedence::Factor, None, Some(Parser::binary)),
-            Token::Star => (Precedence::Factor, None, Some(Parser::binary)),
+            Token::Star | Token::Percent | Token::TildeSlash => {
+                (Precedence::Factor, None, Some(Parser::binary))
+            }
             Token::EqualEqual | Token::BangEqual => {
                 (Precedence::Equality, None, Some(Parser::binary))
             }
@@ -170,12 +367,16 @@ where
             }
             Token::Semicolon => (Precedence::None, None, None),
             Token::NumberLiteral(_) => (Precedence::None, Some(Parser::number), None),
+            Token::Identifier(_) => (Precedence::None, Some(Parser::variable), None),
             Token::True | Token::False | Token::Nil | Token::StringLiteral(_) => {
                 (Precedence::None, Some(Parser::literal), None)
             }
-            Token::Or => (Precedence::Or, None, Some(Parser::binary)),
-            Token::And => (Precedence::And, None, Some(Parser::binary)),
-            _ => unimplemented!(),
+            Token::StringFragment(_) => (Precedence::None, Some(Parser::interpolation), None),
+            Token::Fun => (Precedence::None, Some(Parser::lambda), None),
+            Token::Or => (Precedence::Or, None, Some(Parser::or)),
+            Token::And => (Precedence::And, None, Some(Parser::and)),
+            // Tokens that can't start or continue an expression yet
+            _ => (Precedence::None, None, None),
         }
     }
 
@@ -183,17 +384,25 @@ where
     /// It peeks tokens to figure out what rule to apply and dispatches the corresponding
     /// functions.
     fn parse_precedence(&mut self, precedence: Precedence) -> Result<(), ParsingError> {
+        let can_assign = precedence <= Precedence::Assignment;
         let prefix_function = {
             let (_, prefix_function, _) = {
-                let peeked = self.peek().ok_or(ParsingError::UnexpectedEndOfFile)?;
-                Self::find_rule(&peeked.token)
+                match self.peek() {
+                    Some(peeked) => Self::find_rule(&peeked.token),
+                    None => return Err(self.unexpected_end_of_file(vec![Expected::Expression])),
+                }
             };
             prefix_function.ok_or_else(|| {
                 // This is a bad token. We need to consume it so we can carry on.
                 let token = self.advance().unwrap();
-                ParsingError::Unexpected(token.lexeme.clone(), token.position)
+                ParsingError::Unexpected(
+                    vec![Expected::Expression],
+                    token.lexeme.clone(),
+                    token.position,
+                )
             })?
         };
+        self.can_assign = can_assign;
         prefix_function(self)?;
         loop {
             let infix_function = {
@@ -207,14 +416,35 @@ where
                     infix_function.ok_or_else(|| {
                         // This is a bad token. We need to consume it so we can carry on.
                         let token = self.advance().unwrap();
-                        ParsingError::Unexpected(token.lexeme.clone(), token.position)
+                        ParsingError::Unexpected(
+                            vec![Expected::Operator],
+                            token.lexeme.clone(),
+                            token.position,
+                        )
                     })?
                 } else {
-                    return Ok(());
+                    break;
                 }
             };
+            self.can_assign = can_assign;
             infix_function(self)?;
         }
+        if can_assign && self.check(&Token::Equal) {
+            let token = self.advance().unwrap();
+            return Err(ParsingError::Invalid(
+                "Invalid assignment target.",
+                token.lexeme,
+                token.position,
+            ));
+        }
+        Ok(())
+    }
+
+    /// Parses the whole input as a single expression, telling whether
+    /// it is one. Like in the tree-walker, this comes first, so that a
+    /// map literal isn't taken for a block.
+    fn parse_standalone_expression(mut self) -> bool {
+        self.expression().is_ok() && self.peek().is_none() && self.errors.is_empty()
     }
 
     /// Top level function of the parser.
@@ -222,9 +452,7 @@ where
     /// applies the recovery logic for cleaner error messages.
     fn parse(mut self) -> Result<(), Vec<CompilationError>> {
         while let Some(_) = self.peek() {
-            if let Err(error) = self.expression() {
-                self.errors.push(CompilationError::ParsingError(error));
-            }
+            self.declaration();
         }
         if !self.errors.is_empty() {
             Err(self.errors)
@@ -233,6 +461,731 @@ where
         }
     }
 
+    /// Compiles a declaration or a statement. After an error, the tokens
+    /// up to the next statement are skipped, so that what follows can be
+    /// checked too.
+    fn declaration(&mut self) {
+        let result = if self.advance_if(&Token::Var) {
+            self.var_declaration()
+        } else if self.advance_if(&Token::Fun) {
+            self.fun_declaration()
+        } else {
+            self.statement()
+        };
+        if let Err(error) = result {
+            self.errors.push(CompilationError::ParsingError(error));
+            self.synchronize();
+        }
+    }
+
+    fn synchronize(&mut self) {
+        while let Some(token) = self.peek().map(|t| t.token.clone()) {
+            match token {
+                Token::Semicolon => {
+                    self.advance();
+                    return;
+                }
+                Token::Class
+                | Token::Fun
+                | Token::Var
+                | Token::For
+                | Token::If
+                | Token::While
+                | Token::Print
+                | Token::Return
+                | Token::Break
+                | Token::Continue
+                | Token::Throw
+                | Token::Try
+                | Token::Import => return,
+                _ => {
+                    self.advance();
+                }
+            }
+        }
+    }
+
+    /// Consumes an identifier, giving its name and its token
+    fn identifier(&mut self) -> Result<(String, TokenWithContext), ParsingError> {
+        let token = match self.advance() {
+            Some(token) => token,
+            None => return Err(self.unexpected_end_of_file(vec![Expected::Identifier])),
+        };
+        match token.token {
+            Token::Identifier(ref name) => Ok((name.clone(), token)),
+            _ => Err(ParsingError::Unexpected(
+                vec![Expected::Identifier],
+                token.lexeme,
+                token.position,
+            )),
+        }
+    }
+
+    /// Variables declared at the top level are globals, the others are
+    /// locals living on the stack
+    fn var_declaration(&mut self) -> Result<(), ParsingError> {
+        let (name, token) = self.identifier()?;
+        self.var_initializer(name, token)
+    }
+
+    /// The rest of a variable declaration, after its name
+    fn var_initializer(
+        &mut self,
+        name: String,
+        token: TokenWithContext,
+    ) -> Result<(), ParsingError> {
+        if self.scope_depth > 0 {
+            self.declare_local(name.clone(), &token)?;
+        }
+        if self.advance_if(&Token::Equal) {
+            self.expression()?;
+        } else {
+            let nil = self.chunk.add_constant(Constant::Nil);
+            self.emit(OpCode::Constant(nil), token.position.line);
+        }
+        self.consume(&Token::Semicolon)?;
+        if self.scope_depth > 0 {
+            // The value of the initializer is where the local lives
+            self.mark_initialized();
+        } else {
+            let name = self.chunk.add_constant(Constant::String(name));
+            self.emit(OpCode::DefineGlobal(name), token.position.line);
+        }
+        Ok(())
+    }
+
+    /// Like variables, functions declared at the top level are globals.
+    /// The others are locals, initialized before the body is compiled
+    /// for it to call the function.
+    fn fun_declaration(&mut self) -> Result<(), ParsingError> {
+        let (name, token) = self.identifier()?;
+        let line = token.position.line;
+        if self.scope_depth > 0 {
+            self.declare_local(name.clone(), &token)?;
+            self.mark_initialized();
+        }
+        self.function(Some(name.clone()), line)?;
+        if self.scope_depth == 0 {
+            let name = self.chunk.add_constant(Constant::String(name));
+            self.emit(OpCode::DefineGlobal(name), line);
+        }
+        Ok(())
+    }
+
+    /// An anonymous function
+    fn lambda(&mut self) -> Result<(), ParsingError> {
+        self.consume(&Token::Fun)?;
+        let line = self.last_position.line;
+        self.function(None, line)
+    }
+
+    /// Compiles the parameters and the body of a function, what comes
+    /// before them being already consumed, into a chunk of its own, with
+    /// the locals, the loops and the `finally` blocks of the enclosing
+    /// function put aside. The function goes in the constants of the
+    /// enclosing one, which pushes a closure of it.
+    fn function(&mut self, name: Option<String>, line: usize) -> Result<(), ParsingError> {
+        let enclosing = Enclosing {
+            chunk: mem::take(self.chunk),
+            // The function being called is in the first slot
+            locals: mem::replace(&mut self.locals, vec![Local::new(String::new(), 0)]),
+            upvalues: mem::take(&mut self.upvalues),
+            scope_depth: mem::replace(&mut self.scope_depth, 1),
+            kept_errors: mem::replace(&mut self.kept_errors, 0),
+            loops: mem::take(&mut self.loops),
+            returns: mem::take(&mut self.returns),
+        };
+        self.enclosing.push(enclosing);
+        let arity = self.function_body();
+        let enclosing = self
+            .enclosing
+            .pop()
+            .expect("The enclosing function was just pushed");
+        let chunk = mem::replace(self.chunk, enclosing.chunk);
+        let upvalues = mem::replace(&mut self.upvalues, enclosing.upvalues);
+        self.locals = enclosing.locals;
+        self.scope_depth = enclosing.scope_depth;
+        self.kept_errors = enclosing.kept_errors;
+        self.loops = enclosing.loops;
+        self.returns = enclosing.returns;
+        let function = Function {
+            name,
+            arity: arity?,
+            upvalues,
+            chunk,
+        };
+        let function = self
+            .chunk
+            .add_constant(Constant::Function(Rc::new(function)));
+        self.emit(OpCode::Closure(function), line);
+        Ok(())
+    }
+
+    /// The parameters are the locals following the function. The body
+    /// returns nil when it ends without a `return` statement.
+    /// Gives the number of parameters.
+    fn function_body(&mut self) -> Result<usize, ParsingError> {
+        self.consume(&Token::LeftParen)?;
+        let mut arity = 0;
+        if !self.check(&Token::RightParen) {
+            loop {
+                let (name, token) = self.identifier()?;
+                if arity == 8 {
+                    return Err(ParsingError::Invalid(
+                        "Can't have more than 8 parameters.",
+                        token.lexeme,
+                        token.position,
+                    ));
+                }
+                self.declare_local(name, &token)?;
+                self.mark_initialized();
+                arity += 1;
+                if !self.advance_if(&Token::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(&Token::RightParen)?;
+        self.consume(&Token::LeftBrace)?;
+        self.block()?;
+        let line = self.last_position.line;
+        let nil = self.chunk.add_constant(Constant::Nil);
+        self.emit(OpCode::Constant(nil), line);
+        self.emit(OpCode::Return, line);
+        Ok(arity)
+    }
+
+    fn declare_local(
+        &mut self,
+        name: String,
+        token: &TokenWithContext,
+    ) -> Result<(), ParsingError> {
+        let depth = self.scope_depth;
+        if self
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth == depth)
+            .any(|local| local.name == name)
+        {
+            return Err(ParsingError::Invalid(
+                "Already a variable with this name in this scope.",
+                token.lexeme.clone(),
+                token.position,
+            ));
+        }
+        self.locals.push(Local {
+            name,
+            depth,
+            initialized: false,
+            captured: false,
+        });
+        Ok(())
+    }
+
+    /// Lets the code use the local variable declared last
+    fn mark_initialized(&mut self) {
+        if let Some(local) = self.locals.last_mut() {
+            local.initialized = true;
+        }
+    }
+
+    /// The slot of the local variable with the given name, if there is one
+    fn resolve_local(
+        &self,
+        name: &str,
+        token: &TokenWithContext,
+    ) -> Result<Option<usize>, ParsingError> {
+        match self
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|&(_, local)| local.name == name)
+        {
+            Some((_, local)) if !local.initialized => Err(ParsingError::Invalid(
+                "Can't read local variable in its own initializer.",
+                token.lexeme.clone(),
+                token.position,
+            )),
+            Some((slot, _)) => Ok(Some(slot)),
+            None => Ok(None),
+        }
+    }
+
+    /// The index of the variable with the given name among those the
+    /// function being compiled captures, if a function it's in declares it
+    fn resolve_upvalue(
+        &mut self,
+        name: &str,
+        token: &TokenWithContext,
+    ) -> Result<Option<usize>, ParsingError> {
+        Ok(capture(&mut self.enclosing, name, token)?
+            .map(|capture| add_upvalue(&mut self.upvalues, capture)))
+    }
+
+    fn begin_scope(&mut self) {
+        self.scope_depth += 1;
+    }
+
+    /// Pops the locals of the block being closed off the stack
+    fn end_scope(&mut self, line: usize) {
+        self.scope_depth -= 1;
+        while self
+            .locals
+            .last()
+            .is_some_and(|local| local.depth > self.scope_depth)
+        {
+            let local = self.locals.pop().expect("The local was just found");
+            self.emit(local.pop(), line);
+        }
+    }
+
+    fn statement(&mut self) -> Result<(), ParsingError> {
+        let token = match self.peek() {
+            Some(token) => token.token.clone(),
+            None => return Err(self.unexpected_end_of_file(vec![Expected::Expression])),
+        };
+        match token {
+            Token::Print => self.print_statement(),
+            Token::LeftBrace => {
+                self.advance();
+                let line = self.last_position.line;
+                self.begin_scope();
+                let result = self.block();
+                self.end_scope(line);
+                result
+            }
+            Token::If => self.if_statement(),
+            Token::While => self.while_statement(),
+            Token::For => self.for_statement(),
+            Token::Break | Token::Continue => self.break_statement(),
+            Token::Return => self.return_statement(),
+            Token::Throw => self.throw_statement(),
+            Token::Try => self.try_statement(),
+            _ => self.expression_statement(),
+        }
+    }
+
+    /// Compiles the body of a statement
+    fn nested_statement(&mut self) -> Result<(), ParsingError> {
+        self.nesting += 1;
+        let result = self.statement();
+        self.nesting -= 1;
+        result
+    }
+
+    /// Compiles the declarations of a block up to its closing brace, the
+    /// opening one being already consumed
+    fn block(&mut self) -> Result<(), ParsingError> {
+        self.nesting += 1;
+        while self.peek().is_some_and(|t| t.token != Token::RightBrace) {
+            self.declaration();
+        }
+        self.nesting -= 1;
+        self.consume(&Token::RightBrace)
+    }
+
+    fn print_statement(&mut self) -> Result<(), ParsingError> {
+        self.advance();
+        let line = self.last_position.line;
+        self.expression()?;
+        self.consume(&Token::Semicolon)?;
+        self.emit(OpCode::Print, line);
+        Ok(())
+    }
+
+    fn expression_statement(&mut self) -> Result<(), ParsingError> {
+        self.expression()?;
+        if self.nesting == 0 && self.peek().is_none() {
+            // Left on the stack, it's returned for the REPL to show it
+            return Ok(());
+        }
+        let line = self.last_position.line;
+        self.consume(&Token::Semicolon)?;
+        self.emit(OpCode::Pop, line);
+        Ok(())
+    }
+
+    fn if_statement(&mut self) -> Result<(), ParsingError> {
+        self.advance();
+        let line = self.last_position.line;
+        self.consume(&Token::LeftParen)?;
+        self.expression()?;
+        self.consume(&Token::RightParen)?;
+        let then_jump = self.chunk.instruction_count();
+        self.emit(OpCode::JumpIfFalse(0), line);
+        self.emit(OpCode::Pop, line);
+        self.nested_statement()?;
+        let else_jump = self.chunk.instruction_count();
+        self.emit(OpCode::Jump(0), line);
+        self.chunk.patch_jump(then_jump);
+        self.emit(OpCode::Pop, line);
+        if self.advance_if(&Token::Else) {
+            self.nested_statement()?;
+        }
+        self.chunk.patch_jump(else_jump);
+        Ok(())
+    }
+
+    fn while_statement(&mut self) -> Result<(), ParsingError> {
+        self.advance();
+        let line = self.last_position.line;
+        let loop_start = self.chunk.instruction_count();
+        self.consume(&Token::LeftParen)?;
+        self.expression()?;
+        self.consume(&Token::RightParen)?;
+        let exit_jump = self.chunk.instruction_count();
+        self.emit(OpCode::JumpIfFalse(0), line);
+        self.emit(OpCode::Pop, line);
+        let breaks = self.loop_body(loop_start, self.locals.len())?;
+        self.chunk.add_loop(loop_start, line);
+        self.chunk.patch_jump(exit_jump);
+        self.emit(OpCode::Pop, line);
+        for jump in breaks {
+            self.chunk.patch_jump(jump);
+        }
+        Ok(())
+    }
+
+    /// The initializer is in a scope of its own, the increment is
+    /// compiled before the body but jumped over to run after it
+    fn for_statement(&mut self) -> Result<(), ParsingError> {
+        self.advance();
+        let line = self.last_position.line;
+        self.consume(&Token::LeftParen)?;
+        self.begin_scope();
+        let result = self.for_statement_rest(line);
+        self.end_scope(line);
+        result
+    }
+
+    fn for_statement_rest(&mut self, line: usize) -> Result<(), ParsingError> {
+        if self.advance_if(&Token::Var) {
+            let (name, token) = self.identifier()?;
+            // `in` is only a keyword here
+            if self.advance_if(&Token::Identifier("in".into())) {
+                return self.for_in_statement(name, line);
+            }
+            self.var_initializer(name, token)?;
+        } else if !self.advance_if(&Token::Semicolon) {
+            self.expression()?;
+            self.consume(&Token::Semicolon)?;
+            self.emit(OpCode::Pop, line);
+        }
+        let mut loop_start = self.chunk.instruction_count();
+        let mut exit_jump = None;
+        if !self.advance_if(&Token::Semicolon) {
+            self.expression()?;
+            self.consume(&Token::Semicolon)?;
+            exit_jump = Some(self.chunk.instruction_count());
+            self.emit(OpCode::JumpIfFalse(0), line);
+            self.emit(OpCode::Pop, line);
+        }
+        if !self.advance_if(&Token::RightParen) {
+            let body_jump = self.chunk.instruction_count();
+            self.emit(OpCode::Jump(0), line);
+            let increment_start = self.chunk.instruction_count();
+            self.expression()?;
+            self.emit(OpCode::Pop, line);
+            self.consume(&Token::RightParen)?;
+            self.chunk.add_loop(loop_start, line);
+            loop_start = increment_start;
+            self.chunk.patch_jump(body_jump);
+        }
+        let breaks = self.loop_body(loop_start, self.locals.len())?;
+        self.chunk.add_loop(loop_start, line);
+        if let Some(exit_jump) = exit_jump {
+            self.chunk.patch_jump(exit_jump);
+            self.emit(OpCode::Pop, line);
+        }
+        for jump in breaks {
+            self.chunk.patch_jump(jump);
+        }
+        Ok(())
+    }
+
+    /// Like the tree-walker, keeps the list and the index of the element
+    /// in locals the program can't name, then has the element in a local
+    /// of its own for each iteration. The increment is before the body,
+    /// like in a `for` loop, for `continue` to go through it.
+    fn for_in_statement(&mut self, name: String, line: usize) -> Result<(), ParsingError> {
+        self.expression()?;
+        self.consume(&Token::RightParen)?;
+        let list = self.locals.len();
+        let index = list + 1;
+        let zero = self.chunk.add_constant(Constant::Number(0.0));
+        self.emit(OpCode::Constant(zero), line);
+        for hidden in &["$list", "$index"] {
+            self.locals
+                .push(Local::new(hidden.to_string(), self.scope_depth));
+        }
+        let condition = self.chunk.instruction_count();
+        self.emit(OpCode::GetLocal(index), line);
+        self.emit(OpCode::GetLocal(list), line);
+        self.emit(OpCode::Length, line);
+        self.emit(OpCode::Binary(BinaryOp::Less), line);
+        let exit_jump = self.chunk.instruction_count();
+        self.emit(OpCode::JumpIfFalse(0), line);
+        self.emit(OpCode::Pop, line);
+        let body_jump = self.chunk.instruction_count();
+        self.emit(OpCode::Jump(0), line);
+        let increment = self.chunk.instruction_count();
+        let one = self.chunk.add_constant(Constant::Number(1.0));
+        self.emit(OpCode::GetLocal(index), line);
+        self.emit(OpCode::Constant(one), line);
+        self.emit(OpCode::Binary(BinaryOp::Add), line);
+        self.emit(OpCode::SetLocal(index), line);
+        self.emit(OpCode::Pop, line);
+        self.chunk.add_loop(condition, line);
+        self.chunk.patch_jump(body_jump);
+        let locals = self.locals.len();
+        self.begin_scope();
+        self.emit(OpCode::GetLocal(list), line);
+        self.emit(OpCode::GetLocal(index), line);
+        self.emit(OpCode::Index, line);
+        self.locals.push(Local::new(name, self.scope_depth));
+        let breaks = self.loop_body(increment, locals);
+        self.end_scope(line);
+        let breaks = breaks?;
+        self.chunk.add_loop(increment, line);
+        self.chunk.patch_jump(exit_jump);
+        self.emit(OpCode::Pop, line);
+        for jump in breaks {
+            self.chunk.patch_jump(jump);
+        }
+        Ok(())
+    }
+
+    /// Compiles the body of a loop, `continue` jumping back to `start`, and
+    /// returns the jumps of its `break` statements to patch with its exit,
+    /// which comes after the condition is popped. The locals declared
+    /// after the given number of them are popped when leaving the body.
+    fn loop_body(&mut self, start: usize, locals: usize) -> Result<Vec<usize>, ParsingError> {
+        self.loops.push(Loop {
+            start,
+            locals,
+            kept_errors: self.kept_errors,
+            breaks: vec![],
+            exits: vec![],
+        });
+        let result = self.nested_statement();
+        let body = self.loops.pop().expect("The loop was just pushed");
+        result.map(|_| body.breaks)
+    }
+
+    /// Pops the locals of the body of the loop, then jumps to its exit
+    /// for `break` or back to its start for `continue`
+    fn break_statement(&mut self) -> Result<(), ParsingError> {
+        let keyword = self
+            .advance()
+            .expect("The statement starts with the keyword");
+        let line = keyword.position.line;
+        self.consume(&Token::Semicolon)?;
+        let is_break = keyword.token == Token::Break;
+        let (start, locals, kept_errors) = match self.loops.last() {
+            Some(body) => (body.start, body.locals, body.kept_errors),
+            None if is_break => {
+                return Err(ParsingError::Invalid(
+                    "Can't use 'break' outside of a loop.",
+                    keyword.lexeme,
+                    keyword.position,
+                ))
+            }
+            None => {
+                return Err(ParsingError::Invalid(
+                    "Can't use 'continue' outside of a loop.",
+                    keyword.lexeme,
+                    keyword.position,
+                ))
+            }
+        };
+        if kept_errors < self.kept_errors {
+            return Err(ParsingError::Invalid(
+                "Can't leave a finally block.",
+                keyword.lexeme,
+                keyword.position,
+            ));
+        }
+        for slot in (locals..self.locals.len()).rev() {
+            let pop = self.locals[slot].pop();
+            self.emit(pop, line);
+        }
+        let exit = self.chunk.instruction_count();
+        if is_break {
+            self.emit(OpCode::Jump(0), line);
+        } else {
+            self.chunk.add_loop(start, line);
+        }
+        let body = self.loops.last_mut().expect("The loop was found above");
+        if is_break {
+            body.breaks.push(exit);
+        }
+        body.exits.push((exit, keyword.lexeme, keyword.position));
+        Ok(())
+    }
+
+    /// The value, nil if there's none, is left on the stack for `Return`,
+    /// which also pops the locals of the function
+    fn return_statement(&mut self) -> Result<(), ParsingError> {
+        let keyword = self
+            .advance()
+            .expect("The statement starts with the keyword");
+        let line = keyword.position.line;
+        if self.enclosing.is_empty() {
+            return Err(ParsingError::Invalid(
+                "Can't return from top-level code.",
+                keyword.lexeme,
+                keyword.position,
+            ));
+        }
+        if self.kept_errors > 0 {
+            return Err(ParsingError::Invalid(
+                "Can't leave a finally block.",
+                keyword.lexeme,
+                keyword.position,
+            ));
+        }
+        if self.check(&Token::Semicolon) {
+            let nil = self.chunk.add_constant(Constant::Nil);
+            self.emit(OpCode::Constant(nil), line);
+        } else {
+            self.expression()?;
+        }
+        self.consume(&Token::Semicolon)?;
+        self.returns.push((
+            self.chunk.instruction_count(),
+            keyword.lexeme,
+            keyword.position,
+        ));
+        self.emit(OpCode::Return, line);
+        Ok(())
+    }
+
+    fn throw_statement(&mut self) -> Result<(), ParsingError> {
+        self.advance();
+        let line = self.last_position.line;
+        self.expression()?;
+        self.consume(&Token::Semicolon)?;
+        self.emit(OpCode::Throw, line);
+        Ok(())
+    }
+
+    /// The body, then the catch block, if any, are covered by handlers
+    /// in the table of the chunk.
+    ///
+    /// The finally block is compiled once: when the rest completes it
+    /// starts with `EnterFinally`, when the rest fails its handler jumps
+    /// right after it keeping the error, which `EndFinally` raises again.
+    fn try_statement(&mut self) -> Result<(), ParsingError> {
+        self.advance();
+        let line = self.last_position.line;
+        let stack_depth = self.locals.len();
+        let kept_errors = self.kept_errors;
+        let start = self.chunk.instruction_count();
+        self.consume(&Token::LeftBrace)?;
+        self.begin_scope();
+        let result = self.block();
+        self.end_scope(line);
+        result?;
+        let body_end = self.chunk.instruction_count();
+        let mut exits = vec![body_end];
+        self.emit(OpCode::Jump(0), line);
+        let mut handlers = vec![];
+        let has_catch = self.advance_if(&Token::Catch);
+        if has_catch {
+            handlers.push(Handler {
+                start,
+                end: body_end,
+                target: self.chunk.instruction_count(),
+                stack_depth,
+                kept_errors,
+                kind: HandlerKind::Catch,
+            });
+            self.catch_block(line)?;
+            exits.push(self.chunk.instruction_count());
+            self.emit(OpCode::Jump(0), line);
+        }
+        if self.check(&Token::Finally) || !has_catch {
+            self.consume(&Token::Finally).map_err(|error| match error {
+                ParsingError::Unexpected(_, lexeme, position) => ParsingError::Unexpected(
+                    vec![
+                        Expected::Token(Token::Catch),
+                        Expected::Token(Token::Finally),
+                    ],
+                    lexeme,
+                    position,
+                ),
+                ParsingError::UnexpectedEndOfFile(_, position) => {
+                    ParsingError::UnexpectedEndOfFile(
+                        vec![
+                            Expected::Token(Token::Catch),
+                            Expected::Token(Token::Finally),
+                        ],
+                        position,
+                    )
+                }
+                error => error,
+            })?;
+            let end = self.chunk.instruction_count();
+            for exit in exits.drain(..) {
+                self.chunk.patch_jump(exit);
+            }
+            self.emit(OpCode::EnterFinally, line);
+            handlers.push(Handler {
+                start,
+                end,
+                target: self.chunk.instruction_count(),
+                stack_depth,
+                kept_errors,
+                kind: HandlerKind::Finally,
+            });
+            self.consume(&Token::LeftBrace)?;
+            self.kept_errors += 1;
+            self.begin_scope();
+            let result = self.block();
+            self.end_scope(line);
+            self.kept_errors -= 1;
+            result?;
+            self.emit(OpCode::EndFinally, line);
+            // The finally block would have to run before the jump
+            let skipping = self
+                .loops
+                .iter()
+                .flat_map(|body| body.exits.iter())
+                .chain(self.returns.iter())
+                .find(|&&(exit, _, _)| start <= exit && exit < end);
+            if let Some((_, lexeme, position)) = skipping {
+                return Err(ParsingError::Invalid(
+                    "Can't leave a try statement with a finally block.",
+                    lexeme.clone(),
+                    *position,
+                ));
+            }
+        }
+        for exit in exits {
+            self.chunk.patch_jump(exit);
+        }
+        for handler in handlers {
+            self.chunk.add_handler(handler);
+        }
+        Ok(())
+    }
+
+    /// The caught value, pushed by the handler, is the first local of
+    /// the scope of the block
+    fn catch_block(&mut self, line: usize) -> Result<(), ParsingError> {
+        self.consume(&Token::LeftParen)?;
+        let (name, _) = self.identifier()?;
+        self.consume(&Token::RightParen)?;
+        self.consume(&Token::LeftBrace)?;
+        self.begin_scope();
+        self.locals.push(Local::new(name, self.scope_depth));
+        let result = self.block();
+        self.end_scope(line);
+        result
+    }
+
     fn expression(&mut self) -> Result<(), ParsingError> {
         self.parse_precedence(Precedence::Assignment)
     }
@@ -258,6 +1211,65 @@ where
         Ok(())
     }
 
+    /// Turns `"a${b}c"` into `"a" + str(b) + "c"`, like the tree-walker,
+    /// without looking `str` up so that programs can define their own.
+    /// The string is split by the scanner into fragments, each followed
+    /// by the tokens of an expression, and the literal ending it.
+    fn interpolation(&mut self) -> Result<(), ParsingError> {
+        let token = self.advance().unwrap();
+        let line = token.position.line;
+        let mut rest = match token.token {
+            Token::StringFragment(s) => s,
+            _ => unreachable!(),
+        };
+        let mut parts = 0;
+        loop {
+            self.string_part(rest, &mut parts, line);
+            self.expression()?;
+            self.emit(OpCode::ToString, line);
+            self.concatenate(&mut parts, line);
+            let token = match self.advance() {
+                Some(token) => token,
+                None => {
+                    return Err(
+                        self.unexpected_end_of_file(vec![Expected::Token(Token::RightBrace)])
+                    )
+                }
+            };
+            rest = match token.token {
+                Token::StringFragment(s) => s,
+                Token::StringLiteral(s) => {
+                    self.string_part(s, &mut parts, line);
+                    return Ok(());
+                }
+                _ => {
+                    return Err(ParsingError::Unexpected(
+                        vec![Expected::Token(Token::RightBrace)],
+                        token.lexeme,
+                        token.position,
+                    ))
+                }
+            };
+        }
+    }
+
+    /// Pushes a literal part of an interpolation, unless it's empty
+    fn string_part(&mut self, part: String, parts: &mut usize, line: usize) {
+        if !part.is_empty() {
+            let constant = self.chunk.add_constant(Constant::String(part));
+            self.emit(OpCode::Constant(constant), line);
+            self.concatenate(parts, line);
+        }
+    }
+
+    /// Adds the part of an interpolation just pushed to the ones before
+    fn concatenate(&mut self, parts: &mut usize, line: usize) {
+        if *parts > 0 {
+            self.emit(OpCode::Binary(BinaryOp::Add), line);
+        }
+        *parts += 1;
+    }
+
     fn number(&mut self) -> Result<(), ParsingError> {
         let current = self.advance();
         let (value, line) = if let Some(ref t) = current {
@@ -274,12 +1286,134 @@ where
         Ok(())
     }
 
+    /// Reads or, when followed by `=`, assigns a variable
+    fn variable(&mut self) -> Result<(), ParsingError> {
+        let can_assign = self.can_assign;
+        let token = self.advance().unwrap();
+        let name = match token.token {
+            Token::Identifier(ref name) => name.clone(),
+            _ => unreachable!(),
+        };
+        let line = token.position.line;
+        let (get, set) = match self.resolve_local(&name, &token)? {
+            Some(slot) => (OpCode::GetLocal(slot), OpCode::SetLocal(slot)),
+            None => match self.resolve_upvalue(&name, &token)? {
+                Some(index) => (OpCode::GetUpvalue(index), OpCode::SetUpvalue(index)),
+                None => {
+                    let name = self.chunk.add_constant(Constant::String(name));
+                    (OpCode::GetGlobal(name), OpCode::SetGlobal(name))
+                }
+            },
+        };
+        if can_assign && self.advance_if(&Token::Equal) {
+            self.expression()?;
+            self.emit(set, line);
+        } else {
+            self.emit(get, line);
+        }
+        Ok(())
+    }
+
     fn grouping(&mut self) -> Result<(), ParsingError> {
         self.consume(&Token::LeftParen)?;
         self.expression()?;
         self.consume(&Token::RightParen)
     }
 
+    fn list(&mut self) -> Result<(), ParsingError> {
+        self.consume(&Token::LeftBracket)?;
+        let line = self.last_position.line;
+        let mut count = 0;
+        if self.peek().map(|t| &t.token) != Some(&Token::RightBracket) {
+            loop {
+                self.expression()?;
+                count += 1;
+                if self.peek().map(|t| &t.token) == Some(&Token::Comma) {
+                    self.advance();
+                } else {
+                    break;
+                }
+            }
+        }
+        self.consume(&Token::RightBracket)?;
+        self.emit(OpCode::List(count), line);
+        Ok(())
+    }
+
+    fn map(&mut self) -> Result<(), ParsingError> {
+        self.consume(&Token::LeftBrace)?;
+        let line = self.last_position.line;
+        let mut count = 0;
+        if self.peek().map(|t| &t.token) != Some(&Token::RightBrace) {
+            loop {
+                self.expression()?;
+                self.consume(&Token::Colon)?;
+                self.expression()?;
+                count += 1;
+                if self.peek().map(|t| &t.token) == Some(&Token::Comma) {
+                    self.advance();
+                } else {
+                    break;
+                }
+            }
+        }
+        self.consume(&Token::RightBrace)?;
+        self.emit(OpCode::Map(count), line);
+        Ok(())
+    }
+
+    fn call(&mut self) -> Result<(), ParsingError> {
+        self.consume(&Token::LeftParen)?;
+        let line = self.last_position.line;
+        let mut count = 0;
+        if !self.check(&Token::RightParen) {
+            loop {
+                let first = self.peek().map(|t| (t.lexeme.clone(), t.position));
+                self.expression()?;
+                if count == 8 {
+                    let (lexeme, position) = first.unwrap();
+                    return Err(ParsingError::Invalid(
+                        "Can't have more than 8 arguments.",
+                        lexeme,
+                        position,
+                    ));
+                }
+                count += 1;
+                if !self.advance_if(&Token::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(&Token::RightParen)?;
+        self.emit(OpCode::Call(count), line);
+        Ok(())
+    }
+
+    /// Only modules have properties in the VM, which can't be assigned
+    fn dot(&mut self) -> Result<(), ParsingError> {
+        self.consume(&Token::Dot)?;
+        let line = self.last_position.line;
+        let (name, _) = self.identifier()?;
+        let name = self.chunk.add_constant(Constant::String(name));
+        self.emit(OpCode::GetProperty(name), line);
+        Ok(())
+    }
+
+    fn index(&mut self) -> Result<(), ParsingError> {
+        let can_assign = self.can_assign;
+        self.consume(&Token::LeftBracket)?;
+        let line = self.last_position.line;
+        self.expression()?;
+        self.consume(&Token::RightBracket)?;
+        if can_assign && self.advance_if(&Token::Equal) {
+            self.expression()?;
+            self.emit(OpCode::SetIndex, line);
+        } else {
+            self.emit(OpCode::Index, line);
+        }
+        Ok(())
+    }
+
     fn unary(&mut self) -> Result<(), ParsingError> {
         let (opcode, line) = match self.advance() {
             Some(TokenWithContext {
@@ -307,14 +1441,14 @@ where
                 Token::Minus => BinaryOp::Subtract,
                 Token::Star => BinaryOp::Multiply,
                 Token::Slash => BinaryOp::Divide,
+                Token::Percent => BinaryOp::Modulo,
+                Token::TildeSlash => BinaryOp::IntegerDivide,
                 Token::EqualEqual => BinaryOp::Equals,
                 Token::BangEqual => BinaryOp::NotEqual,
                 Token::Greater => BinaryOp::Greater,
                 Token::GreaterEqual => BinaryOp::GreaterEqual,
                 Token::Less => BinaryOp::Less,
                 Token::LessEqual => BinaryOp::LessEqual,
-                Token::Or => BinaryOp::Or,
-                Token::And => BinaryOp::And,
                 _ => unreachable!(),
             };
             let (precedence, _, _) = Self::find_rule(&t.token);
@@ -326,6 +1460,78 @@ where
         self.emit(opcode, line);
         Ok(())
     }
+
+    /// The right operand is evaluated only if the left one is truthy,
+    /// otherwise the left operand is the result.
+    fn and(&mut self) -> Result<(), ParsingError> {
+        let line = match self.advance() {
+            Some(t) => t.position.line,
+            None => unreachable!(),
+        };
+        let end_jump = self.chunk.instruction_count();
+        self.emit(OpCode::JumpIfFalse(0), line);
+        self.emit(OpCode::Pop, line);
+        self.parse_precedence(Precedence::And.next())?;
+        self.chunk.patch_jump(end_jump);
+        Ok(())
+    }
+
+    /// The right operand is evaluated only if the left one is falsey,
+    /// otherwise the left operand is the result.
+    fn or(&mut self) -> Result<(), ParsingError> {
+        let line = match self.advance() {
+            Some(t) => t.position.line,
+            None => unreachable!(),
+        };
+        let else_jump = self.chunk.instruction_count();
+        self.emit(OpCode::JumpIfFalse(0), line);
+        let end_jump = self.chunk.instruction_count();
+        self.emit(OpCode::Jump(0), line);
+        self.chunk.patch_jump(else_jump);
+        self.emit(OpCode::Pop, line);
+        self.parse_precedence(Precedence::Or.next())?;
+        self.chunk.patch_jump(end_jump);
+        Ok(())
+    }
+}
+
+/// Finds the variable in the innermost of the functions that declares it,
+/// the last one, marking it captured. The functions in between capture
+/// it in turn, for the closures they create to have it.
+fn capture(
+    enclosing: &mut [Enclosing],
+    name: &str,
+    token: &TokenWithContext,
+) -> Result<Option<Capture>, ParsingError> {
+    let (function, outer) = match enclosing.split_last_mut() {
+        Some(split) => split,
+        None => return Ok(None),
+    };
+    match function.locals.iter().rposition(|local| local.name == name) {
+        Some(slot) if !function.locals[slot].initialized => Err(ParsingError::Invalid(
+            "Can't read local variable in its own initializer.",
+            token.lexeme.clone(),
+            token.position,
+        )),
+        Some(slot) => {
+            function.locals[slot].captured = true;
+            Ok(Some(Capture::Local(slot)))
+        }
+        None => Ok(capture(outer, name, token)?
+            .map(|capture| Capture::Upvalue(add_upvalue(&mut function.upvalues, capture)))),
+    }
+}
+
+/// The index of the capture among the upvalues of a function, where
+/// it's added unless it's already there
+fn add_upvalue(upvalues: &mut Vec<Capture>, capture: Capture) -> usize {
+    match upvalues.iter().position(|&other| other == capture) {
+        Some(index) => index,
+        None => {
+            upvalues.push(capture);
+            upvalues.len() - 1
+        }
+    }
 }
 
 /// Compiles a text producing either the corresponding chunk of bytecode
@@ -334,12 +1540,11 @@ where
 /// "recovery logic".
 pub fn compile(text: &str) -> Result<Chunk, Vec<CompilationError>> {
     let mut chunk = Chunk::default();
-    let tokens = scan_into_iterator(text);
     {
-        {
-            let parser = Parser::new(&mut chunk, tokens);
+        if !Parser::new(&mut chunk, scan_into_iterator(text)).parse_standalone_expression() {
+            chunk = Chunk::default();
+            let parser = Parser::new(&mut chunk, scan_into_iterator(text));
             let _ = parser.parse()?;
-            // TODO: assert that we consumed everything
         }
         // Line is meaningless, but this is temporary to see some results
         // while the implementation is in progress.
@@ -347,3 +1552,106 @@ pub fn compile(text: &str) -> Result<Chunk, Vec<CompilationError>> {
     }
     Ok(chunk)
 }
+
+#[cfg(test)]
+mod tests {
+    use vm::compiler::*;
+
+    #[test]
+    fn unexpected_token_reports_what_was_expected() {
+        let errors = compile("(1 + 2;").unwrap_err();
+        match errors[0] {
+            CompilationError::ParsingError(ParsingError::Unexpected(
+                ref expected,
+                ref lexeme,
+                _,
+            )) => {
+                assert_eq!(&vec![Expected::Token(Token::RightParen)], expected);
+                assert_eq!(";", lexeme);
+            }
+            ref error => panic!("Unexpected error {:?}", error),
+        }
+    }
+
+    #[test]
+    fn end_of_file_points_at_the_last_token() {
+        let errors = compile("1 +\n 2 *").unwrap_err();
+        match errors[0] {
+            CompilationError::ParsingError(ParsingError::UnexpectedEndOfFile(
+                ref expected,
+                ref position,
+            )) => {
+                assert_eq!(&vec![Expected::Expression], expected);
+                assert_eq!(2, position.line);
+            }
+            ref error => panic!("Unexpected error {:?}", error),
+        }
+    }
+
+    #[test]
+    fn unclosed_list() {
+        let errors = compile("[1, 2").unwrap_err();
+        match errors[0] {
+            CompilationError::ParsingError(ParsingError::UnexpectedEndOfFile(ref expected, _)) => {
+                assert_eq!(&vec![Expected::Token(Token::RightBracket)], expected);
+            }
+            ref error => panic!("Unexpected error {:?}", error),
+        }
+    }
+
+    #[test]
+    fn map_entries_need_a_colon() {
+        let errors = compile("var map = {1, 2};").unwrap_err();
+        match errors[0] {
+            CompilationError::ParsingError(ParsingError::Unexpected(ref expected, _, _)) => {
+                assert_eq!(&vec![Expected::Token(Token::Colon)], expected);
+            }
+            ref error => panic!("Unexpected error {:?}", error),
+        }
+    }
+
+    #[test]
+    fn interpolations_end_with_a_brace() {
+        let errors = compile("\"a${1 2}\"").unwrap_err();
+        assert_eq!(1, errors.len());
+        match errors[0] {
+            CompilationError::ParsingError(ParsingError::Unexpected(ref expected, _, _)) => {
+                assert_eq!(&vec![Expected::Token(Token::RightBrace)], expected);
+            }
+            ref error => panic!("Unexpected error {:?}", error),
+        }
+    }
+
+    #[test]
+    fn loops_can_only_be_left_before_finally_blocks() {
+        let invalid = |text| match compile(text).unwrap_err()[0] {
+            CompilationError::ParsingError(ParsingError::Invalid(reason, _, _)) => reason,
+            ref error => panic!("Unexpected error {:?}", error),
+        };
+        assert_eq!(
+            "Can't use 'break' outside of a loop.",
+            invalid("if (true) break;")
+        );
+        assert_eq!(
+            "Can't use 'continue' outside of a loop.",
+            invalid("{ continue; }")
+        );
+        assert_eq!(
+            "Can't leave a try statement with a finally block.",
+            invalid("while (true) try { break; } finally {}")
+        );
+        assert_eq!(
+            "Can't leave a finally block.",
+            invalid("while (true) try {} finally { continue; }")
+        );
+        assert!(compile("while (true) try { break; } catch (e) {}").is_ok());
+        assert!(compile("try { while (true) break; } finally {}").is_ok());
+    }
+
+    #[test]
+    fn unsupported_tokens_are_reported() {
+        assert!(compile("print 1").is_err());
+        assert!(compile("class A {}").is_err());
+        assert!(compile("import \"a.lox\"").is_err());
+    }
+}
@@ -1,7 +1,23 @@
-use std::f64::EPSILON;
+use fnv::FnvHashMap;
+use map::{Key, OrderedMap};
+use natives::{Capabilities, Native, NativeError, NativeValue, FILE_SYSTEM, NATIVES, OS};
+use number;
+use output;
+use std::cell::RefCell;
+use std::fmt;
 use std::io::{Error, LineWriter, Write};
+use std::mem;
+use std::ptr;
 use std::rc::Rc;
-use vm::bytecode::{disassemble_instruction, BinaryOp, Chunk, Constant, OpCode};
+use vm::bytecode::{
+    disassemble_instruction, BinaryOp, Capture, Chunk, Constant, Function, Handler, HandlerKind,
+    OpCode,
+};
+use vm::register;
+use vm::value::Packed;
+
+/// How many calls can be nested, as each one has a frame
+const FRAMES_MAX: usize = 1024;
 
 /// A Lox value, which could be either a value
 /// or a reference type.
@@ -12,13 +28,406 @@ pub enum Value {
     Nil,
     Object(ObjectReference),
 }
+
+impl Value {
+    /// Like in Ruby, only nil and false are falsey
+    pub fn is_falsey(&self) -> bool {
+        matches!(*self, Value::Nil | Value::Bool(false))
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(f, &mut vec![])
+    }
+}
+
+/// The lists and maps being printed, as those containing themselves would
+/// be visited forever
+type Visiting = Vec<*const ObjectValue>;
+/// The pairs of lists or maps being compared, for the same reason
+type Comparing = Vec<(*const ObjectValue, *const ObjectValue)>;
+
+impl Value {
+    fn write(&self, f: &mut fmt::Formatter, visiting: &mut Visiting) -> fmt::Result {
+        match *self {
+            Value::Number(n) => f.write_str(&number::format(n)),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Nil => f.write_str("nil"),
+            Value::Object(ref object) => object.write(f, visiting),
+        }
+    }
+
+    fn equals(&self, other: &Value, comparing: &mut Comparing) -> bool {
+        match (self, other) {
+            (Value::Object(l), Value::Object(r)) => l.equals(r, comparing),
+            _ => self == other,
+        }
+    }
+}
+
 /// Reference types.
-/// TODO: this should probably be a refcell, we will want to mutate objects
 /// TODO: this should be a reference to a (GcMetadata, ObjectValue)
 pub type ObjectReference = Rc<ObjectValue>;
-#[derive(Debug, Clone, PartialEq)]
 pub enum ObjectValue {
     String(String),
+    /// Shared and changed in place, compared by content
+    List(RefCell<Vec<Value>>),
+    /// Shared and changed in place like lists, equal when the entries are
+    Map(RefCell<OrderedMap<Value>>),
+    Native(Native),
+    /// `len` and the natives changing lists and maps
+    Collection(CollectionNative),
+    /// `fs` or `os`, with what they export by name
+    Module(String, FnvHashMap<String, Value>),
+    /// What `os.args()` gives as a list: the command line arguments
+    Arguments(Vec<String>),
+    /// A function of the stack VM
+    Closure(Closure),
+    /// A function of the register VM, which has its own bytecode
+    RegisterClosure(Closure<register::bytecode::Chunk>),
+}
+
+/// A variable captured by closures. It stays in its slot, on the stack or
+/// in a register, while the function declaring it runs, then it's moved
+/// into the upvalue when it leaves the slot, for the closures to keep it.
+pub enum Upvalue {
+    /// The slot, counted from the bottom of the stack or from the first
+    /// register
+    Open(usize),
+    Closed(Value),
+}
+
+pub type UpvalueReference = Rc<RefCell<Upvalue>>;
+
+/// A function with the variables it captured, which the closures created
+/// by the same function share when it's the same variable
+pub struct Closure<C = Chunk> {
+    pub function: Rc<Function<C>>,
+    pub upvalues: Rc<[UpvalueReference]>,
+}
+
+impl<C> Clone for Closure<C> {
+    fn clone(&self) -> Closure<C> {
+        Closure {
+            function: self.function.clone(),
+            upvalues: self.upvalues.clone(),
+        }
+    }
+}
+
+/// Creates a closure of the function. It captures the locals it needs in
+/// the frame starting at the given slot, sharing the upvalues already
+/// open for them, and the variables it needs among the ones the closure
+/// being run captured.
+pub fn closure<C>(
+    function: &Rc<Function<C>>,
+    base: usize,
+    enclosing: Option<&Closure<C>>,
+    open_upvalues: &mut Vec<UpvalueReference>,
+) -> Result<Closure<C>, RuntimeError> {
+    let upvalues = function
+        .upvalues
+        .iter()
+        .map(|&capture| match capture {
+            Capture::Local(slot) => Ok(open_upvalue(open_upvalues, base + slot)),
+            Capture::Upvalue(index) => enclosing
+                .and_then(|closure| closure.upvalues.get(index))
+                .cloned()
+                .ok_or(RuntimeError::UpvalueOutOfBound),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Closure {
+        function: function.clone(),
+        upvalues: upvalues.into(),
+    })
+}
+
+fn open_upvalue(open_upvalues: &mut Vec<UpvalueReference>, slot: usize) -> UpvalueReference {
+    let open = open_upvalues
+        .iter()
+        .find(|upvalue| matches!(*upvalue.borrow(), Upvalue::Open(s) if s == slot));
+    match open {
+        Some(upvalue) => upvalue.clone(),
+        None => {
+            let upvalue = Rc::new(RefCell::new(Upvalue::Open(slot)));
+            open_upvalues.push(upvalue.clone());
+            upvalue
+        }
+    }
+}
+
+/// Fails unless the closure can be called with the given number of
+/// arguments, while the given number of calls are being run
+pub fn check_call<C>(closure: &Closure<C>, count: usize, calls: usize) -> Result<(), RuntimeError> {
+    if count != closure.function.arity {
+        return Err(RuntimeError::WrongNumberOfArguments(
+            closure.function.arity,
+            count,
+        ));
+    }
+    if calls == FRAMES_MAX {
+        return Err(RuntimeError::StackOverflow);
+    }
+    Ok(())
+}
+
+/// Moves the variables in the given slot and above, which are about to be
+/// popped, out of their slots and into their upvalues
+pub fn close_upvalues<F>(open_upvalues: &mut Vec<UpvalueReference>, first: usize, value: F)
+where
+    F: Fn(usize) -> Value,
+{
+    open_upvalues.retain(|upvalue| {
+        let slot = match *upvalue.borrow() {
+            Upvalue::Open(slot) if slot >= first => slot,
+            _ => return true,
+        };
+        *upvalue.borrow_mut() = Upvalue::Closed(value(slot));
+        false
+    });
+}
+
+impl ObjectValue {
+    /// A list or a map already being printed is shown as `[...]` or `{...}`
+    fn write(&self, f: &mut fmt::Formatter, visiting: &mut Visiting) -> fmt::Result {
+        let is_collection = matches!(*self, ObjectValue::List(_) | ObjectValue::Map(_));
+        if is_collection && visiting.contains(&(self as *const ObjectValue)) {
+            return f.write_str(match *self {
+                ObjectValue::List(_) => "[...]",
+                _ => "{...}",
+            });
+        }
+        match *self {
+            ObjectValue::String(ref s) => f.write_str(s),
+            ObjectValue::List(ref elements) => {
+                visiting.push(self);
+                f.write_str("[")?;
+                for (i, element) in elements.borrow().iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    element.write(f, visiting)?;
+                }
+                visiting.pop();
+                f.write_str("]")
+            }
+            ObjectValue::Map(ref map) => {
+                visiting.push(self);
+                f.write_str("{")?;
+                for (i, (key, value)) in map.borrow().iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{}: ", key)?;
+                    value.write(f, visiting)?;
+                }
+                visiting.pop();
+                f.write_str("}")
+            }
+            ObjectValue::Native(native) => f.write_str(native.name()),
+            ObjectValue::Collection(native) => f.write_str(native.name()),
+            ObjectValue::Module(ref name, _) => write!(f, "<module {}>", name),
+            ObjectValue::Arguments(_) => f.write_str("args"),
+            ObjectValue::Closure(ref closure) => write!(f, "{}", closure.function),
+            ObjectValue::RegisterClosure(ref closure) => write!(f, "{}", closure.function),
+        }
+    }
+
+    /// Two lists or maps already being compared are taken as equal, the
+    /// other elements deciding whether they are
+    fn equals(&self, other: &ObjectValue, comparing: &mut Comparing) -> bool {
+        if ptr::eq(self, other) {
+            return true;
+        }
+        let pair = (self as *const ObjectValue, other as *const ObjectValue);
+        match (self, other) {
+            (ObjectValue::List(_), ObjectValue::List(_))
+            | (ObjectValue::Map(_), ObjectValue::Map(_))
+                if comparing.contains(&pair) =>
+            {
+                true
+            }
+            (ObjectValue::String(l), ObjectValue::String(r)) => l == r,
+            (ObjectValue::List(l), ObjectValue::List(r)) => {
+                comparing.push(pair);
+                let (l, r) = (l.borrow(), r.borrow());
+                let equal = l.len() == r.len()
+                    && l.iter().zip(r.iter()).all(|(l, r)| l.equals(r, comparing));
+                comparing.pop();
+                equal
+            }
+            (ObjectValue::Map(l), ObjectValue::Map(r)) => {
+                comparing.push(pair);
+                let (l, r) = (l.borrow(), r.borrow());
+                let equal = l.len() == r.len()
+                    && l.iter().all(|(key, value)| {
+                        r.get(key)
+                            .is_some_and(|other| value.equals(other, comparing))
+                    });
+                comparing.pop();
+                equal
+            }
+            (ObjectValue::Native(l), ObjectValue::Native(r)) => l == r,
+            (ObjectValue::Collection(l), ObjectValue::Collection(r)) => l == r,
+            (ObjectValue::Module(l, l_exports), ObjectValue::Module(r, r_exports)) => {
+                l == r && l_exports == r_exports
+            }
+            (ObjectValue::Arguments(l), ObjectValue::Arguments(r)) => l == r,
+            _ => false,
+        }
+    }
+}
+
+/// Lists and maps are compared by content, like strings
+impl PartialEq for ObjectValue {
+    fn eq(&self, other: &ObjectValue) -> bool {
+        self.equals(other, &mut vec![])
+    }
+}
+
+/// Lists and maps are shown like `print` shows them, as they can contain
+/// themselves
+impl fmt::Debug for ObjectValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ObjectValue::String(ref s) => write!(f, "String({:?})", s),
+            _ => self.write(f, &mut vec![]),
+        }
+    }
+}
+
+/// The natives working on lists and maps, which need the representation
+/// of the VMs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CollectionNative {
+    Length,
+    Push,
+    Pop,
+    Insert,
+    Remove,
+    Slice,
+    Keys,
+    Values,
+    Has,
+}
+
+pub const COLLECTION_NATIVES: [CollectionNative; 9] = [
+    CollectionNative::Length,
+    CollectionNative::Push,
+    CollectionNative::Pop,
+    CollectionNative::Insert,
+    CollectionNative::Remove,
+    CollectionNative::Slice,
+    CollectionNative::Keys,
+    CollectionNative::Values,
+    CollectionNative::Has,
+];
+
+impl CollectionNative {
+    pub fn name(self) -> &'static str {
+        match self {
+            CollectionNative::Length => "len",
+            CollectionNative::Push => "push",
+            CollectionNative::Pop => "pop",
+            CollectionNative::Insert => "insert",
+            CollectionNative::Remove => "remove",
+            CollectionNative::Slice => "slice",
+            CollectionNative::Keys => "keys",
+            CollectionNative::Values => "values",
+            CollectionNative::Has => "has",
+        }
+    }
+
+    fn arity(self) -> usize {
+        match self {
+            CollectionNative::Length
+            | CollectionNative::Pop
+            | CollectionNative::Keys
+            | CollectionNative::Values => 1,
+            CollectionNative::Push | CollectionNative::Remove | CollectionNative::Has => 2,
+            CollectionNative::Insert | CollectionNative::Slice => 3,
+        }
+    }
+
+    /// Like the natives of the tree-walker, the collection comes first
+    fn call(self, arguments: &[Value]) -> Result<Value, RuntimeError> {
+        if arguments.len() != self.arity() {
+            return Err(RuntimeError::WrongNumberOfArguments(
+                self.arity(),
+                arguments.len(),
+            ));
+        }
+        match arguments[0] {
+            Value::Object(ref object) => match **object {
+                ObjectValue::List(ref elements) => {
+                    self.call_on_list(&mut elements.borrow_mut(), arguments)
+                }
+                ObjectValue::Map(ref map) => self.call_on_map(&mut map.borrow_mut(), arguments),
+                _ if self == CollectionNative::Length => length(&arguments[0]),
+                _ => Err(RuntimeError::NotACollection(arguments[0].clone())),
+            },
+            ref value => Err(RuntimeError::NotACollection(value.clone())),
+        }
+    }
+
+    fn call_on_list(
+        self,
+        elements: &mut Vec<Value>,
+        arguments: &[Value],
+    ) -> Result<Value, RuntimeError> {
+        // Elements can be inserted, or a slice can end, right after the last one
+        let length = elements.len();
+        match self {
+            CollectionNative::Length => Ok(Value::Number(length as f64)),
+            CollectionNative::Push => {
+                elements.push(arguments[1].clone());
+                Ok(Value::Nil)
+            }
+            CollectionNative::Pop => elements
+                .pop()
+                .ok_or_else(|| RuntimeError::InvalidArgument(arguments[0].clone())),
+            CollectionNative::Insert => {
+                let index = list_index(&arguments[1], length + 1)?;
+                elements.insert(index, arguments[2].clone());
+                Ok(Value::Nil)
+            }
+            CollectionNative::Remove => {
+                let index = list_index(&arguments[1], length)?;
+                Ok(elements.remove(index))
+            }
+            CollectionNative::Slice => {
+                let start = list_index(&arguments[1], length + 1)?;
+                let end = list_index(&arguments[2], length + 1)?;
+                if start > end {
+                    return Err(RuntimeError::InvalidIndex(arguments[2].clone()));
+                }
+                Ok(Value::list(elements[start..end].to_vec()))
+            }
+            _ => Err(RuntimeError::InvalidArgument(arguments[0].clone())),
+        }
+    }
+
+    fn call_on_map(
+        self,
+        map: &mut OrderedMap<Value>,
+        arguments: &[Value],
+    ) -> Result<Value, RuntimeError> {
+        match self {
+            CollectionNative::Length => Ok(Value::Number(map.len() as f64)),
+            CollectionNative::Keys => Ok(Value::list(
+                map.iter().map(|(key, _)| key_value(key)).collect(),
+            )),
+            CollectionNative::Values => Ok(Value::list(
+                map.iter().map(|(_, value)| value.clone()).collect(),
+            )),
+            CollectionNative::Has => Ok(Value::Bool(map.contains_key(&map_key(&arguments[1])?))),
+            CollectionNative::Remove => map
+                .remove(&map_key(&arguments[1])?)
+                .ok_or_else(|| RuntimeError::UndefinedKey(arguments[1].clone())),
+            _ => Err(RuntimeError::InvalidArgument(arguments[0].clone())),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -32,13 +441,284 @@ pub enum RuntimeError {
     // at every instruction
     InstructionOutOfBound,
     ValueOutOfBound,
-    TypeError,
+    UnaryMinusTypeMismatch,
+    BinaryOperatorTypeMismatch(BinaryOp),
+    /// Only lists and maps can be indexed
+    NotACollection(Value),
+    /// Lists can only be indexed by whole numbers within their bounds
+    InvalidIndex(Value),
+    /// Only nil, booleans, numbers and strings can be keys of maps
+    UnhashableKey(Value),
+    UndefinedKey(Value),
+    UndefinedVariable(String),
+    /// Variables are named by constants, which have to be strings
+    NotAName,
+    /// Closures are created from constants, which have to be functions
+    NotAFunction,
+    UpvalueOutOfBound,
+    NotCallable,
+    /// Only modules have properties in the VM, but the wording is the one
+    /// of the tree-walker
+    NotAnInstance,
+    UndefinedProperty(String),
+    /// The number of arguments expected and the one given
+    WrongNumberOfArguments(usize, usize),
+    /// Too many calls are nested
+    StackOverflow,
+    InvalidArgument(Value),
+    /// A native failed to access the system
+    Io(String),
+    /// A value thrown by the program
+    Thrown(Value),
+    /// The program called `exit` with the code. Nothing catches it, and
+    /// nothing runs after it, not even `finally` blocks.
+    Exit(i32),
+    /// The line of the instruction that raised the error
+    AtLine(usize, Box<RuntimeError>),
+}
+
+impl RuntimeError {
+    /// The error without its line
+    fn without_line(self) -> RuntimeError {
+        match self {
+            RuntimeError::AtLine(_, error) => *error,
+            error => error,
+        }
+    }
+
+    /// What is shown for the error: its message, then the line it comes
+    /// from if it's known
+    pub fn report(&self) -> Vec<String> {
+        let mut lines = vec![self.to_string()];
+        if let RuntimeError::AtLine(line, _) = *self {
+            lines.push(format!("[line {}]", line));
+        }
+        lines
+    }
+}
+
+/// The wording of the tree-walker, itself the one of the reference
+/// implementation where it has one
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RuntimeError::TracingError(ref error) => write!(f, "Tracing failed: {}", error),
+            RuntimeError::StackUnderflow => f.write_str("Invalid bytecode: stack underflow."),
+            RuntimeError::InstructionOutOfBound => {
+                f.write_str("Invalid bytecode: no instruction left.")
+            }
+            RuntimeError::ValueOutOfBound => f.write_str("Invalid bytecode: undefined constant."),
+            RuntimeError::UnaryMinusTypeMismatch => f.write_str("Operand must be a number."),
+            RuntimeError::BinaryOperatorTypeMismatch(BinaryOp::Add) => {
+                f.write_str("Operands must be two numbers or two strings.")
+            }
+            RuntimeError::BinaryOperatorTypeMismatch(_) => f.write_str("Operands must be numbers."),
+            RuntimeError::NotACollection(ref value) => {
+                write!(f, "{} is not a list or a map", value)
+            }
+            RuntimeError::InvalidIndex(ref value) => write!(f, "Invalid index {}", value),
+            RuntimeError::UnhashableKey(ref value) => write!(f, "{} can't be a key", value),
+            RuntimeError::UndefinedKey(ref value) => write!(f, "Undefined key {}", value),
+            RuntimeError::UndefinedVariable(ref name) => {
+                write!(f, "Undefined variable '{}'.", name)
+            }
+            RuntimeError::NotAName => f.write_str("Invalid bytecode: the constant isn't a name."),
+            RuntimeError::NotAFunction => {
+                f.write_str("Invalid bytecode: the constant isn't a function.")
+            }
+            RuntimeError::UpvalueOutOfBound => f.write_str("Invalid bytecode: undefined upvalue."),
+            RuntimeError::NotCallable => f.write_str("Can only call functions and classes."),
+            RuntimeError::NotAnInstance => f.write_str("Only instances have properties."),
+            RuntimeError::UndefinedProperty(ref name) => {
+                write!(f, "Undefined property '{}'.", name)
+            }
+            RuntimeError::WrongNumberOfArguments(expected, given) => {
+                write!(f, "Expected {} arguments but got {}.", expected, given)
+            }
+            RuntimeError::StackOverflow => f.write_str("Stack overflow."),
+            RuntimeError::InvalidArgument(ref value) => write!(f, "Invalid argument {}", value),
+            RuntimeError::Io(ref message) => f.write_str(message),
+            RuntimeError::Thrown(ref value) => write!(f, "{}", value),
+            RuntimeError::Exit(code) => write!(f, "Exit with code {}", code),
+            RuntimeError::AtLine(_, ref error) => write!(f, "{}", error),
+        }
+    }
+}
+
+/// The global variables, which outlive the chunks run with them so that
+/// the REPL can define them one line at a time.
+/// They are packed like the values on the stack.
+pub struct Globals {
+    values: FnvHashMap<String, Packed>,
+}
+
+/// Only the natives every program gets are defined
+impl Default for Globals {
+    fn default() -> Globals {
+        let mut globals = Globals {
+            values: FnvHashMap::default(),
+        };
+        for &native in &NATIVES {
+            globals.define(
+                native.name(),
+                Value::Object(Rc::new(ObjectValue::Native(native))).into(),
+            );
+        }
+        for &native in &COLLECTION_NATIVES {
+            globals.define(
+                native.name(),
+                Value::Object(Rc::new(ObjectValue::Collection(native))).into(),
+            );
+        }
+        globals
+    }
+}
+
+impl Globals {
+    /// Defines the modules and the natives accessing the system that the
+    /// capabilities allow
+    pub fn define_system_access(&mut self, capabilities: Capabilities, arguments: &[String]) {
+        let native = |native: Native| Value::Object(Rc::new(ObjectValue::Native(native)));
+        let module = |name: &str, natives: &[Native], extra: Option<(&str, ObjectValue)>| {
+            let mut exports: FnvHashMap<String, Value> = natives
+                .iter()
+                .map(|&n| (n.name().to_string(), native(n)))
+                .collect();
+            if let Some((extra_name, object)) = extra {
+                exports.insert(extra_name.into(), Value::Object(Rc::new(object)));
+            }
+            Packed::from(Value::Object(Rc::new(ObjectValue::Module(
+                name.into(),
+                exports,
+            ))))
+        };
+        if capabilities.file_system {
+            self.define("fs", module("fs", &FILE_SYSTEM, None));
+        }
+        if capabilities.os {
+            let args = ObjectValue::Arguments(arguments.to_vec());
+            self.define("os", module("os", &OS, Some(("args", args))));
+        }
+        for &(allowed, n) in &[
+            (capabilities.input, Native::Input),
+            (capabilities.exit, Native::Exit),
+        ] {
+            if allowed {
+                self.define(n.name(), native(n).into());
+            }
+        }
+    }
+
+    pub fn define(&mut self, name: &str, value: Packed) {
+        self.values.insert(name.into(), value);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Packed> {
+        self.values.get(name)
+    }
+
+    /// Changes the value of a variable, failing when it isn't defined
+    pub fn assign(&mut self, name: &str, value: Packed) -> Result<(), RuntimeError> {
+        match self.values.get_mut(name) {
+            Some(variable) => {
+                *variable = value;
+                Ok(())
+            }
+            None => Err(RuntimeError::UndefinedVariable(name.into())),
+        }
+    }
+
+    /// The names of the variables and the descriptions of their values,
+    /// sorted by name
+    pub fn describe(&self) -> Vec<(String, String)> {
+        let mut globals: Vec<(String, String)> = self
+            .values
+            .iter()
+            .map(|(name, value)| (name.clone(), value.to_value().to_string()))
+            .collect();
+        globals.sort();
+        globals
+    }
+}
+
+/// The name of a variable, kept in a constant
+pub fn name<C>(constant: Option<&Constant<C>>) -> Result<&str, RuntimeError> {
+    match constant {
+        Some(Constant::String(ref name)) => Ok(name),
+        Some(_) => Err(RuntimeError::NotAName),
+        None => Err(RuntimeError::ValueOutOfBound),
+    }
+}
+
+/// A call being run, by either VM
+pub struct Frame<C = Chunk> {
+    /// The closure called, none for the script
+    pub closure: Option<Closure<C>>,
+    pub program_counter: usize,
+    /// The slot of the closure, followed by its arguments and its locals,
+    /// whose slots are counted from there
+    pub base: usize,
+    /// How many errors kept for `finally` blocks belong to the callers
+    pub kept_errors: usize,
+}
+
+impl<C> Frame<C> {
+    /// The frame of the script
+    pub fn script() -> Frame<C> {
+        Frame {
+            closure: None,
+            program_counter: 0,
+            base: 0,
+            kept_errors: 0,
+        }
+    }
+
+    /// The frame of a call to the closure, whose slot is `base`
+    pub fn call(closure: Closure<C>, base: usize, kept_errors: usize) -> Frame<C> {
+        Frame {
+            closure: Some(closure),
+            program_counter: 0,
+            base,
+            kept_errors,
+        }
+    }
+
+    /// The function being run, none for the script. Its chunk is shared
+    /// with the closure for the time it runs.
+    pub fn function(&self) -> Option<Rc<Function<C>>> {
+        self.closure
+            .as_ref()
+            .map(|closure| closure.function.clone())
+    }
+
+    /// The variable the closure being run captured with the given index
+    pub fn upvalue(&self, index: usize) -> Result<UpvalueReference, RuntimeError> {
+        self.closure
+            .as_ref()
+            .and_then(|closure| closure.upvalues.get(index))
+            .cloned()
+            .ok_or(RuntimeError::UpvalueOutOfBound)
+    }
 }
 
 struct Vm<'a> {
+    /// The chunk of the script
     chunk: &'a Chunk,
-    program_counter: usize,
-    stack: Vec<Value>,
+    globals: &'a mut Globals,
+    frame: Frame,
+    /// The frames of the callers, innermost last
+    frames: Vec<Frame>,
+    stack: Vec<Packed>,
+    /// The upvalues of the variables still on the stack
+    open_upvalues: Vec<UpvalueReference>,
+    /// The errors the `finally` blocks being run raise again at their
+    /// end, none for those entered without one
+    kept_errors: Vec<Option<RuntimeError>>,
+    /// The constants of the script, turned into values once. Those of
+    /// the functions are turned into values when they're used.
+    constants: Vec<Packed>,
+    /// The value returned by the chunk, if any
+    result: Option<Value>,
     /// Allocated objects so the GC can keep track of them.
     /// The variants of ObjectReference is a ref-counted
     /// pointer to their actual data. That is not enough to
@@ -49,16 +729,38 @@ struct Vm<'a> {
 }
 
 impl<'a> Vm<'a> {
-    fn new(chunk: &'a Chunk) -> Vm<'a> {
-        Vm {
+    fn new(chunk: &'a Chunk, globals: &'a mut Globals) -> Vm<'a> {
+        let mut vm = Vm {
             chunk,
-            program_counter: 0,
+            globals,
+            frame: Frame::script(),
+            frames: vec![],
             stack: vec![],
+            open_upvalues: vec![],
+            kept_errors: vec![],
+            constants: vec![],
+            result: None,
             objects: vec![],
+        };
+        for offset in 0..chunk.values_count() {
+            let constant = vm.constant(chunk.get_value(offset));
+            vm.constants.push(constant);
+        }
+        vm
+    }
+
+    fn constant(&mut self, constant: &Constant) -> Packed {
+        match *constant {
+            Constant::Number(n) => Packed::number(n),
+            Constant::Bool(b) => Packed::boolean(b),
+            Constant::Nil => Packed::nil(),
+            Constant::String(ref s) => Value::Object(self.allocate_string(s.clone())).into(),
+            // Only used by `Closure`, which doesn't need a value
+            Constant::Function(_) => Packed::nil(),
         }
     }
 
-    fn pop(&mut self) -> Result<Value, RuntimeError> {
+    fn pop(&mut self) -> Result<Packed, RuntimeError> {
         self.stack.pop().ok_or(RuntimeError::StackUnderflow)
     }
 
@@ -72,6 +774,84 @@ impl<'a> Vm<'a> {
         o
     }
 
+    fn allocate_list(&mut self, elements: Vec<Value>) -> ObjectReference {
+        let o = Rc::new(ObjectValue::List(RefCell::new(elements)));
+        self.objects.push(o.clone());
+        o
+    }
+
+    fn allocate_map(&mut self, map: OrderedMap<Value>) -> ObjectReference {
+        let o = Rc::new(ObjectValue::Map(RefCell::new(map)));
+        self.objects.push(o.clone());
+        o
+    }
+
+    fn allocate_closure(&mut self, closure: Closure) -> ObjectReference {
+        let o = Rc::new(ObjectValue::Closure(closure));
+        self.objects.push(o.clone());
+        o
+    }
+
+    /// Moves the variables in the given slot and above out of the stack,
+    /// see `close_upvalues`
+    fn close_upvalues(&mut self, first: usize) {
+        let stack = &self.stack;
+        close_upvalues(&mut self.open_upvalues, first, |slot| {
+            stack.get(slot).map_or(Value::Nil, Packed::to_value)
+        });
+    }
+
+    /// Runs the closure in a frame of its own, the arguments being on top
+    /// of the stack, above the closure
+    fn call_closure(&mut self, closure: Closure, count: usize) -> Result<(), RuntimeError> {
+        check_call(&closure, count, self.frames.len())?;
+        let frame = Frame::call(
+            closure,
+            self.stack.len() - count - 1,
+            self.kept_errors.len(),
+        );
+        self.frames.push(mem::replace(&mut self.frame, frame));
+        Ok(())
+    }
+
+    /// Goes back to the caller, popping the slots of the function being
+    /// run
+    fn leave_frame(&mut self, caller: Frame) {
+        let base = self.frame.base;
+        self.close_upvalues(base);
+        self.stack.truncate(base);
+        self.kept_errors.truncate(self.frame.kept_errors);
+        self.frame = caller;
+    }
+
+    /// What a catch handler gets: thrown values are given as they are,
+    /// the other errors as a map with their message and line
+    fn caught_value(&mut self, error: RuntimeError) -> Value {
+        caught_value(error, |object| {
+            let o = Rc::new(object);
+            self.objects.push(o.clone());
+            o
+        })
+    }
+
+    /// Unwinds the stack to where the handler expects it, and has it
+    /// deal with the error
+    fn handle(&mut self, error: RuntimeError, handler: Handler) {
+        let depth = self.frame.base + handler.stack_depth;
+        self.close_upvalues(depth);
+        self.stack.truncate(depth);
+        self.kept_errors
+            .truncate(self.frame.kept_errors + handler.kept_errors);
+        match handler.kind {
+            HandlerKind::Catch => {
+                let value = self.caught_value(error);
+                self.stack.push(value.into());
+            }
+            HandlerKind::Finally => self.kept_errors.push(Some(error)),
+        }
+        self.frame.program_counter = handler.target;
+    }
+
     /// Interprets the next instruction.
     /// The execution of this function have some side effects including:
     ///  * update of the program counter to have it point to the next
@@ -81,106 +861,306 @@ impl<'a> Vm<'a> {
     /// This function true if there are other instructions left to execute
     /// or false if we're done interpreting the chunk.
     fn interpret_next(&mut self) -> Result<bool, RuntimeError> {
-        self.program_counter += 1;
-        if self.program_counter > self.chunk.instruction_count() {
+        let function = self.frame.function();
+        let chunk = function.as_ref().map_or(self.chunk, |f| &f.chunk);
+        self.frame.program_counter += 1;
+        if self.frame.program_counter > chunk.instruction_count() {
             return Err(RuntimeError::InstructionOutOfBound);
         }
-        match self.chunk.get(self.program_counter - 1) {
+        match chunk.get(self.frame.program_counter - 1) {
             OpCode::Return => {
-                let value = self.stack.pop();
-                println!{"{:?}", value};
-                // Temporarily changed the meaning
-                return Ok(false);
+                let result = self.stack.pop();
+                match self.frames.pop() {
+                    Some(caller) => {
+                        self.leave_frame(caller);
+                        self.stack.push(result.unwrap_or_else(Packed::nil));
+                    }
+                    None => {
+                        // Temporarily changed the meaning
+                        self.result = result.map(|value| value.to_value());
+                        return Ok(false);
+                    }
+                }
             }
             OpCode::Constant(offset) => {
-                if offset >= self.chunk.values_count() {
-                    return Err(RuntimeError::ValueOutOfBound);
-                }
-                let value = match self.chunk.get_value(offset) {
-                    Constant::Number(n) => Value::Number(*n),
-                    Constant::Bool(b) => Value::Bool(*b),
-                    Constant::Nil => Value::Nil,
-                    Constant::String(ref s) => Value::Object(self.allocate_string(s.clone())),
+                let value = match function {
+                    Some(_) => {
+                        let constant = chunk
+                            .find_value(offset)
+                            .ok_or(RuntimeError::ValueOutOfBound)?;
+                        self.constant(constant)
+                    }
+                    None => self
+                        .constants
+                        .get(offset)
+                        .ok_or(RuntimeError::ValueOutOfBound)?
+                        .clone(),
                 };
                 self.stack.push(value)
             }
             OpCode::Negate => {
-                match self.pop()? {
-                    Value::Number(n) => self.stack.push(Value::Number(-n)),
-                    _ => return Err(RuntimeError::TypeError),
+                match self.pop()?.as_number() {
+                    Some(n) => self.stack.push(Packed::number(-n)),
+                    None => return Err(RuntimeError::UnaryMinusTypeMismatch),
                 };
             }
             OpCode::Not => {
-                match self.pop()? {
-                    Value::Bool(b) => self.stack.push(Value::Bool(!b)),
-                    Value::Nil => self.stack.push(Value::Bool(true)),
-                    _ => return Err(RuntimeError::TypeError),
+                let value = self.pop()?;
+                self.stack.push(Packed::boolean(value.is_falsey()));
+            }
+            OpCode::JumpIfFalse(offset) => {
+                let condition = self.stack.last().ok_or(RuntimeError::StackUnderflow)?;
+                if condition.is_falsey() {
+                    self.frame.program_counter += offset;
+                }
+            }
+            OpCode::Jump(offset) => self.frame.program_counter += offset,
+            OpCode::Pop => {
+                self.pop()?;
+            }
+            OpCode::List(count) => {
+                if count > self.stack.len() {
+                    return Err(RuntimeError::StackUnderflow);
+                }
+                let first = self.stack.len() - count;
+                let elements = self.stack.drain(first..).map(|e| e.to_value()).collect();
+                let list = self.allocate_list(elements);
+                self.stack.push(Value::Object(list).into());
+            }
+            OpCode::Map(count) => {
+                if 2 * count > self.stack.len() {
+                    return Err(RuntimeError::StackUnderflow);
+                }
+                let first = self.stack.len() - 2 * count;
+                let entries: Vec<Value> = self.stack.drain(first..).map(|e| e.to_value()).collect();
+                let map = self.allocate_map(map(entries)?);
+                self.stack.push(Value::Object(map).into());
+            }
+            OpCode::Index => {
+                let position = self.pop()?;
+                let collection = self.pop()?;
+                let element = index(collection.to_value(), position.to_value())?;
+                self.stack.push(element.into());
+            }
+            OpCode::SetIndex => {
+                let value = self.pop()?;
+                let position = self.pop()?;
+                let collection = self.pop()?;
+                set_index(
+                    &collection.to_value(),
+                    &position.to_value(),
+                    value.to_value(),
+                )?;
+                self.stack.push(value);
+            }
+            OpCode::Length => {
+                let value = self.pop()?;
+                self.stack.push(length(&value.to_value())?.into());
+            }
+            OpCode::Loop(offset) => {
+                if offset > self.frame.program_counter {
+                    return Err(RuntimeError::InstructionOutOfBound);
+                }
+                self.frame.program_counter -= offset;
+            }
+            OpCode::Print => {
+                let value = self.pop()?;
+                output::print(value.to_value());
+            }
+            OpCode::DefineGlobal(offset) => {
+                let value = self.pop()?;
+                self.globals.define(name(chunk.find_value(offset))?, value);
+            }
+            OpCode::GetGlobal(offset) => {
+                let name = name(chunk.find_value(offset))?;
+                let value = self
+                    .globals
+                    .get(name)
+                    .ok_or_else(|| RuntimeError::UndefinedVariable(name.into()))?;
+                self.stack.push(value.clone());
+            }
+            OpCode::SetGlobal(offset) => {
+                let value = self.stack.last().ok_or(RuntimeError::StackUnderflow)?;
+                self.globals
+                    .assign(name(chunk.find_value(offset))?, value.clone())?;
+            }
+            OpCode::GetLocal(slot) => {
+                let value = self
+                    .stack
+                    .get(self.frame.base + slot)
+                    .ok_or(RuntimeError::StackUnderflow)?
+                    .clone();
+                self.stack.push(value);
+            }
+            OpCode::SetLocal(slot) => {
+                let value = self
+                    .stack
+                    .last()
+                    .ok_or(RuntimeError::StackUnderflow)?
+                    .clone();
+                *self
+                    .stack
+                    .get_mut(self.frame.base + slot)
+                    .ok_or(RuntimeError::StackUnderflow)? = value;
+            }
+            OpCode::Closure(offset) => {
+                let function = match chunk.find_value(offset) {
+                    Some(Constant::Function(ref function)) => function,
+                    Some(_) => return Err(RuntimeError::NotAFunction),
+                    None => return Err(RuntimeError::ValueOutOfBound),
+                };
+                let closure = closure(
+                    function,
+                    self.frame.base,
+                    self.frame.closure.as_ref(),
+                    &mut self.open_upvalues,
+                )?;
+                let closure = self.allocate_closure(closure);
+                self.stack.push(Value::Object(closure).into());
+            }
+            OpCode::GetUpvalue(index) => {
+                let value = match *self.frame.upvalue(index)?.borrow() {
+                    Upvalue::Open(slot) => self
+                        .stack
+                        .get(slot)
+                        .ok_or(RuntimeError::StackUnderflow)?
+                        .clone(),
+                    Upvalue::Closed(ref value) => value.clone().into(),
                 };
+                self.stack.push(value);
+            }
+            OpCode::SetUpvalue(index) => {
+                let value = self
+                    .stack
+                    .last()
+                    .ok_or(RuntimeError::StackUnderflow)?
+                    .clone();
+                match *self.frame.upvalue(index)?.borrow_mut() {
+                    Upvalue::Open(slot) => {
+                        *self
+                            .stack
+                            .get_mut(slot)
+                            .ok_or(RuntimeError::StackUnderflow)? = value
+                    }
+                    Upvalue::Closed(ref mut variable) => *variable = value.to_value(),
+                }
             }
-            OpCode::Binary(ref operator) => {
-                let result = match (self.pop()?, self.pop()?) {
-                    // Note the order!
-                    // Op2 is the topmost element of the stack,
-                    // Op1 is the second topmost element
-                    (Value::Number(op2), Value::Number(op1)) => match *operator {
-                        BinaryOp::Add => Value::Number(op1 + op2),
-                        BinaryOp::Subtract => Value::Number(op1 - op2),
-                        BinaryOp::Multiply => Value::Number(op1 * op2),
-                        BinaryOp::Divide => Value::Number(op1 / op2),
-                        BinaryOp::Equals => Value::Bool((op1 - op2).abs() < EPSILON),
-                        BinaryOp::NotEqual => Value::Bool((op1 - op2).abs() >= EPSILON),
-                        BinaryOp::Greater => Value::Bool(op1 > op2),
-                        BinaryOp::GreaterEqual => Value::Bool(op1 >= op2),
-                        BinaryOp::Less => Value::Bool(op1 < op2),
-                        BinaryOp::LessEqual => Value::Bool(op1 <= op2),
-                        _ => return Err(RuntimeError::TypeError),
-                    },
-                    (Value::Bool(op1), Value::Bool(op2)) => match *operator {
-                        BinaryOp::Equals => Value::Bool(op1 == op2),
-                        BinaryOp::NotEqual => Value::Bool(op1 != op2),
-                        BinaryOp::Or => Value::Bool(op1 || op2),
-                        BinaryOp::And => Value::Bool(op1 && op2),
-                        _ => return Err(RuntimeError::TypeError),
-                    },
-                    (Value::Nil, Value::Nil) => match *operator {
-                        BinaryOp::Equals => Value::Bool(true),
-                        BinaryOp::NotEqual => Value::Bool(false),
-                        _ => return Err(RuntimeError::TypeError),
-                    },
-                    (Value::Object(v1), Value::Object(v2)) => match (&*v1, &*v2) {
-                        (ObjectValue::String(ref s1), ObjectValue::String(ref s2)) => {
-                            match *operator {
-                                BinaryOp::Equals => Value::Bool(s1 == s2),
-                                BinaryOp::NotEqual => Value::Bool(s1 != s2),
-                                BinaryOp::Add => {
-                                    let mut result = s1.clone();
-                                    result.push_str(&*s2);
-                                    Value::Object(self.allocate_string(result))
-                                }
-                                _ => return Err(RuntimeError::TypeError),
-                            }
-                        }
-                    },
-                    _ => return Err(RuntimeError::TypeError),
+            OpCode::CloseUpvalue => {
+                let top = self.stack.len().saturating_sub(1);
+                self.close_upvalues(top);
+                self.pop()?;
+            }
+            OpCode::ToString => {
+                let value = self.pop()?.to_value();
+                let string = self.allocate_string(value.to_string());
+                self.stack.push(Value::Object(string).into());
+            }
+            OpCode::GetProperty(offset) => {
+                let object = self.pop()?.to_value();
+                let value = property(object, name(chunk.find_value(offset))?)?;
+                self.stack.push(value.into());
+            }
+            OpCode::Call(count) => {
+                if count >= self.stack.len() {
+                    return Err(RuntimeError::StackUnderflow);
+                }
+                let first = self.stack.len() - count;
+                if let Value::Object(ref object) = self.stack[first - 1].to_value() {
+                    if let ObjectValue::Closure(ref closure) = **object {
+                        self.call_closure(closure.clone(), count)?;
+                        return Ok(true);
+                    }
+                }
+                let arguments: Vec<Value> =
+                    self.stack.drain(first..).map(|a| a.to_value()).collect();
+                let callee = self.pop()?.to_value();
+                let result = call(&callee, &arguments)?;
+                if let Value::Object(ref object) = result {
+                    self.objects.push(object.clone());
+                }
+                self.stack.push(result.into());
+            }
+            OpCode::Throw => return Err(RuntimeError::Thrown(self.pop()?.to_value())),
+            OpCode::EnterFinally => self.kept_errors.push(None),
+            OpCode::EndFinally => {
+                if let Some(error) = self.kept_errors.pop().ok_or(RuntimeError::StackUnderflow)? {
+                    return Err(error);
+                }
+            }
+            OpCode::Binary(operator) => {
+                // Note the order!
+                // Op2 is the topmost element of the stack,
+                // Op1 is the second topmost element
+                let op2 = self.pop()?;
+                let op1 = self.pop()?;
+                let result = match (op1.as_number(), op2.as_number()) {
+                    (Some(op1), Some(op2)) => numeric(operator, op1, op2),
+                    _ => binary(operator, op1.to_value(), op2.to_value(), |s| {
+                        self.allocate_string(s)
+                    })?
+                    .into(),
                 };
                 self.stack.push(result);
             }
         };
         Ok(true)
     }
+
+    /// Interprets the next instruction, see `interpret_next`, giving its
+    /// line to the errors it raises and passing them to their handler
+    fn step(&mut self) -> Result<bool, RuntimeError> {
+        let error = match self.interpret_next() {
+            Ok(more) => return Ok(more),
+            Err(RuntimeError::Exit(code)) => return Err(RuntimeError::Exit(code)),
+            Err(error) => error,
+        };
+        let function = self.frame.function();
+        let chunk = function.as_ref().map_or(self.chunk, |f| &f.chunk);
+        if self.frame.program_counter > chunk.instruction_count() {
+            return Err(error);
+        }
+        let line = chunk.get_line(self.frame.program_counter - 1);
+        let error = match error {
+            // Raised again by a finally block, it keeps its line
+            RuntimeError::AtLine(..) => error,
+            error => RuntimeError::AtLine(line, Box::new(error)),
+        };
+        self.unwind(error)
+    }
+
+    /// Passes the error to the handler of the instruction that raised it
+    /// or, when there is none, to the one of the call in the caller, and
+    /// so on
+    fn unwind(&mut self, error: RuntimeError) -> Result<bool, RuntimeError> {
+        loop {
+            let function = self.frame.function();
+            let chunk = function.as_ref().map_or(self.chunk, |f| &f.chunk);
+            if let Some(&handler) = chunk.handler_for(self.frame.program_counter - 1) {
+                self.handle(error, handler);
+                return Ok(true);
+            }
+            match self.frames.pop() {
+                Some(caller) => self.leave_frame(caller),
+                None => return Err(error),
+            }
+        }
+    }
+
     fn trace<T>(&mut self, out: &mut LineWriter<T>) -> Result<(), Error>
     where
         T: Write,
     {
-        write!(out, "Program Counter: {}", self.program_counter)?;
+        write!(out, "Program Counter: {}", self.frame.program_counter)?;
         writeln!(out)?;
         write!(out, "Stack: ")?;
         for value in &self.stack {
             write!(out, "[ {:?} ]", value)?;
         }
         writeln!(out)?;
-        if self.program_counter < self.chunk.instruction_count() {
-            disassemble_instruction(&self.chunk.get(self.program_counter), self.chunk, out)
+        let function = self.frame.function();
+        let chunk = function.as_ref().map_or(self.chunk, |f| &f.chunk);
+        if self.frame.program_counter < chunk.instruction_count() {
+            disassemble_instruction(&chunk.get(self.frame.program_counter), chunk, out)
         } else {
             //TODO: is this really ok?
             Ok(())
@@ -188,21 +1168,331 @@ impl<'a> Vm<'a> {
     }
 }
 
-pub fn interpret(chunk: &Chunk) -> Result<(), RuntimeError> {
-    let mut vm = Vm::new(chunk);
-    while vm.interpret_next()? {}
-    Ok(())
+/// Applies the operator to two numbers, without going through `Value`
+fn numeric(operator: BinaryOp, op1: f64, op2: f64) -> Packed {
+    match operator {
+        BinaryOp::Add => Packed::number(op1 + op2),
+        BinaryOp::Subtract => Packed::number(op1 - op2),
+        BinaryOp::Multiply => Packed::number(op1 * op2),
+        BinaryOp::Divide => Packed::number(op1 / op2),
+        BinaryOp::Modulo => Packed::number(number::modulo(op1, op2)),
+        BinaryOp::IntegerDivide => Packed::number(number::integer_division(op1, op2)),
+        BinaryOp::Equals => Packed::boolean(op1 == op2),
+        BinaryOp::NotEqual => Packed::boolean(op1 != op2),
+        BinaryOp::Greater => Packed::boolean(op1 > op2),
+        BinaryOp::GreaterEqual => Packed::boolean(op1 >= op2),
+        BinaryOp::Less => Packed::boolean(op1 < op2),
+        BinaryOp::LessEqual => Packed::boolean(op1 <= op2),
+    }
+}
+
+/// Applies the operator to the operands.
+/// Concatenated strings are allocated through `allocate_string`.
+pub fn binary<F>(
+    operator: BinaryOp,
+    op1: Value,
+    op2: Value,
+    allocate_string: F,
+) -> Result<Value, RuntimeError>
+where
+    F: FnOnce(String) -> ObjectReference,
+{
+    Ok(match (operator, op1, op2) {
+        // Values of different types are never equal
+        (BinaryOp::Equals, op1, op2) => Value::Bool(op1 == op2),
+        (BinaryOp::NotEqual, op1, op2) => Value::Bool(op1 != op2),
+        (operator, Value::Number(op1), Value::Number(op2)) => match operator {
+            BinaryOp::Add => Value::Number(op1 + op2),
+            BinaryOp::Subtract => Value::Number(op1 - op2),
+            BinaryOp::Multiply => Value::Number(op1 * op2),
+            BinaryOp::Divide => Value::Number(op1 / op2),
+            BinaryOp::Modulo => Value::Number(number::modulo(op1, op2)),
+            BinaryOp::IntegerDivide => Value::Number(number::integer_division(op1, op2)),
+            BinaryOp::Greater => Value::Bool(op1 > op2),
+            BinaryOp::GreaterEqual => Value::Bool(op1 >= op2),
+            BinaryOp::Less => Value::Bool(op1 < op2),
+            BinaryOp::LessEqual => Value::Bool(op1 <= op2),
+            _ => unreachable!("Equality is handled for all the types"),
+        },
+        (operator, Value::Object(op1), Value::Object(op2)) => match (&*op1, &*op2) {
+            (ObjectValue::String(ref s1), ObjectValue::String(ref s2)) => match operator {
+                BinaryOp::Add => {
+                    let mut result = s1.clone();
+                    result.push_str(s2);
+                    Value::Object(allocate_string(result))
+                }
+                _ => return Err(RuntimeError::BinaryOperatorTypeMismatch(operator)),
+            },
+            _ => return Err(RuntimeError::BinaryOperatorTypeMismatch(operator)),
+        },
+        (operator, _, _) => return Err(RuntimeError::BinaryOperatorTypeMismatch(operator)),
+    })
+}
+
+/// What a catch handler gets for the error, see `Vm::caught_value`.
+/// The objects of the map are allocated through `allocate`.
+pub fn caught_value<F>(error: RuntimeError, mut allocate: F) -> Value
+where
+    F: FnMut(ObjectValue) -> ObjectReference,
+{
+    let line = match error {
+        RuntimeError::AtLine(line, _) => Value::Number(line as f64),
+        _ => Value::Nil,
+    };
+    match error.without_line() {
+        RuntimeError::Thrown(value) => value,
+        error => {
+            let message = Value::Object(allocate(ObjectValue::String(error.to_string())));
+            let mut map = OrderedMap::new();
+            map.insert(Key::String("message".into()), message);
+            map.insert(Key::String("line".into()), line);
+            Value::Object(allocate(ObjectValue::Map(RefCell::new(map))))
+        }
+    }
+}
+
+fn map_key(value: &Value) -> Result<Key, RuntimeError> {
+    match *value {
+        Value::Nil => Ok(Key::Nil),
+        Value::Bool(b) => Ok(Key::Boolean(b)),
+        Value::Number(n) => Ok(Key::number(n)),
+        Value::Object(ref object) => match **object {
+            ObjectValue::String(ref s) => Ok(Key::String(s.as_str().into())),
+            _ => Err(RuntimeError::UnhashableKey(value.clone())),
+        },
+    }
+}
+
+fn key_value(key: &Key) -> Value {
+    match *key {
+        Key::Nil => Value::Nil,
+        Key::Boolean(b) => Value::Bool(b),
+        Key::Number(bits) => Value::Number(f64::from_bits(bits)),
+        Key::String(ref s) => Value::string(s.clone()),
+    }
+}
+
+/// The map holding the entries, given as alternating keys and values.
+/// Later entries replace the earlier ones with the same key.
+pub fn map(entries: Vec<Value>) -> Result<OrderedMap<Value>, RuntimeError> {
+    let mut map = OrderedMap::new();
+    let mut entries = entries.into_iter();
+    while let (Some(key), Some(value)) = (entries.next(), entries.next()) {
+        map.insert(map_key(&key)?, value);
+    }
+    Ok(map)
+}
+
+/// The position in a list of the given length the value refers to
+fn list_index(value: &Value, length: usize) -> Result<usize, RuntimeError> {
+    match *value {
+        Value::Number(n) if n >= 0.0 && n.fract() == 0.0 && n < length as f64 => Ok(n as usize),
+        _ => Err(RuntimeError::InvalidIndex(value.clone())),
+    }
+}
+
+/// The element of the list, or the value of the map, at the given position
+pub fn index(collection: Value, position: Value) -> Result<Value, RuntimeError> {
+    let object = match collection {
+        Value::Object(ref object) => object,
+        _ => return Err(RuntimeError::NotACollection(collection)),
+    };
+    match (&**object, position) {
+        (ObjectValue::List(ref elements), position) => {
+            let elements = elements.borrow();
+            Ok(elements[list_index(&position, elements.len())?].clone())
+        }
+        (ObjectValue::Map(ref map), ref key) => map
+            .borrow()
+            .get(&map_key(key)?)
+            .cloned()
+            .ok_or_else(|| RuntimeError::UndefinedKey(key.clone())),
+        _ => Err(RuntimeError::NotACollection(collection.clone())),
+    }
+}
+
+/// Replaces the element of the list at the given position, or sets the
+/// value of the key in the map
+pub fn set_index(collection: &Value, position: &Value, value: Value) -> Result<(), RuntimeError> {
+    if let Value::Object(ref object) = *collection {
+        match **object {
+            ObjectValue::List(ref elements) => {
+                let mut elements = elements.borrow_mut();
+                let index = list_index(position, elements.len())?;
+                elements[index] = value;
+                return Ok(());
+            }
+            ObjectValue::Map(ref map) => {
+                let key = map_key(position)?;
+                map.borrow_mut().insert(key, value);
+                return Ok(());
+            }
+            _ => {}
+        }
+    }
+    Err(RuntimeError::NotACollection(collection.clone()))
+}
+
+/// The number of characters of a string, or of elements of a list or map
+pub fn length(value: &Value) -> Result<Value, RuntimeError> {
+    let length = match *value {
+        Value::Object(ref object) => match **object {
+            ObjectValue::String(ref s) => s.chars().count(),
+            ObjectValue::List(ref elements) => elements.borrow().len(),
+            ObjectValue::Map(ref map) => map.borrow().len(),
+            _ => return Err(RuntimeError::NotACollection(value.clone())),
+        },
+        _ => return Err(RuntimeError::NotACollection(value.clone())),
+    };
+    Ok(Value::Number(length as f64))
+}
+
+/// What the module exports under the name
+pub fn property(object: Value, name: &str) -> Result<Value, RuntimeError> {
+    match object {
+        Value::Object(ref object) => match **object {
+            ObjectValue::Module(_, ref exports) => exports
+                .get(name)
+                .cloned()
+                .ok_or_else(|| RuntimeError::UndefinedProperty(name.into())),
+            _ => Err(RuntimeError::NotAnInstance),
+        },
+        _ => Err(RuntimeError::NotAnInstance),
+    }
+}
+
+/// Calls the value with the arguments, which only natives accept
+pub fn call(callee: &Value, arguments: &[Value]) -> Result<Value, RuntimeError> {
+    let object = match *callee {
+        Value::Object(ref object) => object,
+        _ => return Err(RuntimeError::NotCallable),
+    };
+    match **object {
+        ObjectValue::Native(native) => native.call(arguments).map_err(|error| match error {
+            NativeError::WrongNumberOfArguments(arity) => {
+                RuntimeError::WrongNumberOfArguments(arity, arguments.len())
+            }
+            NativeError::InvalidArgument(i) => RuntimeError::InvalidArgument(arguments[i].clone()),
+            NativeError::Io(message) => RuntimeError::Io(message),
+            NativeError::Exit(code) => RuntimeError::Exit(code),
+        }),
+        ObjectValue::Collection(native) => native.call(arguments),
+        ObjectValue::Arguments(ref script_arguments) => {
+            if !arguments.is_empty() {
+                return Err(RuntimeError::WrongNumberOfArguments(0, arguments.len()));
+            }
+            Ok(Value::list(
+                script_arguments
+                    .iter()
+                    .map(|argument| Value::string(argument.as_str().into()))
+                    .collect(),
+            ))
+        }
+        _ => Err(RuntimeError::NotCallable),
+    }
+}
+
+impl NativeValue for Value {
+    fn nil() -> Value {
+        Value::Nil
+    }
+
+    fn number(n: f64) -> Value {
+        Value::Number(n)
+    }
+
+    fn string(s: Rc<str>) -> Value {
+        Value::Object(Rc::new(ObjectValue::String(s.to_string())))
+    }
+
+    fn list(elements: Vec<Value>) -> Value {
+        Value::Object(Rc::new(ObjectValue::List(RefCell::new(elements))))
+    }
+
+    fn as_number(&self) -> Option<f64> {
+        match *self {
+            Value::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    fn as_string(&self) -> Option<&str> {
+        match *self {
+            Value::Object(ref object) => match **object {
+                ObjectValue::String(ref s) => Some(s),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn describe(&self) -> String {
+        self.to_string()
+    }
+
+    fn type_name(&self) -> &'static str {
+        match *self {
+            Value::Nil => "nil",
+            Value::Bool(_) => "boolean",
+            Value::Number(_) => "number",
+            Value::Object(ref object) => match **object {
+                ObjectValue::String(_) => "string",
+                ObjectValue::List(_) => "list",
+                ObjectValue::Map(_) => "map",
+                ObjectValue::Native(_)
+                | ObjectValue::Collection(_)
+                | ObjectValue::Arguments(_)
+                | ObjectValue::Closure(_)
+                | ObjectValue::RegisterClosure(_) => "function",
+                ObjectValue::Module(..) => "module",
+            },
+        }
+    }
+}
+
+/// Runs the chunk with the given global variables and returns the value
+/// it produced
+pub fn run(chunk: &Chunk, globals: &mut Globals) -> Result<Option<Value>, RuntimeError> {
+    let mut vm = Vm::new(chunk, globals);
+    let mut result = Ok(true);
+    while let Ok(true) = result {
+        result = vm.step();
+    }
+    // The closures kept in the globals outlive the stack when the program
+    // fails
+    vm.close_upvalues(0);
+    result?;
+    Ok(vm.result)
+}
+
+/// Runs the chunk, without any global variable, and returns the value it
+/// produced
+pub fn interpret(chunk: &Chunk) -> Result<Option<Value>, RuntimeError> {
+    run(chunk, &mut Globals::default())
+}
+
+/// Runs the chunk and returns how many instructions it executed
+pub fn dispatch_count(chunk: &Chunk) -> Result<usize, RuntimeError> {
+    let mut globals = Globals::default();
+    let mut vm = Vm::new(chunk, &mut globals);
+    let mut count = 1;
+    while vm.step()? {
+        count += 1;
+    }
+    Ok(count)
 }
 
 pub fn trace<T>(chunk: &Chunk, writer: &mut LineWriter<T>) -> Result<(), RuntimeError>
 where
     T: Write,
 {
-    let mut vm = Vm::new(chunk);
+    let mut globals = Globals::default();
+    let mut vm = Vm::new(chunk, &mut globals);
     while {
         vm.trace(writer).map_err(RuntimeError::TracingError)?;
-        vm.interpret_next()?
+        vm.step()?
     } {}
+    writeln!(writer, "{:?}", vm.result).map_err(RuntimeError::TracingError)?;
     Ok(())
 }
 
@@ -220,23 +1510,53 @@ mod tests {
             prop_oneof![
                 prop::num::f64::ANY.prop_map(Constant::Number),
                 prop::bool::ANY.prop_map(Constant::Bool),
-            ].boxed(),
+            ]
+            .boxed(),
             0..max_constants,
         )
     }
 
+    /// Loops are left out, as they could run forever
     fn arb_instruction(max_offset: usize) -> BoxedStrategy<OpCode> {
         prop_oneof![
             (0..max_offset).prop_map(OpCode::Constant),
             Just(OpCode::Return),
             Just(OpCode::Negate),
+            Just(OpCode::Not),
+            Just(OpCode::Pop),
+            (0..max_offset).prop_map(OpCode::List),
+            (0..max_offset).prop_map(OpCode::Map),
+            Just(OpCode::Index),
+            Just(OpCode::SetIndex),
+            Just(OpCode::Length),
+            (0..max_offset).prop_map(OpCode::DefineGlobal),
+            (0..max_offset).prop_map(OpCode::GetGlobal),
+            (0..max_offset).prop_map(OpCode::SetGlobal),
+            (0..max_offset).prop_map(OpCode::GetLocal),
+            (0..max_offset).prop_map(OpCode::SetLocal),
+            Just(OpCode::ToString),
+            (0..max_offset).prop_map(OpCode::GetProperty),
+            (0..max_offset).prop_map(OpCode::Call),
+            (0..max_offset).prop_map(OpCode::Closure),
+            (0..max_offset).prop_map(OpCode::GetUpvalue),
+            (0..max_offset).prop_map(OpCode::SetUpvalue),
+            Just(OpCode::CloseUpvalue),
+            Just(OpCode::Throw),
+            Just(OpCode::EnterFinally),
+            Just(OpCode::EndFinally),
+            (0..max_offset).prop_map(OpCode::Jump),
+            (0..max_offset).prop_map(OpCode::JumpIfFalse),
             prop_oneof![
                 Just(BinaryOp::Add),
                 Just(BinaryOp::Subtract),
                 Just(BinaryOp::Multiply),
                 Just(BinaryOp::Divide),
-            ].prop_map(OpCode::Binary),
-        ].boxed()
+                Just(BinaryOp::Modulo),
+                Just(BinaryOp::IntegerDivide),
+            ]
+            .prop_map(OpCode::Binary),
+        ]
+        .boxed()
     }
 
     fn arb_instructions(
@@ -246,7 +1566,7 @@ mod tests {
         prop::collection::vec(arb_instruction(max_offset), 0..max_instructions)
     }
 
-    prop_compose!{
+    prop_compose! {
         fn arb_chunk(max_offset : usize, max_instructions : usize)
             (constants in arb_constants(max_offset),
              instructions in arb_instructions(max_offset, max_instructions)) -> Chunk{
@@ -294,69 +1614,76 @@ mod tests {
 // require lots of boilerplate for little benefit.
 #[cfg(test)]
 mod end_to_end_tests {
+    use natives::Capabilities;
     use vm::compiler::compile;
-    use vm::interpreter::{Value, Vm};
+    use vm::interpreter::{interpret, run, Globals, RuntimeError, Value, Vm};
 
     #[test]
     pub fn number() {
         let chunk = compile("5").unwrap();
-        let mut vm = Vm::new(&chunk);
+        let mut globals = Globals::default();
+        let mut vm = Vm::new(&chunk, &mut globals);
 
         let _ = vm.interpret_next().unwrap();
 
-        assert_eq!(Value::Number(5.0), vm.pop().unwrap());
+        assert_eq!(Value::Number(5.0), vm.pop().unwrap().to_value());
     }
 
     #[test]
     pub fn unary() {
         let chunk = compile("-5").unwrap();
-        let mut vm = Vm::new(&chunk);
+        let mut globals = Globals::default();
+        let mut vm = Vm::new(&chunk, &mut globals);
 
         let _ = vm.interpret_next().unwrap(); // Puts 5 on the stack
         let _ = vm.interpret_next().unwrap(); // Negates it
 
-        assert_eq!(Value::Number(-5.0), vm.pop().unwrap());
+        assert_eq!(Value::Number(-5.0), vm.pop().unwrap().to_value());
     }
 
     #[test]
     pub fn unary_bool() {
         let chunk = compile("!true").unwrap();
-        let mut vm = Vm::new(&chunk);
+        let mut globals = Globals::default();
+        let mut vm = Vm::new(&chunk, &mut globals);
 
         let _ = vm.interpret_next().unwrap(); // Puts 5 on the stack
         let _ = vm.interpret_next().unwrap(); // Negates it
 
-        assert_eq!(Value::Bool(false), vm.pop().unwrap());
+        assert_eq!(Value::Bool(false), vm.pop().unwrap().to_value());
     }
 
     #[test]
     pub fn binary() {
         let chunk = compile("5+10").unwrap();
-        let mut vm = Vm::new(&chunk);
+        let mut globals = Globals::default();
+        let mut vm = Vm::new(&chunk, &mut globals);
 
         let _ = vm.interpret_next().unwrap(); // Puts 5 on the stack
         let _ = vm.interpret_next().unwrap(); // Puts 10 on the stack
         let _ = vm.interpret_next().unwrap(); // Adds them
 
-        assert_eq!(Value::Number(15.0), vm.pop().unwrap());
+        assert_eq!(Value::Number(15.0), vm.pop().unwrap().to_value());
     }
 
     #[test]
     pub fn binary_bool() {
         let chunk = compile("true or false").unwrap();
-        let mut vm = Vm::new(&chunk);
+        let mut globals = Globals::default();
+        let mut vm = Vm::new(&chunk, &mut globals);
 
         let _ = vm.interpret_next().unwrap(); // Puts true on the stack
         let _ = vm.interpret_next().unwrap(); // Puts false on the stack
         let _ = vm.interpret_next().unwrap(); // Or
 
-        assert_eq!(Value::Bool(true), vm.pop().unwrap());
+        assert_eq!(Value::Bool(true), vm.pop().unwrap().to_value());
     }
 
     #[test]
     pub fn grouping() {
         let chunk = compile("(5+10)*3").unwrap();
-        let mut vm = Vm::new(&chunk);
+        let mut globals = Globals::default();
+        let mut vm = Vm::new(&chunk, &mut globals);
 
         let _ = vm.interpret_next().unwrap(); // Puts 5 on the stack
         let _ = vm.interpret_next().unwrap(); // Puts 10 on the stack
@@ -364,13 +1691,14 @@ mod end_to_end_tests {
         let _ = vm.interpret_next().unwrap(); // Puts 3 on the stack
         let _ = vm.interpret_next().unwrap(); // Multiplication
 
-        assert_eq!(Value::Number(45.0), vm.pop().unwrap());
+        assert_eq!(Value::Number(45.0), vm.pop().unwrap().to_value());
     }
 
     #[test]
     pub fn precedence() {
         let chunk = compile("-5+10*3").unwrap();
-        let mut vm = Vm::new(&chunk);
+        let mut globals = Globals::default();
+        let mut vm = Vm::new(&chunk, &mut globals);
 
         let _ = vm.interpret_next().unwrap(); // Puts 5 on the stack
         let _ = vm.interpret_next().unwrap(); // Negates it
@@ -379,13 +1707,233 @@ mod end_to_end_tests {
         let _ = vm.interpret_next().unwrap(); // Multiplication
         let _ = vm.interpret_next().unwrap(); // Addition
 
-        assert_eq!(Value::Number(25.0), vm.pop().unwrap());
+        assert_eq!(Value::Number(25.0), vm.pop().unwrap().to_value());
+    }
+
+    #[test]
+    pub fn result() {
+        let chunk = compile("\"a\" + \"b\"").unwrap();
+        let result = interpret(&chunk).unwrap().unwrap();
+        assert_eq!("ab", result.to_string());
+    }
+
+    #[test]
+    pub fn not_uses_truthiness() {
+        for &(source, expected) in &[
+            ("!nil", true),
+            ("!false", true),
+            ("!true", false),
+            ("!0", false),
+            ("!\"\"", false),
+            ("![]", false),
+            ("!{}", false),
+        ] {
+            let chunk = compile(source).unwrap();
+            assert_eq!(Some(Value::Bool(expected)), interpret(&chunk).unwrap());
+        }
+    }
+
+    #[test]
+    pub fn short_circuit() {
+        for &(source, ref expected) in &[
+            ("nil or 2", Value::Number(2.0)),
+            ("1 or -nil", Value::Number(1.0)),
+            ("1 and false", Value::Bool(false)),
+            ("false and -nil", Value::Bool(false)),
+        ] {
+            let chunk = compile(source).unwrap();
+            assert_eq!(Some(expected.clone()), interpret(&chunk).unwrap());
+        }
+    }
+
+    #[test]
+    pub fn lists() {
+        for &(source, expected) in &[
+            ("[]", "[]"),
+            ("[1, \"a\" + \"b\", [nil]]", "[1, ab, [nil]]"),
+            ("[1, [2, 3]][1][0]", "2"),
+            ("-[1, 2][1] * 2", "-4"),
+            ("[1, [2]] == [1, [2]]", "true"),
+        ] {
+            let chunk = compile(source).unwrap();
+            assert_eq!(expected, interpret(&chunk).unwrap().unwrap().to_string());
+        }
+        for source in &["[1][1]", "[1][-1]", "[1][0.5]", "nil[0]", "[1][nil]"] {
+            assert!(
+                interpret(&compile(source).unwrap()).is_err(),
+                "Running {}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    pub fn maps() {
+        for &(source, expected) in &[
+            ("{}", "{}"),
+            (
+                "{\"a\": 1, nil: [2], 1: 3, \"a\": 4}",
+                "{a: 4, nil: [2], 1: 3}",
+            ),
+            ("{true: {\"b\": 2}}[true][\"b\"]", "2"),
+            ("{0: 1}[-0] + {\"a\" + \"b\": 2}[\"ab\"]", "3"),
+            ("{1: nil, 2: 3} == {2: 3, 1: nil}", "true"),
+            ("{1: nil} == {1: false}", "false"),
+        ] {
+            let chunk = compile(source).unwrap();
+            assert_eq!(expected, interpret(&chunk).unwrap().unwrap().to_string());
+        }
+        for source in &["{}[1]", "{[1]: 2}", "{1: 2}[{}]", "{1: 2}[\"1\"]"] {
+            assert!(
+                interpret(&compile(source).unwrap()).is_err(),
+                "Running {}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    pub fn equality_across_types() {
+        let chunk = compile("1 == \"1\" or nil != false").unwrap();
+        assert_eq!(Some(Value::Bool(true)), interpret(&chunk).unwrap());
+    }
+
+    #[test]
+    pub fn errors_report_their_line() {
+        let chunk = compile("1 +\n-\"a\"").unwrap();
+        let error = interpret(&chunk).unwrap_err();
+        assert_eq!(
+            vec![
+                "Operand must be a number.".to_string(),
+                "[line 2]".to_string()
+            ],
+            error.report()
+        );
+        let chunk = compile("1 + nil").unwrap();
+        let error = interpret(&chunk).unwrap_err();
+        assert_eq!(
+            vec![
+                "Operands must be two numbers or two strings.".to_string(),
+                "[line 1]".to_string()
+            ],
+            error.report()
+        );
+    }
+
+    #[test]
+    pub fn variables_and_loops() {
+        for &(source, expected) in &[
+            ("var a = 1; a = a + 1; a", "2"),
+            ("var a = 1; { var a = 2; a = 3; } a", "1"),
+            (
+                "var sum = 0; for (var i = 0; i < 4; i = i + 1) sum = sum + i; sum",
+                "6",
+            ),
+            ("var i = 0; while (i < 3) { i = i + 1; } i", "3"),
+            ("var a; if (a) a = 1; else a = 2; a", "2"),
+        ] {
+            let chunk = compile(source).unwrap();
+            assert_eq!(expected, interpret(&chunk).unwrap().unwrap().to_string());
+        }
+        assert!(interpret(&compile("a").unwrap()).is_err());
+        assert!(compile("{ var a = a; }").is_err());
+        assert!(compile("1 + 2 = 3;").is_err());
+    }
+
+    #[test]
+    pub fn thrown_values_are_caught() {
+        for &(source, expected) in &[
+            ("var a; try { throw 1; } catch (e) { a = e; } a", "1"),
+            ("var a; try { -nil; } catch (e) { a = e[\"line\"]; } a", "1"),
+            ("var a = 0; try { a = 1; } finally { a = a + 1; } a", "2"),
+            (
+                "var a = 0; try { try { throw 1; } finally { a = 2; } } catch (e) { a = a + e; } a",
+                "3",
+            ),
+            (
+                "var a; try { try { throw 1; } catch (e) { throw e + 1; } } catch (e) { a = e; } a",
+                "2",
+            ),
+        ] {
+            let chunk = compile(source).unwrap();
+            assert_eq!(expected, interpret(&chunk).unwrap().unwrap().to_string());
+        }
+        let chunk = compile("try { throw 1; } finally { }").unwrap();
+        assert_eq!(
+            vec!["1".to_string(), "[line 1]".to_string()],
+            interpret(&chunk).unwrap_err().report()
+        );
+    }
+
+    #[test]
+    pub fn natives_are_called() {
+        for &(source, expected) in &[
+            ("str(1) + upper(\"a\")", "1A"),
+            ("len(\"hé\") + len([1]) + len({1: 2, 3: 4})", "5"),
+            ("type(len) + type(nil)", "functionnil"),
+            ("pow(2, sqrt(9))", "8"),
+            ("\"a${1 + 1}b${nil}\"", "a2bnil"),
+            ("\"${\"${[1]}\"}\"", "[1]"),
+            ("var str = 1; \"${str}\"", "1"),
+        ] {
+            let chunk = compile(source).unwrap();
+            assert_eq!(expected, interpret(&chunk).unwrap().unwrap().to_string());
+        }
+        for &(source, message) in &[
+            ("len(1)", "1 is not a list or a map"),
+            ("floor(1, 2)", "Expected 1 arguments but got 2."),
+            ("nil()", "Can only call functions and classes."),
+            ("exit(0)", "Undefined variable 'exit'."),
+        ] {
+            let error = interpret(&compile(source).unwrap()).unwrap_err();
+            assert_eq!(message, error.to_string());
+        }
+        assert!(compile("clock(1, 2, 3, 4, 5, 6, 7, 8, 9)").is_err());
+    }
+
+    #[test]
+    pub fn exit_skips_the_handlers() {
+        let chunk = compile("try { exit(2); } catch (e) { } finally { }").unwrap();
+        let mut globals = Globals::default();
+        globals.define_system_access(
+            Capabilities {
+                exit: true,
+                ..Capabilities::default()
+            },
+            &[],
+        );
+        match run(&chunk, &mut globals) {
+            Err(RuntimeError::Exit(2)) => {}
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    pub fn system_modules_need_capabilities() {
+        let chunk = compile("[type(os), os.args(), str(fs)]").unwrap();
+        let error = interpret(&chunk).unwrap_err();
+        assert_eq!("Undefined variable 'os'.", error.to_string());
+        let mut globals = Globals::default();
+        globals.define_system_access(Capabilities::all(), &["one".to_string()]);
+        assert_eq!(
+            "[module, [one], <module fs>]",
+            run(&chunk, &mut globals).unwrap().unwrap().to_string()
+        );
+        for &(source, message) in &[
+            ("os.nope", "Undefined property 'nope'."),
+            ("os.args(1)", "Expected 0 arguments but got 1."),
+            ("nil.args", "Only instances have properties."),
+        ] {
+            let error = run(&compile(source).unwrap(), &mut globals).unwrap_err();
+            assert_eq!(message, error.to_string());
+        }
     }
 
     #[test]
     pub fn complex() {
         let chunk = compile("!(5 - 4 > 3 * 2 == !nil)").unwrap();
-        let mut vm = Vm::new(&chunk);
+        let mut globals = Globals::default();
+        let mut vm = Vm::new(&chunk, &mut globals);
 
         let _ = vm.interpret_next().unwrap(); // Puts 5 on the stack
         let _ = vm.interpret_next().unwrap(); // Puts 4 on the stack
@@ -399,6 +1947,6 @@ mod end_to_end_tests {
         let _ = vm.interpret_next().unwrap(); // Equals
         let _ = vm.interpret_next().unwrap(); // Not
 
-        assert_eq!(Value::Bool(true), vm.pop().unwrap());
+        assert_eq!(Value::Bool(true), vm.pop().unwrap().to_value());
     }
 }
@@ -0,0 +1,394 @@
+use std::fmt;
+use std::io::{Error, LineWriter, Write};
+use vm::bytecode;
+use vm::bytecode::{disassemble_handlers, BinaryOp, Handler};
+
+type Offset = usize;
+type Line = usize;
+pub type Register = usize;
+/// The constants of the stack VM, with functions translated for the
+/// register VM
+pub type Constant = bytecode::Constant<Chunk>;
+pub type Function = bytecode::Function<Chunk>;
+
+/// Where an instruction reads one of its inputs from.
+/// Reading constants directly saves the instructions the stack VM
+/// needs to push them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operand {
+    Register(Register),
+    Constant(Offset),
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Operand::Register(register) => write!(f, "r{}", register),
+            Operand::Constant(offset) => write!(f, "k{}", offset),
+        }
+    }
+}
+
+/// Three-address instructions: the first register is where the result
+/// is stored.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instruction {
+    Load(Register, Offset),
+    Negate(Register, Operand),
+    Not(Register, Operand),
+    Binary(BinaryOp, Register, Operand, Operand),
+    /// Skips the given number of instructions when the value in the
+    /// register is falsey
+    JumpIfFalse(Register, Offset),
+    /// Skips the given number of instructions
+    Jump(Offset),
+    Return(Option<Operand>),
+    /// Builds a list from the given number of registers, starting from
+    /// the one where the list is stored
+    List(Register, usize),
+    /// Builds a map from the given number of entries, each a key followed
+    /// by its value, starting from the register where the map is stored
+    Map(Register, usize),
+    /// Reads the element of a list, or the value of a map, at the given index
+    Index(Register, Operand, Operand),
+    /// Stores the value as the element of the list in the register at the
+    /// given index, then in the register, as the result
+    SetIndex(Register, Operand, Operand),
+    /// Stores the length of the string, list or map
+    Length(Register, Operand),
+    /// Copies a value, which is how local variables are read and assigned
+    Move(Register, Operand),
+    /// Goes back to the instruction the given number of instructions
+    /// before the next one
+    Loop(Offset),
+    Print(Operand),
+    /// Defines the global variable named by the constant
+    DefineGlobal(Offset, Operand),
+    /// Reads the global variable named by the constant
+    GetGlobal(Register, Offset),
+    /// Assigns the global variable named by the constant
+    SetGlobal(Offset, Operand),
+    /// Stores the string `print` shows for the value
+    ToString(Register, Operand),
+    /// Reads what the module exports under the name in the constant
+    GetProperty(Register, Operand, Offset),
+    /// Calls the value in the register with the given number of
+    /// arguments, in the registers following it, storing the result
+    /// where the value was
+    Call(Register, usize),
+    /// Stores a closure of the function in the constant, see the stack VM
+    Closure(Register, Offset),
+    /// Reads the variable the closure being run captured with the given
+    /// index
+    GetUpvalue(Register, usize),
+    /// Assigns the variable the closure being run captured with the given
+    /// index
+    SetUpvalue(usize, Operand),
+    /// Moves the local variable in the register out of it, for the
+    /// closures that captured it
+    CloseUpvalue(Register),
+    Throw(Operand),
+    /// See the stack VM
+    EnterFinally,
+    /// See the stack VM
+    EndFinally,
+}
+
+impl Instruction {
+    /// The highest register the instruction uses, if any
+    fn highest_register(&self) -> Option<Register> {
+        let register = |operand: Operand| match operand {
+            Operand::Register(register) => Some(register),
+            Operand::Constant(_) => None,
+        };
+        match *self {
+            Instruction::Load(destination, _) => Some(destination),
+            Instruction::Negate(destination, operand) | Instruction::Not(destination, operand) => {
+                register(operand).max(Some(destination))
+            }
+            Instruction::Binary(_, destination, left, right)
+            | Instruction::Index(destination, left, right)
+            | Instruction::SetIndex(destination, left, right) => {
+                register(left).max(register(right)).max(Some(destination))
+            }
+            Instruction::Move(destination, operand)
+            | Instruction::ToString(destination, operand)
+            | Instruction::Length(destination, operand)
+            | Instruction::GetProperty(destination, operand, _) => {
+                register(operand).max(Some(destination))
+            }
+            Instruction::GetGlobal(destination, _)
+            | Instruction::Closure(destination, _)
+            | Instruction::GetUpvalue(destination, _)
+            | Instruction::CloseUpvalue(destination) => Some(destination),
+            Instruction::Print(operand)
+            | Instruction::DefineGlobal(_, operand)
+            | Instruction::SetGlobal(_, operand)
+            | Instruction::SetUpvalue(_, operand)
+            | Instruction::Throw(operand) => register(operand),
+            Instruction::Loop(_) | Instruction::EnterFinally | Instruction::EndFinally => None,
+            Instruction::List(destination, count) => Some(destination + count.max(1) - 1),
+            Instruction::Call(callee, count) => Some(callee + count),
+            Instruction::Map(destination, count) => Some(destination + (2 * count).max(1) - 1),
+            Instruction::JumpIfFalse(condition, _) => Some(condition),
+            Instruction::Jump(_) => None,
+            Instruction::Return(result) => result.and_then(register),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Chunk {
+    instructions: Vec<Instruction>,
+    values: Vec<Constant>,
+    lines: Vec<Line>,
+    /// How many registers the instructions use, so that the VM can
+    /// allocate them upfront
+    register_count: usize,
+    /// Like the ones of the stack VM, with the caught values going in
+    /// the register with the depth of the stack
+    handlers: Vec<Handler>,
+}
+
+impl Chunk {
+    pub fn get(&self, index: usize) -> Instruction {
+        self.instructions[index]
+    }
+
+    pub fn get_value(&self, index: usize) -> &Constant {
+        &self.values[index]
+    }
+
+    /// The constant at the given offset, if there is one
+    pub fn find_value(&self, index: usize) -> Option<&Constant> {
+        self.values.get(index)
+    }
+
+    pub fn get_line(&self, index: usize) -> Line {
+        self.lines[index]
+    }
+
+    /// Adds a new instruction to the chunk, making room for the
+    /// registers it uses
+    /// # Example
+    /// ```
+    /// use rulox::vm::register::bytecode::*;
+    /// let mut chunk = Chunk::default();
+    /// let line = 1;
+    /// chunk.add_instruction(Instruction::Return(Some(Operand::Register(2))), line);
+    /// assert_eq!(3, chunk.register_count());
+    /// ```
+    pub fn add_instruction(&mut self, instruction: Instruction, line: Line) {
+        if let Some(register) = instruction.highest_register() {
+            self.register_count = self.register_count.max(register + 1);
+        }
+        self.instructions.push(instruction);
+        self.lines.push(line);
+    }
+
+    /// Makes the jump at the given index land on the target instruction
+    pub fn patch_jump(&mut self, index: usize, target: usize) {
+        self.instructions[index] = match self.instructions[index] {
+            Instruction::Loop(_) => Instruction::Loop(index + 1 - target),
+            Instruction::Jump(_) => Instruction::Jump(target - index - 1),
+            Instruction::JumpIfFalse(condition, _) => {
+                Instruction::JumpIfFalse(condition, target - index - 1)
+            }
+            instruction => panic!("{:?} is not a jump", instruction),
+        }
+    }
+
+    pub fn instruction_count(&self) -> usize {
+        self.instructions.len()
+    }
+
+    pub fn register_count(&self) -> usize {
+        self.register_count
+    }
+
+    /// Constants live in a separate pool, instructions reference them
+    /// by the offset returned by this function.
+    pub fn add_constant(&mut self, constant: Constant) -> Offset {
+        self.values.push(constant);
+        self.values.len() - 1
+    }
+
+    pub fn values_count(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Adds a handler, after the ones of the `try` statements nested in
+    /// it, making room for the register of what it catches
+    pub fn add_handler(&mut self, handler: Handler) {
+        self.register_count = self.register_count.max(handler.stack_depth + 1);
+        self.handlers.push(handler)
+    }
+
+    /// The handler for the errors of the instruction at the given index
+    pub fn handler_for(&self, index: usize) -> Option<&Handler> {
+        self.handlers
+            .iter()
+            .find(|handler| handler.start <= index && index < handler.end)
+    }
+}
+
+fn write_operand<T>(operand: Operand, chunk: &Chunk, out: &mut LineWriter<T>) -> Result<(), Error>
+where
+    T: Write,
+{
+    match operand {
+        Operand::Constant(offset) if offset < chunk.values_count() => {
+            write!(out, " {}'{:?}'", operand, chunk.get_value(offset))
+        }
+        Operand::Constant(_) => write!(out, " {}'ILLEGAL_ACCESS'", operand),
+        Operand::Register(_) => write!(out, " {}", operand),
+    }
+}
+
+pub fn disassemble_instruction<T>(
+    instruction: &Instruction,
+    chunk: &Chunk,
+    out: &mut LineWriter<T>,
+) -> Result<(), Error>
+where
+    T: Write,
+{
+    match *instruction {
+        Instruction::Load(destination, offset) => {
+            write!(out, "OP_LOAD r{}", destination)?;
+            write_operand(Operand::Constant(offset), chunk, out)?;
+        }
+        Instruction::Negate(destination, operand) => {
+            write!(out, "OP_NEGATE r{}", destination)?;
+            write_operand(operand, chunk, out)?;
+        }
+        Instruction::Not(destination, operand) => {
+            write!(out, "OP_NOT r{}", destination)?;
+            write_operand(operand, chunk, out)?;
+        }
+        Instruction::Binary(operator, destination, left, right) => {
+            let name = match operator {
+                BinaryOp::Add => "OP_ADD",
+                BinaryOp::Subtract => "OP_SUBTRACT",
+                BinaryOp::Multiply => "OP_MULTIPLY",
+                BinaryOp::Divide => "OP_DIVIDE",
+                BinaryOp::Modulo => "OP_MODULO",
+                BinaryOp::IntegerDivide => "OP_INTEGER_DIVIDE",
+                BinaryOp::Equals => "OP_EQUALS",
+                BinaryOp::NotEqual => "OP_NOT_EQUAL",
+                BinaryOp::Greater => "OP_GREATER",
+                BinaryOp::GreaterEqual => "OP_GREATER_EQUAL",
+                BinaryOp::Less => "OP_LESS",
+                BinaryOp::LessEqual => "OP_LESS_EQUAL",
+            };
+            write!(out, "{} r{}", name, destination)?;
+            write_operand(left, chunk, out)?;
+            write_operand(right, chunk, out)?;
+        }
+        Instruction::List(destination, count) => {
+            write!(out, "OP_LIST r{} {:4}", destination, count)?
+        }
+        Instruction::Map(destination, count) => write!(out, "OP_MAP r{} {:5}", destination, count)?,
+        Instruction::Index(destination, list, index) => {
+            write!(out, "OP_INDEX r{}", destination)?;
+            write_operand(list, chunk, out)?;
+            write_operand(index, chunk, out)?;
+        }
+        Instruction::SetIndex(list, index, value) => {
+            write!(out, "OP_SET_INDEX r{}", list)?;
+            write_operand(index, chunk, out)?;
+            write_operand(value, chunk, out)?;
+        }
+        Instruction::Length(destination, operand) => {
+            write!(out, "OP_LENGTH r{}", destination)?;
+            write_operand(operand, chunk, out)?;
+        }
+        Instruction::JumpIfFalse(condition, offset) => {
+            write!(out, "OP_JUMP_IF_FALSE r{} {:4}", condition, offset)?
+        }
+        Instruction::Jump(offset) => write!(out, "OP_JUMP {:10}", offset)?,
+        Instruction::Loop(offset) => write!(out, "OP_LOOP {:10}", offset)?,
+        Instruction::Move(destination, operand) => {
+            write!(out, "OP_MOVE r{}", destination)?;
+            write_operand(operand, chunk, out)?;
+        }
+        Instruction::Print(operand) => {
+            write!(out, "OP_PRINT")?;
+            write_operand(operand, chunk, out)?;
+        }
+        Instruction::DefineGlobal(name, operand) => {
+            write!(out, "OP_DEFINE_GLOBAL")?;
+            write_operand(Operand::Constant(name), chunk, out)?;
+            write_operand(operand, chunk, out)?;
+        }
+        Instruction::GetGlobal(destination, name) => {
+            write!(out, "OP_GET_GLOBAL r{}", destination)?;
+            write_operand(Operand::Constant(name), chunk, out)?;
+        }
+        Instruction::SetGlobal(name, operand) => {
+            write!(out, "OP_SET_GLOBAL")?;
+            write_operand(Operand::Constant(name), chunk, out)?;
+            write_operand(operand, chunk, out)?;
+        }
+        Instruction::ToString(destination, operand) => {
+            write!(out, "OP_TO_STRING r{}", destination)?;
+            write_operand(operand, chunk, out)?;
+        }
+        Instruction::GetProperty(destination, operand, name) => {
+            write!(out, "OP_GET_PROPERTY r{}", destination)?;
+            write_operand(operand, chunk, out)?;
+            write_operand(Operand::Constant(name), chunk, out)?;
+        }
+        Instruction::Call(callee, count) => write!(out, "OP_CALL r{} {:4}", callee, count)?,
+        Instruction::Closure(destination, offset) => {
+            write!(out, "OP_CLOSURE r{}", destination)?;
+            write_operand(Operand::Constant(offset), chunk, out)?;
+        }
+        Instruction::GetUpvalue(destination, index) => {
+            write!(out, "OP_GET_UPVALUE r{} u{}", destination, index)?
+        }
+        Instruction::SetUpvalue(index, operand) => {
+            write!(out, "OP_SET_UPVALUE u{}", index)?;
+            write_operand(operand, chunk, out)?;
+        }
+        Instruction::CloseUpvalue(register) => write!(out, "OP_CLOSE_UPVALUE r{}", register)?,
+        Instruction::Throw(operand) => {
+            write!(out, "OP_THROW")?;
+            write_operand(operand, chunk, out)?;
+        }
+        Instruction::EnterFinally => write!(out, "OP_ENTER_FINALLY")?,
+        Instruction::EndFinally => write!(out, "OP_END_FINALLY")?,
+        Instruction::Return(None) => write!(out, "OP_RETURN")?,
+        Instruction::Return(Some(result)) => {
+            write!(out, "OP_RETURN")?;
+            write_operand(result, chunk, out)?;
+        }
+    }
+    writeln!(out)
+}
+
+pub fn disassemble<T>(chunk: &Chunk, name: &str, out: &mut LineWriter<T>) -> Result<(), Error>
+where
+    T: Write,
+{
+    writeln!(out, "== {} ==", name)?;
+    let mut line = 0;
+    for (i, instruction) in chunk.instructions.iter().enumerate() {
+        write!(out, "{:04}", i)?;
+        if line == chunk.lines[i] {
+            write!(out, "   |")?;
+        } else {
+            line = chunk.lines[i];
+            write!(out, "{:4}", line)?;
+        }
+        write!(out, " ")?;
+        disassemble_instruction(instruction, chunk, out)?;
+    }
+    disassemble_handlers(&chunk.handlers, out)?;
+    for constant in &chunk.values {
+        if let Constant::Function(ref function) = *constant {
+            disassemble(&function.chunk, &function.to_string(), out)?;
+        }
+    }
+    Ok(())
+}
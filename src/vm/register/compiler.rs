@@ -0,0 +1,456 @@
+use std::rc::Rc;
+use vm::bytecode;
+use vm::bytecode::{Capture, Handler, HandlerKind, OpCode};
+use vm::compiler::{self, CompilationError};
+use vm::register::bytecode::{Chunk, Constant, Function, Instruction, Operand};
+
+/// Turns the code of the stack VM into three-address instructions.
+///
+/// The allocator keeps track of where the values the stack VM would
+/// push live: the value at depth `n` of the stack is either kept in
+/// register `n` or, until something forces it into a register, read
+/// straight from the constant pool. Registers are therefore reused as
+/// soon as the values in them are consumed, like stack slots are.
+struct RegisterAllocator<'a> {
+    source: &'a bytecode::Chunk,
+    chunk: Chunk,
+    stack: Vec<Operand>,
+    /// Where each instruction of the source starts in the new chunk
+    starts: Vec<usize>,
+    /// The jumps to patch once all the instructions are emitted, as
+    /// their index in the new chunk, their target in the source and the
+    /// depth of the stack when they jump
+    jumps: Vec<(usize, usize, usize)>,
+}
+
+impl<'a> RegisterAllocator<'a> {
+    fn new(source: &'a bytecode::Chunk) -> RegisterAllocator<'a> {
+        let mut chunk = Chunk::default();
+        // Offsets are kept, so that constants can be referenced as they are
+        for offset in 0..source.values_count() {
+            chunk.add_constant(match *source.get_value(offset) {
+                bytecode::Constant::Number(n) => Constant::Number(n),
+                bytecode::Constant::Bool(b) => Constant::Bool(b),
+                bytecode::Constant::Nil => Constant::Nil,
+                bytecode::Constant::String(ref s) => Constant::String(s.clone()),
+                bytecode::Constant::Function(ref function) => {
+                    Constant::Function(Rc::new(allocate_function(function)))
+                }
+            });
+        }
+        RegisterAllocator {
+            source,
+            chunk,
+            stack: vec![],
+            starts: vec![],
+            jumps: vec![],
+        }
+    }
+
+    fn pop(&mut self) -> Operand {
+        self.stack
+            .pop()
+            .expect("The stack compiler never pops from an empty stack")
+    }
+
+    /// The register for the result of an instruction, which takes the
+    /// place of its operands on the stack
+    fn push_result(&mut self) -> usize {
+        let register = self.stack.len();
+        self.stack.push(Operand::Register(register));
+        register
+    }
+
+    /// Moves the constants on the stack into their registers.
+    /// This is needed wherever control flow splits or merges, so that
+    /// every path agrees on where values are.
+    fn materialize(&mut self, line: usize) {
+        self.materialize_from(0, line)
+    }
+
+    /// Moves the constants on the stack from the given depth up into
+    /// their registers
+    fn materialize_from(&mut self, first: usize, line: usize) {
+        for register in first..self.stack.len() {
+            self.materialize_at(register, line);
+        }
+    }
+
+    /// Moves the value at the given depth of the stack into its register
+    /// if it's a constant
+    fn materialize_at(&mut self, register: usize, line: usize) {
+        if let Operand::Constant(offset) = self.stack[register] {
+            self.chunk
+                .add_instruction(Instruction::Load(register, offset), line);
+            self.stack[register] = Operand::Register(register);
+        }
+    }
+
+    /// Adds the jump at the given index of the source, to be patched to
+    /// land where the instruction at the target index of the source starts
+    fn jump(&mut self, instruction: Instruction, index: usize, target: usize) {
+        self.jumps
+            .push((self.chunk.instruction_count(), target, self.stack.len()));
+        self.chunk
+            .add_instruction(instruction, self.source.get_line(index));
+    }
+
+    /// Where the stack is when the handler starts: unwound to the
+    /// locals, which are in their registers since the try started, and
+    /// topped by what was caught for a catch block
+    fn unwind(&mut self, handler: &Handler) {
+        self.stack = (0..handler.stack_depth).map(Operand::Register).collect();
+        if handler.kind == HandlerKind::Catch {
+            self.stack.push(Operand::Register(handler.stack_depth));
+        }
+    }
+
+    fn allocate(mut self) -> Chunk {
+        let count = self.source.instruction_count();
+        let mut targets = vec![false; count];
+        for index in 0..count {
+            match self.source.get(index) {
+                OpCode::Jump(offset) | OpCode::JumpIfFalse(offset)
+                    if index + 1 + offset < count =>
+                {
+                    targets[index + 1 + offset] = true
+                }
+                OpCode::Loop(offset) if offset <= index + 1 => targets[index + 1 - offset] = true,
+                _ => {}
+            }
+        }
+        // The handlers of a try expect its locals in their registers
+        for handler in self.source.handlers() {
+            if handler.start < count {
+                targets[handler.start] = true;
+            }
+        }
+        let mut falls_through = true;
+        for (index, &is_target) in targets.iter().enumerate() {
+            let line = self.source.get_line(index);
+            if !falls_through {
+                // Only reached by jumping, with the stack of the jumps,
+                // all in registers
+                if let Some(&(_, _, depth)) =
+                    self.jumps.iter().find(|&&(_, target, _)| target == index)
+                {
+                    self.stack = (0..depth).map(Operand::Register).collect();
+                }
+            }
+            if let Some(&handler) = self
+                .source
+                .handlers()
+                .iter()
+                .find(|handler| handler.target == index)
+            {
+                self.unwind(&handler);
+            }
+            if is_target {
+                self.materialize(line);
+            }
+            self.starts.push(self.chunk.instruction_count());
+            let instruction = self.source.get(index);
+            falls_through = !matches!(
+                instruction,
+                OpCode::Jump(_) | OpCode::Loop(_) | OpCode::Return
+            );
+            match instruction {
+                OpCode::Constant(offset) => self.stack.push(Operand::Constant(offset)),
+                OpCode::Negate => {
+                    let operand = self.pop();
+                    let destination = self.push_result();
+                    self.chunk
+                        .add_instruction(Instruction::Negate(destination, operand), line);
+                }
+                OpCode::Not => {
+                    let operand = self.pop();
+                    let destination = self.push_result();
+                    self.chunk
+                        .add_instruction(Instruction::Not(destination, operand), line);
+                }
+                OpCode::Binary(operator) => {
+                    let right = self.pop();
+                    let left = self.pop();
+                    let destination = self.push_result();
+                    self.chunk.add_instruction(
+                        Instruction::Binary(operator, destination, left, right),
+                        line,
+                    );
+                }
+                OpCode::List(count) => {
+                    // The elements have to be in consecutive registers
+                    let first = self.stack.len() - count;
+                    self.materialize_from(first, line);
+                    self.stack.truncate(first);
+                    let destination = self.push_result();
+                    self.chunk
+                        .add_instruction(Instruction::List(destination, count), line);
+                }
+                OpCode::Map(count) => {
+                    let first = self.stack.len() - 2 * count;
+                    self.materialize_from(first, line);
+                    self.stack.truncate(first);
+                    let destination = self.push_result();
+                    self.chunk
+                        .add_instruction(Instruction::Map(destination, count), line);
+                }
+                OpCode::Index => {
+                    let index = self.pop();
+                    let list = self.pop();
+                    let destination = self.push_result();
+                    self.chunk
+                        .add_instruction(Instruction::Index(destination, list, index), line);
+                }
+                OpCode::SetIndex => {
+                    let value = self.pop();
+                    let index = self.pop();
+                    // The result replaces the list, which has to be in
+                    // its register
+                    let list = self.stack.len() - 1;
+                    self.materialize_from(list, line);
+                    self.pop();
+                    let list = self.push_result();
+                    self.chunk
+                        .add_instruction(Instruction::SetIndex(list, index, value), line);
+                }
+                OpCode::Length => {
+                    let value = self.pop();
+                    let destination = self.push_result();
+                    self.chunk
+                        .add_instruction(Instruction::Length(destination, value), line);
+                }
+                OpCode::JumpIfFalse(offset) => {
+                    self.materialize(line);
+                    let condition = self.stack.len() - 1;
+                    self.jump(
+                        Instruction::JumpIfFalse(condition, 0),
+                        index,
+                        index + 1 + offset,
+                    );
+                }
+                OpCode::Jump(offset) => {
+                    self.materialize(line);
+                    self.jump(Instruction::Jump(0), index, index + 1 + offset);
+                }
+                OpCode::Loop(offset) => {
+                    self.materialize(line);
+                    self.jump(Instruction::Loop(0), index, index + 1 - offset);
+                }
+                OpCode::GetLocal(slot) => {
+                    let value = self.stack[slot];
+                    let destination = self.push_result();
+                    self.chunk
+                        .add_instruction(Instruction::Move(destination, value), line);
+                }
+                OpCode::SetLocal(slot) => {
+                    let value = *self
+                        .stack
+                        .last()
+                        .expect("The stack compiler only assigns values on the stack");
+                    self.chunk
+                        .add_instruction(Instruction::Move(slot, value), line);
+                    self.stack[slot] = Operand::Register(slot);
+                }
+                OpCode::Print => {
+                    let value = self.pop();
+                    self.chunk.add_instruction(Instruction::Print(value), line);
+                }
+                OpCode::DefineGlobal(name) => {
+                    let value = self.pop();
+                    self.chunk
+                        .add_instruction(Instruction::DefineGlobal(name, value), line);
+                }
+                OpCode::GetGlobal(name) => {
+                    let destination = self.push_result();
+                    self.chunk
+                        .add_instruction(Instruction::GetGlobal(destination, name), line);
+                }
+                OpCode::SetGlobal(name) => {
+                    let value = *self
+                        .stack
+                        .last()
+                        .expect("The stack compiler only assigns values on the stack");
+                    self.chunk
+                        .add_instruction(Instruction::SetGlobal(name, value), line);
+                }
+                OpCode::ToString => {
+                    let value = self.pop();
+                    let destination = self.push_result();
+                    self.chunk
+                        .add_instruction(Instruction::ToString(destination, value), line);
+                }
+                OpCode::GetProperty(name) => {
+                    let object = self.pop();
+                    let destination = self.push_result();
+                    self.chunk
+                        .add_instruction(Instruction::GetProperty(destination, object, name), line);
+                }
+                OpCode::Call(count) => {
+                    // The callee and the arguments have to be in
+                    // consecutive registers
+                    let callee = self.stack.len() - count - 1;
+                    self.materialize_from(callee, line);
+                    self.stack.truncate(callee);
+                    let destination = self.push_result();
+                    self.chunk
+                        .add_instruction(Instruction::Call(destination, count), line);
+                }
+                OpCode::Closure(offset) => {
+                    // The locals it captures are read from their registers
+                    if let bytecode::Constant::Function(ref function) =
+                        *self.source.get_value(offset)
+                    {
+                        for &capture in &function.upvalues {
+                            if let Capture::Local(slot) = capture {
+                                self.materialize_at(slot, line);
+                            }
+                        }
+                    }
+                    let destination = self.push_result();
+                    self.chunk
+                        .add_instruction(Instruction::Closure(destination, offset), line);
+                }
+                OpCode::GetUpvalue(index) => {
+                    let destination = self.push_result();
+                    self.chunk
+                        .add_instruction(Instruction::GetUpvalue(destination, index), line);
+                }
+                OpCode::SetUpvalue(index) => {
+                    let value = *self
+                        .stack
+                        .last()
+                        .expect("The stack compiler only assigns values on the stack");
+                    self.chunk
+                        .add_instruction(Instruction::SetUpvalue(index, value), line);
+                }
+                OpCode::CloseUpvalue => {
+                    let local = self.stack.len() - 1;
+                    self.materialize_from(local, line);
+                    self.pop();
+                    self.chunk
+                        .add_instruction(Instruction::CloseUpvalue(local), line);
+                }
+                OpCode::Throw => {
+                    let value = self.pop();
+                    self.chunk.add_instruction(Instruction::Throw(value), line);
+                }
+                OpCode::EnterFinally => self.chunk.add_instruction(Instruction::EnterFinally, line),
+                OpCode::EndFinally => self.chunk.add_instruction(Instruction::EndFinally, line),
+                OpCode::Pop => {
+                    self.pop();
+                }
+                OpCode::Return => {
+                    let result = self.stack.pop();
+                    self.chunk
+                        .add_instruction(Instruction::Return(result), line);
+                }
+            }
+        }
+        self.starts.push(self.chunk.instruction_count());
+        for &(jump, target, _) in &self.jumps {
+            let target = self.starts[target.min(count)];
+            self.chunk.patch_jump(jump, target);
+        }
+        for handler in self.source.handlers() {
+            let start = |index: usize| self.starts[index.min(count)];
+            let handler = Handler {
+                start: start(handler.start),
+                end: start(handler.end),
+                target: start(handler.target),
+                ..*handler
+            };
+            self.chunk.add_handler(handler);
+        }
+        self.chunk
+    }
+}
+
+/// Translates a function like a script, its parameters being in the
+/// registers following the one of the function like they are on the stack
+fn allocate_function(function: &bytecode::Function) -> Function {
+    let mut allocator = RegisterAllocator::new(&function.chunk);
+    allocator.stack = (0..=function.arity).map(Operand::Register).collect();
+    Function {
+        name: function.name.clone(),
+        arity: function.arity,
+        upvalues: function.upvalues.clone(),
+        chunk: allocator.allocate(),
+    }
+}
+
+/// Translates bytecode of the stack VM into bytecode for the register VM
+pub fn allocate_registers(chunk: &bytecode::Chunk) -> Chunk {
+    RegisterAllocator::new(chunk).allocate()
+}
+
+/// Compiles a text producing either the corresponding chunk of
+/// bytecode or an error. The language is the one of the stack VM, whose
+/// compiler is used before allocating registers.
+pub fn compile(text: &str) -> Result<Chunk, Vec<CompilationError>> {
+    compiler::compile(text).map(|chunk| allocate_registers(&chunk))
+}
+
+#[cfg(test)]
+mod tests {
+    use vm::bytecode::BinaryOp;
+    use vm::register::bytecode::{Instruction, Operand};
+    use vm::register::compiler::compile;
+
+    fn instructions(source: &str) -> Vec<Instruction> {
+        let chunk = compile(source).unwrap();
+        (0..chunk.instruction_count())
+            .map(|i| chunk.get(i))
+            .collect()
+    }
+
+    #[test]
+    fn constants_are_operands() {
+        assert_eq!(
+            vec![
+                Instruction::Binary(
+                    BinaryOp::Multiply,
+                    1,
+                    Operand::Constant(1),
+                    Operand::Constant(2),
+                ),
+                Instruction::Binary(BinaryOp::Add, 0, Operand::Constant(0), Operand::Register(1)),
+                Instruction::Return(Some(Operand::Register(0))),
+            ],
+            instructions("1 + 2 * 3")
+        );
+    }
+
+    #[test]
+    fn registers_are_reused() {
+        let chunk = compile("(1 + 2) * (3 + 4) - (5 + 6)").unwrap();
+        assert_eq!(2, chunk.register_count());
+    }
+
+    #[test]
+    fn list_elements_are_loaded_in_order() {
+        assert_eq!(
+            vec![
+                Instruction::Load(1, 1),
+                Instruction::Load(2, 2),
+                Instruction::List(1, 2),
+                Instruction::Index(1, Operand::Register(1), Operand::Constant(3)),
+                Instruction::Binary(BinaryOp::Add, 0, Operand::Constant(0), Operand::Register(1)),
+                Instruction::Return(Some(Operand::Register(0))),
+            ],
+            instructions("1 + [2, 3][0]")
+        );
+    }
+
+    #[test]
+    fn branches_agree_on_registers() {
+        assert_eq!(
+            vec![
+                Instruction::Load(0, 0),
+                Instruction::JumpIfFalse(0, 1),
+                Instruction::Load(0, 1),
+                Instruction::Return(Some(Operand::Register(0))),
+            ],
+            instructions("true and 1")
+        );
+    }
+}
@@ -0,0 +1,639 @@
+use map::OrderedMap;
+use output;
+use std::cell::RefCell;
+use std::io::{Error, LineWriter, Write};
+use std::mem;
+use std::rc::Rc;
+use vm::bytecode::{Handler, HandlerKind};
+use vm::interpreter::{
+    binary, call, caught_value, check_call, close_upvalues, closure, index, length, map, name,
+    property, set_index, Closure, Frame, Globals, ObjectReference, ObjectValue, RuntimeError,
+    Upvalue, UpvalueReference, Value,
+};
+use vm::register::bytecode::{disassemble_instruction, Chunk, Constant, Instruction, Operand};
+
+struct Vm<'a> {
+    /// The chunk of the script
+    chunk: &'a Chunk,
+    globals: &'a mut Globals,
+    /// The registers of a call start at the one of the closure, see the
+    /// stack VM
+    frame: Frame<Chunk>,
+    /// The frames of the callers, innermost last
+    frames: Vec<Frame<Chunk>>,
+    /// All the registers the chunks being run use, so that instructions
+    /// can access them without bound checks failing
+    registers: Vec<Value>,
+    /// The upvalues of the variables still in their registers
+    open_upvalues: Vec<UpvalueReference>,
+    /// The value returned by the chunk, if any
+    result: Option<Value>,
+    /// Allocated objects, see the stack VM
+    objects: Vec<ObjectReference>,
+    /// See the stack VM
+    kept_errors: Vec<Option<RuntimeError>>,
+}
+
+impl<'a> Vm<'a> {
+    fn new(chunk: &'a Chunk, globals: &'a mut Globals) -> Vm<'a> {
+        Vm {
+            chunk,
+            globals,
+            frame: Frame::script(),
+            frames: vec![],
+            registers: vec![Value::Nil; chunk.register_count()],
+            open_upvalues: vec![],
+            result: None,
+            objects: vec![],
+            kept_errors: vec![],
+        }
+    }
+
+    fn allocate_string(&mut self, value: String) -> ObjectReference {
+        let o = Rc::new(ObjectValue::String(value));
+        self.objects.push(o.clone());
+        o
+    }
+
+    fn allocate_list(&mut self, elements: Vec<Value>) -> ObjectReference {
+        let o = Rc::new(ObjectValue::List(RefCell::new(elements)));
+        self.objects.push(o.clone());
+        o
+    }
+
+    fn allocate_map(&mut self, map: OrderedMap<Value>) -> ObjectReference {
+        let o = Rc::new(ObjectValue::Map(RefCell::new(map)));
+        self.objects.push(o.clone());
+        o
+    }
+
+    fn allocate_closure(&mut self, closure: Closure<Chunk>) -> ObjectReference {
+        let o = Rc::new(ObjectValue::RegisterClosure(closure));
+        self.objects.push(o.clone());
+        o
+    }
+
+    /// Moves the variables in the given register and above out of their
+    /// registers, see `close_upvalues`
+    fn close_upvalues(&mut self, first: usize) {
+        let registers = &self.registers;
+        close_upvalues(&mut self.open_upvalues, first, |register| {
+            registers.get(register).cloned().unwrap_or(Value::Nil)
+        });
+    }
+
+    /// Runs the closure in a frame of its own, its registers starting at
+    /// the one of the closure, which the arguments follow
+    fn call_closure(
+        &mut self,
+        closure: Closure<Chunk>,
+        callee: usize,
+        count: usize,
+    ) -> Result<(), RuntimeError> {
+        check_call(&closure, count, self.frames.len())?;
+        let base = self.frame.base + callee;
+        let needed = base + closure.function.chunk.register_count().max(count + 1);
+        if self.registers.len() < needed {
+            self.registers.resize(needed, Value::Nil);
+        }
+        let frame = Frame::call(closure, base, self.kept_errors.len());
+        self.frames.push(mem::replace(&mut self.frame, frame));
+        Ok(())
+    }
+
+    /// Goes back to the caller, closing the variables of the function
+    /// being run
+    fn leave_frame(&mut self, caller: Frame<Chunk>) {
+        let base = self.frame.base;
+        self.close_upvalues(base);
+        self.kept_errors.truncate(self.frame.kept_errors);
+        self.frame = caller;
+    }
+
+    /// Has the handler deal with the error, what a catch block gets
+    /// going in the register after the locals
+    fn handle(&mut self, error: RuntimeError, handler: Handler) {
+        let depth = self.frame.base + handler.stack_depth;
+        self.close_upvalues(depth);
+        self.kept_errors
+            .truncate(self.frame.kept_errors + handler.kept_errors);
+        match handler.kind {
+            HandlerKind::Catch => {
+                let objects = &mut self.objects;
+                self.registers[depth] = caught_value(error, |object| {
+                    let o = Rc::new(object);
+                    objects.push(o.clone());
+                    o
+                });
+            }
+            HandlerKind::Finally => self.kept_errors.push(Some(error)),
+        }
+        self.frame.program_counter = handler.target;
+    }
+
+    fn constant(&mut self, chunk: &Chunk, offset: usize) -> Result<Value, RuntimeError> {
+        if offset >= chunk.values_count() {
+            return Err(RuntimeError::ValueOutOfBound);
+        }
+        Ok(match chunk.get_value(offset) {
+            Constant::Number(n) => Value::Number(*n),
+            Constant::Bool(b) => Value::Bool(*b),
+            Constant::Nil => Value::Nil,
+            Constant::String(ref s) => Value::Object(self.allocate_string(s.clone())),
+            // Only used by `Closure`, which doesn't need a value
+            Constant::Function(_) => Value::Nil,
+        })
+    }
+
+    fn read(&mut self, chunk: &Chunk, operand: Operand) -> Result<Value, RuntimeError> {
+        match operand {
+            Operand::Register(register) => Ok(self.registers[self.frame.base + register].clone()),
+            Operand::Constant(offset) => self.constant(chunk, offset),
+        }
+    }
+
+    /// Interprets the next instruction, updating the program counter
+    /// and the registers.
+    ///
+    /// This function returns true if there are other instructions left
+    /// to execute or false if we're done interpreting the chunk.
+    fn interpret_next(&mut self) -> Result<bool, RuntimeError> {
+        let function = self.frame.function();
+        let chunk = function.as_ref().map_or(self.chunk, |f| &f.chunk);
+        let base = self.frame.base;
+        self.frame.program_counter += 1;
+        if self.frame.program_counter > chunk.instruction_count() {
+            return Err(RuntimeError::InstructionOutOfBound);
+        }
+        match chunk.get(self.frame.program_counter - 1) {
+            Instruction::Return(result) => {
+                let result = match result {
+                    Some(operand) => Some(self.read(chunk, operand)?),
+                    None => None,
+                };
+                match self.frames.pop() {
+                    Some(caller) => {
+                        self.leave_frame(caller);
+                        self.registers[base] = result.unwrap_or(Value::Nil);
+                    }
+                    None => {
+                        self.result = result;
+                        return Ok(false);
+                    }
+                }
+            }
+            Instruction::Load(destination, offset) => {
+                self.registers[base + destination] = self.constant(chunk, offset)?;
+            }
+            Instruction::Negate(destination, operand) => match self.read(chunk, operand)? {
+                Value::Number(n) => self.registers[base + destination] = Value::Number(-n),
+                _ => return Err(RuntimeError::UnaryMinusTypeMismatch),
+            },
+            Instruction::Not(destination, operand) => {
+                let value = self.read(chunk, operand)?;
+                self.registers[base + destination] = Value::Bool(value.is_falsey());
+            }
+            Instruction::JumpIfFalse(condition, offset) => {
+                if self.registers[base + condition].is_falsey() {
+                    self.frame.program_counter += offset;
+                }
+            }
+            Instruction::Jump(offset) => self.frame.program_counter += offset,
+            Instruction::Loop(offset) => {
+                if offset > self.frame.program_counter {
+                    return Err(RuntimeError::InstructionOutOfBound);
+                }
+                self.frame.program_counter -= offset;
+            }
+            Instruction::Move(destination, operand) => {
+                self.registers[base + destination] = self.read(chunk, operand)?;
+            }
+            Instruction::Print(operand) => output::print(self.read(chunk, operand)?),
+            Instruction::DefineGlobal(offset, operand) => {
+                let value = self.read(chunk, operand)?;
+                self.globals
+                    .define(name(chunk.find_value(offset))?, value.into());
+            }
+            Instruction::GetGlobal(destination, offset) => {
+                let name = name(chunk.find_value(offset))?;
+                self.registers[base + destination] = self
+                    .globals
+                    .get(name)
+                    .ok_or_else(|| RuntimeError::UndefinedVariable(name.into()))?
+                    .to_value();
+            }
+            Instruction::SetGlobal(offset, operand) => {
+                let value = self.read(chunk, operand)?;
+                self.globals
+                    .assign(name(chunk.find_value(offset))?, value.into())?;
+            }
+            Instruction::Closure(destination, offset) => {
+                let function = match chunk.find_value(offset) {
+                    Some(Constant::Function(ref function)) => function,
+                    Some(_) => return Err(RuntimeError::NotAFunction),
+                    None => return Err(RuntimeError::ValueOutOfBound),
+                };
+                let closure = closure(
+                    function,
+                    base,
+                    self.frame.closure.as_ref(),
+                    &mut self.open_upvalues,
+                )?;
+                self.registers[base + destination] = Value::Object(self.allocate_closure(closure));
+            }
+            Instruction::GetUpvalue(destination, index) => {
+                let value = match *self.frame.upvalue(index)?.borrow() {
+                    Upvalue::Open(register) => self.registers[register].clone(),
+                    Upvalue::Closed(ref value) => value.clone(),
+                };
+                self.registers[base + destination] = value;
+            }
+            Instruction::SetUpvalue(index, operand) => {
+                let value = self.read(chunk, operand)?;
+                match *self.frame.upvalue(index)?.borrow_mut() {
+                    Upvalue::Open(register) => self.registers[register] = value,
+                    Upvalue::Closed(ref mut variable) => *variable = value,
+                }
+            }
+            Instruction::CloseUpvalue(register) => self.close_upvalues(base + register),
+            Instruction::ToString(destination, operand) => {
+                let value = self.read(chunk, operand)?;
+                self.registers[base + destination] =
+                    Value::Object(self.allocate_string(value.to_string()));
+            }
+            Instruction::GetProperty(destination, object, offset) => {
+                let object = self.read(chunk, object)?;
+                self.registers[base + destination] =
+                    property(object, name(chunk.find_value(offset))?)?;
+            }
+            Instruction::Call(callee, count) => {
+                if let Value::Object(ref object) = self.registers[base + callee] {
+                    if let ObjectValue::RegisterClosure(ref closure) = **object {
+                        let closure = closure.clone();
+                        self.call_closure(closure, callee, count)?;
+                        return Ok(true);
+                    }
+                }
+                let first = base + callee + 1;
+                let arguments = &self.registers[first..first + count];
+                let result = call(&self.registers[base + callee], arguments)?;
+                if let Value::Object(ref object) = result {
+                    self.objects.push(object.clone());
+                }
+                self.registers[base + callee] = result;
+            }
+            Instruction::Throw(operand) => {
+                return Err(RuntimeError::Thrown(self.read(chunk, operand)?))
+            }
+            Instruction::EnterFinally => self.kept_errors.push(None),
+            Instruction::EndFinally => {
+                if let Some(error) = self.kept_errors.pop().ok_or(RuntimeError::StackUnderflow)? {
+                    return Err(error);
+                }
+            }
+            Instruction::Binary(operator, destination, left, right) => {
+                let left = self.read(chunk, left)?;
+                let right = self.read(chunk, right)?;
+                self.registers[base + destination] =
+                    binary(operator, left, right, |s| self.allocate_string(s))?;
+            }
+            Instruction::List(destination, count) => {
+                let first = base + destination;
+                let elements = self.registers[first..first + count].to_vec();
+                self.registers[first] = Value::Object(self.allocate_list(elements));
+            }
+            Instruction::Map(destination, count) => {
+                let first = base + destination;
+                let entries = self.registers[first..first + 2 * count].to_vec();
+                let map = map(entries)?;
+                self.registers[first] = Value::Object(self.allocate_map(map));
+            }
+            Instruction::SetIndex(list, position, value) => {
+                let position = self.read(chunk, position)?;
+                let value = self.read(chunk, value)?;
+                set_index(&self.registers[base + list], &position, value.clone())?;
+                self.registers[base + list] = value;
+            }
+            Instruction::Length(destination, operand) => {
+                let value = self.read(chunk, operand)?;
+                self.registers[base + destination] = length(&value)?;
+            }
+            Instruction::Index(destination, collection, position) => {
+                let collection = self.read(chunk, collection)?;
+                let position = self.read(chunk, position)?;
+                self.registers[base + destination] = index(collection, position)?;
+            }
+        };
+        Ok(true)
+    }
+
+    /// Interprets the next instruction, giving its line to the errors
+    /// it raises and passing them to their handler like the stack VM
+    fn step(&mut self) -> Result<bool, RuntimeError> {
+        let error = match self.interpret_next() {
+            Ok(more) => return Ok(more),
+            Err(RuntimeError::Exit(code)) => return Err(RuntimeError::Exit(code)),
+            Err(error) => error,
+        };
+        let function = self.frame.function();
+        let chunk = function.as_ref().map_or(self.chunk, |f| &f.chunk);
+        if self.frame.program_counter > chunk.instruction_count() {
+            return Err(error);
+        }
+        let error = match error {
+            RuntimeError::AtLine(..) => error,
+            error => RuntimeError::AtLine(
+                chunk.get_line(self.frame.program_counter - 1),
+                Box::new(error),
+            ),
+        };
+        self.unwind(error)
+    }
+
+    /// Passes the error to the handler of the instruction that raised it,
+    /// or of the calls that led to it, like the stack VM
+    fn unwind(&mut self, error: RuntimeError) -> Result<bool, RuntimeError> {
+        loop {
+            let function = self.frame.function();
+            let chunk = function.as_ref().map_or(self.chunk, |f| &f.chunk);
+            if let Some(&handler) = chunk.handler_for(self.frame.program_counter - 1) {
+                self.handle(error, handler);
+                return Ok(true);
+            }
+            match self.frames.pop() {
+                Some(caller) => self.leave_frame(caller),
+                None => return Err(error),
+            }
+        }
+    }
+
+    fn trace<T>(&mut self, out: &mut LineWriter<T>) -> Result<(), Error>
+    where
+        T: Write,
+    {
+        writeln!(out, "Program Counter: {}", self.frame.program_counter)?;
+        write!(out, "Registers: ")?;
+        for value in &self.registers[self.frame.base..] {
+            write!(out, "[ {:?} ]", value)?;
+        }
+        writeln!(out)?;
+        let function = self.frame.function();
+        let chunk = function.as_ref().map_or(self.chunk, |f| &f.chunk);
+        if self.frame.program_counter < chunk.instruction_count() {
+            disassemble_instruction(&chunk.get(self.frame.program_counter), chunk, out)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Runs the chunk with the given global variables and returns the value
+/// it produced
+pub fn run(chunk: &Chunk, globals: &mut Globals) -> Result<Option<Value>, RuntimeError> {
+    let mut vm = Vm::new(chunk, globals);
+    let mut result = Ok(true);
+    while let Ok(true) = result {
+        result = vm.step();
+    }
+    // See the stack VM
+    vm.close_upvalues(0);
+    result?;
+    Ok(vm.result)
+}
+
+/// Runs the chunk, without any global variable, and returns the value it
+/// produced
+pub fn interpret(chunk: &Chunk) -> Result<Option<Value>, RuntimeError> {
+    run(chunk, &mut Globals::default())
+}
+
+/// Runs the chunk and returns how many instructions it executed
+pub fn dispatch_count(chunk: &Chunk) -> Result<usize, RuntimeError> {
+    let mut globals = Globals::default();
+    let mut vm = Vm::new(chunk, &mut globals);
+    let mut count = 1;
+    while vm.step()? {
+        count += 1;
+    }
+    Ok(count)
+}
+
+pub fn trace<T>(chunk: &Chunk, writer: &mut LineWriter<T>) -> Result<(), RuntimeError>
+where
+    T: Write,
+{
+    let mut globals = Globals::default();
+    let mut vm = Vm::new(chunk, &mut globals);
+    while {
+        vm.trace(writer).map_err(RuntimeError::TracingError)?;
+        vm.step()?
+    } {}
+    writeln!(writer, "{:?}", vm.result).map_err(RuntimeError::TracingError)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use std::io::*;
+    use vm::bytecode::{BinaryOp, Constant};
+    use vm::register::bytecode::*;
+    use vm::register::interpreter::{interpret, trace};
+
+    fn arb_operand(max: usize) -> BoxedStrategy<Operand> {
+        prop_oneof![
+            (0..max).prop_map(Operand::Register),
+            (0..max).prop_map(Operand::Constant),
+        ]
+        .boxed()
+    }
+
+    /// Loops are left out, as they could run forever
+    fn arb_instruction(max: usize) -> BoxedStrategy<Instruction> {
+        prop_oneof![
+            ((0..max), (0..max)).prop_map(|(r, k)| Instruction::Load(r, k)),
+            ((0..max), arb_operand(max)).prop_map(|(r, o)| Instruction::Negate(r, o)),
+            ((0..max), arb_operand(max)).prop_map(|(r, o)| Instruction::Not(r, o)),
+            ((0..max), arb_operand(max), arb_operand(max))
+                .prop_map(|(r, l, o)| Instruction::Binary(BinaryOp::Add, r, l, o)),
+            ((0..max), (0..max)).prop_map(|(r, o)| Instruction::JumpIfFalse(r, o)),
+            (0..max).prop_map(Instruction::Jump),
+            prop::option::of(arb_operand(max)).prop_map(Instruction::Return),
+            ((0..max), (0..max)).prop_map(|(r, n)| Instruction::List(r, n)),
+            ((0..max), (0..max)).prop_map(|(r, n)| Instruction::Map(r, n)),
+            ((0..max), arb_operand(max), arb_operand(max))
+                .prop_map(|(r, l, i)| Instruction::Index(r, l, i)),
+            ((0..max), arb_operand(max)).prop_map(|(r, o)| Instruction::Move(r, o)),
+            ((0..max), arb_operand(max)).prop_map(|(k, o)| Instruction::DefineGlobal(k, o)),
+            ((0..max), (0..max)).prop_map(|(r, k)| Instruction::GetGlobal(r, k)),
+            ((0..max), arb_operand(max)).prop_map(|(k, o)| Instruction::SetGlobal(k, o)),
+            ((0..max), arb_operand(max), arb_operand(max))
+                .prop_map(|(r, i, o)| Instruction::SetIndex(r, i, o)),
+            ((0..max), arb_operand(max)).prop_map(|(r, o)| Instruction::Length(r, o)),
+            ((0..max), arb_operand(max)).prop_map(|(r, o)| Instruction::ToString(r, o)),
+            ((0..max), arb_operand(max), (0..max))
+                .prop_map(|(r, o, k)| Instruction::GetProperty(r, o, k)),
+            ((0..max), (0..max)).prop_map(|(r, n)| Instruction::Call(r, n)),
+            ((0..max), (0..max)).prop_map(|(r, k)| Instruction::Closure(r, k)),
+            ((0..max), (0..max)).prop_map(|(r, i)| Instruction::GetUpvalue(r, i)),
+            ((0..max), arb_operand(max)).prop_map(|(i, o)| Instruction::SetUpvalue(i, o)),
+            (0..max).prop_map(Instruction::CloseUpvalue),
+            arb_operand(max).prop_map(Instruction::Throw),
+            Just(Instruction::EnterFinally),
+            Just(Instruction::EndFinally),
+        ]
+        .boxed()
+    }
+
+    prop_compose! {
+        fn arb_chunk(max : usize, max_instructions : usize)
+            (constants in prop::collection::vec(prop::num::f64::ANY, 0..max),
+             instructions in prop::collection::vec(arb_instruction(max), 0..max_instructions)) -> Chunk {
+            let mut chunk = Chunk::default();
+            for constant in constants {
+                chunk.add_constant(Constant::Number(constant));
+            }
+            for (line, instruction) in instructions.into_iter().enumerate() {
+                chunk.add_instruction(instruction, line);
+            }
+            chunk
+        }
+    }
+
+    proptest! {
+    #[test]
+    fn interpret_doesnt_crash(ref chunk in arb_chunk(10, 20)) {
+        let _ = interpret(chunk);
+    }
+
+    #[test]
+    fn trace_doesnt_crash(ref chunk in arb_chunk(10, 20)) {
+        let mut writer = LineWriter::new(sink());
+        let _ = trace(chunk, &mut writer);
+    }
+
+    #[test]
+    fn disassemble_doesnt_crash(ref chunk in arb_chunk(10, 20)) {
+        let mut writer = LineWriter::new(sink());
+        let _ = disassemble(chunk, "Test", &mut writer);
+    }
+    }
+}
+
+#[cfg(test)]
+mod end_to_end_tests {
+    use vm::interpreter::Value;
+    use vm::register::compiler::compile;
+    use vm::register::interpreter::{dispatch_count, interpret};
+    use vm::{compiler, interpreter};
+
+    fn run(source: &str) -> Option<Value> {
+        interpret(&compile(source).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(Some(Value::Number(25.0)), run("-5+10*3"));
+        assert_eq!(Some(Value::Number(33.0)), run("(5+6)*(1+2)"));
+        assert_eq!(Some(Value::Number(5.0)), run("-7 % 3 + 0b111 ~/ 2"));
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!("abc", run("\"a\" + \"b\" + \"c\"").unwrap().to_string());
+    }
+
+    #[test]
+    fn short_circuit() {
+        for &(source, ref expected) in &[
+            ("nil or 2", Value::Number(2.0)),
+            ("1 or -nil", Value::Number(1.0)),
+            ("1 and false", Value::Bool(false)),
+            ("false and -nil", Value::Bool(false)),
+            ("1 + (nil or 2 and 3)", Value::Number(4.0)),
+        ] {
+            assert_eq!(Some(expected.clone()), run(source), "Running {}", source);
+        }
+    }
+
+    #[test]
+    fn lists() {
+        assert_eq!(
+            "[1, ab, [nil]]",
+            run("[1, \"a\" + \"b\", [nil]]").unwrap().to_string()
+        );
+        assert_eq!(Some(Value::Number(5.0)), run("[1, [2, 3]][1][1] + 2"));
+        assert_eq!(Some(Value::Bool(true)), run("[] == [] and [1] != [2]"));
+        assert!(interpret(&compile("[1][1]").unwrap()).is_err());
+    }
+
+    #[test]
+    fn maps() {
+        assert_eq!(
+            "{a: 1, 2: [nil]}",
+            run("{\"a\": 1, 2: [nil]}").unwrap().to_string()
+        );
+        assert_eq!(Some(Value::Number(5.0)), run("{nil: {1: 3}}[nil][1] + 2"));
+        assert_eq!(
+            Some(Value::Bool(true)),
+            run("{} == {} and {1: 2} != {2: 1}")
+        );
+        assert!(interpret(&compile("{1: 2}[2]").unwrap()).is_err());
+        assert!(interpret(&compile("{[]: 2}").unwrap()).is_err());
+    }
+
+    #[test]
+    fn variables_and_loops() {
+        assert_eq!(
+            Some(Value::Number(1.0)),
+            run("var a = 1; { var a = 2; a = 3; } a")
+        );
+        assert_eq!(
+            Some(Value::Number(6.0)),
+            run("var sum = 0; for (var i = 0; i < 4; i = i + 1) sum = sum + i; sum")
+        );
+        assert_eq!(
+            Some(Value::Number(2.0)),
+            run("var a; if (a) a = 1; else a = 2; a")
+        );
+    }
+
+    #[test]
+    fn thrown_values_are_caught() {
+        assert_eq!(
+            Some(Value::Number(3.0)),
+            run(
+                "var a = 0; try { try { throw 1; } finally { a = 2; } } catch (e) { a = a + e; } a"
+            )
+        );
+        assert_eq!(
+            Some(Value::Number(1.0)),
+            run("var a; { var b = 1; try { -nil; } catch (e) { a = b; } } a")
+        );
+    }
+
+    #[test]
+    fn natives_are_called() {
+        assert_eq!("1A", run("str(1) + upper(\"a\")").unwrap().to_string());
+        assert_eq!(
+            Some(Value::Number(8.0)),
+            run("pow(2, sqrt(9)) + len([]) * floor(0.5)")
+        );
+        assert!(interpret(&compile("nil()").unwrap()).is_err());
+    }
+
+    #[test]
+    fn type_errors() {
+        assert!(interpret(&compile("-\"a\"").unwrap()).is_err());
+        assert!(interpret(&compile("1 + true").unwrap()).is_err());
+    }
+
+    #[test]
+    fn fewer_dispatches_than_the_stack_vm() {
+        let source = "!(5 - 4 > 3 * 2 == !nil)";
+        let stack = interpreter::dispatch_count(&compiler::compile(source).unwrap()).unwrap();
+        let registers = dispatch_count(&compile(source).unwrap()).unwrap();
+        assert_eq!(12, stack);
+        assert_eq!(7, registers);
+    }
+}
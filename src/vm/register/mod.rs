@@ -0,0 +1,99 @@
+//! An experimental variant of the VM working with registers instead of
+//! a stack. It runs the same language as the stack VM, whose bytecode is
+//! translated into three-address instructions by the compiler.
+
+pub mod bytecode;
+pub mod compiler;
+pub mod interpreter;
+
+use natives::Capabilities;
+use std::io::{stdout, LineWriter};
+use user_interface::{LoxImplementation, RunError};
+use vm::interpreter::{Globals, Value};
+use vm::{compilation_failed, runtime_failed};
+
+/// Compiles the source and prints the resulting bytecode
+pub fn disassemble(source: &str) -> Result<(), RunError> {
+    let chunk = compiler::compile(source).map_err(compilation_failed)?;
+    let stdout = stdout();
+    let mut writer = LineWriter::new(stdout.lock());
+    bytecode::disassemble(&chunk, source, &mut writer).map_err(|e| RunError::IoError(e.to_string()))
+}
+
+/// Runs the source and returns how many instructions were executed
+pub fn dispatch_count(source: &str) -> Result<usize, RunError> {
+    let chunk = compiler::compile(source).map_err(compilation_failed)?;
+    interpreter::dispatch_count(&chunk).map_err(|e| RunError::RuntimeError(e.report()))
+}
+
+/// Keeps the global variables from one run to the next, for the REPL
+#[derive(Default)]
+pub struct RegisterVm {
+    globals: Globals,
+    capabilities: Capabilities,
+    arguments: Vec<String>,
+}
+
+impl RegisterVm {
+    fn define_system_access(&mut self) {
+        self.globals
+            .define_system_access(self.capabilities, &self.arguments);
+    }
+
+    /// Compiles the source without running it, for the benchmarks to time
+    /// the execution alone
+    pub fn compile(&self, source: &str) -> Result<bytecode::Chunk, RunError> {
+        compiler::compile(source).map_err(compilation_failed)
+    }
+
+    pub fn run_chunk(&mut self, chunk: &bytecode::Chunk) -> Result<(), RunError> {
+        interpreter::run(chunk, &mut self.globals).map_err(runtime_failed)?;
+        Ok(())
+    }
+}
+
+impl LoxImplementation for RegisterVm {
+    fn run(&mut self, source: &str) -> Result<(), RunError> {
+        let chunk = self.compile(source)?;
+        self.run_chunk(&chunk)
+    }
+
+    fn run_interactive(&mut self, source: &str) -> Result<(), RunError> {
+        let chunk = compiler::compile(source).map_err(compilation_failed)?;
+        let result = interpreter::run(&chunk, &mut self.globals).map_err(runtime_failed)?;
+        match result {
+            // Like the tree-walker, nothing is shown when there's no value
+            None | Some(Value::Nil) => {}
+            Some(value) => println!("{}", value),
+        }
+        Ok(())
+    }
+
+    fn globals(&self) -> Vec<(String, String)> {
+        self.globals.describe()
+    }
+
+    fn set_arguments(&mut self, arguments: &[String]) {
+        self.arguments = arguments.to_vec();
+        // os.args() gives them
+        self.define_system_access();
+    }
+
+    fn set_capabilities(&mut self, capabilities: Capabilities) {
+        self.capabilities = capabilities;
+        self.define_system_access();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use vm::register::*;
+
+    proptest! {
+    #[test]
+    fn doesnt_crash(ref input in "\\PC*") {
+        let mut lox_vm = RegisterVm::default();
+        lox_vm.run(input)
+    }
+    }
+}
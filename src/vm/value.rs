@@ -0,0 +1,264 @@
+//! How the VM stores values on its stack and in its constant table.
+//!
+//! By default `Packed` just wraps `Value`, which takes 16 bytes because
+//! of the tag and of the `Rc` in objects. With the `nan-boxing` feature
+//! values are packed in the 64 bits of a double instead: numbers are
+//! stored as they are, everything else hides in the payload of a quiet
+//! NaN, so arithmetic doesn't need to match on tags.
+//!
+//! Both representations have the same semantics and are converted to
+//! `Value` whenever they leave the VM.
+
+#[cfg(not(feature = "nan-boxing"))]
+pub use self::tagged::Packed;
+
+#[cfg(feature = "nan-boxing")]
+pub use self::nan_boxed::Packed;
+
+#[cfg(not(feature = "nan-boxing"))]
+mod tagged {
+    use std::fmt;
+    use vm::interpreter::Value;
+
+    #[derive(Clone, PartialEq)]
+    pub struct Packed(Value);
+
+    impl Packed {
+        pub fn nil() -> Packed {
+            Packed(Value::Nil)
+        }
+
+        pub fn boolean(b: bool) -> Packed {
+            Packed(Value::Bool(b))
+        }
+
+        pub fn number(n: f64) -> Packed {
+            Packed(Value::Number(n))
+        }
+
+        pub fn as_number(&self) -> Option<f64> {
+            match self.0 {
+                Value::Number(n) => Some(n),
+                _ => None,
+            }
+        }
+
+        pub fn is_falsey(&self) -> bool {
+            self.0.is_falsey()
+        }
+
+        pub fn to_value(&self) -> Value {
+            self.0.clone()
+        }
+    }
+
+    impl From<Value> for Packed {
+        fn from(value: Value) -> Packed {
+            Packed(value)
+        }
+    }
+
+    impl fmt::Debug for Packed {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            self.0.fmt(f)
+        }
+    }
+}
+
+#[cfg(feature = "nan-boxing")]
+mod nan_boxed {
+    use std::fmt;
+    use std::rc::Rc;
+    use vm::interpreter::{ObjectValue, Value};
+
+    /// The bits set in every value that isn't a number.
+    /// Besides the exponent they include the quiet NaN bit and the one
+    /// after it, which is never set by the NaNs produced by arithmetic.
+    const QUIET_NAN: u64 = 0x7ffc_0000_0000_0000;
+    /// Set, together with the quiet NaN bits, for pointers to objects
+    const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+    const NIL: u64 = QUIET_NAN | 1;
+    const FALSE: u64 = QUIET_NAN | 2;
+    const TRUE: u64 = QUIET_NAN | 3;
+    const OBJECT: u64 = QUIET_NAN | SIGN_BIT;
+
+    /// Objects keep the pointer of the `Rc` they come from, which is
+    /// only 48 bits long on the platforms we support. The packed value
+    /// owns one strong reference.
+    pub struct Packed(u64);
+
+    impl Packed {
+        pub fn nil() -> Packed {
+            Packed(NIL)
+        }
+
+        pub fn boolean(b: bool) -> Packed {
+            Packed(if b { TRUE } else { FALSE })
+        }
+
+        pub fn number(n: f64) -> Packed {
+            // Other NaNs could have the same bits as values that aren't
+            // numbers, they all behave the same anyway
+            if n.is_nan() {
+                Packed(f64::NAN.to_bits())
+            } else {
+                Packed(n.to_bits())
+            }
+        }
+
+        pub fn as_number(&self) -> Option<f64> {
+            if self.0 & QUIET_NAN == QUIET_NAN {
+                None
+            } else {
+                Some(f64::from_bits(self.0))
+            }
+        }
+
+        fn as_object(&self) -> Option<*const ObjectValue> {
+            if self.0 & OBJECT == OBJECT {
+                Some((self.0 & !OBJECT) as *const ObjectValue)
+            } else {
+                None
+            }
+        }
+
+        pub fn is_falsey(&self) -> bool {
+            self.0 == NIL || self.0 == FALSE
+        }
+
+        pub fn to_value(&self) -> Value {
+            if let Some(n) = self.as_number() {
+                return Value::Number(n);
+            }
+            if let Some(pointer) = self.as_object() {
+                // The strong reference owned by this value stays here
+                unsafe {
+                    Rc::increment_strong_count(pointer);
+                    return Value::Object(Rc::from_raw(pointer));
+                }
+            }
+            match self.0 {
+                NIL => Value::Nil,
+                FALSE => Value::Bool(false),
+                TRUE => Value::Bool(true),
+                bits => unreachable!("Invalid packed value {:x}", bits),
+            }
+        }
+    }
+
+    impl From<Value> for Packed {
+        fn from(value: Value) -> Packed {
+            match value {
+                Value::Number(n) => Packed::number(n),
+                Value::Bool(b) => Packed::boolean(b),
+                Value::Nil => Packed::nil(),
+                Value::Object(object) => {
+                    let pointer = Rc::into_raw(object) as u64;
+                    assert_eq!(0, pointer & OBJECT, "Pointers must fit in 48 bits");
+                    Packed(pointer | OBJECT)
+                }
+            }
+        }
+    }
+
+    impl Clone for Packed {
+        fn clone(&self) -> Packed {
+            if let Some(pointer) = self.as_object() {
+                unsafe { Rc::increment_strong_count(pointer) }
+            }
+            Packed(self.0)
+        }
+    }
+
+    impl Drop for Packed {
+        fn drop(&mut self) {
+            if let Some(pointer) = self.as_object() {
+                unsafe { Rc::decrement_strong_count(pointer) }
+            }
+        }
+    }
+
+    impl PartialEq for Packed {
+        fn eq(&self, other: &Packed) -> bool {
+            match (self.as_number(), other.as_number()) {
+                (Some(n1), Some(n2)) => n1 == n2,
+                (None, None) => match (self.as_object(), other.as_object()) {
+                    // Objects are compared by content, like `Value` does
+                    (Some(o1), Some(o2)) => unsafe { *o1 == *o2 },
+                    _ => self.0 == other.0,
+                },
+                _ => false,
+            }
+        }
+    }
+
+    impl fmt::Debug for Packed {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            self.to_value().fmt(f)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use std::rc::Rc;
+    use vm::interpreter::{ObjectValue, Value};
+    use vm::value::Packed;
+
+    fn arb_value() -> BoxedStrategy<Value> {
+        prop_oneof![
+            prop::num::f64::ANY.prop_map(Value::Number),
+            any::<bool>().prop_map(Value::Bool),
+            Just(Value::Nil),
+            "[a-z]{0,3}".prop_map(|s| Value::Object(Rc::new(ObjectValue::String(s)))),
+        ]
+        .boxed()
+    }
+
+    fn same(v1: &Value, v2: &Value) -> bool {
+        match (v1, v2) {
+            (Value::Number(n1), Value::Number(n2)) if n1.is_nan() => n2.is_nan(),
+            _ => v1 == v2,
+        }
+    }
+
+    proptest! {
+    #[test]
+    fn values_survive_packing(ref value in arb_value()) {
+        prop_assert!(same(value, &Packed::from(value.clone()).to_value()));
+    }
+
+    #[test]
+    fn equality_is_preserved(ref v1 in arb_value(), ref v2 in arb_value()) {
+        prop_assert_eq!(v1 == v2, Packed::from(v1.clone()) == Packed::from(v2.clone()));
+    }
+    }
+
+    #[test]
+    fn objects_are_reference_counted() {
+        let object = Rc::new(ObjectValue::String("a".into()));
+        let packed = Packed::from(Value::Object(object.clone()));
+        let copy = packed.clone();
+        assert_eq!(3, Rc::strong_count(&object));
+        drop(packed);
+        assert_eq!(Value::Object(object.clone()), copy.to_value());
+        drop(copy);
+        assert_eq!(1, Rc::strong_count(&object));
+    }
+
+    #[test]
+    fn falsey_values() {
+        assert!(Packed::nil().is_falsey());
+        assert!(Packed::boolean(false).is_falsey());
+        assert!(!Packed::boolean(true).is_falsey());
+        assert!(!Packed::number(0.0).is_falsey());
+        assert_eq!(Some(-0.0), Packed::number(-0.0).as_number());
+    }
+
+    #[cfg(feature = "nan-boxing")]
+    #[test]
+    fn values_take_64_bits() {
+        assert_eq!(8, ::std::mem::size_of::<Packed>());
+    }
+}
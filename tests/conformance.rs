@@ -0,0 +1,261 @@
+//! Runs the Lox programs in `tests/lox` against every backend and checks
+//! them against the annotations used by the craftinginterpreters test suite:
+//!
+//! - `// expect: output` for each line the program prints
+//! - `// expect runtime error: message` when the program fails at runtime
+//! - `// Error at 'x': message` or `// [line N] Error ...` for compile errors,
+//!   `[java line N]` and `[c line N]` being specific to one kind of backend
+//!
+//! Runtime errors are only checked by message, we don't report their line yet.
+//! Files in `lib` directories are the modules the tests import, not tests.
+//!
+//! The programs each backend is known to get wrong are listed in
+//! `tests/lox/<backend>.failing`: the test fails when any other program fails,
+//! and when one of those starts passing, so that the lists are kept up to date.
+//!
+//! Another tree of tests, e.g. the official suite, can be run by setting
+//! `RULOX_TEST_SUITE` to its path. In that case results are only reported.
+
+use std::collections::BTreeSet;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+#[derive(Clone, Copy)]
+struct Backend {
+    name: &'static str,
+    /// The prefix of error annotations specific to this kind of backend
+    dialect: &'static str,
+}
+
+const BACKENDS: [Backend; 4] = [
+    Backend {
+        name: "treewalk",
+        dialect: "java",
+    },
+    Backend {
+        name: "closures",
+        dialect: "java",
+    },
+    Backend {
+        name: "vm",
+        dialect: "c",
+    },
+    Backend {
+        name: "register",
+        dialect: "c",
+    },
+];
+
+#[derive(Default)]
+struct Expectations {
+    output: Vec<String>,
+    compile_errors: Vec<String>,
+    runtime_error: Option<String>,
+}
+
+impl Expectations {
+    fn parse(source: &str, backend: Backend) -> Expectations {
+        let mut expectations = Expectations::default();
+        for (index, line) in source.lines().enumerate() {
+            let comment = match line.find("// ") {
+                Some(start) => &line[start + 3..],
+                None => continue,
+            };
+            if comment.starts_with("expect: ") {
+                expectations.output.push(comment["expect: ".len()..].into());
+            } else if comment.starts_with("expect runtime error: ") {
+                expectations.runtime_error = Some(comment["expect runtime error: ".len()..].into());
+            } else if comment.starts_with("Error") {
+                expectations
+                    .compile_errors
+                    .push(format!("[line {}] {}", index + 1, comment));
+            } else if comment.starts_with('[') {
+                if let Some(error) = Expectations::parse_error_with_line(comment, backend) {
+                    expectations.compile_errors.push(error);
+                }
+            }
+        }
+        expectations
+    }
+
+    /// Parses `[line N] Error ...`, dropping the annotations meant for
+    /// the other kind of backend
+    fn parse_error_with_line(comment: &str, backend: Backend) -> Option<String> {
+        let end = comment.find(']')?;
+        let (position, error) = (&comment[1..end], comment[end + 1..].trim_start());
+        let line = match position.find("line ") {
+            Some(0) => &position["line ".len()..],
+            Some(start) if position[..start].trim() == backend.dialect => {
+                &position[start + "line ".len()..]
+            }
+            _ => return None,
+        };
+        if !error.starts_with("Error") {
+            return None;
+        }
+        Some(format!("[line {}] {}", line, error))
+    }
+
+    fn exit_code(&self) -> i32 {
+        if !self.compile_errors.is_empty() {
+            65
+        } else if self.runtime_error.is_some() {
+            70
+        } else {
+            0
+        }
+    }
+}
+
+/// Drops the column from the position of an error, which the reference
+/// implementations don't report
+fn without_column(error: &str) -> String {
+    match (error.find(", column "), error.find(']')) {
+        (Some(start), Some(end)) if error.starts_with("[line ") && start < end => {
+            format!("{}{}", &error[..start], &error[end..])
+        }
+        _ => error.into(),
+    }
+}
+
+/// Runs the program and returns what went wrong, if anything
+fn run_test(path: &Path, backend: Backend) -> Result<(), Vec<String>> {
+    let source = fs::read_to_string(path).map_err(|e| vec![e.to_string()])?;
+    let expectations = Expectations::parse(&source, backend);
+    let output = Command::new(env!("CARGO_BIN_EXE_rulox"))
+        .arg("--backend")
+        .arg(backend.name)
+        .arg(path)
+        .output()
+        .map_err(|e| vec![e.to_string()])?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let mut failures = vec![];
+
+    let printed: Vec<&str> = stdout.lines().collect();
+    if printed != expectations.output {
+        failures.push(format!(
+            "Expected output {:?}, got {:?}",
+            expectations.output, printed
+        ));
+    }
+    let errors: Vec<String> = stderr.lines().map(without_column).collect();
+    if !expectations.compile_errors.is_empty() && errors != expectations.compile_errors {
+        failures.push(format!(
+            "Expected compile errors {:?}, got {:?}",
+            expectations.compile_errors, errors
+        ));
+    }
+    if let Some(ref message) = expectations.runtime_error {
+        if errors.first() != Some(message) {
+            failures.push(format!(
+                "Expected runtime error {:?}, got {:?}",
+                message, errors
+            ));
+        }
+    }
+    let exit_code = output.status.code().unwrap_or(-1);
+    if exit_code != expectations.exit_code() {
+        failures.push(format!(
+            "Expected exit code {}, got {}",
+            expectations.exit_code(),
+            exit_code
+        ));
+    }
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(failures)
+    }
+}
+
+fn lox_files(directory: &Path, files: &mut Vec<PathBuf>) {
+    let mut entries: Vec<PathBuf> = fs::read_dir(directory)
+        .unwrap_or_else(|e| panic!("Cannot read {}: {}", directory.display(), e))
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            // Modules imported by the tests aren't tests themselves
+            if path.file_name().map_or(false, |name| name != "lib") {
+                lox_files(&path, files);
+            }
+        } else if path
+            .extension()
+            .map_or(false, |extension| extension == "lox")
+        {
+            files.push(path);
+        }
+    }
+}
+
+/// The tests listed in `<backend>.failing`, relative to the suite root
+fn known_failures(suite: &Path, backend: Backend) -> BTreeSet<String> {
+    let list = suite.join(format!("{}.failing", backend.name));
+    fs::read_to_string(list)
+        .unwrap_or_default()
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.to_string())
+        .collect()
+}
+
+fn relative_name(suite: &Path, path: &Path) -> String {
+    path.strip_prefix(suite)
+        .unwrap()
+        .to_string_lossy()
+        .replace('\\', "/")
+}
+
+#[test]
+fn conformance() {
+    let (suite, strict) = match env::var("RULOX_TEST_SUITE") {
+        Ok(path) => (PathBuf::from(path), false),
+        Err(_) => (
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/lox"),
+            true,
+        ),
+    };
+    let mut files = vec![];
+    lox_files(&suite, &mut files);
+
+    let mut problems = vec![];
+    for backend in &BACKENDS {
+        let known_failures = known_failures(&suite, *backend);
+        let mut failing = BTreeSet::new();
+        for path in &files {
+            let name = relative_name(&suite, path);
+            if let Err(failures) = run_test(path, *backend) {
+                if !known_failures.contains(&name) {
+                    problems.push(format!(
+                        "{} [{}]:\n  {}",
+                        name,
+                        backend.name,
+                        failures.join("\n  ")
+                    ));
+                }
+                failing.insert(name);
+            }
+        }
+        for name in known_failures.difference(&failing) {
+            problems.push(format!(
+                "{} [{}]: passes now, remove it from {}.failing",
+                name, backend.name, backend.name
+            ));
+        }
+        println!(
+            "{}: {} passed, {} failed out of {}",
+            backend.name,
+            files.len() - failing.len(),
+            failing.len(),
+            files.len()
+        );
+    }
+    if strict && !problems.is_empty() {
+        panic!("Unexpected results:\n{}", problems.join("\n"));
+    }
+}
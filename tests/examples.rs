@@ -0,0 +1,125 @@
+//! Snapshot tests for the programs in `examples/`.
+//!
+//! Every example is run by both binaries and what they print, together
+//! with the exit code, is compared against the snapshots checked in under
+//! `tests/snapshots/<binary>/<example>.expected`, except for the examples
+//! using features the binary's backend doesn't support.
+//!
+//! Run with `RULOX_BLESS=1` to write the snapshots instead, e.g. after
+//! adding an example or changing the output on purpose, and review the diff.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+struct Binary {
+    name: &'static str,
+    path: &'static str,
+    /// The examples its backend can't run yet
+    unsupported: &'static [&'static str],
+}
+
+const BINARIES: [Binary; 2] = [
+    Binary {
+        name: "rulox",
+        path: env!("CARGO_BIN_EXE_rulox"),
+        unsupported: &[],
+    },
+    Binary {
+        name: "ruloxvm",
+        path: env!("CARGO_BIN_EXE_ruloxvm"),
+        // The VM doesn't compile functions and classes so far
+        unsupported: &[
+            "bacon.lox",
+            "cake.lox",
+            "closure.lox",
+            "donut.lox",
+            "eclair.lox",
+            "fibonacci_fun.lox",
+            "init.lox",
+            "lexical_scoping.lox",
+        ],
+    },
+];
+
+/// Examples that can't be snapshotted, with the reason why
+const SKIPPED: [(&str, &str); 1] = [(
+    "fibonacci_40.lox",
+    "it's a benchmark: it's slow and prints how long it took",
+)];
+
+fn examples(directory: &Path) -> Vec<PathBuf> {
+    let mut examples: Vec<PathBuf> = fs::read_dir(directory)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| {
+            path.extension()
+                .map_or(false, |extension| extension == "lox")
+        })
+        .filter(|path| {
+            let name = path.file_name().unwrap().to_string_lossy();
+            !SKIPPED.iter().any(|&(skipped, _)| name == skipped)
+        })
+        .collect();
+    examples.sort();
+    examples
+}
+
+/// Runs the example and describes everything it did in a single string
+fn snapshot(binary: &str, example: &Path) -> String {
+    let output = Command::new(binary)
+        .arg(example)
+        .output()
+        .unwrap_or_else(|e| panic!("Cannot run {}: {}", binary, e));
+    format!(
+        "exit code: {}\n--- stdout\n{}--- stderr\n{}",
+        output
+            .status
+            .code()
+            .map_or_else(|| "none".to_string(), |code| code.to_string()),
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    )
+}
+
+#[test]
+fn examples_match_their_snapshots() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let bless = env::var("RULOX_BLESS").is_ok();
+    let mut mismatches = vec![];
+    for example in examples(&root.join("examples")) {
+        let file_name = example.file_name().unwrap().to_string_lossy().into_owned();
+        for binary in &BINARIES {
+            if binary.unsupported.contains(&file_name.as_str()) {
+                continue;
+            }
+            let actual = snapshot(binary.path, &example);
+            let snapshot_file = root
+                .join("tests/snapshots")
+                .join(binary.name)
+                .join(example.with_extension("expected").file_name().unwrap());
+            if bless {
+                fs::create_dir_all(snapshot_file.parent().unwrap()).unwrap();
+                fs::write(&snapshot_file, &actual).unwrap();
+                continue;
+            }
+            match fs::read_to_string(&snapshot_file) {
+                Ok(ref expected) if *expected == actual => {}
+                Ok(expected) => mismatches.push(format!(
+                    "{} differs\nexpected:\n{}\nactual:\n{}",
+                    snapshot_file.display(),
+                    expected,
+                    actual
+                )),
+                Err(_) => mismatches.push(format!(
+                    "{} is missing, run with RULOX_BLESS=1 to create it",
+                    snapshot_file.display()
+                )),
+            }
+        }
+    }
+    if !mismatches.is_empty() {
+        panic!("{}", mismatches.join("\n"));
+    }
+}